@@ -0,0 +1,74 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Plays a recording made with `sielo-core --record <file>` on a fresh core
+//! and prints the replies which changed.
+//!
+//! Usage: `sielo-replay <file> [--database file] [--settings file] [--ignore key,key]`
+//!
+//! `--database` and `--settings` give the database and settings file of the
+//! profile the recording was made on, for the core to start from. Both are
+//! copied and never written.
+
+use std::path::Path;
+use std::process;
+
+use sielo_core::ipc::{recorder, replay};
+
+fn main() {
+    let arguments = match arguments::parse(std::env::args()) {
+        Ok(t) => t,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(2);
+        }
+    };
+    let path = match arguments.orphans.first() {
+        Some(t) => t,
+        None => {
+            println!("Usage: sielo-replay <file> [--database file] [--settings file] [--ignore key,key]");
+            process::exit(2);
+        }
+    };
+
+    let frames = match recorder::read(path) {
+        Ok(t) => t,
+        Err(e) => {
+            println!("{:?}", e);
+            process::exit(2);
+        }
+    };
+    let database = arguments.get::<String>("database");
+    let settings = arguments.get::<String>("settings");
+    let mut replay = match replay::Replay::from_snapshot(database.as_ref().map(Path::new), settings.as_ref().map(Path::new)) {
+        Ok(t) => t,
+        Err(e) => {
+            println!("{:?}", e);
+            process::exit(2);
+        }
+    };
+    if let Some(keys) = arguments.get::<String>("ignore") {
+        for key in keys.split(',').filter(|k| !k.is_empty()) {
+            replay.ignore(key);
+        }
+    }
+
+    let report = match replay.run(&frames) {
+        Ok(t) => t,
+        Err(e) => {
+            println!("{:?}", e);
+            process::exit(2);
+        }
+    };
+    for m in &report.mismatches {
+        println!("Request {} ({}) of client {} differs:", m.id, m.method, m.client);
+        println!("  expected: {:?}", m.expected);
+        println!("  actual:   {:?}", m.actual);
+    }
+    println!("{} requests replayed, {} differ", report.requests, report.mismatches.len());
+    if !report.mismatches.is_empty() {
+        process::exit(1);
+    }
+}
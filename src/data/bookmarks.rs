@@ -0,0 +1,133 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Bookmarks system
+//!
+//! Bookmarks are stored as a tree in the `bookmarks` table: folders have no
+//! URL and every item points to its folder through `parent`. Items with no
+//! parent are at the root of the tree.
+
+use super::db::{Error, TableProvider, FieldType, FieldParameter, FieldValue};
+use super::time::Timestamp;
+
+/// Name of the table used to store the bookmarks.
+pub const TABLE : &str = "bookmarks";
+/// Index used to find the bookmarks of a URL.
+pub const URL_INDEX : &str = "bookmarks_url";
+
+pub struct Bookmarks<'a, T : TableProvider> {
+    db : &'a mut T,
+}
+
+impl<'a, T : TableProvider> Bookmarks<'a, T> {
+    /// Opens the bookmarks stored in `db`, creating or upgrading the table
+    /// when needed.
+    pub fn new(db : &'a mut T) -> Result<Self, Error> {
+        db.use_table(TABLE, &[
+            ("id", &FieldType::Integer, &[FieldParameter::AutoIncrement]),
+            ("url", &FieldType::Text, &[]),
+            ("title", &FieldType::Text, &[FieldParameter::Default(String::new())]),
+            ("date", &FieldType::Integer, &[FieldParameter::Default(String::from("0"))]),
+            ("parent", &FieldType::Integer, &[]),
+        ], false, false)?;
+        db.request(&format!("CREATE INDEX IF NOT EXISTS {} ON {} (url);", URL_INDEX, TABLE), &[])?;
+        Ok(Self { db })
+    }
+
+    /// Gives access to the underlying database.
+    pub fn db(&mut self) -> &mut T {
+        self.db
+    }
+
+    /// Adds a bookmark to `url` in the folder `parent` and returns its id.
+    pub fn add_bookmark(&mut self,
+                        url : &str,
+                        title : &str,
+                        date : Timestamp,
+                        parent : Option<i64>) -> Result<i64, Error> {
+        self.insert(Some(url), title, date, parent)
+    }
+
+    /// Returns the folder named `title` in `parent`, creating it if it does
+    /// not exist yet.
+    pub fn folder(&mut self, title : &str, parent : Option<i64>) -> Result<i64, Error> {
+        let rows = match parent {
+            Some(p) => self.db.request(
+                &format!("SELECT id FROM {} WHERE url IS NULL AND title = ? AND parent = ?;", TABLE),
+                &[title, p.to_string().as_str()]),
+            None => self.db.request(
+                &format!("SELECT id FROM {} WHERE url IS NULL AND title = ? AND parent IS NULL;", TABLE),
+                &[title]),
+        };
+        match rows {
+            Ok(t) => {
+                if let Some(Some(FieldValue::Integer(id))) = t.first().and_then(|r| r.get("id")) {
+                    return Ok(*id);
+                }
+            },
+            Err(e) => return Err(e),
+        }
+
+        self.insert(None, title, Timestamp::now(), parent)
+    }
+
+    /// Checks if `url` is already bookmarked, in any folder.
+    pub fn contains(&mut self, url : &str) -> Result<bool, Error> {
+        match self.db.request(&format!("SELECT COUNT(*) AS count FROM {} WHERE url = ?;", TABLE), &[url]) {
+            Ok(t) => match t.first().and_then(|r| r.get("count")) {
+                Some(Some(FieldValue::Integer(v))) => Ok(*v != 0),
+                _ => Ok(false),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Removes a bookmark or a folder with everything it contains.
+    pub fn remove(&mut self, id : i64) -> Result<(), Error> {
+        self.db.transaction(|db| {
+            let mut to_remove = vec![id];
+
+            while let Some(current) = to_remove.pop() {
+                match db.request(&format!("SELECT id FROM {} WHERE parent = ?;", TABLE),
+                                 &[current.to_string().as_str()]) {
+                    Ok(t) => for row in t {
+                        if let Some(Some(FieldValue::Integer(child))) = row.get("id") {
+                            to_remove.push(*child);
+                        }
+                    },
+                    Err(e) => return Err(e),
+                }
+                db.request(&format!("DELETE FROM {} WHERE id = ?;", TABLE),
+                                           &[current.to_string().as_str()])?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn insert(&mut self,
+              url : Option<&str>,
+              title : &str,
+              date : Timestamp,
+              parent : Option<i64>) -> Result<i64, Error> {
+        let date = date.to_string();
+        let res = match (url, parent) {
+            (Some(u), Some(p)) => self.db.request(
+                &format!("INSERT INTO {} (url, title, date, parent) VALUES (?, ?, ?, ?);", TABLE),
+                &[u, title, date.as_str(), p.to_string().as_str()]),
+            (Some(u), None) => self.db.request(
+                &format!("INSERT INTO {} (url, title, date) VALUES (?, ?, ?);", TABLE),
+                &[u, title, date.as_str()]),
+            (None, Some(p)) => self.db.request(
+                &format!("INSERT INTO {} (title, date, parent) VALUES (?, ?, ?);", TABLE),
+                &[title, date.as_str(), p.to_string().as_str()]),
+            (None, None) => self.db.request(
+                &format!("INSERT INTO {} (title, date) VALUES (?, ?);", TABLE),
+                &[title, date.as_str()]),
+        };
+        res?;
+        self.db.last_insert_id()
+    }
+}
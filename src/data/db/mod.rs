@@ -22,7 +22,7 @@ pub enum FieldType {
     Unknown,
 }
 
-#[derive(Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub enum FieldValue {
     Integer(i64),
     Real(f64),
@@ -39,12 +39,29 @@ pub enum FieldParameter {
     Default(String),
 }
 
-#[derive(Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct Error {
     code : Option<isize>,
     message : Option<String>,
 }
 
+impl Error {
+    pub fn new(code : Option<isize>, message : Option<String>) -> Self {
+        Self { code, message }
+    }
+
+    pub fn code(&self) -> Option<isize> {
+        self.code
+    }
+
+    pub fn message(&self) -> Option<&str> {
+        match &self.message {
+            Some(t) => Some(t.as_str()),
+            None => None,
+        }
+    }
+}
+
 
 pub trait TableProvider {
     type TableProviderType : TableProvider;
@@ -59,6 +76,65 @@ pub trait TableProvider {
     fn request(&mut self, req : &str, arguments : &[&str])
         -> Result<Vec<HashMap<String,Option<FieldValue>>>, Error>;
 
+    /// Same as `request`, but arguments keep their type. Needed to store
+    /// blobs.
+    fn request_values(&mut self, req : &str, arguments : &[FieldValue])
+        -> Result<Vec<HashMap<String,Option<FieldValue>>>, Error>;
+
+    /// Number of rows modified by the last `INSERT`, `UPDATE` or `DELETE`
+    /// request.
+    fn changes(&mut self) -> Result<usize, Error> {
+        match self.request("SELECT changes() AS changes;", &[]) {
+            Ok(t) => match t.first().and_then(|r| r.get("changes")) {
+                Some(Some(FieldValue::Integer(v))) => Ok(*v as usize),
+                _ => Ok(0),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Row id of the last row inserted through this provider.
+    fn last_insert_id(&mut self) -> Result<i64, Error> {
+        match self.request("SELECT last_insert_rowid() AS id;", &[]) {
+            Ok(t) => match t.first().and_then(|r| r.get("id")) {
+                Some(Some(FieldValue::Integer(v))) => Ok(*v),
+                _ => Ok(0),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Runs `f` inside a transaction. Everything done by `f` is committed if
+    /// it returns `Ok`, or rolled back otherwise. Transactions can be nested.
+    fn transaction<R, F>(&mut self, f : F) -> Result<R, Error>
+        where F : FnOnce(&mut Self) -> Result<R, Error>, Self : Sized {
+        self.request("SAVEPOINT sielo_transaction;", &[])?;
+        match f(self) {
+            Ok(t) => match self.request("RELEASE sielo_transaction;", &[]) {
+                Ok(_) => Ok(t),
+                Err(e) => {
+                    let _ = self.request("ROLLBACK TO sielo_transaction;", &[]);
+                    let _ = self.request("RELEASE sielo_transaction;", &[]);
+                    Err(e)
+                }
+            },
+            Err(e) => {
+                let _ = self.request("ROLLBACK TO sielo_transaction;", &[]);
+                let _ = self.request("RELEASE sielo_transaction;", &[]);
+                Err(e)
+            }
+        }
+    }
+
+    /// Runs `f`, aborting the request in progress as soon as `stop`
+    /// returns `true`. The aborted request fails, as does `f` most of the
+    /// time. Providers unable to abort a request just run `f`.
+    fn interruptible<R, F>(&mut self, stop : &dyn Fn() -> bool, f : F) -> Result<R, Error>
+        where F : FnOnce(&mut Self) -> Result<R, Error>, Self : Sized {
+        let _ = stop;
+        f(self)
+    }
+
     fn use_correct_format(val : &str) -> bool {
         const LETTER_RANGE : (&u8,&u8) = (&97u8, &122u8);
         const DIGIT_RANGE : (&u8,&u8) = (&48u8, &57u8);
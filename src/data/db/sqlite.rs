@@ -6,12 +6,23 @@
 //! Abstraction layer implementation for SQLite
 
 extern crate sqlite;
+extern crate sqlite3_sys as ffi;
 
+use std::any::Any;
+use std::cell::Cell;
 use std::collections::hash_map::HashMap;
+use std::os::raw::{c_int, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
 use super::{ Error, TableProvider, FieldType, FieldParameter, FieldValue };
 use crate::data::history::Field;
 use crate::data::db::FieldType::Integer;
 
+/// Number of virtual machine instructions between two checks of an
+/// [interruptible](../trait.TableProvider.html#method.interruptible)
+/// request.
+const PROGRESS_STEPS : c_int = 1000;
+
 pub struct SQLite {
     db : sqlite::Connection,
 }
@@ -24,6 +35,38 @@ impl SQLite {
         }
     }
 
+    /// Opens an existing database without ever writing to it, e.g. the
+    /// profile of another browser.
+    pub fn open_read_only<T: AsRef<std::path::Path>>(db_path : T) -> Result<Self, Error> {
+        match sqlite::Connection::open_with_flags(db_path, sqlite::OpenFlags::new().set_read_only()) {
+            Ok(t) => Ok(Self { db: t }),
+            Err(e) => Err(Error { code: e.code, message: e.message })
+        }
+    }
+
+    /// In-memory copy of the database `db_path`, which is only read: changes
+    /// made to the copy are lost once it is dropped.
+    pub fn copy_in_memory<T: AsRef<std::path::Path>>(db_path : T) -> Result<Self, Error> {
+        let source = Self::open_read_only(db_path)?;
+        let ret = Self::new(":memory:")?;
+
+        let main = b"main\0".as_ptr() as *const std::os::raw::c_char;
+        let code = unsafe {
+            let backup = ffi::sqlite3_backup_init(ret.db.as_raw(), main, source.db.as_raw(), main);
+            if backup.is_null() {
+                ffi::sqlite3_errcode(ret.db.as_raw())
+            } else {
+                let step = ffi::sqlite3_backup_step(backup, -1);
+                let finish = ffi::sqlite3_backup_finish(backup);
+                if step == ffi::SQLITE_DONE { finish } else { step }
+            }
+        };
+        if code != ffi::SQLITE_OK {
+            return Err(Error { code: Some(code as isize), message: Some(String::from("Can not copy the database")) });
+        }
+        Ok(ret)
+    }
+
     pub fn have_table(&mut self, name : &str) -> Result<bool, Error> {
         match self.db.prepare("SELECT COUNT(*) FROM sqlite_master WHERE type='table' AND name=?;") {
             Ok(mut t) => {
@@ -259,7 +302,7 @@ impl SQLite {
         }
         if pk_decl_later {
             if let Some(pk) = primary_key {
-                com += format!(",PRIMARY KEY({})", Self::use_correct_format(pk.as_str())).as_str();
+                com += format!(",PRIMARY KEY({})", pk).as_str();
             }
         }
         com += ");";
@@ -297,6 +340,65 @@ impl SQLite {
 
         Ok(())
     }
+
+    fn read_rows(statement : &mut sqlite::Statement)
+            -> Result<Vec<HashMap<String,Option<FieldValue>>>, Error> {
+        let mut ret = Vec::<HashMap<String,Option<FieldValue>>>::new();
+
+        loop {
+            match statement.next() {
+                Ok(val) => {
+                    if let sqlite::State::Done = val {
+                        break;
+                    }
+                },
+                Err(e) => return Err(Error { code: e.code, message: e.message}),
+            }
+
+            let mut vals = HashMap::<String,Option<FieldValue>>::with_capacity(statement.count());
+
+            for i in 0..statement.count() {
+                let to_add_name = statement.name(i);
+                let to_add_value = match statement.kind(i) {
+                    sqlite::Type::String => {
+                        let tmp = match statement.read::<String>(i) {
+                            Ok(t) => t,
+                            Err(e) => return Err(Error { code: e.code, message: e.message }),
+                        };
+                        Some(FieldValue::Text(tmp))
+                    },
+                    sqlite::Type::Integer => {
+                        let tmp = match statement.read::<i64>(i) {
+                            Ok(t) => t,
+                            Err(e) => return Err(Error { code: e.code, message: e.message }),
+                        };
+                        Some(FieldValue::Integer(tmp))
+                    },
+                    sqlite::Type::Float => {
+                        let tmp = match statement.read::<f64>(i) {
+                            Ok(t) => t,
+                            Err(e) => return Err(Error { code: e.code, message: e.message }),
+                        };
+                        Some(FieldValue::Real(tmp))
+                    },
+                    sqlite::Type::Binary => {
+                        let tmp = match statement.read::<Vec<u8>>(i) {
+                            Ok(t) => t,
+                            Err(e) => return Err(Error { code: e.code, message: e.message }),
+                        };
+                        Some(FieldValue::Blob(tmp))
+                    },
+                    sqlite::Type::Null => None,
+                };
+
+                vals.insert(String::from(to_add_name), to_add_value);
+            }
+
+            ret.push(vals);
+        }
+
+        Ok(ret)
+    }
 }
 
 impl TableProvider for SQLite {
@@ -374,67 +476,151 @@ impl TableProvider for SQLite {
         match self.db.prepare(req) {
             Ok(mut statement) => {
                 for i in 0..arguments.len() {
-                    if let Err(e) = statement.bind(i, arguments[i]) {
+                    if let Err(e) = statement.bind(i + 1, arguments[i]) {
                         return Err(Error { code: e.code, message: e.message});
                     }
                 }
-                let mut ret = Vec::<HashMap<String,Option<FieldValue>>>::new();
+                Self::read_rows(&mut statement)
+            },
+            Err(e) => Err(Error { code: e.code, message: e.message}),
+        }
+    }
 
-                loop {
-                    match statement.next() {
-                        Ok(val) => {
-                            if let sqlite::State::Done = val {
-                                break;
-                            }
-                        },
-                        Err(e) => return Err(Error { code: e.code, message: e.message}),
+    fn request_values(&mut self, req : &str, arguments : &[FieldValue])
+               -> Result<Vec<HashMap<String,Option<FieldValue>>>, Error> {
+        match self.db.prepare(req) {
+            Ok(mut statement) => {
+                for (i, argument) in arguments.iter().enumerate() {
+                    let res = match argument {
+                        FieldValue::Integer(v) => statement.bind(i + 1, *v),
+                        FieldValue::Real(v) => statement.bind(i + 1, *v),
+                        FieldValue::Text(v) => statement.bind(i + 1, v.as_str()),
+                        FieldValue::Blob(v) => statement.bind(i + 1, v.as_slice()),
+                    };
+                    if let Err(e) = res {
+                        return Err(Error { code: e.code, message: e.message});
                     }
+                }
+                Self::read_rows(&mut statement)
+            },
+            Err(e) => Err(Error { code: e.code, message: e.message}),
+        }
+    }
 
-                    let mut vals = HashMap::<String,Option<FieldValue>>::with_capacity(statement.count());
-
-                    for i in 0..statement.count() {
-                        let to_add_name = statement.name(i);
-                        let to_add_value = match statement.kind(i) {
-                            sqlite::Type::String => {
-                                let tmp = match statement.read::<String>(i) {
-                                    Ok(t) => t,
-                                    Err(e) => return Err(Error { code: e.code, message: e.message }),
-                                };
-                                Some(FieldValue::Text(tmp))
-                            },
-                            sqlite::Type::Integer => {
-                                let tmp = match statement.read::<i64>(i) {
-                                    Ok(t) => t,
-                                    Err(e) => return Err(Error { code: e.code, message: e.message }),
-                                };
-                                Some(FieldValue::Integer(tmp))
-                            },
-                            sqlite::Type::Float => {
-                                let tmp = match statement.read::<f64>(i) {
-                                    Ok(t) => t,
-                                    Err(e) => return Err(Error { code: e.code, message: e.message }),
-                                };
-                                Some(FieldValue::Real(tmp))
-                            },
-                            sqlite::Type::Binary => {
-                                let tmp = match statement.read::<Vec<u8>>(i) {
-                                    Ok(t) => t,
-                                    Err(e) => return Err(Error { code: e.code, message: e.message }),
-                                };
-                                Some(FieldValue::Blob(tmp))
-                            },
-                            sqlite::Type::Null => None,
-                        };
+    /// Uses the progress handler of SQLite, which interrupts the statement
+    /// being run, as `sqlite3_interrupt` does, when it returns non zero.
+    /// Nested calls are not supported: the inner one removes the handler.
+    ///
+    /// The handler stops calling `stop` once it interrupted a statement, so
+    /// the rollback which follows can not be interrupted too. A panic of
+    /// `stop` can not unwind through SQLite: it interrupts the statement and
+    /// is resumed once `f` returns.
+    fn interruptible<R, F>(&mut self, stop : &dyn Fn() -> bool, f : F) -> Result<R, Error>
+        where F : FnOnce(&mut Self) -> Result<R, Error> {
+        struct State<'a> {
+            stop : &'a dyn Fn() -> bool,
+            stopped : Cell<bool>,
+            panic : Cell<Option<Box<dyn Any + Send>>>,
+        }
 
-                        vals.insert(String::from(to_add_name), to_add_value);
-                    }
+        extern "C" fn progress(data : *mut c_void) -> c_int {
+            let state = unsafe { &*(data as *const State) };
+            if state.stopped.get() {
+                return 0;
+            }
+            let stop = match panic::catch_unwind(AssertUnwindSafe(state.stop)) {
+                Ok(t) => t,
+                Err(e) => {
+                    state.panic.set(Some(e));
+                    true
+                },
+            };
+            state.stopped.set(stop);
+            if stop { 1 } else { 0 }
+        }
 
-                    ret.push(vals);
-                }
+        // Removes the handler before `state` goes out of scope, even if `f`
+        // panics.
+        struct Handler(*mut ffi::sqlite3);
+        impl Drop for Handler {
+            fn drop(&mut self) {
+                unsafe { ffi::sqlite3_progress_handler(self.0, 0, None, ptr::null_mut()) }
+            }
+        }
 
-                return Ok(ret);
-            },
-            Err(e) => return Err(Error { code: e.code, message: e.message}),
+        let state = State { stop, stopped: Cell::new(false), panic: Cell::new(None) };
+        let data = &state as *const State as *mut c_void;
+        unsafe {
+            ffi::sqlite3_progress_handler(self.db.as_raw(), PROGRESS_STEPS, Some(progress), data);
+        }
+        let handler = Handler(self.db.as_raw());
+        let ret = f(self);
+        drop(handler);
+        if let Some(e) = state.panic.take() {
+            panic::resume_unwind(e);
         }
+        ret
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::panic::{self, AssertUnwindSafe};
+
+    use crate::data::db::{TableProvider, FieldValue};
+    use super::SQLite;
+
+    /// Runs longer than a few progress steps.
+    const SLOW : &str = "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 1000000) SELECT COUNT(*) FROM n;";
+
+    fn rows(db : &mut SQLite) -> i64 {
+        match db.request("SELECT COUNT(*) AS n FROM t;", &[]).unwrap()[0].get("n") {
+            Some(Some(FieldValue::Integer(t))) => *t,
+            _ => panic!("no count"),
+        }
+    }
+
+    fn database() -> SQLite {
+        let mut db = SQLite::new(":memory:").unwrap();
+        db.request("CREATE TABLE t (i INTEGER);", &[]).unwrap();
+        db.request("WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 1000) INSERT INTO t SELECT i FROM n;", &[]).unwrap();
+        db
+    }
+
+    #[test]
+    fn interrupted_transactions_are_rolled_back() {
+        let mut db = database();
+        let armed = Cell::new(false);
+        let result = db.interruptible(&|| armed.get(), |db| {
+            let cleared = db.transaction(|db| {
+                db.request("DELETE FROM t;", &[])?;
+                armed.set(true);
+                db.request(SLOW, &[])
+            });
+            assert!(cleared.is_err());
+            // Like the rollback, statements run after the interruption end.
+            db.request(SLOW, &[])
+        });
+        assert!(result.is_ok());
+        assert_eq!(rows(&mut db), 1000);
+
+        // The handler is gone once `interruptible` returns.
+        assert!(db.request(SLOW, &[]).is_ok());
+    }
+
+    #[test]
+    fn panics_of_stop_interrupt_then_unwind() {
+        let mut db = database();
+        let armed = Cell::new(false);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            db.interruptible(&|| if armed.get() { panic!("stop") } else { false }, |db| db.transaction(|db| {
+                db.request("DELETE FROM t;", &[])?;
+                armed.set(true);
+                db.request(SLOW, &[])
+            }))
+        }));
+        assert_eq!(result.unwrap_err().downcast_ref::<&str>(), Some(&"stop"));
+        assert_eq!(rows(&mut db), 1000);
+    }
+}
@@ -0,0 +1,360 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Favicon store
+//!
+//! Icons are stored once, whatever the number of pages using them:
+//!  * `favicon_data` holds the image bytes, one row per distinct content,
+//!  * `favicons` links an icon URL to each of its resolutions,
+//!  * `favicon_pages` links a page URL to the icon URL it uses.
+
+use super::db::{Error, TableProvider, FieldType, FieldParameter, FieldValue};
+use super::history;
+use super::bookmarks;
+use super::time::Timestamp;
+
+pub const DATA_TABLE : &str = "favicon_data";
+pub const ICONS_TABLE : &str = "favicons";
+pub const PAGES_TABLE : &str = "favicon_pages";
+
+/// One resolution of an icon.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Icon {
+    pub url : String,
+    /// Width in pixels, 0 if unknown.
+    pub width : i64,
+    /// Height in pixels, 0 if unknown.
+    pub height : i64,
+    pub data : Vec<u8>,
+    /// `None` if the icon never expires.
+    pub expires : Option<Timestamp>,
+}
+
+pub struct Favicons<'a, T : TableProvider> {
+    db : &'a mut T,
+}
+
+impl<'a, T : TableProvider> Favicons<'a, T> {
+    /// Opens the favicon store of `db`, creating its tables when needed.
+    pub fn new(db : &'a mut T) -> Result<Self, Error> {
+        db.use_table(DATA_TABLE, &[
+            ("id", &FieldType::Integer, &[FieldParameter::AutoIncrement]),
+            ("hash", &FieldType::Text, &[FieldParameter::NoNull]),
+            ("width", &FieldType::Integer, &[FieldParameter::Default(String::from("0"))]),
+            ("height", &FieldType::Integer, &[FieldParameter::Default(String::from("0"))]),
+            ("data", &FieldType::Blob, &[FieldParameter::NoNull]),
+        ], false, false)?;
+        db.use_table(ICONS_TABLE, &[
+            ("id", &FieldType::Integer, &[FieldParameter::AutoIncrement]),
+            ("url", &FieldType::Text, &[FieldParameter::NoNull]),
+            ("data", &FieldType::Integer, &[FieldParameter::NoNull]),
+            ("expires", &FieldType::Integer, &[FieldParameter::Default(String::from("0"))]),
+        ], false, false)?;
+        db.use_table(PAGES_TABLE, &[
+            ("page", &FieldType::Text, &[FieldParameter::NoNull, FieldParameter::PrimaryKey]),
+            ("icon", &FieldType::Text, &[FieldParameter::NoNull]),
+        ], false, false)?;
+
+        Ok(Self { db })
+    }
+
+    /// Gives access to the underlying database.
+    pub fn db(&mut self) -> &mut T {
+        self.db
+    }
+
+    /// Stores one resolution of the icon `url`.
+    ///
+    /// A resolution already known for this URL is replaced, and identical
+    /// images are only stored once even when used by several URLs.
+    pub fn store(&mut self,
+                 url : &str,
+                 width : i64,
+                 height : i64,
+                 data : &[u8],
+                 expires : Option<Timestamp>) -> Result<(), Error> {
+        let hash = content_hash(data);
+
+        self.db.transaction(|db| {
+            let existing = match db.request_values(
+                &format!("SELECT id, data FROM {} WHERE hash = ? AND width = ? AND height = ?;", DATA_TABLE),
+                &[FieldValue::Text(hash.clone()), FieldValue::Integer(width), FieldValue::Integer(height)]) {
+                Ok(t) => t,
+                Err(e) => return Err(e),
+            };
+            let mut data_id = None;
+            for row in existing {
+                if let (Some(Some(FieldValue::Integer(id))), Some(Some(FieldValue::Blob(bytes)))) =
+                        (row.get("id"), row.get("data")) {
+                    if bytes.as_slice() == data {
+                        data_id = Some(*id);
+                        break;
+                    }
+                }
+            }
+            let data_id = match data_id {
+                Some(t) => t,
+                None => {
+                    db.request_values(
+                        &format!("INSERT INTO {} (hash, width, height, data) VALUES (?, ?, ?, ?);", DATA_TABLE),
+                        &[FieldValue::Text(hash.clone()), FieldValue::Integer(width),
+                          FieldValue::Integer(height), FieldValue::Blob(data.to_vec())])?;
+                    match db.last_insert_id() {
+                        Ok(t) => t,
+                        Err(e) => return Err(e),
+                    }
+                },
+            };
+
+            // Replace the previous image of the same resolution.
+            db.request_values(
+                &format!("DELETE FROM {i} WHERE url = ? AND data IN \
+                           (SELECT id FROM {d} WHERE width = ? AND height = ?);",
+                          i = ICONS_TABLE, d = DATA_TABLE),
+                &[FieldValue::Text(String::from(url)), FieldValue::Integer(width), FieldValue::Integer(height)])?;
+            db.request_values(
+                &format!("INSERT INTO {} (url, data, expires) VALUES (?, ?, ?);", ICONS_TABLE),
+                &[FieldValue::Text(String::from(url)), FieldValue::Integer(data_id),
+                  FieldValue::Integer(match expires {
+                      Some(t) => t.as_millis(),
+                      None => 0,
+                  })])?;
+
+            remove_orphans(db)
+        })
+    }
+
+    /// Records that `page` uses the icon `icon`.
+    pub fn map_page(&mut self, page : &str, icon : &str) -> Result<(), Error> {
+        match self.db.request(
+            &format!("INSERT OR REPLACE INTO {} (page, icon) VALUES (?, ?);", PAGES_TABLE),
+            &[page, icon]) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Every resolution known for the icon `url`, smallest first.
+    pub fn icons(&mut self, url : &str) -> Result<Vec<Icon>, Error> {
+        match self.db.request(
+            &format!("SELECT i.url AS url, i.expires AS expires, d.width AS width, d.height AS height, \
+                       d.data AS data FROM {i} i JOIN {d} d ON d.id = i.data \
+                       WHERE i.url = ? ORDER BY d.width;",
+                      i = ICONS_TABLE, d = DATA_TABLE),
+            &[url]) {
+            Ok(t) => Ok(t.iter().filter_map(|row| {
+                let int = |name : &str| match row.get(name) {
+                    Some(Some(FieldValue::Integer(t))) => *t,
+                    _ => 0,
+                };
+                match (row.get("url"), row.get("data")) {
+                    (Some(Some(FieldValue::Text(url))), Some(Some(FieldValue::Blob(data)))) => Some(Icon {
+                        url: url.clone(),
+                        width: int("width"),
+                        height: int("height"),
+                        data: data.clone(),
+                        expires: match int("expires") {
+                            0 => None,
+                            t => Some(Timestamp::from_millis(t)),
+                        },
+                    }),
+                    _ => None,
+                }
+            }).collect()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Best icon to display `page` at `size` pixels.
+    ///
+    /// The smallest resolution at least as large as `size` is preferred, as
+    /// downscaling looks better than upscaling. If every resolution is
+    /// smaller, the largest one is returned. Expired icons are still
+    /// returned, the UI is expected to refresh them.
+    pub fn best_icon(&mut self, page : &str, size : i64) -> Result<Option<Icon>, Error> {
+        let icon = match self.db.request(
+            &format!("SELECT icon FROM {} WHERE page = ?;", PAGES_TABLE), &[page]) {
+            Ok(t) => match t.first().and_then(|r| r.get("icon")) {
+                Some(Some(FieldValue::Text(t))) => t.clone(),
+                _ => return Ok(None),
+            },
+            Err(e) => return Err(e),
+        };
+        let mut icons = self.icons(&icon)?;
+
+        let larger = icons.iter().position(|i| i.width >= size);
+        match larger {
+            Some(t) => Ok(Some(icons.swap_remove(t))),
+            None => Ok(icons.pop()),
+        }
+    }
+
+    /// Removes icons expired before `now` and returns how many resolutions
+    /// were removed.
+    pub fn remove_expired(&mut self, now : Timestamp) -> Result<usize, Error> {
+        self.db.transaction(|db| {
+            db.request(
+                &format!("DELETE FROM {} WHERE expires != 0 AND expires < ?;", ICONS_TABLE),
+                &[now.to_string().as_str()])?;
+            let removed = match db.changes() {
+                Ok(t) => t,
+                Err(e) => return Err(e),
+            };
+            match remove_orphans(db) {
+                Ok(_) => Ok(removed),
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    /// Removes the icons of pages which are neither in the history nor
+    /// bookmarked anymore.
+    pub fn remove_unused(&mut self) -> Result<(), Error> {
+        history::History::new(&mut *self.db)?;
+        bookmarks::Bookmarks::new(&mut *self.db)?;
+        self.db.request(
+            &format!("DELETE FROM {p} WHERE page NOT IN (SELECT url FROM {h}) \
+                       AND page NOT IN (SELECT url FROM {b} WHERE url IS NOT NULL);",
+                      p = PAGES_TABLE, h = history::TABLE, b = bookmarks::TABLE),
+            &[])?;
+        self.db.request(
+            &format!("DELETE FROM {i} WHERE url NOT IN (SELECT icon FROM {p});",
+                      i = ICONS_TABLE, p = PAGES_TABLE),
+            &[])?;
+        remove_orphans(self.db)
+    }
+
+    /// Removes every icon used by, or served from, `domain` and its
+    /// subdomains. Returns the number of pages whose icon was forgotten.
+    pub fn forget_site(&mut self, domain : &str) -> Result<usize, Error> {
+        let rows = self.db.request(
+            &format!("SELECT page, icon FROM {};", PAGES_TABLE), &[])?;
+
+        let mut removed = 0;
+        for row in rows {
+            let (page, icon) = match (row.get("page"), row.get("icon")) {
+                (Some(Some(FieldValue::Text(p))), Some(Some(FieldValue::Text(i)))) => (p, i),
+                _ => continue,
+            };
+            let matches = |url : &str| match history::host_of(url) {
+                Some(h) => history::host_matches(&h, domain),
+                None => false,
+            };
+            if matches(page) || matches(icon) {
+                self.db.request(
+                    &format!("DELETE FROM {} WHERE page = ?;", PAGES_TABLE), &[page])?;
+                removed += 1;
+            }
+        }
+
+        self.db.request(
+            &format!("DELETE FROM {i} WHERE url NOT IN (SELECT icon FROM {p});",
+                      i = ICONS_TABLE, p = PAGES_TABLE),
+            &[])?;
+        match remove_orphans(self.db) {
+            Ok(_) => Ok(removed),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Moves the icons stored in the legacy `favicon` column of the history
+    /// into the store.
+    ///
+    /// The icon URL of those entries is unknown, so `/favicon.ico` at the
+    /// origin of the page is used, with an unknown size.
+    pub fn migrate_history(&mut self) -> Result<usize, Error> {
+        let rows = self.db.request(
+            &format!("SELECT {id}, {url}, {fav} FROM {t} WHERE {fav} IS NOT NULL;",
+                      id = history::Field::Id.name(), url = history::Field::Url.name(),
+                      fav = history::Field::Favicon.name(), t = history::TABLE),
+            &[])?;
+
+        let mut moved = 0;
+        for row in rows {
+            let (id, page, data) = match (row.get(history::Field::Id.name()),
+                                          row.get(history::Field::Url.name()),
+                                          row.get(history::Field::Favicon.name())) {
+                (Some(Some(FieldValue::Integer(i))), Some(Some(FieldValue::Text(u))), Some(Some(FieldValue::Blob(d)))) =>
+                    (*i, u.clone(), d.clone()),
+                _ => continue,
+            };
+            let icon = match url::Url::parse(&page) {
+                Ok(t) => format!("{}/favicon.ico", t.origin().ascii_serialization()),
+                Err(_) => continue,
+            };
+
+            self.store(&icon, 0, 0, &data, None)?;
+            self.map_page(&page, &icon)?;
+            self.db.request(
+                &format!("UPDATE {t} SET {fav} = NULL WHERE {id} = ?;",
+                          t = history::TABLE, fav = history::Field::Favicon.name(),
+                          id = history::Field::Id.name()),
+                &[id.to_string().as_str()])?;
+            moved += 1;
+        }
+
+        Ok(moved)
+    }
+}
+
+/// Removes images no icon URL refers to anymore.
+fn remove_orphans<T : TableProvider>(db : &mut T) -> Result<(), Error> {
+    match db.request(
+        &format!("DELETE FROM {d} WHERE id NOT IN (SELECT data FROM {i});",
+                  d = DATA_TABLE, i = ICONS_TABLE),
+        &[]) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// 64 bits FNV-1a hash of `data`, as hexadecimal. It only narrows down the
+/// images to compare, equality is always checked on the bytes.
+fn content_hash(data : &[u8]) -> String {
+    let mut hash : u64 = 0xcbf29ce484222325;
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::db::{TableProvider, FieldValue};
+    use crate::data::db::sqlite::SQLite;
+    use crate::data::history::{self, History, Field};
+    use crate::data::time::Timestamp;
+    use super::Favicons;
+
+    #[test]
+    fn history_icons_move_to_the_store() {
+        let mut db = SQLite::new(":memory:").unwrap();
+        History::new(&mut db).unwrap().add("https://a.org/page", "A", Timestamp::from_millis(1)).unwrap();
+        db.request_values(&format!("UPDATE {} SET {} = ?;", history::TABLE, Field::Favicon.name()),
+                          &[FieldValue::Blob(vec![1, 2, 3])]).unwrap();
+
+        History::new(&mut db).unwrap();
+        let icon = Favicons::new(&mut db).unwrap().best_icon("https://a.org/page", 16).unwrap().unwrap();
+        assert_eq!((icon.url.as_str(), icon.data), ("https://a.org/favicon.ico", vec![1, 2, 3]));
+        let left = db.request(&format!("SELECT {} FROM {} WHERE {} IS NOT NULL;", Field::Id.name(),
+                                        history::TABLE, Field::Favicon.name()), &[]).unwrap();
+        assert!(left.is_empty());
+    }
+
+    #[test]
+    fn expired_icons_are_removed() {
+        let mut db = SQLite::new(":memory:").unwrap();
+        let mut favicons = Favicons::new(&mut db).unwrap();
+        favicons.store("https://a.org/a.ico", 16, 16, &[1], Some(Timestamp::from_millis(10))).unwrap();
+        favicons.store("https://a.org/a.ico", 32, 32, &[2], None).unwrap();
+        favicons.store("https://b.org/b.ico", 16, 16, &[3], Some(Timestamp::from_millis(30))).unwrap();
+
+        assert_eq!(favicons.remove_expired(Timestamp::from_millis(20)).unwrap(), 1);
+        let sizes : Vec<_> = favicons.icons("https://a.org/a.ico").unwrap().iter().map(|i| i.width).collect();
+        assert_eq!(sizes, vec![32]);
+        assert_eq!(favicons.icons("https://b.org/b.ico").unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,796 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! History system
+//!
+//! Stores every visited page in the `history` table and gives the UI the
+//! tools to list it, grouped by day, and to clear it, either on a time range
+//! or for a whole site.
+//!
+//! Dates are [timestamps](../time/struct.Timestamp.html) stored as integers.
+//! Databases created before used a `TEXT` column, they are converted when
+//! opened.
+
+use super::db::{Error, TableProvider, FieldType, FieldParameter, FieldValue};
+use super::favicon::Favicons;
+use super::site_settings::SiteSettings;
+use super::mime::{self, Category};
+use super::time::Timestamp;
+
+/// Name of the table used to store the history.
+pub const TABLE : &str = "history";
+/// Index used to find a visit, as imports do for every visit they read.
+pub const VISIT_INDEX : &str = "history_url_date";
+
+/// Columns of the history table.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum Field {
+    Id,
+    MimeType,
+    Url,
+    Date,
+    Title,
+    /// Legacy per-entry icon, icons now live in the
+    /// [favicon store](../favicon/index.html).
+    Favicon,
+    Parent,
+    Children,
+}
+
+impl Field {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Field::Id => "id",
+            Field::MimeType => "mime_type",
+            Field::Url => "url",
+            Field::Date => "date",
+            Field::Title => "title",
+            Field::Favicon => "favicon",
+            Field::Parent => "parent",
+            Field::Children => "children",
+        }
+    }
+}
+
+/// Time range to clear, relative to the current time.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum TimeRange {
+    LastHour,
+    LastDay,
+    LastWeek,
+    LastFourWeeks,
+    AllTime,
+    /// Explicit range, both bounds included.
+    Between(Timestamp, Timestamp),
+}
+
+impl TimeRange {
+    /// Relative range named `name`, like `last_hour` or `all_time`.
+    pub fn from_name(name : &str) -> Option<TimeRange> {
+        match name {
+            "last_hour" => Some(TimeRange::LastHour),
+            "last_day" => Some(TimeRange::LastDay),
+            "last_week" => Some(TimeRange::LastWeek),
+            "last_four_weeks" => Some(TimeRange::LastFourWeeks),
+            "all_time" => Some(TimeRange::AllTime),
+            _ => None,
+        }
+    }
+
+    /// Name of a relative range, `None` for an explicit one.
+    pub fn name(&self) -> Option<&'static str> {
+        match self {
+            TimeRange::LastHour => Some("last_hour"),
+            TimeRange::LastDay => Some("last_day"),
+            TimeRange::LastWeek => Some("last_week"),
+            TimeRange::LastFourWeeks => Some("last_four_weeks"),
+            TimeRange::AllTime => Some("all_time"),
+            TimeRange::Between(_, _) => None,
+        }
+    }
+
+    /// Bounds of the range when the current time is `now`, both included.
+    pub fn bounds(&self, now : Timestamp) -> (Timestamp, Timestamp) {
+        const HOUR : i64 = 3600 * 1000;
+        const DAY : i64 = 24 * HOUR;
+
+        match self {
+            TimeRange::LastHour => (now.add_millis(-HOUR), Timestamp::MAX),
+            TimeRange::LastDay => (now.add_millis(-DAY), Timestamp::MAX),
+            TimeRange::LastWeek => (now.add_millis(-7 * DAY), Timestamp::MAX),
+            TimeRange::LastFourWeeks => (now.add_millis(-28 * DAY), Timestamp::MAX),
+            TimeRange::AllTime => (Timestamp::MIN, Timestamp::MAX),
+            TimeRange::Between(from, to) => (*from, *to),
+        }
+    }
+}
+
+/// Groups used by the UI to display the history, in local time.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum Period {
+    Today,
+    Yesterday,
+    /// The five days before yesterday.
+    LastWeek,
+    /// The 23 days before the last week.
+    LastMonth,
+    Older,
+}
+
+impl Period {
+    pub const ALL : [Period; 5] = [
+        Period::Today,
+        Period::Yesterday,
+        Period::LastWeek,
+        Period::LastMonth,
+        Period::Older,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Period::Today => "today",
+            Period::Yesterday => "yesterday",
+            Period::LastWeek => "last_week",
+            Period::LastMonth => "last_month",
+            Period::Older => "older",
+        }
+    }
+
+    /// Range covered by this period when the current time is `now`. Days
+    /// start at midnight in the timezone of the system.
+    pub fn range<T : TableProvider>(&self, db : &mut T, now : Timestamp) -> Result<TimeRange, Error> {
+        // Number of days between the start of today and the start of the
+        // period, and the same for the start of the next period.
+        let (start, end) = match self {
+            Period::Today => (0, None),
+            Period::Yesterday => (-1, Some(0)),
+            Period::LastWeek => (-6, Some(-1)),
+            Period::LastMonth => (-29, Some(-6)),
+            Period::Older => (i64::MIN, Some(-29)),
+        };
+
+        let from = if start == i64::MIN {
+            Timestamp::MIN
+        } else {
+            now.local_day_start(db, start)?
+        };
+        let to = match end {
+            Some(d) => match now.local_day_start(db, d) {
+                Ok(t) => t.add_millis(-1),
+                Err(e) => return Err(e),
+            },
+            None => Timestamp::MAX,
+        };
+
+        Ok(TimeRange::Between(from, to))
+    }
+}
+
+/// Summary of a clearing operation, sent back to the UI.
+#[derive(Eq, PartialEq, Debug, Default, Clone)]
+pub struct ClearReport {
+    /// Number of history entries removed.
+    pub entries : usize,
+    /// Hosts that had at least one entry removed.
+    pub hosts : Vec<String>,
+    /// Number of pages whose icon was removed.
+    pub favicons : usize,
+    /// Number of per-site exceptions removed.
+    pub site_settings : usize,
+}
+
+/// An entry of the history, as given to the UI.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Entry {
+    pub id : i64,
+    pub url : String,
+    pub title : String,
+    pub date : Timestamp,
+    pub mime_type : String,
+    pub category : Category,
+}
+
+/// Criteria used to list history entries. Every criterion is optional, the
+/// default filter returns the whole history.
+#[derive(Eq, PartialEq, Debug, Default, Clone)]
+pub struct Filter {
+    /// Only keep entries of these categories. Empty means every category.
+    pub categories : Vec<Category>,
+    /// Text searched, case insensitively, in the URL and title.
+    pub text : Option<String>,
+    pub range : Option<TimeRange>,
+    /// Maximum number of entries returned, most recent first.
+    pub limit : Option<usize>,
+}
+
+pub struct History<'a, T : TableProvider> {
+    db : &'a mut T,
+}
+
+impl<'a, T : TableProvider> History<'a, T> {
+    /// Opens the history stored in `db`, creating or upgrading its table
+    /// when needed.
+    pub fn new(db : &'a mut T) -> Result<Self, Error> {
+        migrate_dates(db)?;
+        use_history_table(db, TABLE)?;
+        db.request(&format!("CREATE INDEX IF NOT EXISTS {i} ON {t} ({url}, {date});",
+                            i = VISIT_INDEX, t = TABLE, url = Field::Url.name(), date = Field::Date.name()), &[])?;
+        match migrate_favicons(db) {
+            Ok(_) => Ok(Self { db }),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Gives access to the underlying database.
+    pub fn db(&mut self) -> &mut T {
+        self.db
+    }
+
+    /// Adds a visit of `url` at `date` and returns its id.
+    pub fn add(&mut self, url : &str, title : &str, date : Timestamp) -> Result<i64, Error> {
+        self.add_typed(url, title, mime::UNKNOWN, date)
+    }
+
+    /// Same as `add`, for a content whose MIME type is known.
+    pub fn add_typed(&mut self, url : &str, title : &str, mime_type : &str, date : Timestamp) -> Result<i64, Error> {
+        self.db.request(
+            &format!("INSERT INTO {t} ({url}, {title}, {mime}, {date}) VALUES (?, ?, ?, ?);",
+                      t = TABLE, url = Field::Url.name(), title = Field::Title.name(),
+                      mime = Field::MimeType.name(), date = Field::Date.name()),
+            &[url, title, mime::normalize(mime_type).as_str(), date.to_string().as_str()])?;
+        self.db.last_insert_id()
+    }
+
+    /// Sets the MIME type of an entry, once the content type of the page is
+    /// known.
+    pub fn set_mime_type(&mut self, id : i64, mime_type : &str) -> Result<(), Error> {
+        match self.db.request(
+            &format!("UPDATE {t} SET {mime} = ? WHERE {id} = ?;",
+                      t = TABLE, mime = Field::MimeType.name(), id = Field::Id.name()),
+            &[mime::normalize(mime_type).as_str(), id.to_string().as_str()]) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Lists the entries matching `filter`, most recent first.
+    pub fn entries(&mut self, filter : &Filter) -> Result<Vec<Entry>, Error> {
+        let category = Category::sql_expression(Field::MimeType.name());
+        let mut conditions = Vec::<String>::new();
+        let mut arguments = Vec::<String>::new();
+
+        if !filter.categories.is_empty() {
+            conditions.push(format!("({}) IN ({})", category,
+                                    filter.categories.iter()
+                                        .map(|c| format!("'{}'", c.name()))
+                                        .collect::<Vec<String>>()
+                                        .join(", ")));
+        }
+        if let Some(text) = &filter.text {
+            conditions.push(format!("({url} LIKE ? ESCAPE '\\' OR {title} LIKE ? ESCAPE '\\')",
+                                    url = Field::Url.name(), title = Field::Title.name()));
+            let pattern = format!("%{}%", text.replace('\\', "\\\\")
+                                              .replace('%', "\\%")
+                                              .replace('_', "\\_"));
+            arguments.push(pattern.clone());
+            arguments.push(pattern);
+        }
+        if let Some(range) = &filter.range {
+            let (from, to) = range.bounds(Timestamp::now());
+            conditions.push(format!("{} BETWEEN ? AND ?", Field::Date.name()));
+            arguments.push(from.to_string());
+            arguments.push(to.to_string());
+        }
+
+        let mut req = format!("SELECT {id}, {url}, {title}, {date}, {mime} FROM {t}",
+                              id = Field::Id.name(), url = Field::Url.name(),
+                              title = Field::Title.name(), date = Field::Date.name(),
+                              mime = Field::MimeType.name(), t = TABLE);
+        if !conditions.is_empty() {
+            req += " WHERE ";
+            req += &*conditions.join(" AND ");
+        }
+        req += &*format!(" ORDER BY {} DESC, {} DESC", Field::Date.name(), Field::Id.name());
+        if let Some(limit) = filter.limit {
+            req += &*format!(" LIMIT {}", limit);
+        }
+        req += ";";
+
+        let arguments : Vec<&str> = arguments.iter().map(|a| a.as_str()).collect();
+        match self.db.request(&req, &arguments) {
+            Ok(t) => Ok(t.iter().filter_map(|row| {
+                let text = |field : Field| match row.get(field.name()) {
+                    Some(Some(FieldValue::Text(t))) => Some(t.clone()),
+                    _ => None,
+                };
+                let id = match row.get(Field::Id.name()) {
+                    Some(Some(FieldValue::Integer(t))) => *t,
+                    _ => return None,
+                };
+                let mime_type = text(Field::MimeType).unwrap_or_else(|| String::from(mime::UNKNOWN));
+                Some(Entry {
+                    id,
+                    url: match text(Field::Url) {
+                        Some(t) => t,
+                        None => return None,
+                    },
+                    title: text(Field::Title).unwrap_or_default(),
+                    date: match row.get(Field::Date.name()) {
+                        Some(Some(FieldValue::Integer(t))) => Timestamp::from_millis(*t),
+                        _ => Timestamp::default(),
+                    },
+                    category: Category::of(&mime_type),
+                    mime_type,
+                })
+            }).collect()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Lists the entries matching `filter`, grouped by period relative to
+    /// `now`. Empty periods are left out.
+    pub fn grouped(&mut self, filter : &Filter, now : Timestamp) -> Result<Vec<(Period, Vec<Entry>)>, Error> {
+        let mut ret = Vec::new();
+
+        for period in Period::ALL.iter() {
+            let range = period.range(self.db, now)?;
+            let (from, to) = range.bounds(now);
+            let mut filter = filter.clone();
+            filter.range = match filter.range {
+                // Keep the intersection of both ranges
+                Some(r) => {
+                    let (f, t) = r.bounds(now);
+                    Some(TimeRange::Between(std::cmp::max(f, from), std::cmp::min(t, to)))
+                },
+                None => Some(range),
+            };
+
+            match self.entries(&filter) {
+                Ok(t) => if !t.is_empty() {
+                    ret.push((*period, t));
+                },
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// Number of entries in each category, for the filters of the UI.
+    pub fn count_by_category(&mut self) -> Result<Vec<(Category, usize)>, Error> {
+        match self.db.request(
+            &format!("SELECT {c} AS category, COUNT(*) AS count FROM {t} GROUP BY {c};",
+                      c = Category::sql_expression(Field::MimeType.name()), t = TABLE),
+            &[]) {
+            Ok(t) => Ok(t.iter().filter_map(|row| {
+                match (row.get("category"), row.get("count")) {
+                    (Some(Some(FieldValue::Text(c))), Some(Some(FieldValue::Integer(n)))) =>
+                        Category::from_name(c).map(|c| (c, *n as usize)),
+                    _ => None,
+                }
+            }).collect()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Checks if a visit of `url` at `date` is already recorded.
+    pub fn contains(&mut self, url : &str, date : Timestamp) -> Result<bool, Error> {
+        match self.db.request(
+            &format!("SELECT COUNT(*) AS count FROM {t} WHERE {url} = ? AND {date} = ?;",
+                      t = TABLE, url = Field::Url.name(), date = Field::Date.name()),
+            &[url, date.to_string().as_str()]) {
+            Ok(t) => match t.first().and_then(|r| r.get("count")) {
+                Some(Some(FieldValue::Integer(v))) => Ok(*v != 0),
+                _ => Ok(false),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Removes every entry visited within `range`.
+    pub fn clear_range(&mut self, range : TimeRange) -> Result<ClearReport, Error> {
+        let (from, to) = range.bounds(Timestamp::now());
+        let from = from.to_string();
+        let to = to.to_string();
+
+        self.db.transaction(|db| {
+            let rows = match db.request(
+                &format!("SELECT {url} FROM {t} WHERE {date} BETWEEN ? AND ? ORDER BY {id};",
+                          url = Field::Url.name(), t = TABLE, date = Field::Date.name(), id = Field::Id.name()),
+                &[from.as_str(), to.as_str()]) {
+                Ok(t) => t,
+                Err(e) => return Err(e),
+            };
+            db.request(
+                &format!("DELETE FROM {t} WHERE {date} BETWEEN ? AND ?;",
+                          t = TABLE, date = Field::Date.name()),
+                &[from.as_str(), to.as_str()])?;
+
+            let mut report = ClearReport { entries: db.changes()?, ..ClearReport::default() };
+            for row in rows {
+                if let Some(Some(FieldValue::Text(url))) = row.get(Field::Url.name()) {
+                    if let Some(host) = host_of(url) {
+                        if !report.hosts.contains(&host) {
+                            report.hosts.push(host);
+                        }
+                    }
+                }
+            }
+
+            match Favicons::new(&mut *db) {
+                Ok(mut t) => t.remove_unused()?,
+                Err(e) => return Err(e),
+            }
+
+            Ok(report)
+        })
+    }
+
+    /// Forgets everything about `domain` and its subdomains.
+    ///
+    /// Hosts are compared after being parsed by the `url` crate, so
+    /// `example.com` matches `https://www.example.com/page` but not
+    /// `https://notexample.com`.
+    pub fn forget_site(&mut self, domain : &str) -> Result<ClearReport, Error> {
+        let domain = domain.trim_matches('.').to_lowercase();
+        if domain.is_empty() {
+            return Err(Error::new(Some(2001), Some(String::from("Empty domain can not be forgotten"))));
+        }
+
+        self.db.transaction(|db| {
+            let rows = match db.request(
+                &format!("SELECT {id}, {url} FROM {t} ORDER BY {id};",
+                          id = Field::Id.name(), url = Field::Url.name(), t = TABLE),
+                &[]) {
+                Ok(t) => t,
+                Err(e) => return Err(e),
+            };
+
+            let mut report = ClearReport::default();
+            let mut ids = Vec::new();
+            for row in rows {
+                let id = match row.get(Field::Id.name()) {
+                    Some(Some(FieldValue::Integer(t))) => *t,
+                    _ => continue,
+                };
+                let host = match row.get(Field::Url.name()) {
+                    Some(Some(FieldValue::Text(url))) => match host_of(url) {
+                        Some(t) => t,
+                        None => continue,
+                    },
+                    _ => continue,
+                };
+                if !host_matches(&host, &domain) {
+                    continue;
+                }
+
+                ids.push(id.to_string());
+                if !report.hosts.contains(&host) {
+                    report.hosts.push(host);
+                }
+            }
+            // Ids are integers, so they can be written in the statement
+            // whatever their number.
+            if !ids.is_empty() {
+                db.request(
+                    &format!("DELETE FROM {t} WHERE {id} IN ({ids});",
+                              t = TABLE, id = Field::Id.name(), ids = ids.join(", ")),
+                    &[])?;
+                report.entries = db.changes()?;
+            }
+
+            report.favicons = match Favicons::new(&mut *db) {
+                Ok(mut t) => match t.forget_site(&domain) {
+                    Ok(t) => t,
+                    Err(e) => return Err(e),
+                },
+                Err(e) => return Err(e),
+            };
+            report.site_settings = match SiteSettings::new(&mut *db) {
+                Ok(mut t) => match t.forget_site(&domain) {
+                    Ok(t) => t,
+                    Err(e) => return Err(e),
+                },
+                Err(e) => return Err(e),
+            };
+
+            Ok(report)
+        })
+    }
+}
+
+/// Returns the lowercase host of `url`, if it has one.
+pub fn host_of(url : &str) -> Option<String> {
+    match url::Url::parse(url) {
+        Ok(t) => t.host_str().map(|h| h.to_lowercase()),
+        Err(_) => None,
+    }
+}
+
+/// Checks if `host` is `domain` or one of its subdomains.
+pub fn host_matches(host : &str, domain : &str) -> bool {
+    host == domain || host.ends_with(&*format!(".{}", domain))
+}
+
+fn use_history_table<T : TableProvider>(db : &mut T, name : &str) -> Result<(), Error> {
+    db.use_table(name, &[
+        (Field::Id.name(), &FieldType::Integer, &[FieldParameter::AutoIncrement]),
+        (Field::MimeType.name(), &FieldType::Text, &[FieldParameter::Default(String::from(mime::UNKNOWN))]),
+        (Field::Url.name(), &FieldType::Text, &[FieldParameter::NoNull]),
+        (Field::Date.name(), &FieldType::Integer, &[FieldParameter::Default(String::from("0"))]),
+        (Field::Title.name(), &FieldType::Text, &[FieldParameter::Default(String::new())]),
+        (Field::Favicon.name(), &FieldType::Blob, &[]),
+        (Field::Parent.name(), &FieldType::Integer, &[]),
+        (Field::Children.name(), &FieldType::Blob, &[]),
+    ], false, false)
+}
+
+/// Moves the icons of the legacy `favicon` column into the
+/// [favicon store](../favicon/index.html).
+fn migrate_favicons<T : TableProvider>(db : &mut T) -> Result<(), Error> {
+    match db.request(&format!("SELECT {id} FROM {t} WHERE {fav} IS NOT NULL LIMIT 1;",
+                               id = Field::Id.name(), t = TABLE, fav = Field::Favicon.name()), &[]) {
+        Ok(t) if t.is_empty() => return Ok(()),
+        Ok(_) => (),
+        Err(e) => return Err(e),
+    }
+
+    db.transaction(|db| match Favicons::new(&mut *db) {
+        Ok(mut t) => t.migrate_history().map(|_| ()),
+        Err(e) => Err(e),
+    })
+}
+
+/// Converts the `TEXT` dates of older databases into timestamps.
+///
+/// Text dates were either seconds since the UNIX epoch or dates understood
+/// by SQLite like `2019-10-05 14:02:00`, both in UTC. Anything else becomes
+/// 0.
+fn migrate_dates<T : TableProvider>(db : &mut T) -> Result<(), Error> {
+    let columns = db.request(&format!("PRAGMA table_info({});", TABLE), &[])?;
+    let is_text = columns.iter().any(|c| {
+        match (c.get("name"), c.get("type")) {
+            (Some(Some(FieldValue::Text(n))), Some(Some(FieldValue::Text(t)))) =>
+                n == Field::Date.name() && t.eq_ignore_ascii_case("TEXT"),
+            _ => false,
+        }
+    });
+    if !is_text {
+        return Ok(());
+    }
+
+    let temporary = format!("{}_migration", TABLE);
+    let date = Field::Date.name();
+    let others = [Field::Id, Field::MimeType, Field::Url, Field::Title,
+                  Field::Favicon, Field::Parent, Field::Children].iter()
+        .map(|f| f.name())
+        .collect::<Vec<&str>>()
+        .join(", ");
+
+    db.transaction(|db| {
+        use_history_table(db, &temporary)?;
+        db.request(
+            &format!("INSERT INTO {tmp} ({others}, {date}) SELECT {others}, \
+                       CASE WHEN {date} GLOB '[0-9]*' AND NOT {date} GLOB '*[^0-9]*' THEN CAST({date} AS INTEGER) * 1000 \
+                       WHEN strftime('%s', {date}) IS NOT NULL THEN CAST(strftime('%s', {date}) AS INTEGER) * 1000 \
+                       ELSE 0 END FROM {t};",
+                      tmp = temporary, others = others, date = date, t = TABLE),
+            &[])?;
+        db.request(&format!("DROP TABLE {};", TABLE), &[])?;
+        match db.request(&format!("ALTER TABLE {} RENAME TO {};", temporary, TABLE), &[]) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::db::{TableProvider, FieldType, FieldParameter};
+    use crate::data::db::sqlite::SQLite;
+    use crate::data::favicon::Favicons;
+    use crate::data::site_settings::{SiteSettings, Permission, Decision, Value};
+    use crate::data::mime::Category;
+    use crate::data::time::Timestamp;
+    use super::{History, TimeRange, Period, Filter, TABLE};
+
+    fn urls(db : &mut SQLite) -> Vec<String> {
+        let mut ret : Vec<String> = History::new(db).unwrap().entries(&Filter::default()).unwrap()
+            .into_iter().map(|e| e.url).collect();
+        ret.sort();
+        ret
+    }
+
+    #[test]
+    fn relative_ranges_end_now() {
+        const HOUR : i64 = 3600 * 1000;
+        let now = Timestamp::from_millis(100 * 24 * HOUR);
+        assert_eq!(TimeRange::LastHour.bounds(now), (now.add_millis(-HOUR), Timestamp::MAX));
+        assert_eq!(TimeRange::LastFourWeeks.bounds(now), (now.add_millis(-28 * 24 * HOUR), Timestamp::MAX));
+        assert_eq!(TimeRange::AllTime.bounds(now), (Timestamp::MIN, Timestamp::MAX));
+        let between = TimeRange::Between(Timestamp::from_millis(1), Timestamp::from_millis(2));
+        assert_eq!(between.bounds(now), (Timestamp::from_millis(1), Timestamp::from_millis(2)));
+    }
+
+    #[test]
+    fn text_dates_become_timestamps() {
+        let mut db = SQLite::new(":memory:").unwrap();
+        // Table of the first versions of the core, with text dates.
+        db.use_table(TABLE, &[
+            ("id", &FieldType::Integer, &[FieldParameter::AutoIncrement]),
+            ("mime_type", &FieldType::Text, &[FieldParameter::Default(String::from("sielo/unknown"))]),
+            ("url", &FieldType::Text, &[FieldParameter::NoNull]),
+            ("date", &FieldType::Text, &[FieldParameter::Default(String::from("0"))]),
+            ("title", &FieldType::Text, &[FieldParameter::Default(String::new())]),
+            ("favicon", &FieldType::Blob, &[]),
+            ("parent", &FieldType::Integer, &[]),
+            ("children", &FieldType::Blob, &[]),
+        ], false, false).unwrap();
+        for (url, date) in &[("https://seconds.org/", "1571234567"), ("https://sqlite.org/", "2019-10-05 14:02:00"),
+                             ("https://garbage.org/", "garbage"), ("https://mixed.org/", "12abc")] {
+            db.request(&format!("INSERT INTO {} (url, title, date) VALUES (?, 'Title', ?);", TABLE), &[url, date]).unwrap();
+        }
+
+        let mut entries = History::new(&mut db).unwrap().entries(&Filter::default()).unwrap();
+        entries.sort_by(|a, b| a.url.cmp(&b.url));
+        let dates : Vec<(&str, i64)> = entries.iter().map(|e| (e.url.as_str(), e.date.as_millis())).collect();
+        assert_eq!(dates, vec![("https://garbage.org/", 0), ("https://mixed.org/", 0),
+                               ("https://seconds.org/", 1_571_234_567_000), ("https://sqlite.org/", 1_570_284_120_000)]);
+        assert!(entries.iter().all(|e| e.title == "Title"));
+
+        // Opening it again keeps the converted dates.
+        let again = History::new(&mut db).unwrap().entries(&Filter::default()).unwrap();
+        assert_eq!(again.len(), 4);
+        assert!(again.iter().any(|e| e.date.as_millis() == 1_571_234_567_000));
+    }
+
+    #[test]
+    fn periods_start_at_local_midnight() {
+        let mut db = SQLite::new(":memory:").unwrap();
+        // A bit after local midnight, so yesterday ended a minute ago.
+        let midnight = Timestamp::from_millis(1_571_234_567_000).local_day_start(&mut db, 0).unwrap();
+        let now = midnight.add_millis(60 * 1000);
+        let yesterday = midnight.local_day_start(&mut db, -1).unwrap();
+        let mut history = History::new(&mut db).unwrap();
+        for (url, date) in &[("https://today.org/midnight", midnight), ("https://today.org/now", now),
+                             ("https://yesterday.org/end", midnight.add_millis(-1)),
+                             ("https://yesterday.org/start", yesterday),
+                             ("https://week.org/", yesterday.add_millis(-1))] {
+            history.add(url, "Page", *date).unwrap();
+        }
+
+        let groups : Vec<(Period, Vec<String>)> = history.grouped(&Filter::default(), now).unwrap().into_iter()
+            .map(|(p, e)| (p, e.into_iter().map(|e| e.url).collect())).collect();
+        assert_eq!(groups, vec![
+            (Period::Today, vec![String::from("https://today.org/now"), String::from("https://today.org/midnight")]),
+            (Period::Yesterday, vec![String::from("https://yesterday.org/end"), String::from("https://yesterday.org/start")]),
+            (Period::LastWeek, vec![String::from("https://week.org/")]),
+        ]);
+
+        // A range given by the filter only keeps its part of each period.
+        let filter = Filter { range: Some(TimeRange::LastHour), ..Filter::default() };
+        let groups : Vec<Period> = history.grouped(&filter, now).unwrap().into_iter().map(|(p, _)| p).collect();
+        assert_eq!(groups, vec![Period::Today, Period::Yesterday]);
+    }
+
+    #[test]
+    fn entries_are_filtered_by_category_and_text() {
+        let mut db = SQLite::new(":memory:").unwrap();
+        let mut history = History::new(&mut db).unwrap();
+        for (i, (url, title, mime)) in [("https://a.org/", "Home", "text/html; charset=utf-8"),
+                                        ("https://a.org/doc.pdf", "Report", "Application/PDF"),
+                                        ("https://b.org/cat.png", "Cat 100%", "image/png"),
+                                        ("https://b.org/dog.png", "Dog 1000", "image/png"),
+                                        ("https://c.org/a_b.zip", "Archive", "application/zip"),
+                                        ("https://c.org/axb", "Unknown", "")].iter().enumerate() {
+            history.add_typed(url, title, mime, Timestamp::from_millis(i as i64)).unwrap();
+        }
+        let mut entries = |filter : Filter| -> Vec<String> {
+            history.entries(&filter).unwrap().into_iter().map(|e| e.url).collect()
+        };
+
+        let filter = Filter { categories: vec![Category::Image, Category::Pdf], ..Filter::default() };
+        assert_eq!(entries(filter), vec!["https://b.org/dog.png", "https://b.org/cat.png", "https://a.org/doc.pdf"]);
+        let filter = Filter { categories: vec![Category::Other], ..Filter::default() };
+        assert_eq!(entries(filter), vec!["https://c.org/axb"]);
+        // Wildcards of LIKE are searched literally.
+        let filter = Filter { text: Some(String::from("100%")), ..Filter::default() };
+        assert_eq!(entries(filter), vec!["https://b.org/cat.png"]);
+        let filter = Filter { text: Some(String::from("A_B")), ..Filter::default() };
+        assert_eq!(entries(filter), vec!["https://c.org/a_b.zip"]);
+        let filter = Filter { categories: vec![Category::Image], text: Some(String::from("dog")), ..Filter::default() };
+        assert_eq!(entries(filter), vec!["https://b.org/dog.png"]);
+
+        let all = history.entries(&Filter::default()).unwrap();
+        assert_eq!(all.iter().find(|e| e.url == "https://a.org/").unwrap().mime_type, "text/html");
+        let mut counts = history.count_by_category().unwrap();
+        counts.sort_by_key(|(c, _)| c.name());
+        assert_eq!(counts, vec![(Category::Download, 1), (Category::Image, 2), (Category::Other, 1),
+                                (Category::Page, 1), (Category::Pdf, 1)]);
+    }
+
+    #[test]
+    fn clear_range_includes_both_bounds() {
+        let mut db = SQLite::new(":memory:").unwrap();
+        let mut history = History::new(&mut db).unwrap();
+        for (url, date) in &[("https://a.org/", 999), ("https://b.org/1", 1000), ("https://b.org/2", 1500),
+                             ("https://c.org/", 2000), ("https://d.org/", 2001)] {
+            history.add(url, "Page", Timestamp::from_millis(*date)).unwrap();
+        }
+
+        let from = Timestamp::from_millis(1000);
+        let to = Timestamp::from_millis(2000);
+        let report = history.clear_range(TimeRange::Between(from, to)).unwrap();
+        assert_eq!(report.entries, 3);
+        assert_eq!(report.hosts, vec![String::from("b.org"), String::from("c.org")]);
+        assert_eq!(urls(&mut db), vec![String::from("https://a.org/"), String::from("https://d.org/")]);
+
+        let report = History::new(&mut db).unwrap().clear_range(TimeRange::Between(from, to)).unwrap();
+        assert_eq!(report.entries, 0);
+        assert!(report.hosts.is_empty());
+    }
+
+    #[test]
+    fn clear_range_removes_orphaned_icons() {
+        let mut db = SQLite::new(":memory:").unwrap();
+        let mut history = History::new(&mut db).unwrap();
+        history.add("https://a.org/", "Old", Timestamp::from_millis(1000)).unwrap();
+        history.add("https://b.org/", "New", Timestamp::from_millis(5000)).unwrap();
+        let mut icons = Favicons::new(&mut db).unwrap();
+        icons.store("https://a.org/a.ico", 16, 16, b"a", None).unwrap();
+        icons.store("https://b.org/b.ico", 16, 16, b"b", None).unwrap();
+        icons.map_page("https://a.org/", "https://a.org/a.ico").unwrap();
+        icons.map_page("https://b.org/", "https://b.org/b.ico").unwrap();
+
+        let range = TimeRange::Between(Timestamp::from_millis(0), Timestamp::from_millis(2000));
+        History::new(&mut db).unwrap().clear_range(range).unwrap();
+        let mut icons = Favicons::new(&mut db).unwrap();
+        assert_eq!(icons.best_icon("https://a.org/", 16).unwrap(), None);
+        assert!(icons.icons("https://a.org/a.ico").unwrap().is_empty());
+        assert!(icons.best_icon("https://b.org/", 16).unwrap().is_some());
+    }
+
+    #[test]
+    fn forget_site_keeps_other_hosts() {
+        let mut db = SQLite::new(":memory:").unwrap();
+        let mut history = History::new(&mut db).unwrap();
+        for (i, url) in ["https://example.com/", "https://a.example.com/1", "https://a.example.com/2",
+                         "https://example.org/", "https://myexample.com/"].iter().enumerate() {
+            history.add(url, "Page", Timestamp::from_millis(i as i64)).unwrap();
+        }
+
+        let report = history.forget_site("example.com").unwrap();
+        assert_eq!(report.entries, 3);
+        assert_eq!(report.hosts, vec![String::from("example.com"), String::from("a.example.com")]);
+        assert_eq!(urls(&mut db), vec![String::from("https://example.org/"), String::from("https://myexample.com/")]);
+        assert_eq!(History::new(&mut db).unwrap().forget_site("example.com").unwrap().entries, 0);
+    }
+
+    #[test]
+    fn forget_site_removes_its_exceptions() {
+        let mut db = SQLite::new(":memory:").unwrap();
+        let mut history = History::new(&mut db).unwrap();
+        history.add("https://example.com/", "Example", Timestamp::from_millis(1)).unwrap();
+        history.add("https://www.example.com/page", "Page", Timestamp::from_millis(2)).unwrap();
+        history.add("https://notexample.com/", "Other", Timestamp::from_millis(3)).unwrap();
+
+        let mut sites = SiteSettings::new(&mut db).unwrap();
+        let block = Value::Decision(Decision::Block);
+        for pattern in &["example.com", "*.example.com", "https://sub.example.com", "notexample.com", "*"] {
+            sites.set(pattern, Permission::Popups, block, None).unwrap();
+        }
+        sites.set("example.com", Permission::Zoom, Value::Zoom(1.5), None).unwrap();
+
+        let report = History::new(&mut db).unwrap().forget_site("Example.com").unwrap();
+        assert_eq!(report.entries, 2);
+        assert_eq!(report.hosts, vec![String::from("example.com"), String::from("www.example.com")]);
+        assert_eq!(report.site_settings, 4);
+
+        let left : Vec<String> = SiteSettings::new(&mut db).unwrap()
+            .exceptions(None, Timestamp::from_millis(0)).unwrap()
+            .iter().map(|e| e.pattern.to_string()).collect();
+        assert_eq!(left, vec![String::from("*"), String::from("notexample.com")]);
+    }
+}
@@ -0,0 +1,256 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Importer for Chromium based browsers
+//!
+//! The history is the `History` SQLite database and bookmarks are stored in
+//! the `Bookmarks` JSON file of the profile.
+
+use std::path::Path;
+
+use crate::data::db::{Error, TableProvider, FieldValue};
+use crate::data::db::sqlite::SQLite;
+use crate::data::time::Timestamp;
+use super::{ImportData, Visit, BookmarkNode};
+
+/// Seconds between 1601-01-01, the epoch used by Chromium, and 1970-01-01.
+const EPOCH_OFFSET : i64 = 11_644_473_600;
+
+/// Converts a Chromium date (microseconds since 1601-01-01) into a
+/// timestamp.
+fn convert_date(date : i64) -> Timestamp {
+    if date == 0 {
+        Timestamp::default()
+    } else {
+        Timestamp::from_micros(date).add_millis(-EPOCH_OFFSET * 1000)
+    }
+}
+
+/// Reads the history and bookmarks of the Chromium profile in `profile`.
+///
+/// A missing `Bookmarks` file is not an error, Chromium only creates it once
+/// a bookmark has been added.
+pub fn read(profile : &Path, warnings : &mut Vec<String>) -> Result<ImportData, Error> {
+    let path = profile.join("History");
+    if !path.is_file() {
+        return Err(Error::new(Some(2101), Some(format!("{} not found", path.display()))));
+    }
+    let mut db = SQLite::open_read_only(&path)?;
+    let visits = read_visits(&mut db)?;
+
+    let path = profile.join("Bookmarks");
+    let bookmarks = if path.is_file() {
+        read_bookmarks(&path)?
+    } else {
+        warnings.push(format!("{} not found, no bookmark imported", path.display()));
+        Vec::new()
+    };
+
+    Ok(ImportData { visits, bookmarks })
+}
+
+fn read_visits(db : &mut SQLite) -> Result<Vec<Visit>, Error> {
+    let rows = db.request("SELECT u.url AS url, u.title AS title, v.visit_time AS date \
+                                 FROM visits v JOIN urls u ON u.id = v.url \
+                                 ORDER BY v.visit_time;", &[])?;
+
+    let mut ret = Vec::with_capacity(rows.len());
+    for row in rows {
+        let url = match row.get("url") {
+            Some(Some(FieldValue::Text(t))) => t.clone(),
+            _ => continue,
+        };
+        let title = match row.get("title") {
+            Some(Some(FieldValue::Text(t))) => t.clone(),
+            _ => String::new(),
+        };
+        let date = match row.get("date") {
+            Some(Some(FieldValue::Integer(t))) => convert_date(*t),
+            _ => Timestamp::default(),
+        };
+        ret.push(Visit { url, title, date });
+    }
+
+    Ok(ret)
+}
+
+fn read_bookmarks(path : &Path) -> Result<Vec<BookmarkNode>, Error> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => return Err(Error::new(Some(2102), Some(format!("Can not read {}: {}", path.display(), e)))),
+    };
+    let json : serde_json::Value = match serde_json::from_str(&content) {
+        Ok(t) => t,
+        Err(e) => return Err(Error::new(Some(2103), Some(format!("Invalid bookmarks file {}: {}", path.display(), e)))),
+    };
+
+    let mut ret = Vec::new();
+    if let Some(roots) = json.get("roots").and_then(|r| r.as_object()) {
+        for root in ["bookmark_bar", "other", "synced"].iter() {
+            if let Some(node) = roots.get(*root) {
+                if let Some(node) = convert_node(node) {
+                    ret.push(node);
+                }
+            }
+        }
+    }
+
+    Ok(ret)
+}
+
+fn convert_node(node : &serde_json::Value) -> Option<BookmarkNode> {
+    let title = match node.get("name").and_then(|n| n.as_str()) {
+        Some(t) => String::from(t),
+        None => String::new(),
+    };
+    // Dates are stored as strings in this file.
+    let date = match node.get("date_added").and_then(|d| d.as_str()) {
+        Some(t) => convert_date(t.parse::<i64>().unwrap_or(0)),
+        None => Timestamp::default(),
+    };
+
+    match node.get("type").and_then(|t| t.as_str()) {
+        Some("url") => Some(BookmarkNode {
+            title,
+            url: node.get("url").and_then(|u| u.as_str()).map(String::from),
+            date,
+            children: Vec::new(),
+        }),
+        Some("folder") => Some(BookmarkNode {
+            title,
+            url: None,
+            date,
+            children: match node.get("children").and_then(|c| c.as_array()) {
+                Some(t) => t.iter().filter_map(convert_node).collect(),
+                None => Vec::new(),
+            },
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::data::db::TableProvider;
+    use crate::data::db::sqlite::SQLite;
+    use crate::data::history::{History, Filter};
+    use crate::data::import::{self, Browser, Progress, Stage};
+    use crate::data::time::Timestamp;
+
+    /// Chromium date of `millis` milliseconds after the UNIX epoch.
+    fn date(millis : i64) -> i64 {
+        (millis + super::EPOCH_OFFSET * 1000) * 1000
+    }
+
+    /// Profile whose `History` holds three visits of two pages, with a
+    /// `Bookmarks` file holding a bookmark in the bar and another in a folder
+    /// of the other bookmarks, if `bookmarks`.
+    fn profile(name : &str, bookmarks : bool) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sielo-chromium-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut db = SQLite::new(dir.join("History")).unwrap();
+        for request in &[
+            String::from("CREATE TABLE urls (id INTEGER PRIMARY KEY, url TEXT, title TEXT);"),
+            String::from("CREATE TABLE visits (id INTEGER PRIMARY KEY, url INTEGER, visit_time INTEGER);"),
+            String::from("INSERT INTO urls VALUES (1, 'https://a.org/', 'A'), (2, 'https://b.org/', 'B');"),
+            format!("INSERT INTO visits VALUES (1, 1, {}), (2, 2, {}), (3, 1, {});", date(1000), date(3000), date(2000)),
+        ] {
+            db.request(request, &[]).unwrap();
+        }
+
+        if bookmarks {
+            let json = serde_json::json!({
+                "version": 1,
+                "roots": {
+                    "bookmark_bar": {
+                        "type": "folder", "name": "Bookmarks bar", "date_added": "0",
+                        "children": [
+                            { "type": "url", "name": "B", "url": "https://b.org/", "date_added": date(5000).to_string() },
+                        ],
+                    },
+                    "other": {
+                        "type": "folder", "name": "Other bookmarks", "date_added": "0",
+                        "children": [
+                            { "type": "folder", "name": "Sub", "date_added": "0", "children": [
+                                { "type": "url", "name": "A", "url": "https://a.org/", "date_added": "0" },
+                            ]},
+                        ],
+                    },
+                },
+            });
+            std::fs::write(dir.join("Bookmarks"), json.to_string()).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn reads_visits_and_bookmarks() {
+        let mut warnings = Vec::new();
+        let data = super::read(&profile("read", true), &mut warnings).unwrap();
+        assert!(warnings.is_empty());
+
+        let visits : Vec<_> = data.visits.iter().map(|v| (v.url.as_str(), v.date)).collect();
+        assert_eq!(visits, vec![("https://a.org/", Timestamp::from_millis(1000)),
+                                ("https://a.org/", Timestamp::from_millis(2000)),
+                                ("https://b.org/", Timestamp::from_millis(3000))]);
+
+        let roots : Vec<_> = data.bookmarks.iter().map(|b| b.title.as_str()).collect();
+        assert_eq!(roots, vec!["Bookmarks bar", "Other bookmarks"]);
+        assert_eq!(data.bookmarks[0].children[0].date, Timestamp::from_millis(5000));
+        assert_eq!(data.bookmarks[1].children[0].children[0].url.as_deref(), Some("https://a.org/"));
+        assert_eq!(data.bookmarks[1].children[0].children[0].date, Timestamp::default());
+    }
+
+    #[test]
+    fn missing_bookmarks_only_warn() {
+        let mut warnings = Vec::new();
+        let data = super::read(&profile("warn", false), &mut warnings).unwrap();
+        assert_eq!(data.visits.len(), 3);
+        assert!(data.bookmarks.is_empty());
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn second_import_is_deduplicated() {
+        let dir = profile("dedup", true);
+        let mut db = SQLite::new(":memory:").unwrap();
+
+        let first = import::import(Browser::Chromium, &dir, &mut db, &mut |_| true).unwrap();
+        assert_eq!((first.history_imported, first.history_skipped), (3, 0));
+        assert_eq!((first.bookmarks_imported, first.bookmarks_skipped), (2, 0));
+
+        let second = import::import(Browser::Chromium, &dir, &mut db, &mut |_| true).unwrap();
+        assert_eq!((second.history_imported, second.history_skipped), (0, 3));
+        assert_eq!((second.bookmarks_imported, second.bookmarks_skipped), (0, 2));
+        assert_eq!(History::new(&mut db).unwrap().entries(&Filter::default()).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn progress_is_reported() {
+        let dir = profile("progress", true);
+        let mut db = SQLite::new(":memory:").unwrap();
+        let mut seen = Vec::new();
+        import::import(Browser::Chromium, &dir, &mut db, &mut |p| {
+            seen.push(*p);
+            true
+        }).unwrap();
+
+        assert_eq!(seen.len(), 5);
+        assert_eq!(seen[2], Progress { stage: Stage::History, done: 3, total: 3 });
+        assert_eq!(seen[4], Progress { stage: Stage::Bookmarks, done: 2, total: 2 });
+    }
+
+    #[test]
+    fn stopping_rolls_back() {
+        let dir = profile("stop", true);
+        let mut db = SQLite::new(":memory:").unwrap();
+        let result = import::import(Browser::Chromium, &dir, &mut db, &mut |p| p.stage == Stage::History);
+        assert_eq!(result.err().and_then(|e| e.code()), Some(2104));
+        assert!(History::new(&mut db).unwrap().entries(&Filter::default()).unwrap().is_empty());
+    }
+}
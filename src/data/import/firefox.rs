@@ -0,0 +1,238 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Importer for Firefox profiles
+//!
+//! History and bookmarks both live in `places.sqlite`. Firefox keeps the file
+//! locked while running, so it should be closed before importing.
+
+use std::collections::hash_map::HashMap;
+use std::path::Path;
+
+use crate::data::db::{Error, TableProvider, FieldValue};
+use crate::data::db::sqlite::SQLite;
+use crate::data::time::Timestamp;
+use super::{ImportData, Visit, BookmarkNode};
+
+/// `moz_bookmarks.type` of a bookmark.
+const TYPE_BOOKMARK : i64 = 1;
+/// `moz_bookmarks.type` of a folder.
+const TYPE_FOLDER : i64 = 2;
+/// GUID of the folder holding tags, which are not real bookmarks.
+const TAGS_GUID : &str = "tags________";
+
+/// Reads the history and bookmarks of the Firefox profile in `profile`.
+pub fn read(profile : &Path, _warnings : &mut Vec<String>) -> Result<ImportData, Error> {
+    let path = profile.join("places.sqlite");
+    if !path.is_file() {
+        return Err(Error::new(Some(2101), Some(format!("{} not found", path.display()))));
+    }
+    let mut db = SQLite::open_read_only(&path)?;
+
+    let visits = read_visits(&mut db)?;
+    let bookmarks = read_bookmarks(&mut db)?;
+
+    Ok(ImportData { visits, bookmarks })
+}
+
+fn read_visits(db : &mut SQLite) -> Result<Vec<Visit>, Error> {
+    let rows = db.request("SELECT p.url AS url, p.title AS title, v.visit_date AS date \
+                                 FROM moz_historyvisits v JOIN moz_places p ON p.id = v.place_id \
+                                 ORDER BY v.visit_date;", &[])?;
+
+    let mut ret = Vec::with_capacity(rows.len());
+    for row in rows {
+        let url = match row.get("url") {
+            Some(Some(FieldValue::Text(t))) => t.clone(),
+            _ => continue,
+        };
+        let title = match row.get("title") {
+            Some(Some(FieldValue::Text(t))) => t.clone(),
+            _ => String::new(),
+        };
+        // Firefox dates are in microseconds since the UNIX epoch.
+        let date = match row.get("date") {
+            Some(Some(FieldValue::Integer(t))) => Timestamp::from_micros(*t),
+            _ => Timestamp::default(),
+        };
+        ret.push(Visit { url, title, date });
+    }
+
+    Ok(ret)
+}
+
+fn read_bookmarks(db : &mut SQLite) -> Result<Vec<BookmarkNode>, Error> {
+    let rows = db.request("SELECT b.id AS id, b.type AS type, b.parent AS parent, \
+                                 b.title AS title, b.dateAdded AS date, b.guid AS guid, p.url AS url \
+                                 FROM moz_bookmarks b LEFT JOIN moz_places p ON p.id = b.fk \
+                                 ORDER BY b.parent, b.position;", &[])?;
+
+    let mut children = HashMap::<i64, Vec<(i64, BookmarkNode)>>::new();
+    let mut root = None;
+    for row in rows {
+        let int = |name : &str| match row.get(name) {
+            Some(Some(FieldValue::Integer(t))) => *t,
+            _ => 0,
+        };
+        let text = |name : &str| match row.get(name) {
+            Some(Some(FieldValue::Text(t))) => t.clone(),
+            _ => String::new(),
+        };
+
+        let id = int("id");
+        let parent = int("parent");
+        if parent == 0 {
+            root = Some(id);
+            continue;
+        }
+        if text("guid") == TAGS_GUID {
+            continue;
+        }
+        let url = match int("type") {
+            TYPE_BOOKMARK => Some(text("url")),
+            TYPE_FOLDER => None,
+            // Separators
+            _ => continue,
+        };
+
+        children.entry(parent).or_default().push((id, BookmarkNode {
+            title: text("title"),
+            url,
+            date: Timestamp::from_micros(int("date")),
+            children: Vec::new(),
+        }));
+    }
+
+    match root {
+        Some(t) => Ok(build_tree(t, &mut children)),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn build_tree(parent : i64, children : &mut HashMap<i64, Vec<(i64, BookmarkNode)>>) -> Vec<BookmarkNode> {
+    let nodes = match children.remove(&parent) {
+        Some(t) => t,
+        None => return Vec::new(),
+    };
+
+    nodes.into_iter().map(|(id, mut node)| {
+        if node.url.is_none() {
+            node.children = build_tree(id, children);
+        }
+        node
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::data::db::TableProvider;
+    use crate::data::db::sqlite::SQLite;
+    use crate::data::history::{History, Filter};
+    use crate::data::import::{self, Browser, Progress, Stage};
+    use crate::data::time::Timestamp;
+
+    /// Profile whose `places.sqlite` holds three visits of two pages, and a
+    /// menu with a bookmark, a separator and a folder. The tags folder must
+    /// be left out.
+    fn profile(name : &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sielo-firefox-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut db = SQLite::new(dir.join("places.sqlite")).unwrap();
+        for request in &[
+            "CREATE TABLE moz_places (id INTEGER PRIMARY KEY, url TEXT, title TEXT);",
+            "CREATE TABLE moz_historyvisits (id INTEGER PRIMARY KEY, place_id INTEGER, visit_date INTEGER);",
+            "CREATE TABLE moz_bookmarks (id INTEGER PRIMARY KEY, type INTEGER, fk INTEGER, parent INTEGER, \
+             position INTEGER, title TEXT, dateAdded INTEGER, guid TEXT);",
+            "INSERT INTO moz_places VALUES (1, 'https://a.org/', 'A'), (2, 'https://b.org/', NULL);",
+            "INSERT INTO moz_historyvisits VALUES (1, 1, 1000000), (2, 2, 3000000), (3, 1, 2000000);",
+            "INSERT INTO moz_bookmarks VALUES \
+             (1, 2, NULL, 0, 0, '', 0, 'root________'), \
+             (2, 2, NULL, 1, 0, 'menu', 0, 'menu________'), \
+             (3, 2, NULL, 1, 1, 'tags', 0, 'tags________'), \
+             (4, 1, 2, 2, 0, 'B', 5000000, 'b'), \
+             (5, 3, NULL, 2, 1, '', 0, 'separator'), \
+             (6, 2, NULL, 2, 2, 'Sub', 0, 'sub'), \
+             (7, 1, 1, 6, 0, 'A', 6000000, 'a'), \
+             (8, 2, NULL, 3, 0, 'tag', 0, 'tag'), \
+             (9, 1, 1, 8, 0, '', 0, 'tagged');",
+        ] {
+            db.request(request, &[]).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn reads_visits_and_bookmarks() {
+        let mut warnings = Vec::new();
+        let data = super::read(&profile("read"), &mut warnings).unwrap();
+        assert!(warnings.is_empty());
+
+        let visits : Vec<_> = data.visits.iter().map(|v| (v.url.as_str(), v.title.as_str(), v.date)).collect();
+        assert_eq!(visits, vec![("https://a.org/", "A", Timestamp::from_millis(1000)),
+                                ("https://a.org/", "A", Timestamp::from_millis(2000)),
+                                ("https://b.org/", "", Timestamp::from_millis(3000))]);
+
+        assert_eq!(data.bookmarks.len(), 1);
+        let menu = &data.bookmarks[0];
+        assert_eq!((menu.title.as_str(), menu.url.as_ref()), ("menu", None));
+        assert_eq!(menu.children.len(), 2);
+        assert_eq!(menu.children[0].url.as_deref(), Some("https://b.org/"));
+        assert_eq!(menu.children[0].date, Timestamp::from_millis(5000));
+        assert_eq!(menu.children[1].title, "Sub");
+        assert_eq!(menu.children[1].children[0].url.as_deref(), Some("https://a.org/"));
+    }
+
+    #[test]
+    fn missing_profile_fails() {
+        let dir = std::env::temp_dir().join(format!("sielo-firefox-missing-{}", std::process::id()));
+        assert_eq!(super::read(&dir, &mut Vec::new()).err().and_then(|e| e.code()), Some(2101));
+    }
+
+    #[test]
+    fn second_import_is_deduplicated() {
+        let dir = profile("dedup");
+        let mut db = SQLite::new(":memory:").unwrap();
+
+        let first = import::import(Browser::Firefox, &dir, &mut db, &mut |_| true).unwrap();
+        assert_eq!((first.history_imported, first.history_skipped), (3, 0));
+        assert_eq!((first.bookmarks_imported, first.bookmarks_skipped), (2, 0));
+
+        let second = import::import(Browser::Firefox, &dir, &mut db, &mut |_| true).unwrap();
+        assert_eq!((second.history_imported, second.history_skipped), (0, 3));
+        assert_eq!((second.bookmarks_imported, second.bookmarks_skipped), (0, 2));
+        assert_eq!(History::new(&mut db).unwrap().entries(&Filter::default()).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn progress_is_reported() {
+        let dir = profile("progress");
+        let mut db = SQLite::new(":memory:").unwrap();
+        let mut seen = Vec::new();
+        import::import(Browser::Firefox, &dir, &mut db, &mut |p| {
+            seen.push(*p);
+            true
+        }).unwrap();
+
+        assert_eq!(seen, vec![
+            Progress { stage: Stage::History, done: 1, total: 3 },
+            Progress { stage: Stage::History, done: 2, total: 3 },
+            Progress { stage: Stage::History, done: 3, total: 3 },
+            Progress { stage: Stage::Bookmarks, done: 1, total: 2 },
+            Progress { stage: Stage::Bookmarks, done: 2, total: 2 },
+        ]);
+    }
+
+    #[test]
+    fn stopping_rolls_back() {
+        let dir = profile("stop");
+        let mut db = SQLite::new(":memory:").unwrap();
+        let result = import::import(Browser::Firefox, &dir, &mut db, &mut |p| p.done < 2);
+        assert_eq!(result.err().and_then(|e| e.code()), Some(2104));
+        assert!(History::new(&mut db).unwrap().entries(&Filter::default()).unwrap().is_empty());
+    }
+}
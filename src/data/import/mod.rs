@@ -0,0 +1,267 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Import of history and bookmarks from other browsers
+//!
+//! Every importer reads the profile of another browser into an
+//! [`ImportData`](struct.ImportData.html), which is then written into Sielo's
+//! stores by [`write`](fn.write.html). Entries already present are skipped,
+//! so importing the same profile twice is harmless.
+
+use std::path::Path;
+
+use super::db::{Error, TableProvider};
+use super::history::History;
+use super::bookmarks::Bookmarks;
+use super::time::Timestamp;
+
+pub mod firefox;
+pub mod chromium;
+
+/// Browsers Sielo can import from.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum Browser {
+    Firefox,
+    Chromium,
+}
+
+impl Browser {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Browser::Firefox => "Firefox",
+            Browser::Chromium => "Chromium",
+        }
+    }
+
+    /// Browser named `name`, whatever the case.
+    pub fn from_name(name : &str) -> Option<Browser> {
+        [Browser::Firefox, Browser::Chromium].iter().cloned().find(|b| b.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// A visit read from another browser.
+#[derive(Debug, Clone)]
+pub struct Visit {
+    pub url : String,
+    pub title : String,
+    pub date : Timestamp,
+}
+
+/// A bookmark, or a folder when `url` is `None`, read from another browser.
+#[derive(Debug, Clone)]
+pub struct BookmarkNode {
+    pub title : String,
+    pub url : Option<String>,
+    pub date : Timestamp,
+    pub children : Vec<BookmarkNode>,
+}
+
+/// Everything read from a profile, not written anywhere yet.
+#[derive(Debug, Default, Clone)]
+pub struct ImportData {
+    pub visits : Vec<Visit>,
+    pub bookmarks : Vec<BookmarkNode>,
+}
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum Stage {
+    History,
+    Bookmarks,
+}
+
+impl Stage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::History => "history",
+            Stage::Bookmarks => "bookmarks",
+        }
+    }
+}
+
+/// Progress of an import, given to the callback after every entry. The
+/// callback returns `false` to stop the import, which is then rolled back.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct Progress {
+    pub stage : Stage,
+    pub done : usize,
+    pub total : usize,
+}
+
+/// Summary of an import, sent back to the UI.
+#[derive(Eq, PartialEq, Debug, Default, Clone)]
+pub struct ImportReport {
+    pub history_imported : usize,
+    pub history_skipped : usize,
+    pub bookmarks_imported : usize,
+    pub bookmarks_skipped : usize,
+    /// Non fatal problems, like a missing bookmarks file.
+    pub warnings : Vec<String>,
+}
+
+/// Reads the profile of `browser` located in `profile` and writes its
+/// content into `db`.
+pub fn import<T : TableProvider>(browser : Browser,
+                                 profile : &Path,
+                                 db : &mut T,
+                                 progress : &mut dyn FnMut(&Progress) -> bool) -> Result<ImportReport, Error> {
+    let mut warnings = Vec::new();
+    let data = match browser {
+        Browser::Firefox => firefox::read(profile, &mut warnings),
+        Browser::Chromium => chromium::read(profile, &mut warnings),
+    };
+    let data = data?;
+
+    match write(&data, browser.name(), db, progress) {
+        Ok(mut t) => {
+            t.warnings = warnings;
+            Ok(t)
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes `data` into the history and bookmark stores of `db`.
+///
+/// Bookmarks are put in a root folder named "Imported from `source`". The
+/// whole import is done in a single transaction.
+pub fn write<T : TableProvider>(data : &ImportData,
+                                source : &str,
+                                db : &mut T,
+                                progress : &mut dyn FnMut(&Progress) -> bool) -> Result<ImportReport, Error> {
+    db.transaction(|db| {
+        let mut report = ImportReport::default();
+
+        {
+            let mut history = match History::new(&mut *db) {
+                Ok(t) => t,
+                Err(e) => return Err(e),
+            };
+            let total = data.visits.len();
+            for (i, visit) in data.visits.iter().enumerate() {
+                match history.contains(&visit.url, visit.date) {
+                    Ok(true) => report.history_skipped += 1,
+                    Ok(false) => match history.add(&visit.url, &visit.title, visit.date) {
+                        Ok(_) => report.history_imported += 1,
+                        Err(e) => return Err(e),
+                    },
+                    Err(e) => return Err(e),
+                }
+                if !progress(&Progress { stage: Stage::History, done: i + 1, total }) {
+                    return Err(stopped());
+                }
+            }
+        }
+
+        if !data.bookmarks.is_empty() {
+            let mut bookmarks = match Bookmarks::new(&mut *db) {
+                Ok(t) => t,
+                Err(e) => return Err(e),
+            };
+            let root = match bookmarks.folder(&format!("Imported from {}", source), None) {
+                Ok(t) => t,
+                Err(e) => return Err(e),
+            };
+            let total = data.bookmarks.iter().map(count_bookmarks).sum();
+            let mut done = 0;
+            for node in &data.bookmarks {
+                write_bookmark(&mut bookmarks, node, root, &mut report,
+                                               &mut done, total, progress)?
+            }
+        }
+
+        Ok(report)
+    })
+}
+
+fn count_bookmarks(node : &BookmarkNode) -> usize {
+    match node.url {
+        Some(_) => 1,
+        None => node.children.iter().map(count_bookmarks).sum(),
+    }
+}
+
+fn write_bookmark<T : TableProvider>(bookmarks : &mut Bookmarks<T>,
+                                     node : &BookmarkNode,
+                                     parent : i64,
+                                     report : &mut ImportReport,
+                                     done : &mut usize,
+                                     total : usize,
+                                     progress : &mut dyn FnMut(&Progress) -> bool) -> Result<(), Error> {
+    match &node.url {
+        Some(url) => {
+            match bookmarks.contains(url) {
+                Ok(true) => report.bookmarks_skipped += 1,
+                Ok(false) => match bookmarks.add_bookmark(url, &node.title, node.date, Some(parent)) {
+                    Ok(_) => report.bookmarks_imported += 1,
+                    Err(e) => return Err(e),
+                },
+                Err(e) => return Err(e),
+            }
+            *done += 1;
+            if !progress(&Progress { stage: Stage::Bookmarks, done: *done, total }) {
+                return Err(stopped());
+            }
+        },
+        None => {
+            let folder = bookmarks.folder(&node.title, Some(parent))?;
+            for child in &node.children {
+                write_bookmark(bookmarks, child, folder, report, done, total, progress)?
+            }
+        },
+    }
+
+    Ok(())
+}
+
+fn stopped() -> Error {
+    Error::new(Some(2104), Some(String::from("Import stopped")))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::db::{TableProvider, FieldValue};
+    use crate::data::db::sqlite::SQLite;
+    use crate::data::{bookmarks, history};
+    use super::*;
+
+    /// Details of the plan SQLite uses for `request`.
+    fn plan(db : &mut SQLite, request : &str, arguments : &[&str]) -> String {
+        db.request(&format!("EXPLAIN QUERY PLAN {}", request), arguments).unwrap().iter()
+            .filter_map(|r| match r.get("detail") {
+                Some(Some(FieldValue::Text(t))) => Some(t.clone()),
+                _ => None,
+            })
+            .collect::<Vec<String>>()
+            .join("; ")
+    }
+
+    #[test]
+    fn duplicates_are_found_through_indexes() {
+        let mut db = SQLite::new(":memory:").unwrap();
+        let data = ImportData {
+            visits: (0..100).map(|i| Visit {
+                url: format!("https://example.com/{}", i % 10),
+                title: String::from("Page"),
+                date: Timestamp::from_millis(i % 50),
+            }).collect(),
+            bookmarks: vec![BookmarkNode {
+                title: String::from("Page"),
+                url: Some(String::from("https://example.com/0")),
+                date: Timestamp::from_millis(0),
+                children: Vec::new(),
+            }],
+        };
+        let report = write(&data, "Test", &mut db, &mut |_| true).unwrap();
+        assert_eq!((report.history_imported, report.history_skipped), (50, 50));
+        let report = write(&data, "Test", &mut db, &mut |_| true).unwrap();
+        assert_eq!((report.history_imported, report.history_skipped), (0, 100));
+        assert_eq!((report.bookmarks_imported, report.bookmarks_skipped), (0, 1));
+
+        let visit = plan(&mut db, "SELECT COUNT(*) AS count FROM history WHERE url = ? AND date = ?;", &["a", "0"]);
+        assert!(visit.contains(history::VISIT_INDEX), "{}", visit);
+        let bookmark = plan(&mut db, "SELECT COUNT(*) AS count FROM bookmarks WHERE url = ?;", &["a"]);
+        assert!(bookmark.contains(bookmarks::URL_INDEX), "{}", bookmark);
+    }
+}
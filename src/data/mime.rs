@@ -0,0 +1,177 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Classification of content types
+//!
+//! Maps MIME types to the categories shown by the UI. The mapping is an
+//! ordered list of patterns, the first matching one wins, so specific types
+//! must come before the wildcards.
+
+/// MIME type stored when the content type of an entry is not known.
+pub const UNKNOWN : &str = "sielo/unknown";
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Hash)]
+pub enum Category {
+    Page,
+    Image,
+    Pdf,
+    Media,
+    Download,
+    Other,
+}
+
+/// Patterns in order of priority. A pattern ending with `/*` matches every
+/// subtype.
+pub const MAPPING : &[(&str, Category)] = &[
+    ("text/html", Category::Page),
+    ("application/xhtml+xml", Category::Page),
+    ("text/plain", Category::Page),
+    ("application/pdf", Category::Pdf),
+    ("image/*", Category::Image),
+    ("audio/*", Category::Media),
+    ("video/*", Category::Media),
+    ("application/ogg", Category::Media),
+    ("application/json", Category::Page),
+    ("application/xml", Category::Page),
+    ("text/*", Category::Page),
+    ("application/*", Category::Download),
+];
+
+impl Category {
+    pub const ALL : [Category; 6] = [
+        Category::Page,
+        Category::Image,
+        Category::Pdf,
+        Category::Media,
+        Category::Download,
+        Category::Other,
+    ];
+
+    /// Category of the content type `mime`.
+    pub fn of(mime : &str) -> Category {
+        let mime = normalize(mime);
+        for (pattern, category) in MAPPING {
+            if matches(pattern, &mime) {
+                return *category;
+            }
+        }
+        Category::Other
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Category::Page => "page",
+            Category::Image => "image",
+            Category::Pdf => "pdf",
+            Category::Media => "media",
+            Category::Download => "download",
+            Category::Other => "other",
+        }
+    }
+
+    pub fn from_name(name : &str) -> Option<Category> {
+        Category::ALL.iter().find(|c| c.name() == name).copied()
+    }
+
+    /// SQL expression giving the category name of the MIME type stored in
+    /// `column`. Values must be [normalized](fn.normalize.html).
+    pub fn sql_expression(column : &str) -> String {
+        let mut ret = String::from("CASE");
+        for (pattern, category) in MAPPING {
+            if pattern.ends_with("/*") {
+                ret += &*format!(" WHEN {} LIKE '{}%' THEN '{}'",
+                                 column, &pattern[..pattern.len() - 1], category.name());
+            } else {
+                ret += &*format!(" WHEN {} = '{}' THEN '{}'", column, pattern, category.name());
+            }
+        }
+        ret += &*format!(" ELSE '{}' END", Category::Other.name());
+        ret
+    }
+}
+
+/// Lowercases `mime` and removes its parameters, so
+/// `Text/HTML; charset=UTF-8` becomes `text/html`.
+pub fn normalize(mime : &str) -> String {
+    let end = match mime.find(';') {
+        Some(t) => t,
+        None => mime.len(),
+    };
+    let ret = mime[..end].trim().to_lowercase();
+    if ret.is_empty() {
+        String::from(UNKNOWN)
+    } else {
+        ret
+    }
+}
+
+fn matches(pattern : &str, mime : &str) -> bool {
+    if pattern.ends_with("/*") {
+        mime.starts_with(&pattern[..pattern.len() - 1])
+    } else {
+        pattern == mime
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::db::{TableProvider, FieldValue};
+    use crate::data::db::sqlite::SQLite;
+    use super::*;
+
+    const TYPES : &[&str] = &[
+        "text/html", "application/xhtml+xml", "text/plain", "text/css", "application/pdf",
+        "image/png", "image/svg+xml", "audio/ogg", "video/webm", "application/ogg",
+        "application/json", "application/xml", "application/zip", "application/octet-stream",
+        "font/woff2", "multipart/form-data", "sielo/unknown", "html", "",
+    ];
+
+    #[test]
+    fn categories_of_types() {
+        assert_eq!(Category::of("text/html"), Category::Page);
+        assert_eq!(Category::of("application/pdf"), Category::Pdf);
+        assert_eq!(Category::of("image/webp"), Category::Image);
+        assert_eq!(Category::of("video/mp4"), Category::Media);
+        assert_eq!(Category::of("application/ogg"), Category::Media);
+        assert_eq!(Category::of("application/json"), Category::Page);
+        assert_eq!(Category::of("application/zip"), Category::Download);
+        assert_eq!(Category::of("font/woff2"), Category::Other);
+        assert_eq!(Category::of(UNKNOWN), Category::Other);
+        // Parameters and case do not change the category.
+        assert_eq!(Category::of("Application/PDF; name=\"a.pdf\""), Category::Pdf);
+        assert_eq!(Category::of(" IMAGE/PNG "), Category::Image);
+    }
+
+    #[test]
+    fn types_are_normalized() {
+        assert_eq!(normalize("Text/HTML; charset=UTF-8"), "text/html");
+        assert_eq!(normalize("  image/PNG  "), "image/png");
+        assert_eq!(normalize("text/plain;"), "text/plain");
+        assert_eq!(normalize(""), UNKNOWN);
+        assert_eq!(normalize("; charset=utf-8"), UNKNOWN);
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for category in Category::ALL.iter() {
+            assert_eq!(Category::from_name(category.name()), Some(*category));
+        }
+        assert_eq!(Category::from_name("Page"), None);
+    }
+
+    #[test]
+    fn sql_expression_agrees_with_mapping() {
+        let mut db = SQLite::new(":memory:").unwrap();
+        for mime in TYPES {
+            let mime = normalize(mime);
+            let rows = db.request(&format!("SELECT {} AS category;", Category::sql_expression("?1")), &[mime.as_str()]).unwrap();
+            let name = match rows.first().and_then(|r| r.get("category")) {
+                Some(Some(FieldValue::Text(t))) => t.clone(),
+                _ => panic!("No category for {}", mime),
+            };
+            assert_eq!(name, Category::of(&mime).name(), "{}", mime);
+        }
+    }
+}
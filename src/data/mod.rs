@@ -6,9 +6,26 @@
 //! Collection of tools used by Sielo for data management purposes.
 //! It implements toolset of:
 //!  * [History system]()
+//!  * [Bookmarks system]()
+//!  * [Favicon store]() shared by history and bookmarks
+//!  * [Site settings]() and permissions overriding the settings per origin
+//!  * [MIME classification]() of history entries
+//!  * [Import]() of history and bookmarks from other browsers
 //!  * [Database interface]() between SQLite and Sielo.
+//!  * [Time representation]() used in the databases
 //!  * [Settings system]() using TOML files
+//!  * [Search engines]() registry with OpenSearch import
+//!  * [Profile management]() with one database and settings file per profile
 //!  * [Modules management]() using OpenSSL and SQLite.
 
 pub mod history;
-pub mod db;
\ No newline at end of file
+pub mod bookmarks;
+pub mod favicon;
+pub mod site_settings;
+pub mod mime;
+pub mod import;
+pub mod db;
+pub mod time;
+pub mod settings;
+pub mod search;
+pub mod profile;
\ No newline at end of file
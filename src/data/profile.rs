@@ -0,0 +1,478 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Profile management
+//!
+//! A profile is a directory holding its own database and settings file.
+//! Profiles live in a root directory, next to a `profiles.toml` index:
+//!
+//! ```toml
+//! default = "default"
+//!
+//! [[profiles]]
+//! name = "Default"
+//! directory = "default"
+//! ```
+//!
+//! Renaming a profile only changes its name, its directory never moves. A
+//! running core [locks](struct.ProfileLock.html) its profile so a second
+//! instance can not open it at the same time.
+//!
+//! The lock is taken by the operating system on the lock file (`flock` on
+//! Unix, `LockFileEx` on Windows), so it goes away with the process holding
+//! it, even if that process crashed.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+
+use toml::Value;
+use toml::value::Table;
+
+use super::db::Error;
+use super::settings;
+
+/// Name of the index file in the root directory.
+pub const INDEX_FILE : &str = "profiles.toml";
+/// Name of the database of a profile.
+pub const DATABASE_FILE : &str = "sielo.db";
+/// Name of the settings file of a profile.
+pub const SETTINGS_FILE : &str = "settings.toml";
+/// Name of the lock file of a profile in use.
+pub const LOCK_FILE : &str = "lock";
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Profile {
+    pub name : String,
+    /// Absolute path of the directory of the profile.
+    pub directory : PathBuf,
+}
+
+impl Profile {
+    pub fn database_path(&self) -> PathBuf {
+        self.directory.join(DATABASE_FILE)
+    }
+
+    pub fn settings_path(&self) -> PathBuf {
+        self.directory.join(SETTINGS_FILE)
+    }
+
+    pub fn lock_path(&self) -> PathBuf {
+        self.directory.join(LOCK_FILE)
+    }
+
+    /// Checks if a running core is using this profile.
+    pub fn is_locked(&self) -> bool {
+        let file = match fs::OpenOptions::new().read(true).write(true).open(self.lock_path()) {
+            Ok(t) => t,
+            Err(e) => return e.kind() != io::ErrorKind::NotFound,
+        };
+        match try_lock(&file) {
+            Ok(t) => !t,
+            Err(_) => true,
+        }
+    }
+}
+
+/// Exclusive use of a profile, released when dropped.
+///
+/// The lock file itself stays in the profile: removing it would let a
+/// process which already opened it lock a file nobody else can see anymore.
+#[derive(Debug)]
+pub struct ProfileLock {
+    path : PathBuf,
+    /// Only kept open: the lock lasts as long as the file.
+    _file : fs::File,
+}
+
+impl ProfileLock {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+pub struct ProfileManager {
+    root : PathBuf,
+    profiles : Vec<Profile>,
+    default : Option<String>,
+}
+
+impl ProfileManager {
+    /// Opens the profiles stored in `root`, creating the directory if
+    /// needed.
+    pub fn open<P : AsRef<Path>>(root : P) -> Result<Self, Error> {
+        let root = root.as_ref().to_path_buf();
+        if let Err(e) = fs::create_dir_all(&root) {
+            return Err(Error::new(Some(5001), Some(format!("Can not create {}: {}", root.display(), e))));
+        }
+
+        let mut ret = Self { root, profiles: Vec::new(), default: None };
+        let index = ret.root.join(INDEX_FILE);
+        if !index.exists() {
+            return Ok(ret);
+        }
+
+        let content = match fs::read_to_string(&index) {
+            Ok(t) => t,
+            Err(e) => return Err(Error::new(Some(5001), Some(format!("Can not read {}: {}", index.display(), e)))),
+        };
+        let document = match content.parse::<Value>() {
+            Ok(Value::Table(t)) => t,
+            _ => return Err(Error::new(Some(5002), Some(format!("Invalid profile index {}", index.display())))),
+        };
+
+        if let Some(Value::Array(profiles)) = document.get("profiles") {
+            for p in profiles {
+                if let (Some(Value::String(name)), Some(Value::String(directory))) = (p.get("name"), p.get("directory")) {
+                    ret.profiles.push(Profile { name: name.clone(), directory: ret.root.join(directory) });
+                }
+            }
+        }
+        ret.default = match document.get("default") {
+            Some(Value::String(t)) if ret.profiles.iter().any(|p| &p.name == t) => Some(t.clone()),
+            _ => None,
+        };
+
+        Ok(ret)
+    }
+
+    /// Default location of the profiles for the current user.
+    pub fn default_root() -> PathBuf {
+        let var = |name : &str| std::env::var_os(name).map(PathBuf::from);
+
+        if cfg!(windows) {
+            match var("APPDATA") {
+                Some(t) => t.join("Sielo"),
+                None => PathBuf::from("Sielo"),
+            }
+        } else if cfg!(target_os = "macos") {
+            match var("HOME") {
+                Some(t) => t.join("Library").join("Application Support").join("Sielo"),
+                None => PathBuf::from(".sielo"),
+            }
+        } else {
+            match (var("XDG_DATA_HOME"), var("HOME")) {
+                (Some(t), _) => t.join("sielo"),
+                (None, Some(t)) => t.join(".local").join("share").join("sielo"),
+                (None, None) => PathBuf::from(".sielo"),
+            }
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn list(&self) -> &[Profile] {
+        &self.profiles
+    }
+
+    pub fn get(&self, name : &str) -> Option<&Profile> {
+        self.profiles.iter().find(|p| p.name == name)
+    }
+
+    /// Profile used when none is asked for: the one marked as default, or
+    /// the first one.
+    pub fn default_profile(&self) -> Option<&Profile> {
+        match &self.default {
+            Some(t) => self.get(t),
+            None => self.profiles.first(),
+        }
+    }
+
+    pub fn set_default(&mut self, name : &str) -> Result<(), Error> {
+        if self.get(name).is_none() {
+            return Err(unknown_profile(name));
+        }
+        self.default = Some(String::from(name));
+        self.save()
+    }
+
+    /// Creates an empty profile named `name`.
+    pub fn create(&mut self, name : &str) -> Result<Profile, Error> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(Error::new(Some(5003), Some(String::from("Profile name can not be empty"))));
+        }
+        if self.get(name).is_some() {
+            return Err(Error::new(Some(5004), Some(format!("Profile {} already exists", name))));
+        }
+
+        // Directory named after the profile, made unique.
+        let base = directory_name(name);
+        let mut directory = base.clone();
+        let mut i = 1;
+        while self.root.join(&directory).exists() {
+            i += 1;
+            directory = format!("{}-{}", base, i);
+        }
+        let directory = self.root.join(directory);
+        if let Err(e) = fs::create_dir_all(&directory) {
+            return Err(Error::new(Some(5001), Some(format!("Can not create {}: {}", directory.display(), e))));
+        }
+
+        let profile = Profile { name: String::from(name), directory };
+        self.profiles.push(profile.clone());
+        if self.default.is_none() {
+            self.default = Some(String::from(name));
+        }
+        match self.save() {
+            Ok(_) => Ok(profile),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn rename(&mut self, name : &str, new_name : &str) -> Result<(), Error> {
+        let new_name = new_name.trim();
+        if new_name.is_empty() {
+            return Err(Error::new(Some(5003), Some(String::from("Profile name can not be empty"))));
+        }
+        if self.get(new_name).is_some() {
+            return Err(Error::new(Some(5004), Some(format!("Profile {} already exists", new_name))));
+        }
+        match self.profiles.iter_mut().find(|p| p.name == name) {
+            Some(p) => p.name = String::from(new_name),
+            None => return Err(unknown_profile(name)),
+        }
+        if self.default.as_ref().map(|d| d == name).unwrap_or(false) {
+            self.default = Some(String::from(new_name));
+        }
+        self.save()
+    }
+
+    /// Deletes a profile and everything it contains. A profile in use, or
+    /// whose directory is not inside the root directory, can not be
+    /// deleted.
+    pub fn delete(&mut self, name : &str) -> Result<(), Error> {
+        let profile = match self.get(name) {
+            Some(t) => t.clone(),
+            None => return Err(unknown_profile(name)),
+        };
+        if !is_inside(&self.root, &profile.directory) {
+            return Err(Error::new(Some(5007), Some(format!("Profile {} is not stored in {}", name, self.root.display()))));
+        }
+        if profile.is_locked() {
+            return Err(Error::new(Some(5005), Some(format!("Profile {} is in use", name))));
+        }
+
+        if profile.directory.exists() {
+            if let Err(e) = fs::remove_dir_all(&profile.directory) {
+                return Err(Error::new(Some(5001), Some(format!("Can not remove {}: {}", profile.directory.display(), e))));
+            }
+        }
+        self.profiles.retain(|p| p.name != name);
+        if self.default.as_ref().map(|d| d == name).unwrap_or(false) {
+            self.default = self.profiles.first().map(|p| p.name.clone());
+        }
+        self.save()
+    }
+
+    /// Takes exclusive use of the profile `name` for this process.
+    ///
+    /// Fails if another running core holds it. The lock of a core which
+    /// crashed was released by the system, so its profile can be used again.
+    pub fn lock(&self, name : &str) -> Result<ProfileLock, Error> {
+        let profile = match self.get(name) {
+            Some(t) => t,
+            None => return Err(unknown_profile(name)),
+        };
+        let path = profile.lock_path();
+
+        let mut file = match fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path) {
+            Ok(t) => t,
+            Err(e) => return Err(Error::new(Some(5001), Some(format!("Can not create {}: {}", path.display(), e)))),
+        };
+        match try_lock(&file) {
+            Ok(true) => (),
+            Ok(false) => return Err(Error::new(Some(5005), Some(format!("Profile {} is in use", name)))),
+            Err(e) => return Err(Error::new(Some(5001), Some(format!("Can not lock {}: {}", path.display(), e)))),
+        }
+
+        // Only informative, for whoever wonders which process holds the profile.
+        if let Err(e) = file.set_len(0).and_then(|_| write!(file, "{}", std::process::id())) {
+            return Err(Error::new(Some(5001), Some(format!("Can not write {}: {}", path.display(), e))));
+        }
+        Ok(ProfileLock { path, _file: file })
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let mut document = Table::new();
+        if let Some(d) = &self.default {
+            document.insert(String::from("default"), Value::String(d.clone()));
+        }
+        document.insert(String::from("profiles"), Value::Array(self.profiles.iter().map(|p| {
+            let mut t = Table::new();
+            t.insert(String::from("name"), Value::String(p.name.clone()));
+            let directory = match p.directory.strip_prefix(&self.root) {
+                Ok(t) => t.to_string_lossy().into_owned(),
+                Err(_) => p.directory.to_string_lossy().into_owned(),
+            };
+            t.insert(String::from("directory"), Value::String(directory));
+            Value::Table(t)
+        }).collect()));
+
+        let content = match toml::to_string(&Value::Table(document)) {
+            Ok(t) => t,
+            Err(e) => return Err(Error::new(Some(5002), Some(format!("Can not serialize profile index: {}", e)))),
+        };
+        settings::write_atomically(&self.root.join(INDEX_FILE), content.as_bytes())
+    }
+}
+
+fn unknown_profile(name : &str) -> Error {
+    Error::new(Some(5006), Some(format!("Unknown profile {}", name)))
+}
+
+/// Lowercase ASCII directory name for the profile `name`.
+fn directory_name(name : &str) -> String {
+    let ret : String = name.chars().map(|c| {
+        if c.is_ascii_alphanumeric() {
+            c.to_ascii_lowercase()
+        } else {
+            '-'
+        }
+    }).collect();
+    let ret = ret.trim_matches('-');
+    if ret.is_empty() {
+        String::from("profile")
+    } else {
+        String::from(ret)
+    }
+}
+
+/// Checks if `directory` is strictly inside `root`, without going up.
+fn is_inside(root : &Path, directory : &Path) -> bool {
+    match directory.strip_prefix(root) {
+        Ok(t) => t.components().next().is_some() && t.components().all(|c| matches!(c, Component::Normal(_))),
+        Err(_) => false,
+    }
+}
+
+/// Tries to take an exclusive lock on `file`, without waiting. Gives `false`
+/// if another open file holds it. The lock is released when the file is
+/// closed, or when the process ends.
+#[cfg(unix)]
+fn try_lock(file : &fs::File) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    extern "C" {
+        fn flock(fd : i32, operation : i32) -> i32;
+    }
+    const LOCK_EX : i32 = 2;
+    const LOCK_NB : i32 = 4;
+
+    if unsafe { flock(file.as_raw_fd(), LOCK_EX | LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let e = io::Error::last_os_error();
+    match e.kind() {
+        io::ErrorKind::WouldBlock => Ok(false),
+        _ => Err(e),
+    }
+}
+
+#[cfg(windows)]
+fn try_lock(file : &fs::File) -> io::Result<bool> {
+    use std::os::raw::c_void;
+    use std::os::windows::io::AsRawHandle;
+
+    #[repr(C)]
+    struct Overlapped {
+        internal : usize,
+        internal_high : usize,
+        offset : u32,
+        offset_high : u32,
+        event : *mut c_void,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn LockFileEx(file : *mut c_void, flags : u32, reserved : u32, length_low : u32, length_high : u32,
+                      overlapped : *mut Overlapped) -> i32;
+    }
+    const LOCKFILE_FAIL_IMMEDIATELY : u32 = 1;
+    const LOCKFILE_EXCLUSIVE_LOCK : u32 = 2;
+    const ERROR_LOCK_VIOLATION : i32 = 33;
+
+    let mut overlapped = Overlapped { internal: 0, internal_high: 0, offset: 0, offset_high: 0, event: std::ptr::null_mut() };
+    let locked = unsafe {
+        LockFileEx(file.as_raw_handle() as *mut c_void, LOCKFILE_EXCLUSIVE_LOCK | LOCKFILE_FAIL_IMMEDIATELY, 0,
+                   u32::MAX, u32::MAX, &mut overlapped)
+    };
+    if locked != 0 {
+        return Ok(true);
+    }
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(ERROR_LOCK_VIOLATION) => Ok(false),
+        _ => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn root(name : &str) -> PathBuf {
+        let ret = std::env::temp_dir().join(format!("sielo-profile-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&ret);
+        ret
+    }
+
+    #[test]
+    fn lock_is_exclusive() {
+        let root = root("lock");
+        let mut profiles = ProfileManager::open(&root).unwrap();
+        let profile = profiles.create("Default").unwrap();
+        assert!(!profile.is_locked());
+
+        let lock = profiles.lock("Default").unwrap();
+        assert!(profile.is_locked());
+        assert_eq!(profiles.lock("Default").unwrap_err().code(), Some(5005));
+        assert_eq!(profiles.delete("Default").unwrap_err().code(), Some(5005));
+
+        drop(lock);
+        assert!(!profile.is_locked());
+        assert!(profiles.lock("Default").is_ok());
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn lock_file_of_a_dead_core_is_not_held() {
+        let root = root("stale");
+        let mut profiles = ProfileManager::open(&root).unwrap();
+        let profile = profiles.create("Default").unwrap();
+        // Left by a core which crashed: the file exists but nobody locks it.
+        fs::write(profile.lock_path(), "4294967295").unwrap();
+
+        assert!(!profile.is_locked());
+        let lock = profiles.lock("Default").unwrap();
+        assert_eq!(fs::read_to_string(lock.path()).unwrap(), std::process::id().to_string());
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn delete_stays_in_root() {
+        let root = root("delete");
+        let outside = root.with_file_name(format!("sielo-profile-outside-{}", std::process::id()));
+        fs::create_dir_all(&outside).unwrap();
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join(INDEX_FILE), format!(
+            "[[profiles]]\nname = \"Up\"\ndirectory = \"../{}\"\n\n[[profiles]]\nname = \"Absolute\"\ndirectory = {:?}\n",
+            outside.file_name().unwrap().to_string_lossy(), outside.display().to_string())).unwrap();
+
+        let mut profiles = ProfileManager::open(&root).unwrap();
+        assert_eq!(profiles.delete("Up").unwrap_err().code(), Some(5007));
+        assert_eq!(profiles.delete("Absolute").unwrap_err().code(), Some(5007));
+        assert!(outside.exists());
+
+        let profile = profiles.create("Other").unwrap();
+        profiles.delete("Other").unwrap();
+        assert!(!profile.directory.exists());
+        assert!(profiles.get("Other").is_none());
+        let _ = fs::remove_dir_all(&root);
+        let _ = fs::remove_dir_all(&outside);
+    }
+}
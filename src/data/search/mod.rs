@@ -0,0 +1,567 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Search engine registry
+//!
+//! Engines are stored in the `search_engines` table. The
+//! [built-in engines](constant.BUILTIN.html) are added when the table is
+//! opened, users can add their own or import them from an
+//! [OpenSearch description](opensearch/index.html).
+//!
+//! Every engine has a stable `key`, used by the `general.default_search_engine`
+//! setting, and may have a keyword: typing `w rust` in the address bar
+//! searches `rust` with the engine whose keyword is `w`.
+//!
+//! Templates follow OpenSearch: `{searchTerms}` is replaced by the encoded
+//! query, optional parameters like `{count?}` are dropped.
+
+use super::db::{Error, TableProvider, FieldType, FieldParameter, FieldValue};
+use super::settings::Settings;
+
+pub mod opensearch;
+
+/// Name of the table used to store the search engines.
+pub const TABLE : &str = "search_engines";
+
+/// Placeholder replaced by the search terms in templates.
+pub const TERMS : &str = "{searchTerms}";
+
+/// Engine shipped with Sielo: key, name, keyword, template and suggestions
+/// template.
+pub struct Builtin {
+    pub key : &'static str,
+    pub name : &'static str,
+    pub keyword : &'static str,
+    pub template : &'static str,
+    pub suggestions : &'static str,
+}
+
+pub const BUILTIN : &[Builtin] = &[
+    Builtin {
+        key: "duckduckgo",
+        name: "DuckDuckGo",
+        keyword: "d",
+        template: "https://duckduckgo.com/?q={searchTerms}",
+        suggestions: "https://duckduckgo.com/ac/?q={searchTerms}&type=list",
+    },
+    Builtin {
+        key: "qwant",
+        name: "Qwant",
+        keyword: "q",
+        template: "https://www.qwant.com/?q={searchTerms}",
+        suggestions: "https://api.qwant.com/api/suggest/?q={searchTerms}&client=opensearch",
+    },
+    Builtin {
+        key: "google",
+        name: "Google",
+        keyword: "g",
+        template: "https://www.google.com/search?q={searchTerms}&ie={inputEncoding}",
+        suggestions: "https://suggestqueries.google.com/complete/search?client=firefox&q={searchTerms}",
+    },
+    Builtin {
+        key: "bing",
+        name: "Bing",
+        keyword: "b",
+        template: "https://www.bing.com/search?q={searchTerms}",
+        suggestions: "https://api.bing.com/osjson.aspx?query={searchTerms}",
+    },
+    Builtin {
+        key: "wikipedia",
+        name: "Wikipedia",
+        keyword: "w",
+        template: "https://en.wikipedia.org/wiki/Special:Search?search={searchTerms}",
+        suggestions: "https://en.wikipedia.org/w/api.php?action=opensearch&search={searchTerms}",
+    },
+];
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Engine {
+    /// Stable identifier, used by the `general.default_search_engine` setting.
+    pub key : String,
+    pub name : String,
+    pub keyword : Option<String>,
+    pub template : String,
+    pub suggestions : Option<String>,
+    pub icon : Option<String>,
+    pub builtin : bool,
+}
+
+impl Engine {
+    /// URL of the result page for `terms`.
+    pub fn search_url(&self, terms : &str) -> Result<url::Url, Error> {
+        expand(&self.template, terms)
+    }
+
+    /// URL giving the suggestions for `terms`, if the engine has some.
+    pub fn suggestions_url(&self, terms : &str) -> Option<Result<url::Url, Error>> {
+        self.suggestions.as_ref().map(|t| expand(t, terms))
+    }
+}
+
+pub struct SearchEngines<'a, T : TableProvider> {
+    db : &'a mut T,
+}
+
+impl<'a, T : TableProvider> SearchEngines<'a, T> {
+    /// Opens the search engines stored in `db`, creating the table and
+    /// adding the built-in engines when needed.
+    pub fn new(db : &'a mut T) -> Result<Self, Error> {
+        db.use_table(TABLE, &[
+            ("id", &FieldType::Integer, &[FieldParameter::AutoIncrement]),
+            ("key", &FieldType::Text, &[FieldParameter::NoNull, FieldParameter::Unique]),
+            ("name", &FieldType::Text, &[FieldParameter::NoNull]),
+            ("keyword", &FieldType::Text, &[FieldParameter::Default(String::new())]),
+            ("template", &FieldType::Text, &[FieldParameter::NoNull]),
+            ("suggestions", &FieldType::Text, &[FieldParameter::Default(String::new())]),
+            ("icon", &FieldType::Text, &[FieldParameter::Default(String::new())]),
+            ("builtin", &FieldType::Integer, &[FieldParameter::Default(String::from("0"))]),
+            ("hidden", &FieldType::Integer, &[FieldParameter::Default(String::from("0"))]),
+        ], false, false)?;
+
+        let mut ret = Self { db };
+        match ret.add_builtins() {
+            Ok(_) => Ok(ret),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Gives access to the underlying database.
+    pub fn db(&mut self) -> &mut T {
+        self.db
+    }
+
+    /// Every engine offered to the user, built-in ones first.
+    pub fn engines(&mut self) -> Result<Vec<Engine>, Error> {
+        self.select("hidden = 0 ORDER BY builtin DESC, id", &[])
+    }
+
+    pub fn get(&mut self, key : &str) -> Result<Option<Engine>, Error> {
+        match self.select("hidden = 0 AND key = ?", &[FieldValue::Text(String::from(key))]) {
+            Ok(t) => Ok(t.into_iter().next()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn by_keyword(&mut self, keyword : &str) -> Result<Option<Engine>, Error> {
+        if keyword.is_empty() {
+            return Ok(None);
+        }
+        match self.select("hidden = 0 AND keyword = ?", &[FieldValue::Text(String::from(keyword))]) {
+            Ok(t) => Ok(t.into_iter().next()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Engine chosen by `general.default_search_engine`, or the first one if it
+    /// does not exist anymore.
+    pub fn default_engine(&mut self, settings : &Settings) -> Result<Option<Engine>, Error> {
+        match self.get(&settings.general().default_search_engine) {
+            Ok(Some(t)) => Ok(Some(t)),
+            Ok(None) => match self.engines() {
+                Ok(t) => Ok(t.into_iter().next()),
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Engine and terms to search for what was typed in the address bar:
+    /// the engine of the keyword if `input` starts with one, the default
+    /// engine otherwise.
+    pub fn resolve(&mut self, input : &str, settings : &Settings) -> Result<Option<(Engine, String)>, Error> {
+        let input = input.trim();
+        if let Some(space) = input.find(char::is_whitespace) {
+            match self.by_keyword(&input[..space]) {
+                Ok(Some(t)) => return Ok(Some((t, String::from(input[space..].trim_start())))),
+                Ok(None) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        match self.default_engine(settings) {
+            Ok(t) => Ok(t.map(|e| (e, String::from(input)))),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Adds an engine made by the user and returns it.
+    pub fn add(&mut self,
+               name : &str,
+               keyword : Option<&str>,
+               template : &str,
+               suggestions : Option<&str>,
+               icon : Option<&str>) -> Result<Engine, Error> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(Error::new(Some(2401), Some(String::from("Search engine name can not be empty"))));
+        }
+        check_template(template)?;
+        if let Some(Err(e)) = suggestions.map(check_template) {
+            return Err(e);
+        }
+        self.check_keyword(keyword, None)?;
+
+        let base = key_of(name);
+        let mut key = base.clone();
+        let mut i = 1;
+        loop {
+            match self.select("key = ?", &[FieldValue::Text(key.clone())]) {
+                Ok(t) if t.is_empty() => break,
+                Ok(_) => {
+                    i += 1;
+                    key = format!("{}-{}", base, i);
+                },
+                Err(e) => return Err(e),
+            }
+        }
+
+        let engine = Engine {
+            key,
+            name: String::from(name),
+            keyword: keyword.map(String::from).filter(|k| !k.is_empty()),
+            template: String::from(template),
+            suggestions: suggestions.map(String::from),
+            icon: icon.map(String::from),
+            builtin: false,
+        };
+        match self.insert(&engine) {
+            Ok(_) => Ok(engine),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Adds the engine described by the OpenSearch document `xml`.
+    pub fn import_opensearch(&mut self, xml : &str, keyword : Option<&str>) -> Result<Engine, Error> {
+        match opensearch::parse(xml) {
+            Ok(t) => self.add(&t.name, keyword, &t.template, t.suggestions.as_deref(),
+                              t.icon.as_deref()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Changes the keyword of an engine, `None` to remove it.
+    pub fn set_keyword(&mut self, key : &str, keyword : Option<&str>) -> Result<(), Error> {
+        self.check_keyword(keyword, Some(key))?;
+        self.update(key, "keyword", keyword.unwrap_or(""))
+    }
+
+    pub fn rename(&mut self, key : &str, name : &str) -> Result<(), Error> {
+        if name.trim().is_empty() {
+            return Err(Error::new(Some(2401), Some(String::from("Search engine name can not be empty"))));
+        }
+        self.update(key, "name", name.trim())
+    }
+
+    /// Removes an engine. Built-in engines are only hidden, so they are not
+    /// added back the next time the table is opened.
+    pub fn remove(&mut self, key : &str) -> Result<(), Error> {
+        let res = self.db.request_values(
+            &format!("DELETE FROM {} WHERE key = ? AND builtin = 0;", TABLE),
+            &[FieldValue::Text(String::from(key))]);
+        res?;
+        match self.db.changes() {
+            Ok(0) => self.update(key, "hidden", "1"),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Shows the hidden built-in engines again, with their original name,
+    /// keyword and templates.
+    pub fn restore_builtins(&mut self) -> Result<(), Error> {
+        match self.db.request(&format!("DELETE FROM {} WHERE builtin = 1;", TABLE), &[]) {
+            Ok(_) => self.add_builtins(),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn add_builtins(&mut self) -> Result<(), Error> {
+        self.db.transaction(|db| {
+            for builtin in BUILTIN {
+                let res = db.request_values(
+                    &format!("INSERT INTO {} (key, name, keyword, template, suggestions, builtin) \
+                               SELECT ?, ?, ?, ?, ?, 1 WHERE NOT EXISTS (SELECT 1 FROM {} WHERE key = ?);",
+                              TABLE, TABLE),
+                    &[FieldValue::Text(String::from(builtin.key)),
+                      FieldValue::Text(String::from(builtin.name)),
+                      FieldValue::Text(String::from(builtin.keyword)),
+                      FieldValue::Text(String::from(builtin.template)),
+                      FieldValue::Text(String::from(builtin.suggestions)),
+                      FieldValue::Text(String::from(builtin.key))]);
+                res?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Refuses a keyword already used by another engine than `key`.
+    fn check_keyword(&mut self, keyword : Option<&str>, key : Option<&str>) -> Result<(), Error> {
+        let keyword = match keyword {
+            Some(t) if t.chars().any(char::is_whitespace) =>
+                return Err(Error::new(Some(2402), Some(format!("Invalid keyword {}", t)))),
+            Some(t) if !t.is_empty() => t,
+            _ => return Ok(()),
+        };
+        match self.by_keyword(keyword) {
+            Ok(Some(t)) if Some(t.key.as_str()) != key =>
+                Err(Error::new(Some(2402), Some(format!("Keyword {} is already used by {}", keyword, t.name)))),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn update(&mut self, key : &str, column : &str, value : &str) -> Result<(), Error> {
+        let res = self.db.request_values(&format!("UPDATE {} SET {} = ? WHERE key = ?;", TABLE, column),
+                                         &[FieldValue::Text(String::from(value)), FieldValue::Text(String::from(key))]);
+        res?;
+        match self.db.changes() {
+            Ok(0) => Err(Error::new(Some(2403), Some(format!("Unknown search engine {}", key)))),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn insert(&mut self, engine : &Engine) -> Result<(), Error> {
+        let optional = |t : &Option<String>| FieldValue::Text(t.clone().unwrap_or_default());
+        match self.db.request_values(
+            &format!("INSERT INTO {} (key, name, keyword, template, suggestions, icon, builtin) \
+                       VALUES (?, ?, ?, ?, ?, ?, ?);", TABLE),
+            &[FieldValue::Text(engine.key.clone()),
+              FieldValue::Text(engine.name.clone()),
+              optional(&engine.keyword),
+              FieldValue::Text(engine.template.clone()),
+              optional(&engine.suggestions),
+              optional(&engine.icon),
+              FieldValue::Integer(engine.builtin as i64)]) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn select(&mut self, condition : &str, arguments : &[FieldValue]) -> Result<Vec<Engine>, Error> {
+        match self.db.request_values(&format!("SELECT * FROM {} WHERE {};", TABLE, condition), arguments) {
+            Ok(t) => Ok(t.iter().filter_map(|r| {
+                let text = |name : &str| match r.get(name) {
+                    Some(Some(FieldValue::Text(t))) => Some(t.clone()),
+                    _ => None,
+                };
+                let optional = |name : &str| text(name).filter(|t| !t.is_empty());
+                Some(Engine {
+                    key: text("key")?,
+                    name: text("name")?,
+                    keyword: optional("keyword"),
+                    template: text("template")?,
+                    suggestions: optional("suggestions"),
+                    icon: optional("icon"),
+                    builtin: match r.get("builtin") {
+                        Some(Some(FieldValue::Integer(t))) => *t != 0,
+                        _ => false,
+                    },
+                })
+            }).collect()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Expands the OpenSearch `template` with `terms`.
+pub fn expand(template : &str, terms : &str) -> Result<url::Url, Error> {
+    let encoded : String = url::form_urlencoded::byte_serialize(terms.as_bytes()).collect();
+    let mut ret = String::with_capacity(template.len() + encoded.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        ret += &rest[..start];
+        let end = match rest[start..].find('}') {
+            Some(t) => start + t,
+            None => {
+                rest = &rest[start..];
+                break;
+            },
+        };
+        let parameter = &rest[start + 1..end];
+        match parameter.trim_end_matches('?') {
+            "searchTerms" => ret += &encoded,
+            "inputEncoding" | "outputEncoding" => ret += "UTF-8",
+            "language" => ret += "*",
+            "count" => ret += "10",
+            "startIndex" | "startPage" => ret += "1",
+            // Unknown optional parameters are left empty.
+            _ if parameter.ends_with('?') => (),
+            _ => return Err(Error::new(Some(2401), Some(format!("Unsupported template parameter {}", parameter)))),
+        }
+        rest = &rest[end + 1..];
+    }
+    ret += rest;
+
+    match url::Url::parse(&ret) {
+        Ok(t) => Ok(t),
+        Err(e) => Err(Error::new(Some(2401), Some(format!("Invalid search template {}: {}", template, e)))),
+    }
+}
+
+/// Suggestions of an OpenSearch suggestions response, like
+/// `["rust", ["rust lang", "rust book"]]`.
+pub fn parse_suggestions(response : &str) -> Result<Vec<String>, Error> {
+    match serde_json::from_str::<serde_json::Value>(response) {
+        Ok(serde_json::Value::Array(t)) => match t.get(1) {
+            Some(serde_json::Value::Array(s)) => Ok(s.iter().filter_map(|v| v.as_str().map(String::from)).collect()),
+            _ => Err(Error::new(Some(2405), Some(String::from("Invalid suggestions response")))),
+        },
+        _ => Err(Error::new(Some(2405), Some(String::from("Invalid suggestions response")))),
+    }
+}
+
+fn check_template(template : &str) -> Result<(), Error> {
+    if !template.contains(TERMS) && !template.contains("{searchTerms?}") {
+        return Err(Error::new(Some(2401), Some(format!("Search template {} has no {}", template, TERMS))));
+    }
+    match expand(template, "sielo") {
+        Ok(t) if t.scheme() == "http" || t.scheme() == "https" => Ok(()),
+        Ok(_) => Err(Error::new(Some(2401), Some(format!("Search template {} is not a web address", template)))),
+        Err(e) => Err(e),
+    }
+}
+
+/// Lowercase ASCII key for an engine named `name`, words joined by `-`.
+fn key_of(name : &str) -> String {
+    let ret = name.to_ascii_lowercase()
+        .split(|c : char| !c.is_ascii_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<&str>>()
+        .join("-");
+    if ret.is_empty() {
+        String::from("engine")
+    } else {
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::db::sqlite::SQLite;
+    use super::*;
+
+    const DESCRIPTION : &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<OpenSearchDescription xmlns="http://a9.com/-/spec/opensearch/1.1/">
+  <ShortName>Example Search</ShortName>
+  <Url type="text/html" template="https://example.com/search?q={searchTerms}&amp;lang={language?}"/>
+</OpenSearchDescription>"#;
+
+    #[test]
+    fn templates_are_expanded() {
+        let url = expand("https://example.com/?q={searchTerms}&n={count?}&page={startPage}&x={sielo:unknown?}",
+                         "rust & c++ é/?").unwrap();
+        assert_eq!(url.as_str(), "https://example.com/?q=rust+%26+c%2B%2B+%C3%A9%2F%3F&n=10&page=1&x=");
+        assert_eq!(expand("https://example.com/{searchTerms}", "a b").unwrap().as_str(), "https://example.com/a+b");
+        assert_eq!(expand("https://example.com/?q={searchTerms}&u={unknown}", "a").unwrap_err().code(), Some(2401));
+        assert_eq!(expand("not a url {searchTerms}", "a").unwrap_err().code(), Some(2401));
+
+        let google = Engine {
+            key: String::from("google"), name: String::from("Google"), keyword: None,
+            template: String::from(BUILTIN[2].template), suggestions: None, icon: None, builtin: true,
+        };
+        assert_eq!(google.search_url("sielo").unwrap().as_str(), "https://www.google.com/search?q=sielo&ie=UTF-8");
+        assert!(google.suggestions_url("sielo").is_none());
+    }
+
+    #[test]
+    fn templates_are_checked() {
+        let mut db = SQLite::new(":memory:").unwrap();
+        let mut engines = SearchEngines::new(&mut db).unwrap();
+        let code = |r : Result<Engine, Error>| r.unwrap_err().code();
+        assert_eq!(code(engines.add("No terms", None, "https://example.com/", None, None)), Some(2401));
+        assert_eq!(code(engines.add("Ftp", None, "ftp://example.com/{searchTerms}", None, None)), Some(2401));
+        assert_eq!(code(engines.add("  ", None, "https://example.com/{searchTerms}", None, None)), Some(2401));
+        assert_eq!(code(engines.add("Bad suggestions", None, "https://example.com/{searchTerms}",
+                                    Some("https://example.com/"), None)), Some(2401));
+        assert_eq!(engines.engines().unwrap().len(), BUILTIN.len());
+    }
+
+    #[test]
+    fn keywords_pick_the_engine() {
+        let mut db = SQLite::new(":memory:").unwrap();
+        let mut engines = SearchEngines::new(&mut db).unwrap();
+        let settings = Settings::new();
+
+        let (engine, terms) = engines.resolve("  w rust   lang ", &settings).unwrap().unwrap();
+        assert_eq!((engine.key.as_str(), terms.as_str()), ("wikipedia", "rust   lang"));
+        let (engine, terms) = engines.resolve("x rust", &settings).unwrap().unwrap();
+        assert_eq!((engine.key.as_str(), terms.as_str()), ("duckduckgo", "x rust"));
+        let (engine, terms) = engines.resolve("w", &settings).unwrap().unwrap();
+        assert_eq!((engine.key.as_str(), terms.as_str()), ("duckduckgo", "w"));
+        assert_eq!(engines.by_keyword("").unwrap(), None);
+
+        let settings = Settings::parse("[general]\ndefault_search_engine = \"gone\"\n").unwrap();
+        assert_eq!(engines.default_engine(&settings).unwrap().unwrap().key, BUILTIN[0].key);
+    }
+
+    #[test]
+    fn keywords_are_unique() {
+        let mut db = SQLite::new(":memory:").unwrap();
+        let mut engines = SearchEngines::new(&mut db).unwrap();
+        let template = "https://example.com/?q={searchTerms}";
+
+        assert_eq!(engines.add("Example", Some("w"), template, None, None).unwrap_err().code(), Some(2402));
+        assert_eq!(engines.add("Example", Some("e x"), template, None, None).unwrap_err().code(), Some(2402));
+        let first = engines.add("Example", Some("e"), template, None, None).unwrap();
+        let second = engines.add("Example", Some(""), template, None, None).unwrap();
+        assert_eq!((first.key.as_str(), second.key.as_str()), ("example", "example-2"));
+        assert_eq!(second.keyword, None);
+
+        // An engine keeps its own keyword, but can not take another one.
+        engines.set_keyword("example", Some("e")).unwrap();
+        assert_eq!(engines.set_keyword("example-2", Some("e")).unwrap_err().code(), Some(2402));
+        assert_eq!(engines.set_keyword("missing", Some("m")).unwrap_err().code(), Some(2403));
+
+        // The keyword of a hidden engine can be used again.
+        engines.remove("wikipedia").unwrap();
+        engines.set_keyword("example-2", Some("w")).unwrap();
+        assert_eq!(engines.by_keyword("w").unwrap().unwrap().key, "example-2");
+        assert!(engines.get("wikipedia").unwrap().is_none());
+    }
+
+    #[test]
+    fn builtins_are_hidden_then_restored() {
+        let mut db = SQLite::new(":memory:").unwrap();
+        let mut engines = SearchEngines::new(&mut db).unwrap();
+        engines.rename("qwant", "My Qwant").unwrap();
+        engines.remove("bing").unwrap();
+        let added = engines.add("Example", None, "https://example.com/?q={searchTerms}", None, None).unwrap();
+
+        let keys : Vec<String> = SearchEngines::new(&mut db).unwrap().engines().unwrap().into_iter().map(|e| e.key).collect();
+        assert_eq!(keys, vec!["duckduckgo", "qwant", "google", "wikipedia", "example"]);
+
+        let mut engines = SearchEngines::new(&mut db).unwrap();
+        engines.restore_builtins().unwrap();
+        assert_eq!(engines.get("qwant").unwrap().unwrap().name, "Qwant");
+        assert!(engines.get("bing").unwrap().is_some());
+        engines.remove(&added.key).unwrap();
+        assert_eq!(engines.engines().unwrap().len(), BUILTIN.len());
+    }
+
+    #[test]
+    fn opensearch_descriptions_are_imported() {
+        let mut db = SQLite::new(":memory:").unwrap();
+        let mut engines = SearchEngines::new(&mut db).unwrap();
+
+        let engine = engines.import_opensearch(DESCRIPTION, Some("ex")).unwrap();
+        assert_eq!(engine.key, "example-search");
+        assert_eq!(engine.template, "https://example.com/search?q={searchTerms}&lang={language?}");
+        assert_eq!(engines.by_keyword("ex").unwrap(), Some(engine.clone()));
+        assert_eq!(engine.search_url("a b").unwrap().as_str(), "https://example.com/search?q=a+b&lang=*");
+
+        assert_eq!(engines.import_opensearch("<html><body>", None).unwrap_err().code(), Some(2404));
+        assert_eq!(engines.import_opensearch(DESCRIPTION, Some("ex")).unwrap_err().code(), Some(2402));
+        assert_eq!(engines.engines().unwrap().len(), BUILTIN.len() + 1);
+    }
+
+    #[test]
+    fn suggestions_are_parsed() {
+        assert_eq!(parse_suggestions(r#"["rust", ["rust lang", "rust book", 3]]"#).unwrap(),
+                   vec!["rust lang", "rust book"]);
+        assert_eq!(parse_suggestions(r#"{"rust": []}"#).unwrap_err().code(), Some(2405));
+        assert_eq!(parse_suggestions(r#"["rust"]"#).unwrap_err().code(), Some(2405));
+    }
+}
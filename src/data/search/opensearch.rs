@@ -0,0 +1,242 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Reader of OpenSearch descriptions
+//!
+//! Sites advertise their search engine with a small XML document:
+//!
+//! ```xml
+//! <OpenSearchDescription xmlns="http://a9.com/-/spec/opensearch/1.1/">
+//!   <ShortName>Example</ShortName>
+//!   <Url type="text/html" template="https://example.com/?q={searchTerms}"/>
+//!   <Url type="application/x-suggestions+json" template="https://example.com/s?q={searchTerms}"/>
+//! </OpenSearchDescription>
+//! ```
+//!
+//! Only the few elements used by the registry are read, so a tiny tag
+//! scanner is enough and no XML library is needed.
+
+use crate::data::db::Error;
+
+/// Type of the `Url` element giving the result page.
+pub const HTML_TYPE : &str = "text/html";
+/// Type of the `Url` element giving the suggestions.
+pub const SUGGESTIONS_TYPE : &str = "application/x-suggestions+json";
+
+/// What a description tells about an engine.
+#[derive(Eq, PartialEq, Debug, Default, Clone)]
+pub struct Description {
+    pub name : String,
+    pub template : String,
+    pub suggestions : Option<String>,
+    pub icon : Option<String>,
+}
+
+/// Reads the OpenSearch description `xml`.
+pub fn parse(xml : &str) -> Result<Description, Error> {
+    let mut ret = Description::default();
+    let mut found_root = false;
+    let mut current : Option<String> = None;
+    let mut rest = xml;
+
+    while let Some(start) = rest.find('<') {
+        let text = &rest[..start];
+        rest = &rest[start..];
+
+        if let Some(element) = &current {
+            let text = decode(text.trim());
+            match element.as_str() {
+                "ShortName" if ret.name.is_empty() => ret.name = text,
+                "Image" if ret.icon.is_none() && !text.is_empty() => ret.icon = Some(text),
+                _ => (),
+            }
+        }
+
+        // Comments, processing instructions and CDATA are skipped whole.
+        let end = if rest.starts_with("<!--") {
+            rest.find("-->").map(|t| t + 3)
+        } else {
+            rest.find('>').map(|t| t + 1)
+        };
+        let end = match end {
+            Some(t) => t,
+            None => return Err(invalid("unterminated tag")),
+        };
+        let tag = &rest[1..end - 1];
+        rest = &rest[end..];
+        if tag.starts_with('!') || tag.starts_with('?') {
+            continue;
+        }
+        if tag.starts_with('/') {
+            current = None;
+            continue;
+        }
+
+        let (name, attributes) = match tag.find(|c : char| c.is_whitespace() || c == '/') {
+            Some(t) => (&tag[..t], &tag[t..]),
+            None => (tag, ""),
+        };
+        let name = local_name(name);
+        current = if tag.ends_with('/') { None } else { Some(String::from(name)) };
+
+        match name {
+            "OpenSearchDescription" => found_root = true,
+            "Url" => {
+                let method = attribute(attributes, "method").unwrap_or_else(|| String::from("get"));
+                if !method.eq_ignore_ascii_case("get") {
+                    continue;
+                }
+                let template = match attribute(attributes, "template") {
+                    Some(t) => t,
+                    None => continue,
+                };
+                match attribute(attributes, "type").as_deref() {
+                    Some(HTML_TYPE) if ret.template.is_empty() => ret.template = template,
+                    Some(SUGGESTIONS_TYPE) if ret.suggestions.is_none() => ret.suggestions = Some(template),
+                    _ => (),
+                }
+            },
+            _ => (),
+        }
+    }
+
+    if !found_root {
+        return Err(invalid("not an OpenSearch description"));
+    }
+    if ret.name.is_empty() {
+        return Err(invalid("missing ShortName"));
+    }
+    if ret.template.is_empty() {
+        return Err(invalid("missing text/html Url"));
+    }
+    Ok(ret)
+}
+
+fn invalid(reason : &str) -> Error {
+    Error::new(Some(2404), Some(format!("Invalid OpenSearch description: {}", reason)))
+}
+
+/// Name of an element without its namespace prefix.
+fn local_name(name : &str) -> &str {
+    match name.rfind(':') {
+        Some(t) => &name[t + 1..],
+        None => name,
+    }
+}
+
+/// Value of the attribute `name` in the attribute list of a tag.
+fn attribute(attributes : &str, name : &str) -> Option<String> {
+    let mut rest = attributes;
+    while let Some(equal) = rest.find('=') {
+        let key = local_name(rest[..equal].trim().trim_start_matches('/').trim());
+        let value = rest[equal + 1..].trim_start();
+        let quote = value.chars().next()?;
+        if quote != '"' && quote != '\'' {
+            return None;
+        }
+        let end = value[1..].find(quote)? + 1;
+        if key == name {
+            return Some(decode(&value[1..end]));
+        }
+        rest = &value[end + 1..];
+    }
+    None
+}
+
+/// Replaces the predefined XML entities and character references.
+fn decode(text : &str) -> String {
+    let mut ret = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        ret += &rest[..start];
+        rest = &rest[start..];
+        let end = match rest.find(';') {
+            Some(t) => t,
+            None => break,
+        };
+        let entity = &rest[1..end];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            t if t.starts_with("#x") => u32::from_str_radix(&t[2..], 16).ok().and_then(std::char::from_u32),
+            t if t.starts_with('#') => t[1..].parse::<u32>().ok().and_then(std::char::from_u32),
+            _ => None,
+        };
+        match decoded {
+            Some(c) => {
+                ret.push(c);
+                rest = &rest[end + 1..];
+            },
+            None => {
+                ret.push('&');
+                rest = &rest[1..];
+            },
+        }
+    }
+
+    ret += rest;
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn description_is_read() {
+        let xml = r#"<?xml version="1.0"?>
+<!-- Served by <example> -->
+<os:OpenSearchDescription xmlns:os="http://a9.com/-/spec/opensearch/1.1/">
+  <os:ShortName> Tom &amp; Jerry &#x263A; </os:ShortName>
+  <os:Description>Not read</os:Description>
+  <os:Url type="text/html" method="post" template="https://example.com/post"/>
+  <os:Url type='text/html' template='https://example.com/?q={searchTerms}&amp;src=sielo'/>
+  <os:Url type="application/x-suggestions+json" template="https://example.com/s?q={searchTerms}"/>
+  <os:Url type="text/html" template="https://example.com/second?q={searchTerms}"/>
+  <os:Image height="16" width="16">https://example.com/favicon.ico</os:Image>
+</os:OpenSearchDescription>"#;
+
+        assert_eq!(parse(xml).unwrap(), Description {
+            name: String::from("Tom & Jerry \u{263A}"),
+            template: String::from("https://example.com/?q={searchTerms}&src=sielo"),
+            suggestions: Some(String::from("https://example.com/s?q={searchTerms}")),
+            icon: Some(String::from("https://example.com/favicon.ico")),
+        });
+    }
+
+    #[test]
+    fn malformed_descriptions_fail() {
+        let root = |content : &str| format!("<OpenSearchDescription>{}</OpenSearchDescription>", content);
+        let url = r#"<Url type="text/html" template="https://example.com/?q={searchTerms}"/>"#;
+        let cases = [
+            (String::new(), "not an OpenSearch description"),
+            (String::from("plain text"), "not an OpenSearch description"),
+            (format!("<ShortName>Example</ShortName>{}", url), "not an OpenSearch description"),
+            (root(url), "missing ShortName"),
+            (root("<ShortName>Example</ShortName>"), "missing text/html Url"),
+            (root(&format!("<ShortName>Example</ShortName>{}", url.replace("text/html", "application/rss+xml"))),
+             "missing text/html Url"),
+            (root("<ShortName>Example</ShortName><Url type=\"text/html\" template=\"unquoted/>"),
+             "missing text/html Url"),
+            (String::from("<OpenSearchDescription><ShortName>Example"), "missing ShortName"),
+            (String::from("<OpenSearchDescription <!-- "), "unterminated tag"),
+        ];
+        for (xml, reason) in cases.iter() {
+            let error = parse(xml).unwrap_err();
+            assert_eq!(error.code(), Some(2404), "{}", xml);
+            assert_eq!(error.message(), Some(&*format!("Invalid OpenSearch description: {}", reason)), "{}", xml);
+        }
+    }
+
+    #[test]
+    fn entities_are_decoded() {
+        assert_eq!(decode("a &lt;b&gt; &quot;c&quot; &apos;d&apos; &#65;&#x42;"), "a <b> \"c\" 'd' AB");
+        assert_eq!(decode("&unknown; & &#xZZ; &amp"), "&unknown; & &#xZZ; &amp");
+    }
+}
@@ -0,0 +1,331 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Settings layers
+//!
+//! The effective value of a key is resolved across four layers, from the
+//! weakest to the strongest:
+//!  1. built-in defaults from the [schema](../schema/index.html),
+//!  2. the system policy, a TOML file written by administrators,
+//!  3. the profile settings file,
+//!  4. overrides given on the command line, like `--appearance.theme dark`.
+//!
+//! The policy can also lock keys by listing them in `policy.locked`:
+//!
+//! ```toml
+//! [policy]
+//! locked = ["privacy.do_not_track"]
+//!
+//! [privacy]
+//! do_not_track = true
+//! ```
+//!
+//! A locked key always takes the policy value and can not be changed.
+
+use std::fs;
+use std::path::Path;
+
+use toml::Value;
+use toml::value::Table;
+
+use crate::data::db::Error;
+use super::{schema, migration, lookup, insert, Settings, Issue};
+
+/// Default location of the system policy.
+#[cfg(windows)]
+pub const POLICY_PATH : &str = "C:\\ProgramData\\Sielo\\policy.toml";
+#[cfg(not(windows))]
+pub const POLICY_PATH : &str = "/etc/sielo/policy.toml";
+
+/// Layer a value comes from.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum Layer {
+    Default,
+    Policy,
+    Profile,
+    CommandLine,
+}
+
+impl Layer {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Layer::Default => "default",
+            Layer::Policy => "policy",
+            Layer::Profile => "profile",
+            Layer::CommandLine => "command_line",
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Default, Clone)]
+pub struct Layers {
+    policy : Table,
+    locked : Vec<String>,
+    command_line : Table,
+    issues : Vec<Issue>,
+}
+
+impl Layers {
+    /// Layers with neither policy nor override.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the system policy from `path`. A missing file means no policy.
+    pub fn load_policy<P : AsRef<Path>>(&mut self, path : P) -> Result<(), Error> {
+        let path = path.as_ref();
+        if !path.exists() {
+            self.set_policy(Table::new());
+            return Ok(());
+        }
+
+        let content = match fs::read_to_string(path) {
+            Ok(t) => t,
+            Err(e) => return Err(Error::new(Some(3005), Some(format!("Can not read {}: {}", path.display(), e)))),
+        };
+        match content.parse::<Value>() {
+            Ok(Value::Table(t)) => {
+                self.set_policy(t);
+                Ok(())
+            },
+            Ok(_) => Err(Error::new(Some(3006), Some(String::from("Policy document must be a table")))),
+            Err(e) => Err(Error::new(Some(3006), Some(format!("Invalid policy {}: {}", path.display(), e)))),
+        }
+    }
+
+    /// Uses `document` as system policy. Invalid values are ignored and
+    /// reported in [`issues`](#method.issues).
+    pub fn set_policy(&mut self, mut document : Table) {
+        self.locked = match super::remove(&mut document, "policy.locked") {
+            Some(Value::Array(t)) => t.into_iter().filter_map(|v| match v {
+                Value::String(s) => Some(s),
+                _ => None,
+            }).collect(),
+            _ => Vec::new(),
+        };
+        super::remove(&mut document, "policy");
+
+        self.issues.retain(|i| !i.message.starts_with("policy: "));
+        for key in schema::SCHEMA {
+            if let Some(v) = lookup(&document, key.path) {
+                if let Err(e) = key.validate(v) {
+                    self.issues.push(Issue { key: String::from(key.path), message: format!("policy: {}", e) });
+                }
+            }
+        }
+        for key in &self.locked {
+            if schema::find(key).is_none() {
+                self.issues.push(Issue { key: key.clone(), message: String::from("policy: unknown locked key") });
+            }
+        }
+
+        self.policy = document;
+    }
+
+    /// Overrides `key` with `value` for this run of the core only.
+    ///
+    /// `value` is read as a TOML literal, and as a plain string when it is
+    /// not one, so both `--general.homepage about:blank` and
+    /// `--appearance.font_size 20` work.
+    pub fn add_override(&mut self, key : &str, value : &str) -> Result<(), Error> {
+        let declaration = match schema::find(key) {
+            Some(t) => t,
+            None => return Err(Error::new(Some(3001), Some(format!("Unknown setting {}", key)))),
+        };
+        let value = match format!("v = {}", value).parse::<Value>() {
+            Ok(Value::Table(mut t)) => match t.remove("v") {
+                Some(v) => v,
+                None => Value::String(String::from(value)),
+            },
+            _ => Value::String(String::from(value)),
+        };
+        if let Err(e) = declaration.validate(&value) {
+            return Err(Error::new(Some(3002), Some(e)));
+        }
+
+        insert(&mut self.command_line, key, declaration.normalize(value))
+    }
+
+    /// Takes the overrides from the command line: every option named after
+    /// a known key is one.
+    pub fn add_arguments(&mut self, arguments : &arguments::Arguments) -> Result<(), Error> {
+        for name in arguments.options.names() {
+            if schema::find(name).is_none() {
+                continue;
+            }
+            let value = match arguments.get::<String>(name) {
+                Some(t) => t,
+                None => continue,
+            };
+            self.add_override(name, &value)?
+        }
+        Ok(())
+    }
+
+    pub fn is_locked(&self, key : &str) -> bool {
+        self.locked.iter().any(|k| k == key)
+    }
+
+    pub fn locked(&self) -> &[String] {
+        &self.locked
+    }
+
+    /// Problems found in the policy.
+    pub fn issues(&self) -> &[Issue] {
+        &self.issues
+    }
+
+    /// Effective value of `key` with the layer it comes from.
+    pub fn resolve(&self, profile : &Settings, key : &str) -> Result<(Value, Layer), Error> {
+        let declaration = match schema::find(key) {
+            Some(t) => t,
+            None => return Err(Error::new(Some(3001), Some(format!("Unknown setting {}", key)))),
+        };
+        let valid = |v : Option<&Value>| match v {
+            Some(v) if declaration.validate(v).is_ok() => Some(declaration.normalize(v.clone())),
+            _ => None,
+        };
+        let policy = valid(lookup(&self.policy, key));
+
+        if self.is_locked(key) {
+            if let Some(v) = policy {
+                return Ok((v, Layer::Policy));
+            }
+            return Ok((declaration.default_value(), Layer::Default));
+        }
+        if let Some(v) = valid(lookup(&self.command_line, key)) {
+            return Ok((v, Layer::CommandLine));
+        }
+        if let Some(v) = valid(lookup(profile.document(), key)) {
+            return Ok((v, Layer::Profile));
+        }
+        if let Some(v) = policy {
+            return Ok((v, Layer::Policy));
+        }
+        Ok((declaration.default_value(), Layer::Default))
+    }
+
+    /// Settings holding the effective value of every key, not bound to any
+    /// file. Typed sections built from it see every layer.
+    pub fn flatten(&self, profile : &Settings) -> Settings {
+        let mut document = Table::new();
+        for key in schema::SCHEMA {
+            if let Ok((v, layer)) = self.resolve(profile, key.path) {
+                if layer != Layer::Default {
+                    let _ = insert(&mut document, key.path, v);
+                }
+            }
+        }
+        document.insert(String::from(migration::VERSION_KEY), Value::Integer(migration::CURRENT_VERSION));
+        Settings::from_document(document).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(content : &str) -> Table {
+        match content.parse::<Value>() {
+            Ok(Value::Table(t)) => t,
+            _ => panic!("Invalid document {}", content),
+        }
+    }
+
+    fn string(t : &str) -> Value {
+        Value::String(String::from(t))
+    }
+
+    #[test]
+    fn stronger_layers_win() {
+        let mut layers = Layers::new();
+        layers.set_policy(table("[general]\nhomepage = \"https://policy.org\"\nlanguage = \"de\"\nrestore_session = false\n"));
+        layers.add_override("general.homepage", "https://command.org").unwrap();
+        let profile = Settings::parse("[general]\nhomepage = \"https://profile.org\"\nlanguage = \"fr\"\n").unwrap();
+
+        assert_eq!(layers.resolve(&profile, "general.homepage").unwrap(), (string("https://command.org"), Layer::CommandLine));
+        assert_eq!(layers.resolve(&profile, "general.language").unwrap(), (string("fr"), Layer::Profile));
+        assert_eq!(layers.resolve(&profile, "general.restore_session").unwrap(), (Value::Boolean(false), Layer::Policy));
+        assert_eq!(layers.resolve(&profile, "general.default_search_engine").unwrap(), (string("duckduckgo"), Layer::Default));
+        assert_eq!(layers.resolve(&profile, "general.nothing").unwrap_err().code(), Some(3001));
+    }
+
+    #[test]
+    fn locked_keys_ignore_other_layers() {
+        let mut layers = Layers::new();
+        layers.set_policy(table("[policy]\nlocked = [\"privacy.do_not_track\", \"appearance.theme\"]\n\
+                                 [privacy]\ndo_not_track = true\n"));
+        layers.add_override("privacy.do_not_track", "false").unwrap();
+        layers.add_override("appearance.theme", "dark").unwrap();
+        let profile = Settings::parse("[privacy]\ndo_not_track = false\n").unwrap();
+
+        assert!(layers.is_locked("privacy.do_not_track"));
+        assert_eq!(layers.resolve(&profile, "privacy.do_not_track").unwrap(), (Value::Boolean(true), Layer::Policy));
+        // Locked without a policy value: the default is enforced.
+        assert_eq!(layers.resolve(&profile, "appearance.theme").unwrap(), (string("system"), Layer::Default));
+        assert!(layers.issues().is_empty());
+    }
+
+    #[test]
+    fn invalid_policy_values_are_reported_and_skipped() {
+        let mut layers = Layers::new();
+        layers.set_policy(table("[policy]\nlocked = [\"network.timeout\", \"network.nothing\"]\n\
+                                 [network]\ntimeout = 0\nproxy_port = \"80\"\nproxy_host = \"proxy\"\n"));
+
+        let issues : Vec<(&str, &str)> = layers.issues().iter().map(|i| (i.key.as_str(), i.message.as_str())).collect();
+        assert_eq!(issues, vec![
+            ("network.proxy_port", "policy: network.proxy_port must be an integer"),
+            ("network.timeout", "policy: network.timeout must be between 1 and 600"),
+            ("network.nothing", "policy: unknown locked key"),
+        ]);
+        let profile = Settings::parse("[network]\ntimeout = 90\nproxy_port = 3128\n").unwrap();
+        assert_eq!(layers.resolve(&profile, "network.timeout").unwrap(), (Value::Integer(30), Layer::Default));
+        assert_eq!(layers.resolve(&profile, "network.proxy_port").unwrap(), (Value::Integer(3128), Layer::Profile));
+        assert_eq!(layers.resolve(&profile, "network.proxy_host").unwrap(), (string("proxy"), Layer::Policy));
+
+        // A new policy replaces the issues of the previous one.
+        layers.set_policy(Table::new());
+        assert!(layers.issues().is_empty());
+        assert!(layers.locked().is_empty());
+    }
+
+    #[test]
+    fn overrides_are_literals_or_strings() {
+        let mut layers = Layers::new();
+        layers.add_override("appearance.font_size", "20").unwrap();
+        layers.add_override("appearance.default_zoom", "2").unwrap();
+        layers.add_override("general.homepage", "about:blank").unwrap();
+        layers.add_override("general.language", "\"fr\"").unwrap();
+        layers.add_override("network.no_proxy_for", "[\"a.org\", \"b.org\"]").unwrap();
+        layers.add_override("shortcuts.quit", "[\"shift+ctrl+q\"]").unwrap();
+        assert_eq!(layers.add_override("appearance.font_size", "big").unwrap_err().code(), Some(3002));
+        assert_eq!(layers.add_override("appearance.nothing", "1").unwrap_err().code(), Some(3001));
+
+        let settings = layers.flatten(&Settings::new());
+        assert_eq!(settings.integer("appearance.font_size"), 20);
+        assert_eq!(settings.float("appearance.default_zoom"), 2.0);
+        assert_eq!(settings.text("general.homepage"), "about:blank");
+        assert_eq!(settings.text("general.language"), "fr");
+        assert_eq!(settings.text_list("network.no_proxy_for"), vec!["a.org", "b.org"]);
+        assert_eq!(settings.text_list("shortcuts.quit"), vec!["Ctrl+Shift+Q"]);
+    }
+
+    #[test]
+    fn flatten_keeps_only_effective_values() {
+        let mut layers = Layers::new();
+        layers.set_policy(table("[appearance]\ntheme = \"dark\"\n"));
+        let profile = Settings::parse("nickname = \"me\"\n[general]\nlanguage = \"fr\"\nhomepage = 1\n").unwrap();
+
+        let settings = layers.flatten(&profile);
+        assert!(settings.path().is_none());
+        assert!(settings.issues().is_empty());
+        assert_eq!(settings.text("appearance.theme"), "dark");
+        assert_eq!(settings.text("general.language"), "fr");
+        assert_eq!(settings.text("general.homepage"), "https://sielo.app");
+        assert_eq!(lookup(settings.document(), "general.homepage"), None);
+        assert_eq!(lookup(settings.document(), "nickname"), None);
+        assert_eq!(settings.document().get(migration::VERSION_KEY), Some(&Value::Integer(migration::CURRENT_VERSION)));
+    }
+}
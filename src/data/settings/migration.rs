@@ -0,0 +1,158 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Migration of settings documents between versions
+//!
+//! Documents carry a top-level `settings_version`. Documents without it were
+//! written before versioning and are version 0, except empty ones which hold
+//! nothing to migrate and are at the current version. Each step of
+//! [`STEPS`](constant.STEPS.html) upgrades a document by exactly one
+//! version, so any older document reaches
+//! [`CURRENT_VERSION`](constant.CURRENT_VERSION.html) by running the steps
+//! in order.
+//!
+//! When adding a step, bump `CURRENT_VERSION` and never change an existing
+//! step: files in the wild may be at any version.
+
+use toml::Value;
+use toml::value::Table;
+
+use crate::data::db::Error;
+
+/// Name of the top-level key holding the version of the document.
+pub const VERSION_KEY : &str = "settings_version";
+
+pub const CURRENT_VERSION : i64 = 1;
+
+/// Upgrade of a document to the next version.
+pub type Step = fn(&mut Table) -> Result<(), Error>;
+
+/// Upgrade from the version at the same index to the next one.
+pub const STEPS : &[Step] = &[
+    from_0_to_1,
+];
+
+/// Version of `document`.
+pub fn version(document : &Table) -> Result<i64, Error> {
+    match document.get(VERSION_KEY) {
+        Some(Value::Integer(t)) if *t >= 0 => Ok(*t),
+        Some(_) => Err(Error::new(Some(3010), Some(format!("{} must be a positive integer", VERSION_KEY)))),
+        None if document.is_empty() => Ok(CURRENT_VERSION),
+        None => Ok(0),
+    }
+}
+
+/// Upgrades `document` to the current version, and returns the version it
+/// had before. Documents from a newer version of Sielo are left untouched.
+pub fn migrate(document : &mut Table) -> Result<i64, Error> {
+    let initial = version(document)?;
+
+    let mut current = initial;
+    while current < CURRENT_VERSION {
+        STEPS[current as usize](document)?;
+        current += 1;
+        document.insert(String::from(VERSION_KEY), Value::Integer(current));
+    }
+
+    Ok(initial)
+}
+
+/// Documents written before versioning have the same layout as version 1.
+fn from_0_to_1(_document : &mut Table) -> Result<(), Error> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::data::settings::Settings;
+
+    /// Written before versioning.
+    const V0 : &str = "[general]\nhomepage = \"https://example.org\"\ndefault_search_engine = \"qwant\"\n";
+    const V1 : &str = "settings_version = 1\n\n[general]\nhomepage = \"https://example.org\"\ndefault_search_engine = \"qwant\"\n";
+
+    fn profile(name : &str, content : &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sielo-migration-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("settings.toml");
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn steps_reach_current_version() {
+        assert_eq!(STEPS.len() as i64, CURRENT_VERSION);
+    }
+
+    #[test]
+    fn version_0_is_stamped_without_a_backup() {
+        let path = profile("v0", V0);
+        let settings = Settings::load(&path).unwrap();
+
+        assert_eq!(settings.migrated_from(), Some(0));
+        assert!(settings.issues().is_empty());
+        assert_eq!(settings.general().homepage, "https://example.org");
+        assert_eq!(settings.general().default_search_engine, "qwant");
+
+        // Going from 0 to 1 only adds the version, there is nothing to back up.
+        assert!(!path.with_file_name("settings.toml.v0.bak").exists());
+        let written = fs::read_to_string(&path).unwrap().parse::<Value>().unwrap();
+        assert_eq!(version(written.as_table().unwrap()).unwrap(), CURRENT_VERSION);
+
+        let settings = Settings::load(&path).unwrap();
+        assert_eq!(settings.migrated_from(), None);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn empty_documents_are_current() {
+        for (name, content) in &[("empty", ""), ("comments", "# Nothing yet\n")] {
+            let path = profile(name, content);
+            let settings = Settings::load(&path).unwrap();
+
+            assert_eq!(settings.migrated_from(), None);
+            assert!(settings.issues().is_empty());
+            assert_eq!(fs::read_to_string(&path).unwrap(), *content);
+            assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+            let _ = fs::remove_dir_all(path.parent().unwrap());
+        }
+        assert_eq!(version(&Table::new()).unwrap(), CURRENT_VERSION);
+    }
+
+    #[test]
+    fn version_1_is_current() {
+        let path = profile("v1", V1);
+        let settings = Settings::load(&path).unwrap();
+
+        assert_eq!(settings.migrated_from(), None);
+        assert!(settings.issues().is_empty());
+        assert_eq!(settings.general().default_search_engine, "qwant");
+        assert!(!path.with_file_name("settings.toml.v1.bak").exists());
+        assert_eq!(fs::read_to_string(&path).unwrap(), V1);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn newer_version_is_left_untouched() {
+        let mut document = Table::new();
+        document.insert(String::from(VERSION_KEY), Value::Integer(CURRENT_VERSION + 1));
+        assert_eq!(migrate(&mut document).unwrap(), CURRENT_VERSION + 1);
+        assert_eq!(version(&document).unwrap(), CURRENT_VERSION + 1);
+
+        let settings = Settings::parse("settings_version = 99\n").unwrap();
+        assert_eq!(settings.migrated_from(), None);
+        assert_eq!(settings.issues().len(), 1);
+    }
+
+    #[test]
+    fn invalid_version_fails() {
+        assert!(Settings::parse("settings_version = -1\n").is_err());
+        assert!(Settings::parse("settings_version = \"1\"\n").is_err());
+    }
+}
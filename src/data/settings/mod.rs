@@ -0,0 +1,500 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Settings system using TOML files
+//!
+//! Settings of a profile are stored in a TOML file split in sections
+//! (`general`, `privacy`, `appearance`, `network`, `downloads`, `ipc` and
+//! [`shortcuts`](shortcuts/index.html)). Known keys are declared in the
+//! [schema](schema/index.html), values are checked against it and missing or
+//! invalid ones fall back to their default. Files written by older versions are
+//! [migrated](migration/index.html) when loaded.
+//!
+//! The whole document is kept when loading, so keys unknown to this version
+//! of Sielo are written back untouched.
+//!
+//! Running parts of the core share the settings through a
+//! [`Store`](store/struct.Store.html), which notifies every change. The
+//! profile file is only one of the [layers](layers/index.html) a value can
+//! come from, and is [watched](watcher/index.html) for hand edits. Settings
+//! can be [exported and imported](transfer/index.html) to move them between
+//! machines.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use toml::Value;
+use toml::value::Table;
+
+use super::db::Error;
+
+pub mod schema;
+pub mod sections;
+pub mod store;
+pub mod layers;
+pub mod watcher;
+pub mod migration;
+pub mod transfer;
+pub mod shortcuts;
+
+pub use self::sections::{General, Privacy, Appearance, Network, Downloads, Ipc};
+pub use self::store::{Store, Batch, Change, Diff};
+pub use self::layers::{Layers, Layer};
+pub use self::watcher::Watcher;
+pub use self::shortcuts::ShortcutMap;
+
+/// Problem found in a settings document, the default value is used instead.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Issue {
+    pub key : String,
+    pub message : String,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Settings {
+    path : Option<PathBuf>,
+    document : Table,
+    issues : Vec<Issue>,
+    migrated_from : Option<i64>,
+    /// Whether the migration changed more than the version of the document.
+    migration_changed : bool,
+}
+
+impl Settings {
+    /// Settings with every key at its default value, not bound to a file.
+    pub fn new() -> Self {
+        Self {
+            path: None,
+            document: Table::new(),
+            issues: Vec::new(),
+            migrated_from: None,
+            migration_changed: false,
+        }
+    }
+
+    /// Loads the settings file `path`. A missing file gives the default
+    /// settings, it will be created on the first save.
+    ///
+    /// A file written by an older version is upgraded and written back. When
+    /// the upgrade changed more than the version, a copy of the original is
+    /// first saved next to it as `<name>.v<version>.bak`.
+    pub fn load<P : AsRef<Path>>(path : P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let ret = Self::read(path)?;
+
+        if let Some(version) = ret.migrated_from {
+            if ret.migration_changed {
+                backup(path, version)?
+            }
+            ret.save()?
+        }
+
+        Ok(ret)
+    }
+
+    /// Same as [`load`](#method.load), but an older file is only migrated in
+    /// memory and never written.
+    pub fn read<P : AsRef<Path>>(path : P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let mut ret = if path.exists() {
+            let content = match fs::read_to_string(path) {
+                Ok(t) => t,
+                Err(e) => return Err(Error::new(Some(3005), Some(format!("Can not read {}: {}", path.display(), e)))),
+            };
+            Self::parse(&content)?
+        } else {
+            Self::new()
+        };
+
+        ret.path = Some(path.to_path_buf());
+        Ok(ret)
+    }
+
+    /// Reads settings from the TOML document `content`, migrating it to the
+    /// current version if needed.
+    pub fn parse(content : &str) -> Result<Self, Error> {
+        match content.parse::<Value>() {
+            Ok(Value::Table(t)) => Self::from_document(t),
+            Ok(_) => Err(Error::new(Some(3006), Some(String::from("Settings document must be a table")))),
+            Err(e) => Err(Error::new(Some(3006), Some(format!("Invalid settings document: {}", e)))),
+        }
+    }
+
+    /// Settings using the already parsed `document`, migrated to the current
+    /// version if needed.
+    pub fn from_document(mut document : Table) -> Result<Self, Error> {
+        let mut original = document.clone();
+        let version = migration::migrate(&mut document)?;
+        let migration_changed = {
+            let mut migrated = document.clone();
+            original.remove(migration::VERSION_KEY);
+            migrated.remove(migration::VERSION_KEY);
+            original != migrated
+        };
+        let mut issues = check(&document);
+        if version > migration::CURRENT_VERSION {
+            issues.push(Issue {
+                key: String::from(migration::VERSION_KEY),
+                message: format!("Settings written by a newer version of Sielo ({})", version),
+            });
+        }
+
+        Ok(Self {
+            path: None,
+            document,
+            issues,
+            migrated_from: if version < migration::CURRENT_VERSION { Some(version) } else { None },
+            migration_changed,
+        })
+    }
+
+    /// Version the document had before being migrated, if it was.
+    pub fn migrated_from(&self) -> Option<i64> {
+        self.migrated_from
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        match &self.path {
+            Some(t) => Some(t.as_path()),
+            None => None,
+        }
+    }
+
+    pub fn set_path<P : AsRef<Path>>(&mut self, path : P) {
+        self.path = Some(path.as_ref().to_path_buf());
+    }
+
+    /// Problems found in the document when it was loaded.
+    pub fn issues(&self) -> &[Issue] {
+        &self.issues
+    }
+
+    /// Raw document, including unknown and invalid keys.
+    pub fn document(&self) -> &Table {
+        &self.document
+    }
+
+    /// Effective value of `key`: the stored one when it is valid, the
+    /// default otherwise.
+    pub fn get(&self, key : &str) -> Result<Value, Error> {
+        let declaration = match schema::find(key) {
+            Some(t) => t,
+            None => return Err(Error::new(Some(3001), Some(format!("Unknown setting {}", key)))),
+        };
+
+        match lookup(&self.document, key) {
+            Some(v) if declaration.validate(v).is_ok() => Ok(declaration.normalize(v.clone())),
+            _ => Ok(declaration.default_value()),
+        }
+    }
+
+    /// Changes the value of `key`, after checking it against the schema.
+    /// Nothing is written until [`save`](#method.save) is called.
+    pub fn set(&mut self, key : &str, value : Value) -> Result<(), Error> {
+        let declaration = match schema::find(key) {
+            Some(t) => t,
+            None => return Err(Error::new(Some(3001), Some(format!("Unknown setting {}", key)))),
+        };
+        if let Err(e) = declaration.validate(&value) {
+            return Err(Error::new(Some(3002), Some(e)));
+        }
+
+        match insert(&mut self.document, key, declaration.normalize(value)) {
+            Ok(_) => {
+                self.issues.retain(|i| i.key != key);
+                Ok(())
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Puts back the default value of `scope`: a full key, a section name,
+    /// or an empty string for every key. Unknown keys are kept.
+    pub fn reset(&mut self, scope : &str) -> Result<(), Error> {
+        let keys = schema::matching(scope);
+        if keys.is_empty() {
+            return Err(Error::new(Some(3001), Some(format!("Unknown setting {}", scope))));
+        }
+
+        for key in keys {
+            remove(&mut self.document, key.path);
+            self.issues.retain(|i| i.key != key.path);
+        }
+        Ok(())
+    }
+
+    /// Writes the settings back to their file.
+    ///
+    /// The document is written to a temporary file which then replaces the
+    /// settings file, so a crash can never leave a truncated file.
+    pub fn save(&self) -> Result<(), Error> {
+        let path = match &self.path {
+            Some(t) => t,
+            None => return Err(Error::new(Some(3003), Some(String::from("Settings are not bound to a file")))),
+        };
+        let mut document = self.document.clone();
+        if !document.contains_key(migration::VERSION_KEY) {
+            document.insert(String::from(migration::VERSION_KEY), Value::Integer(migration::CURRENT_VERSION));
+        }
+        let content = match toml::to_string(&Value::Table(document)) {
+            Ok(t) => t,
+            Err(e) => return Err(Error::new(Some(3006), Some(format!("Can not serialize settings: {}", e)))),
+        };
+
+        write_atomically(path, content.as_bytes())
+    }
+
+    pub fn boolean(&self, key : &str) -> bool {
+        match self.get(key) {
+            Ok(Value::Boolean(t)) => t,
+            _ => false,
+        }
+    }
+
+    pub fn integer(&self, key : &str) -> i64 {
+        match self.get(key) {
+            Ok(Value::Integer(t)) => t,
+            _ => 0,
+        }
+    }
+
+    pub fn float(&self, key : &str) -> f64 {
+        match self.get(key) {
+            Ok(Value::Float(t)) => t,
+            _ => 0.0,
+        }
+    }
+
+    pub fn text(&self, key : &str) -> String {
+        match self.get(key) {
+            Ok(Value::String(t)) => t,
+            _ => String::new(),
+        }
+    }
+
+    pub fn text_list(&self, key : &str) -> Vec<String> {
+        match self.get(key) {
+            Ok(Value::Array(t)) => t.into_iter().filter_map(|v| match v {
+                Value::String(s) => Some(s),
+                _ => None,
+            }).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn general(&self) -> General {
+        General::from(self)
+    }
+
+    pub fn privacy(&self) -> Privacy {
+        Privacy::from(self)
+    }
+
+    pub fn appearance(&self) -> Appearance {
+        Appearance::from(self)
+    }
+
+    pub fn network(&self) -> Network {
+        Network::from(self)
+    }
+
+    pub fn downloads(&self) -> Downloads {
+        Downloads::from(self)
+    }
+
+    pub fn ipc(&self) -> Ipc {
+        Ipc::from(self)
+    }
+
+    pub fn shortcuts(&self) -> ShortcutMap {
+        ShortcutMap::from(self)
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Checks every known key present in `document`.
+pub fn check(document : &Table) -> Vec<Issue> {
+    schema::SCHEMA.iter().filter_map(|k| {
+        match lookup(document, k.path) {
+            Some(v) => match k.validate(v) {
+                Ok(_) => None,
+                Err(e) => Some(Issue { key: String::from(k.path), message: e }),
+            },
+            None => None,
+        }
+    }).collect()
+}
+
+/// Value at the dotted path `path` of `table`.
+pub fn lookup<'a>(table : &'a Table, path : &str) -> Option<&'a Value> {
+    let mut current = table;
+    let mut parts = path.split('.').peekable();
+
+    while let Some(part) = parts.next() {
+        let value = current.get(part)?;
+        if parts.peek().is_none() {
+            return Some(value);
+        }
+        current = match value {
+            Value::Table(t) => t,
+            _ => return None,
+        };
+    }
+
+    None
+}
+
+/// Sets the value at the dotted path `path` of `table`, creating the missing
+/// tables.
+pub fn insert(table : &mut Table, path : &str, value : Value) -> Result<(), Error> {
+    let mut current = table;
+    let parts : Vec<&str> = path.split('.').collect();
+
+    for part in &parts[..parts.len() - 1] {
+        let entry = current.entry(String::from(*part)).or_insert_with(|| Value::Table(Table::new()));
+        current = match entry {
+            Value::Table(t) => t,
+            _ => return Err(Error::new(Some(3004), Some(format!("{} is not a table", part)))),
+        };
+    }
+
+    current.insert(String::from(parts[parts.len() - 1]), value);
+    Ok(())
+}
+
+/// Removes the value at the dotted path `path` of `table`. Tables left
+/// empty are removed too.
+pub fn remove(table : &mut Table, path : &str) -> Option<Value> {
+    match path.find('.') {
+        Some(t) => {
+            let (head, tail) = (&path[..t], &path[t + 1..]);
+            let (ret, empty) = match table.get_mut(head) {
+                Some(Value::Table(sub)) => {
+                    let ret = remove(sub, tail);
+                    (ret, sub.is_empty())
+                },
+                _ => return None,
+            };
+            if empty {
+                table.remove(head);
+            }
+            ret
+        },
+        None => table.remove(path),
+    }
+}
+
+/// Copies the settings file `path`, of version `version`, next to it before
+/// it is migrated.
+fn backup(path : &Path, version : i64) -> Result<(), Error> {
+    let backup = match path.file_name() {
+        Some(t) => path.with_file_name(format!("{}.v{}.bak", t.to_string_lossy(), version)),
+        None => return Err(Error::new(Some(3005), Some(format!("Invalid settings path {}", path.display())))),
+    };
+    match fs::copy(path, &backup) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Error::new(Some(3005), Some(format!("Can not back up {}: {}", path.display(), e)))),
+    }
+}
+
+/// Replaces the content of `path` with `content` through a temporary file in
+/// the same directory.
+pub fn write_atomically(path : &Path, content : &[u8]) -> Result<(), Error> {
+    let temporary = match path.file_name() {
+        Some(t) => path.with_file_name(format!(".{}.tmp", t.to_string_lossy())),
+        None => return Err(Error::new(Some(3005), Some(format!("Invalid settings path {}", path.display())))),
+    };
+    let io_error = |e : std::io::Error| {
+        Error::new(Some(3005), Some(format!("Can not write {}: {}", path.display(), e)))
+    };
+
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() {
+            if let Err(e) = fs::create_dir_all(parent) {
+                return Err(io_error(e));
+            }
+        }
+    }
+    let mut file = match fs::File::create(&temporary) {
+        Ok(t) => t,
+        Err(e) => return Err(io_error(e)),
+    };
+    if let Err(e) = file.write_all(content) {
+        let _ = fs::remove_file(&temporary);
+        return Err(io_error(e));
+    }
+    if let Err(e) = file.sync_all() {
+        let _ = fs::remove_file(&temporary);
+        return Err(io_error(e));
+    }
+    match fs::rename(&temporary, path) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            let _ = fs::remove_file(&temporary);
+            Err(io_error(e))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn unknown_keys_survive_load_and_save() {
+        let dir = std::env::temp_dir().join(format!("sielo-settings-unknown-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("settings.toml");
+        write_atomically(&path, b"settings_version = 1\nplugin = \"adblock\"\n\n\
+                                  [general]\nhomepage = \"https://example.org\"\nnickname = \"me\"\n\n\
+                                  [experiments]\ntabs = [1, 2]\n").unwrap();
+
+        let mut settings = Settings::load(&path).unwrap();
+        assert!(settings.issues().is_empty());
+        settings.set("general.language", Value::String(String::from("fr"))).unwrap();
+        settings.reset("general.homepage").unwrap();
+        settings.save().unwrap();
+
+        let document = Settings::load(&path).unwrap().document().clone();
+        assert_eq!(lookup(&document, "plugin"), Some(&Value::String(String::from("adblock"))));
+        assert_eq!(lookup(&document, "general.nickname"), Some(&Value::String(String::from("me"))));
+        assert_eq!(lookup(&document, "general.language"), Some(&Value::String(String::from("fr"))));
+        assert_eq!(lookup(&document, "general.homepage"), None);
+        assert_eq!(lookup(&document, "experiments.tabs"),
+                   Some(&Value::Array(vec![Value::Integer(1), Value::Integer(2)])));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn backups_are_named_after_the_version() {
+        let dir = std::env::temp_dir().join(format!("sielo-settings-backup-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("settings.toml");
+        fs::write(&path, "old = true\n").unwrap();
+
+        backup(&path, 3).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("settings.toml.v3.bak")).unwrap(), "old = true\n");
+        assert_eq!(backup(&dir.join("missing.toml"), 3).unwrap_err().code(), Some(3005));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn invalid_documents_fail() {
+        assert_eq!(Settings::parse("[general").unwrap_err().code(), Some(3006));
+        let mut settings = Settings::new();
+        assert_eq!(settings.set("general.nickname", Value::Boolean(true)).unwrap_err().code(), Some(3001));
+        assert_eq!(settings.set("general.restore_session", Value::Integer(1)).unwrap_err().code(), Some(3002));
+        assert_eq!(settings.save().unwrap_err().code(), Some(3003));
+    }
+}
@@ -0,0 +1,279 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Declared schema of the settings
+//!
+//! Every known key is listed in [`SCHEMA`](constant.SCHEMA.html) with its
+//! type, constraints and default value. Defaults are written as TOML
+//! literals so the schema can stay a constant.
+
+use toml::Value;
+
+use super::shortcuts;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Kind {
+    Boolean,
+    /// Integer between both bounds, included.
+    Integer(i64, i64),
+    /// Floating-point number between both bounds, included.
+    Float(f64, f64),
+    Text,
+    /// Text which must be one of the listed values.
+    Choice(&'static [&'static str]),
+    TextList,
+    /// List of keyboard [bindings](../shortcuts/index.html).
+    Bindings,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Key {
+    /// Dotted path of the key, like `general.homepage`.
+    pub path : &'static str,
+    pub kind : Kind,
+    /// TOML literal of the default value.
+    pub default : &'static str,
+}
+
+pub const SCHEMA : &[Key] = &[
+    Key { path: "general.homepage", kind: Kind::Text, default: "\"https://sielo.app\"" },
+    Key { path: "general.restore_session", kind: Kind::Boolean, default: "true" },
+    Key { path: "general.language", kind: Kind::Text, default: "\"en\"" },
+    Key { path: "general.default_search_engine", kind: Kind::Text, default: "\"duckduckgo\"" },
+
+    Key { path: "privacy.do_not_track", kind: Kind::Boolean, default: "true" },
+    Key { path: "privacy.block_third_party_cookies", kind: Kind::Boolean, default: "true" },
+    Key { path: "privacy.remember_history", kind: Kind::Boolean, default: "true" },
+    Key { path: "privacy.clear_history_on_exit", kind: Kind::Boolean, default: "false" },
+
+    Key { path: "appearance.theme", kind: Kind::Choice(&["system", "light", "dark"]), default: "\"system\"" },
+    Key { path: "appearance.font_size", kind: Kind::Integer(6, 72), default: "16" },
+    Key { path: "appearance.default_zoom", kind: Kind::Float(0.25, 5.0), default: "1.0" },
+    Key { path: "appearance.show_bookmarks_bar", kind: Kind::Boolean, default: "true" },
+
+    Key { path: "network.proxy_mode", kind: Kind::Choice(&["none", "system", "manual"]), default: "\"system\"" },
+    Key { path: "network.proxy_host", kind: Kind::Text, default: "\"\"" },
+    Key { path: "network.proxy_port", kind: Kind::Integer(0, 65535), default: "8080" },
+    Key { path: "network.no_proxy_for", kind: Kind::TextList, default: "[\"localhost\", \"127.0.0.1\"]" },
+    Key { path: "network.timeout", kind: Kind::Integer(1, 600), default: "30" },
+
+    Key { path: "downloads.directory", kind: Kind::Text, default: "\"\"" },
+    Key { path: "downloads.ask_where_to_save", kind: Kind::Boolean, default: "false" },
+    Key { path: "downloads.max_parallel", kind: Kind::Integer(1, 16), default: "4" },
+
+    Key { path: "ipc.heartbeat_interval", kind: Kind::Integer(100, 600_000), default: "5000" },
+    Key { path: "ipc.client_timeout", kind: Kind::Integer(500, 3_600_000), default: "15000" },
+
+    Key { path: "shortcuts.new_tab", kind: Kind::Bindings, default: "[\"Ctrl+T\"]" },
+    Key { path: "shortcuts.close_tab", kind: Kind::Bindings, default: "[\"Ctrl+W\", \"Ctrl+F4\"]" },
+    Key { path: "shortcuts.reopen_closed_tab", kind: Kind::Bindings, default: "[\"Ctrl+Shift+T\"]" },
+    Key { path: "shortcuts.next_tab", kind: Kind::Bindings, default: "[\"Ctrl+Tab\", \"Ctrl+PageDown\"]" },
+    Key { path: "shortcuts.previous_tab", kind: Kind::Bindings, default: "[\"Ctrl+Shift+Tab\", \"Ctrl+PageUp\"]" },
+    Key { path: "shortcuts.new_window", kind: Kind::Bindings, default: "[\"Ctrl+N\"]" },
+    Key { path: "shortcuts.new_private_window", kind: Kind::Bindings, default: "[\"Ctrl+Shift+P\"]" },
+    Key { path: "shortcuts.close_window", kind: Kind::Bindings, default: "[\"Ctrl+Shift+W\"]" },
+    Key { path: "shortcuts.focus_address_bar", kind: Kind::Bindings, default: "[\"Ctrl+L\", \"Alt+D\", \"F6\"]" },
+    Key { path: "shortcuts.back", kind: Kind::Bindings, default: "[\"Alt+Left\"]" },
+    Key { path: "shortcuts.forward", kind: Kind::Bindings, default: "[\"Alt+Right\"]" },
+    Key { path: "shortcuts.home", kind: Kind::Bindings, default: "[\"Alt+Home\"]" },
+    Key { path: "shortcuts.reload", kind: Kind::Bindings, default: "[\"F5\", \"Ctrl+R\"]" },
+    Key { path: "shortcuts.hard_reload", kind: Kind::Bindings, default: "[\"Ctrl+F5\", \"Ctrl+Shift+R\"]" },
+    Key { path: "shortcuts.stop", kind: Kind::Bindings, default: "[\"Escape\"]" },
+    Key { path: "shortcuts.find", kind: Kind::Bindings, default: "[\"Ctrl+F\"]" },
+    Key { path: "shortcuts.zoom_in", kind: Kind::Bindings, default: "[\"Ctrl+Plus\", \"Ctrl+Equal\"]" },
+    Key { path: "shortcuts.zoom_out", kind: Kind::Bindings, default: "[\"Ctrl+Minus\"]" },
+    Key { path: "shortcuts.zoom_reset", kind: Kind::Bindings, default: "[\"Ctrl+0\"]" },
+    Key { path: "shortcuts.bookmark_page", kind: Kind::Bindings, default: "[\"Ctrl+D\"]" },
+    Key { path: "shortcuts.show_history", kind: Kind::Bindings, default: "[\"Ctrl+H\"]" },
+    Key { path: "shortcuts.show_bookmarks", kind: Kind::Bindings, default: "[\"Ctrl+Shift+O\"]" },
+    Key { path: "shortcuts.show_downloads", kind: Kind::Bindings, default: "[\"Ctrl+J\"]" },
+    Key { path: "shortcuts.fullscreen", kind: Kind::Bindings, default: "[\"F11\"]" },
+    Key { path: "shortcuts.print", kind: Kind::Bindings, default: "[\"Ctrl+P\"]" },
+    Key { path: "shortcuts.save_page", kind: Kind::Bindings, default: "[\"Ctrl+S\"]" },
+    Key { path: "shortcuts.view_source", kind: Kind::Bindings, default: "[\"Ctrl+U\"]" },
+    Key { path: "shortcuts.developer_tools", kind: Kind::Bindings, default: "[\"F12\", \"Ctrl+Shift+I\"]" },
+    Key { path: "shortcuts.quit", kind: Kind::Bindings, default: "[\"Ctrl+Q\"]" },
+];
+
+/// Declaration of the key `path`, if it is known.
+pub fn find(path : &str) -> Option<&'static Key> {
+    SCHEMA.iter().find(|k| k.path == path)
+}
+
+/// Known keys of the section `section`.
+pub fn section(section : &str) -> Vec<&'static Key> {
+    SCHEMA.iter().filter(|k| k.section() == section).collect()
+}
+
+/// Known keys in `scope`: a full key, a section name, or every key when
+/// `scope` is empty or `*`.
+pub fn matching(scope : &str) -> Vec<&'static Key> {
+    match scope {
+        "" | "*" => SCHEMA.iter().collect(),
+        _ => SCHEMA.iter().filter(|k| k.path == scope || k.section() == scope).collect(),
+    }
+}
+
+impl Key {
+    /// Name of the section containing this key.
+    pub fn section(&self) -> &'static str {
+        match self.path.find('.') {
+            Some(t) => &self.path[..t],
+            None => "",
+        }
+    }
+
+    pub fn default_value(&self) -> Value {
+        match format!("v = {}", self.default).parse::<Value>() {
+            Ok(Value::Table(mut t)) => match t.remove("v") {
+                Some(v) => v,
+                None => panic!("Invalid default value for {}", self.path),
+            },
+            _ => panic!("Invalid default value for {}", self.path),
+        }
+    }
+
+    /// Checks if `value` is allowed for this key. The error is a message
+    /// meant for the user.
+    pub fn validate(&self, value : &Value) -> Result<(), String> {
+        match (self.kind, value) {
+            (Kind::Boolean, Value::Boolean(_)) => Ok(()),
+            (Kind::Integer(min, max), Value::Integer(v)) => {
+                if *v < min || *v > max {
+                    Err(format!("{} must be between {} and {}", self.path, min, max))
+                } else {
+                    Ok(())
+                }
+            },
+            (Kind::Float(min, max), Value::Float(v)) => {
+                if *v < min || *v > max || v.is_nan() {
+                    Err(format!("{} must be between {} and {}", self.path, min, max))
+                } else {
+                    Ok(())
+                }
+            },
+            // Integers are accepted as floats, `zoom = 1` is a natural thing to write.
+            (Kind::Float(min, max), Value::Integer(v)) => {
+                if (*v as f64) < min || (*v as f64) > max {
+                    Err(format!("{} must be between {} and {}", self.path, min, max))
+                } else {
+                    Ok(())
+                }
+            },
+            (Kind::Text, Value::String(_)) => Ok(()),
+            (Kind::Choice(choices), Value::String(v)) => {
+                if choices.contains(&v.as_str()) {
+                    Ok(())
+                } else {
+                    Err(format!("{} must be one of {}", self.path, choices.join(", ")))
+                }
+            },
+            (Kind::TextList, Value::Array(v)) => {
+                if v.iter().all(|i| i.is_str()) {
+                    Ok(())
+                } else {
+                    Err(format!("{} must be a list of strings", self.path))
+                }
+            },
+            (Kind::Bindings, Value::Array(v)) => {
+                for i in v {
+                    match i.as_str().map(shortcuts::normalize) {
+                        Some(Ok(_)) => (),
+                        Some(Err(e)) => return Err(e),
+                        None => return Err(format!("{} must be a list of strings", self.path)),
+                    }
+                }
+                Ok(())
+            },
+            (kind, _) => Err(format!("{} must be {}", self.path, match kind {
+                Kind::Boolean => "a boolean",
+                Kind::Integer(_, _) => "an integer",
+                Kind::Float(_, _) => "a number",
+                Kind::Text | Kind::Choice(_) => "a string",
+                Kind::TextList | Kind::Bindings => "a list of strings",
+            })),
+        }
+    }
+
+    /// Normalized form of a valid value, integers given to float keys are
+    /// converted and bindings are written in their canonical form.
+    pub fn normalize(&self, value : Value) -> Value {
+        match (self.kind, value) {
+            (Kind::Float(_, _), Value::Integer(v)) => Value::Float(v as f64),
+            (Kind::Bindings, Value::Array(v)) => Value::Array(v.into_iter().map(|i| {
+                match i.as_str().map(shortcuts::normalize) {
+                    Some(Ok(t)) => Value::String(t),
+                    _ => i,
+                }
+            }).collect()),
+            (_, v) => v,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(literal : &str) -> Value {
+        match format!("v = {}", literal).parse::<Value>() {
+            Ok(Value::Table(mut t)) => t.remove("v").unwrap(),
+            _ => panic!("Invalid literal {}", literal),
+        }
+    }
+
+    fn error(path : &str, literal : &str) -> String {
+        find(path).unwrap().validate(&value(literal)).unwrap_err()
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        for key in SCHEMA {
+            assert_eq!(key.validate(&key.default_value()), Ok(()), "{}", key.path);
+            assert_eq!(key.normalize(key.default_value()), key.default_value(), "{}", key.path);
+            assert_eq!(SCHEMA.iter().filter(|k| k.path == key.path).count(), 1, "{}", key.path);
+        }
+    }
+
+    #[test]
+    fn invalid_values_are_explained() {
+        assert_eq!(error("appearance.font_size", "5"), "appearance.font_size must be between 6 and 72");
+        assert_eq!(error("appearance.font_size", "73"), "appearance.font_size must be between 6 and 72");
+        assert_eq!(error("appearance.font_size", "\"16\""), "appearance.font_size must be an integer");
+        assert_eq!(error("appearance.default_zoom", "6"), "appearance.default_zoom must be between 0.25 and 5");
+        assert_eq!(error("appearance.default_zoom", "nan"), "appearance.default_zoom must be between 0.25 and 5");
+        assert_eq!(error("appearance.theme", "\"blue\""), "appearance.theme must be one of system, light, dark");
+        assert_eq!(error("general.restore_session", "1"), "general.restore_session must be a boolean");
+        assert_eq!(error("network.no_proxy_for", "[\"localhost\", 1]"), "network.no_proxy_for must be a list of strings");
+        assert_eq!(error("shortcuts.quit", "\"Ctrl+Q\""), "shortcuts.quit must be a list of strings");
+        assert!(find("shortcuts.quit").unwrap().validate(&value("[\"Ctrl+Nothing\"]")).is_err());
+
+        assert_eq!(find("appearance.font_size").unwrap().validate(&value("72")), Ok(()));
+        assert_eq!(find("appearance.default_zoom").unwrap().validate(&value("2")), Ok(()));
+    }
+
+    #[test]
+    fn values_are_normalized() {
+        let zoom = find("appearance.default_zoom").unwrap();
+        assert_eq!(zoom.normalize(value("2")), Value::Float(2.0));
+        assert_eq!(zoom.normalize(value("1.5")), Value::Float(1.5));
+        let quit = find("shortcuts.quit").unwrap();
+        assert_eq!(quit.normalize(value("[\"shift+ctrl+q\", \"alt+f4\"]")), value("[\"Ctrl+Shift+Q\", \"Alt+F4\"]"));
+        let font = find("appearance.font_size").unwrap();
+        assert_eq!(font.normalize(value("12")), value("12"));
+    }
+
+    #[test]
+    fn keys_are_found_by_scope() {
+        assert_eq!(find("network.timeout").unwrap().section(), "network");
+        assert!(find("network").is_none());
+        assert!(find("network.unknown").is_none());
+        assert_eq!(section("ipc").len(), 2);
+        assert_eq!(matching("ipc"), section("ipc"));
+        assert_eq!(matching("ipc.client_timeout"), vec![find("ipc.client_timeout").unwrap()]);
+        assert_eq!(matching("*").len(), SCHEMA.len());
+        assert!(matching("nothing").is_empty());
+    }
+}
@@ -0,0 +1,176 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Typed views of the settings sections
+//!
+//! Built from the effective values of a [`Settings`](../struct.Settings.html),
+//! which are always valid, so reading them can not fail.
+
+use super::Settings;
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct General {
+    pub homepage : String,
+    pub restore_session : bool,
+    pub language : String,
+    pub default_search_engine : String,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Privacy {
+    pub do_not_track : bool,
+    pub block_third_party_cookies : bool,
+    pub remember_history : bool,
+    pub clear_history_on_exit : bool,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Appearance {
+    pub theme : String,
+    pub font_size : i64,
+    pub default_zoom : f64,
+    pub show_bookmarks_bar : bool,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Network {
+    pub proxy_mode : String,
+    pub proxy_host : String,
+    pub proxy_port : u16,
+    pub no_proxy_for : Vec<String>,
+    /// Timeout of connections, in seconds.
+    pub timeout : u32,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Downloads {
+    /// Empty when the default download directory of the system is used.
+    pub directory : String,
+    pub ask_where_to_save : bool,
+    pub max_parallel : u32,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Ipc {
+    /// Delay after which an idle client checks the core is alive, in
+    /// milliseconds.
+    pub heartbeat_interval : i64,
+    /// Time after which a silent client is forgotten, in milliseconds.
+    pub client_timeout : i64,
+}
+
+impl<'a> From<&'a Settings> for General {
+    fn from(s : &'a Settings) -> Self {
+        Self {
+            homepage: s.text("general.homepage"),
+            restore_session: s.boolean("general.restore_session"),
+            language: s.text("general.language"),
+            default_search_engine: s.text("general.default_search_engine"),
+        }
+    }
+}
+
+impl<'a> From<&'a Settings> for Privacy {
+    fn from(s : &'a Settings) -> Self {
+        Self {
+            do_not_track: s.boolean("privacy.do_not_track"),
+            block_third_party_cookies: s.boolean("privacy.block_third_party_cookies"),
+            remember_history: s.boolean("privacy.remember_history"),
+            clear_history_on_exit: s.boolean("privacy.clear_history_on_exit"),
+        }
+    }
+}
+
+impl<'a> From<&'a Settings> for Appearance {
+    fn from(s : &'a Settings) -> Self {
+        Self {
+            theme: s.text("appearance.theme"),
+            font_size: s.integer("appearance.font_size"),
+            default_zoom: s.float("appearance.default_zoom"),
+            show_bookmarks_bar: s.boolean("appearance.show_bookmarks_bar"),
+        }
+    }
+}
+
+impl<'a> From<&'a Settings> for Network {
+    fn from(s : &'a Settings) -> Self {
+        Self {
+            proxy_mode: s.text("network.proxy_mode"),
+            proxy_host: s.text("network.proxy_host"),
+            proxy_port: s.integer("network.proxy_port") as u16,
+            no_proxy_for: s.text_list("network.no_proxy_for"),
+            timeout: s.integer("network.timeout") as u32,
+        }
+    }
+}
+
+impl<'a> From<&'a Settings> for Downloads {
+    fn from(s : &'a Settings) -> Self {
+        Self {
+            directory: s.text("downloads.directory"),
+            ask_where_to_save: s.boolean("downloads.ask_where_to_save"),
+            max_parallel: s.integer("downloads.max_parallel") as u32,
+        }
+    }
+}
+
+impl<'a> From<&'a Settings> for Ipc {
+    fn from(s : &'a Settings) -> Self {
+        Self {
+            heartbeat_interval: s.integer("ipc.heartbeat_interval"),
+            client_timeout: s.integer("ipc.client_timeout"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_keys_use_defaults() {
+        let settings = Settings::new();
+        assert_eq!(settings.general(), General {
+            homepage: String::from("https://sielo.app"),
+            restore_session: true,
+            language: String::from("en"),
+            default_search_engine: String::from("duckduckgo"),
+        });
+        assert_eq!(settings.network(), Network {
+            proxy_mode: String::from("system"),
+            proxy_host: String::new(),
+            proxy_port: 8080,
+            no_proxy_for: vec![String::from("localhost"), String::from("127.0.0.1")],
+            timeout: 30,
+        });
+        assert_eq!(settings.ipc(), Ipc { heartbeat_interval: 5000, client_timeout: 15000 });
+    }
+
+    #[test]
+    fn stored_values_override_defaults() {
+        let settings = Settings::parse("[appearance]\ntheme = \"dark\"\ndefault_zoom = 2\n\
+                                        [downloads]\nmax_parallel = 8\n").unwrap();
+        assert!(settings.issues().is_empty());
+        assert_eq!(settings.appearance(), Appearance {
+            theme: String::from("dark"),
+            font_size: 16,
+            default_zoom: 2.0,
+            show_bookmarks_bar: true,
+        });
+        assert_eq!(settings.downloads().max_parallel, 8);
+        assert!(!settings.downloads().ask_where_to_save);
+    }
+
+    #[test]
+    fn invalid_values_use_defaults() {
+        let settings = Settings::parse("[privacy]\ndo_not_track = \"no\"\nremember_history = false\n\
+                                        [network]\nproxy_port = 70000\n").unwrap();
+        let keys : Vec<&str> = settings.issues().iter().map(|i| i.key.as_str()).collect();
+        assert_eq!(keys, vec!["privacy.do_not_track", "network.proxy_port"]);
+        assert!(settings.privacy().do_not_track);
+        assert!(!settings.privacy().remember_history);
+        assert_eq!(settings.network().proxy_port, 8080);
+    }
+}
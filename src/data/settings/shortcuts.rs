@@ -0,0 +1,301 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Keyboard shortcuts
+//!
+//! Every action of the browser is a key of the `shortcuts` section of the
+//! [schema](../schema/index.html), holding its list of bindings. Users
+//! override the defaults in their settings file:
+//!
+//! ```toml
+//! [shortcuts]
+//! new_tab = ["Ctrl+T", "Ctrl+Shift+N"]
+//! quit = []
+//! ```
+//!
+//! Bindings are written as modifiers followed by a key, joined by `+`, and
+//! always stored in the canonical `Ctrl+Alt+Shift+Meta+Key` order. The
+//! resolved [`ShortcutMap`](struct.ShortcutMap.html) is what front ends
+//! read, with the bindings given to several actions.
+
+use std::fmt;
+
+use toml::Value;
+use toml::value::Table;
+
+use super::{schema, Settings};
+
+/// Section of the schema declaring the actions.
+pub const SECTION : &str = "shortcuts";
+
+/// Keys with a name, in their canonical spelling.
+const NAMED_KEYS : &[&str] = &[
+    "Tab", "Enter", "Escape", "Space", "Backspace", "Delete", "Insert",
+    "Left", "Right", "Up", "Down", "PageUp", "PageDown", "Home", "End",
+    "Plus", "Minus", "Equal", "Comma", "Period", "Slash", "Backslash",
+    "Semicolon", "Quote", "Backquote", "BracketLeft", "BracketRight",
+];
+
+/// Other spellings accepted for some keys.
+const ALIASES : &[(&str, &str)] = &[
+    ("return", "Enter"),
+    ("esc", "Escape"),
+    ("del", "Delete"),
+    ("ins", "Insert"),
+    ("pgup", "PageUp"),
+    ("pgdown", "PageDown"),
+    ("pgdn", "PageDown"),
+    ("=", "Equal"),
+    ("-", "Minus"),
+    (",", "Comma"),
+    (".", "Period"),
+    ("/", "Slash"),
+    (";", "Semicolon"),
+];
+
+#[derive(Eq, PartialEq, Hash, Debug, Clone)]
+pub struct Binding {
+    pub ctrl : bool,
+    pub alt : bool,
+    pub shift : bool,
+    pub meta : bool,
+    /// Canonical name of the key.
+    pub key : String,
+}
+
+impl Binding {
+    /// Parses a binding like `ctrl+shift+t`. The error is a message meant
+    /// for the user.
+    pub fn parse(text : &str) -> Result<Self, String> {
+        let text = text.trim();
+        // `Ctrl++` binds the plus key.
+        let (modifiers, key) = if text.ends_with("++") || text == "+" {
+            (&text[..text.len() - 1], "Plus")
+        } else {
+            match text.rfind('+') {
+                Some(t) => (&text[..t + 1], &text[t + 1..]),
+                None => ("", text),
+            }
+        };
+
+        let mut ret = Self { ctrl: false, alt: false, shift: false, meta: false, key: String::new() };
+        for modifier in modifiers.split('+').map(str::trim).filter(|m| !m.is_empty()) {
+            let flag = match modifier.to_lowercase().as_str() {
+                "ctrl" | "control" => &mut ret.ctrl,
+                "alt" | "option" => &mut ret.alt,
+                "shift" => &mut ret.shift,
+                "meta" | "super" | "cmd" | "command" | "win" => &mut ret.meta,
+                _ => return Err(format!("Unknown modifier {} in {}", modifier, text)),
+            };
+            *flag = true;
+        }
+
+        ret.key = match key_name(key.trim()) {
+            Some(t) => t,
+            None => return Err(format!("Unknown key {} in {}", key, text)),
+        };
+        Ok(ret)
+    }
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        for (set, name) in &[(self.ctrl, "Ctrl"), (self.alt, "Alt"), (self.shift, "Shift"), (self.meta, "Meta")] {
+            if *set {
+                write!(f, "{}+", name)?;
+            }
+        }
+        write!(f, "{}", self.key)
+    }
+}
+
+/// Canonical spelling of the binding `text`.
+pub fn normalize(text : &str) -> Result<String, String> {
+    Binding::parse(text).map(|b| b.to_string())
+}
+
+/// Names of every action, in the order of the schema.
+pub fn actions() -> Vec<&'static str> {
+    schema::section(SECTION).iter().map(|k| &k.path[SECTION.len() + 1..]).collect()
+}
+
+/// Binding given to more than one action.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Conflict {
+    pub binding : String,
+    pub actions : Vec<String>,
+}
+
+/// Effective bindings of every action.
+#[derive(Eq, PartialEq, Debug, Default, Clone)]
+pub struct ShortcutMap {
+    bindings : Vec<(String, Vec<String>)>,
+}
+
+impl<'a> From<&'a Settings> for ShortcutMap {
+    fn from(settings : &'a Settings) -> Self {
+        Self {
+            bindings: actions().into_iter().map(|a| {
+                (String::from(a), settings.text_list(&format!("{}.{}", SECTION, a)))
+            }).collect(),
+        }
+    }
+}
+
+impl ShortcutMap {
+    /// Bindings of `action`, empty if it has none or is unknown.
+    pub fn bindings(&self, action : &str) -> &[String] {
+        match self.bindings.iter().find(|(a, _)| a == action) {
+            Some((_, t)) => t,
+            None => &[],
+        }
+    }
+
+    /// Action triggered by `binding`. On a conflict, the first action in
+    /// the schema order wins.
+    pub fn action(&self, binding : &str) -> Option<&str> {
+        let binding = match normalize(binding) {
+            Ok(t) => t,
+            Err(_) => return None,
+        };
+        self.bindings.iter().find(|(_, b)| b.contains(&binding)).map(|(a, _)| a.as_str())
+    }
+
+    /// Bindings given to several actions.
+    pub fn conflicts(&self) -> Vec<Conflict> {
+        let mut ret : Vec<Conflict> = Vec::new();
+        for (action, bindings) in &self.bindings {
+            for binding in bindings {
+                match ret.iter_mut().find(|c| &c.binding == binding) {
+                    Some(c) => c.actions.push(action.clone()),
+                    None => ret.push(Conflict { binding: binding.clone(), actions: vec![action.clone()] }),
+                }
+            }
+        }
+        ret.retain(|c| c.actions.len() > 1);
+        ret
+    }
+
+    /// TOML form sent to the front ends:
+    ///
+    /// ```toml
+    /// [bindings]
+    /// new_tab = ["Ctrl+T"]
+    ///
+    /// [[conflicts]]
+    /// binding = "Ctrl+T"
+    /// actions = ["new_tab", "new_window"]
+    /// ```
+    pub fn to_table(&self) -> Table {
+        let mut bindings = Table::new();
+        for (action, b) in &self.bindings {
+            bindings.insert(action.clone(), Value::Array(b.iter().map(|t| Value::String(t.clone())).collect()));
+        }
+        let mut ret = Table::new();
+        ret.insert(String::from("bindings"), Value::Table(bindings));
+        ret.insert(String::from("conflicts"), Value::Array(self.conflicts().into_iter().map(|c| {
+            let mut t = Table::new();
+            t.insert(String::from("binding"), Value::String(c.binding));
+            t.insert(String::from("actions"), Value::Array(c.actions.into_iter().map(Value::String).collect()));
+            Value::Table(t)
+        }).collect()));
+        ret
+    }
+}
+
+fn key_name(key : &str) -> Option<String> {
+    let lower = key.to_lowercase();
+    if key.chars().count() == 1 {
+        let c = key.chars().next()?;
+        if c.is_ascii_alphanumeric() {
+            return Some(c.to_ascii_uppercase().to_string());
+        }
+    }
+    if let Some(number) = lower.strip_prefix('f') {
+        if let Ok(n) = number.parse::<u8>() {
+            if (1..=24).contains(&n) {
+                return Some(format!("F{}", n));
+            }
+        }
+    }
+    if let Some(t) = NAMED_KEYS.iter().find(|k| k.to_lowercase() == lower) {
+        return Some(String::from(*t));
+    }
+    ALIASES.iter().find(|(a, _)| *a == lower).map(|(_, k)| String::from(*k))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bindings(list : &[&str]) -> Value {
+        Value::Array(list.iter().map(|b| Value::String(String::from(*b))).collect())
+    }
+
+    #[test]
+    fn bindings_are_canonical() {
+        let cases = [
+            ("ctrl+shift+t", "Ctrl+Shift+T"),
+            ("Shift+Ctrl+T", "Ctrl+Shift+T"),
+            (" meta + ALT + shift + control + x ", "Ctrl+Alt+Shift+Meta+X"),
+            ("cmd+option+1", "Alt+Meta+1"),
+            ("ctrl++", "Ctrl+Plus"),
+            ("+", "Plus"),
+            ("Ctrl+=", "Ctrl+Equal"),
+            ("ctrl+-", "Ctrl+Minus"),
+            ("alt+pgdn", "Alt+PageDown"),
+            ("ESC", "Escape"),
+            ("f12", "F12"),
+            ("shift+pageup", "Shift+PageUp"),
+        ];
+        for (text, canonical) in cases.iter() {
+            assert_eq!(normalize(text).as_deref(), Ok(*canonical), "{}", text);
+            assert_eq!(normalize(canonical).as_deref(), Ok(*canonical), "{}", canonical);
+        }
+        assert_eq!(Binding::parse("Ctrl+Shift+T").unwrap(), Binding::parse("shift+CTRL+t").unwrap());
+    }
+
+    #[test]
+    fn invalid_bindings_are_explained() {
+        assert_eq!(normalize("hyper+t"), Err(String::from("Unknown modifier hyper in hyper+t")));
+        assert_eq!(normalize("ctrl+f25"), Err(String::from("Unknown key f25 in ctrl+f25")));
+        assert_eq!(normalize("ctrl+"), Err(String::from("Unknown key  in ctrl+")));
+        assert!(normalize("ctrl+é").is_err());
+        assert!(normalize("").is_err());
+    }
+
+    #[test]
+    fn defaults_have_no_conflict() {
+        let map = Settings::new().shortcuts();
+        assert!(map.conflicts().is_empty());
+        assert_eq!(map.bindings("close_tab"), &[String::from("Ctrl+W"), String::from("Ctrl+F4")]);
+        assert!(map.bindings("unknown").is_empty());
+        assert_eq!(actions().len(), schema::section(SECTION).len());
+        assert_eq!(actions()[0], "new_tab");
+    }
+
+    #[test]
+    fn conflicts_between_actions_are_found() {
+        let mut settings = Settings::new();
+        settings.set("shortcuts.new_window", bindings(&["shift+ctrl+t", "ctrl+N"])).unwrap();
+        settings.set("shortcuts.quit", bindings(&["Ctrl+Shift+T", "ctrl+w"])).unwrap();
+        let map = settings.shortcuts();
+
+        assert_eq!(map.bindings("new_window"), &[String::from("Ctrl+Shift+T"), String::from("Ctrl+N")]);
+        assert_eq!(map.conflicts(), vec![
+            Conflict { binding: String::from("Ctrl+W"), actions: vec![String::from("close_tab"), String::from("quit")] },
+            Conflict { binding: String::from("Ctrl+Shift+T"),
+                       actions: vec![String::from("reopen_closed_tab"), String::from("new_window"), String::from("quit")] },
+        ]);
+        // The first action of the schema wins.
+        assert_eq!(map.action("shift+ctrl+T"), Some("reopen_closed_tab"));
+        assert_eq!(map.action("ctrl+q"), None);
+        assert_eq!(map.action("nothing+q"), None);
+
+        let table = map.to_table();
+        assert_eq!(table["conflicts"].as_array().unwrap().len(), 2);
+        assert_eq!(table["bindings"]["quit"], bindings(&["Ctrl+Shift+T", "Ctrl+W"]));
+    }
+}
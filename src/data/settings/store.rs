@@ -0,0 +1,541 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Live settings shared by the core
+//!
+//! The [`Store`](struct.Store.html) owns the settings of a profile. Every
+//! change goes through it, so it can tell who is interested:
+//!  * subscribers registered on a key path are called for each change,
+//!  * broadcasters, like the IPC channel of the core, receive the whole diff
+//!    once, so UI windows never have to poll the TOML file.
+//!
+//! Values and diffs are always the effective ones, resolved across the
+//! [layers](../layers/index.html).
+//!
+//! Subscribers are called while the store is borrowed, so while whoever
+//! shares it, like the dispatcher or the [watcher](../watcher/index.html),
+//! holds its lock. They must not use the store.
+
+use toml::Value;
+use toml::value::Table;
+
+use crate::data::db::Error;
+use super::{schema, Settings};
+use super::layers::{Layers, Layer};
+
+/// Change of the effective value of one key.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Change {
+    pub key : String,
+    pub old : Value,
+    pub new : Value,
+}
+
+/// Every change made by a single operation.
+#[derive(PartialEq, Debug, Default, Clone)]
+pub struct Diff {
+    pub changes : Vec<Change>,
+}
+
+impl Diff {
+    /// Diff between the values of `old` and `new`.
+    pub fn between(old : &Settings, new : &Settings) -> Self {
+        let mut changes = Vec::new();
+        for key in schema::SCHEMA {
+            let (o, n) = match (old.get(key.path), new.get(key.path)) {
+                (Ok(o), Ok(n)) => (o, n),
+                _ => continue,
+            };
+            if o != n {
+                changes.push(Change { key: String::from(key.path), old: o, new: n });
+            }
+        }
+        Self { changes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// TOML form sent to the UI:
+    ///
+    /// ```toml
+    /// [[changes]]
+    /// key = "appearance.theme"
+    /// old = "light"
+    /// new = "dark"
+    /// ```
+    pub fn to_table(&self) -> Table {
+        let mut ret = Table::new();
+        ret.insert(String::from("changes"), Value::Array(self.changes.iter().map(|c| {
+            let mut t = Table::new();
+            t.insert(String::from("key"), Value::String(c.key.clone()));
+            t.insert(String::from("old"), c.old.clone());
+            t.insert(String::from("new"), c.new.clone());
+            Value::Table(t)
+        }).collect()));
+        ret
+    }
+}
+
+/// Receiver of the diffs, like a socket of the IPC channel.
+pub trait Broadcast {
+    fn broadcast(&mut self, topic : &str, payload : &Table) -> Result<(), Error>;
+}
+
+/// Topic of the event sent to broadcasters when settings change.
+pub const TOPIC : &str = "settings.changed";
+/// Topic of the event sent to broadcasters when the settings file could not
+/// be reloaded.
+pub const ERROR_TOPIC : &str = "settings.error";
+
+impl Broadcast for zmq::Socket {
+    /// Sends a two frames message: the topic, then the payload as TOML.
+    fn broadcast(&mut self, topic : &str, payload : &Table) -> Result<(), Error> {
+        let payload = match toml::to_string(payload) {
+            Ok(t) => t,
+            Err(e) => return Err(Error::new(Some(3006), Some(format!("Can not serialize event: {}", e)))),
+        };
+        match self.send_multipart([topic.as_bytes(), payload.as_bytes()], 0) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::new(Some(3007), Some(format!("Can not broadcast {}: {}", topic, e)))),
+        }
+    }
+}
+
+/// Changes applied together: either all of them succeed or none is made.
+#[derive(PartialEq, Debug, Default, Clone)]
+pub struct Batch {
+    changes : Vec<(String, Value)>,
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, key : &str, value : Value) -> &mut Self {
+        self.changes.push((String::from(key), value));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+#[derive(Eq, PartialEq, Hash, Debug, Clone, Copy)]
+pub struct SubscriptionId(usize);
+
+struct Subscriber {
+    id : SubscriptionId,
+    pattern : String,
+    callback : Box<dyn FnMut(&Change) + Send>,
+}
+
+impl Subscriber {
+    /// `pattern` is either empty, to match everything, a section like
+    /// `appearance` or a full key like `appearance.theme`.
+    fn matches(&self, key : &str) -> bool {
+        self.pattern.is_empty() || self.pattern == "*" || key == self.pattern ||
+            (key.starts_with(&*self.pattern) && key[self.pattern.len()..].starts_with('.'))
+    }
+}
+
+pub struct Store {
+    settings : Settings,
+    layers : Layers,
+    effective : Settings,
+    subscribers : Vec<Subscriber>,
+    broadcasters : Vec<Box<dyn Broadcast + Send>>,
+    next_id : usize,
+    last_error : Option<Error>,
+}
+
+impl Store {
+    pub fn new(settings : Settings) -> Self {
+        Self::with_layers(settings, Layers::new())
+    }
+
+    /// Store for the profile `settings`, resolved with the policy and
+    /// overrides of `layers`.
+    pub fn with_layers(settings : Settings, layers : Layers) -> Self {
+        let effective = layers.flatten(&settings);
+        Self {
+            settings,
+            layers,
+            effective,
+            subscribers: Vec::new(),
+            broadcasters: Vec::new(),
+            next_id: 0,
+            last_error: None,
+        }
+    }
+
+    /// Settings of the profile layer only.
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Effective settings, to build typed sections from.
+    pub fn effective(&self) -> &Settings {
+        &self.effective
+    }
+
+    pub fn layers(&self) -> &Layers {
+        &self.layers
+    }
+
+    /// Replaces the policy and overrides, and notifies the values that
+    /// changed because of it.
+    pub fn set_layers(&mut self, layers : Layers) -> Diff {
+        self.layers = layers;
+        let next = self.settings.clone();
+        self.replace(next)
+    }
+
+    pub fn get(&self, key : &str) -> Result<Value, Error> {
+        self.effective.get(key)
+    }
+
+    /// Layer the effective value of `key` comes from.
+    pub fn source(&self, key : &str) -> Result<Layer, Error> {
+        match self.layers.resolve(&self.settings, key) {
+            Ok((_, layer)) => Ok(layer),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Calls `callback` for every change of a key matching `pattern`: a
+    /// full key path, a section name, or an empty string for every key.
+    ///
+    /// `callback` runs synchronously, from the method which changed the
+    /// settings. It must not lock a `Mutex` holding this store, which is
+    /// already locked and would never be released: a callback needing the
+    /// settings reads them from the change, or hands the change to another
+    /// thread.
+    pub fn subscribe<F>(&mut self, pattern : &str, callback : F) -> SubscriptionId
+        where F : FnMut(&Change) + Send + 'static {
+        let id = SubscriptionId(self.next_id);
+        self.next_id += 1;
+        self.subscribers.push(Subscriber {
+            id,
+            pattern: String::from(pattern),
+            callback: Box::new(callback),
+        });
+        id
+    }
+
+    pub fn unsubscribe(&mut self, id : SubscriptionId) {
+        self.subscribers.retain(|s| s.id != id);
+    }
+
+    /// Adds a receiver of every diff, under the topic
+    /// [`TOPIC`](constant.TOPIC.html).
+    pub fn add_broadcaster(&mut self, broadcaster : Box<dyn Broadcast + Send>) {
+        self.broadcasters.push(broadcaster);
+    }
+
+    /// Changes a single key. See [`commit`](#method.commit).
+    pub fn set(&mut self, key : &str, value : Value) -> Result<Diff, Error> {
+        let mut batch = Batch::new();
+        batch.set(key, value);
+        self.commit(&batch)
+    }
+
+    /// Applies every change of `batch`, saves the settings if they are bound
+    /// to a file, and notifies a single diff.
+    ///
+    /// Nothing is changed if one of the values is refused, or if one of the
+    /// keys is locked by the policy.
+    pub fn commit(&mut self, batch : &Batch) -> Result<Diff, Error> {
+        match self.apply(batch) {
+            Ok(t) => self.save_and_replace(t),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Diff `batch` would give if it was committed, without changing
+    /// anything.
+    pub fn preview(&self, batch : &Batch) -> Result<Diff, Error> {
+        match self.apply(batch) {
+            Ok(t) => Ok(Diff::between(&self.effective, &self.layers.flatten(&t))),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Puts back the default value of `scope`: a full key, a section name,
+    /// or an empty string for every key. Keys locked by the policy keep
+    /// their value anyway.
+    pub fn reset(&mut self, scope : &str) -> Result<Diff, Error> {
+        let mut next = self.settings.clone();
+        match next.reset(scope) {
+            Ok(_) => self.save_and_replace(next),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reads the settings file again and applies what changed.
+    ///
+    /// The new file must be entirely valid: on any error the current
+    /// settings are kept, the error is broadcast under
+    /// [`ERROR_TOPIC`](constant.ERROR_TOPIC.html) and can be read back with
+    /// [`last_error`](#method.last_error) until a valid file is loaded.
+    pub fn reload(&mut self) -> Result<Diff, Error> {
+        let path = match self.settings.path() {
+            Some(t) => t.to_path_buf(),
+            None => return Err(Error::new(Some(3003), Some(String::from("Settings are not bound to a file")))),
+        };
+
+        let next = match Settings::read(&path) {
+            Ok(t) => {
+                if t.issues().is_empty() {
+                    Ok(t)
+                } else {
+                    Err(Error::new(Some(3009), Some(t.issues().iter()
+                        .map(|i| i.message.clone())
+                        .collect::<Vec<String>>()
+                        .join("; "))))
+                }
+            },
+            Err(e) => Err(e),
+        };
+
+        match next {
+            Ok(t) => {
+                self.last_error = None;
+                Ok(self.replace(t))
+            },
+            Err(e) => {
+                let mut payload = Table::new();
+                if let Some(code) = e.code() {
+                    payload.insert(String::from("code"), Value::Integer(code as i64));
+                }
+                payload.insert(String::from("message"), Value::String(String::from(e.message().unwrap_or(""))));
+                payload.insert(String::from("path"), Value::String(path.display().to_string()));
+                for broadcaster in self.broadcasters.iter_mut() {
+                    if let Err(e) = broadcaster.broadcast(ERROR_TOPIC, &payload) {
+                        println!("Warning: {:?}", e);
+                    }
+                }
+
+                self.last_error = Some(e.clone());
+                Err(e)
+            },
+        }
+    }
+
+    /// Error of the last failed reload, if the file is still invalid.
+    pub fn last_error(&self) -> Option<&Error> {
+        match &self.last_error {
+            Some(t) => Some(t),
+            None => None,
+        }
+    }
+
+    /// Replaces the profile settings by `next` and notifies what changed.
+    pub fn replace(&mut self, next : Settings) -> Diff {
+        let effective = self.layers.flatten(&next);
+        let diff = Diff::between(&self.effective, &effective);
+        self.settings = next;
+        self.effective = effective;
+        self.notify(&diff);
+        diff
+    }
+
+    /// Profile settings with every change of `batch`.
+    fn apply(&self, batch : &Batch) -> Result<Settings, Error> {
+        let mut next = self.settings.clone();
+        for (key, value) in &batch.changes {
+            if self.layers.is_locked(key) {
+                return Err(Error::new(Some(3008), Some(format!("{} is locked by the system policy", key))));
+            }
+            next.set(key, value.clone())?
+        }
+        Ok(next)
+    }
+
+    fn save_and_replace(&mut self, next : Settings) -> Result<Diff, Error> {
+        if next.path().is_some() {
+            next.save()?
+        }
+        Ok(self.replace(next))
+    }
+
+    fn notify(&mut self, diff : &Diff) {
+        if diff.is_empty() {
+            return;
+        }
+
+        for change in &diff.changes {
+            for subscriber in self.subscribers.iter_mut() {
+                if subscriber.matches(&change.key) {
+                    (subscriber.callback)(change);
+                }
+            }
+        }
+
+        let payload = diff.to_table();
+        for broadcaster in self.broadcasters.iter_mut() {
+            if let Err(e) = broadcaster.broadcast(TOPIC, &payload) {
+                println!("Warning: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// Broadcaster keeping what it is sent.
+    #[derive(Default, Clone)]
+    struct Events(Arc<Mutex<Vec<(String, Table)>>>);
+
+    impl Broadcast for Events {
+        fn broadcast(&mut self, topic : &str, payload : &Table) -> Result<(), Error> {
+            self.0.lock().unwrap().push((String::from(topic), payload.clone()));
+            Ok(())
+        }
+    }
+
+    impl Events {
+        fn take(&self) -> Vec<(String, Table)> {
+            self.0.lock().unwrap().drain(..).collect()
+        }
+    }
+
+    fn string(t : &str) -> Value {
+        Value::String(String::from(t))
+    }
+
+    /// Store with a broadcaster and a subscriber on every key.
+    fn store(settings : Settings) -> (Store, Events, Arc<Mutex<Vec<String>>>) {
+        let mut store = Store::new(settings);
+        let events = Events::default();
+        store.add_broadcaster(Box::new(events.clone()));
+        let keys = Arc::new(Mutex::new(Vec::new()));
+        let seen = keys.clone();
+        store.subscribe("", move |c| seen.lock().unwrap().push(c.key.clone()));
+        (store, events, keys)
+    }
+
+    #[test]
+    fn subscribers_follow_their_pattern() {
+        let mut store = Store::new(Settings::new());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut ids = Vec::new();
+        for pattern in &["*", "appearance", "appearance.theme", "appear", "network"] {
+            let seen = seen.clone();
+            let name = String::from(*pattern);
+            ids.push(store.subscribe(pattern, move |c| {
+                seen.lock().unwrap().push(format!("{} {}", name, c.key));
+            }));
+        }
+
+        store.set("appearance.theme", string("dark")).unwrap();
+        assert_eq!(seen.lock().unwrap().drain(..).collect::<Vec<String>>(),
+                   vec!["* appearance.theme", "appearance appearance.theme", "appearance.theme appearance.theme"]);
+
+        store.unsubscribe(ids[1]);
+        store.set("appearance.font_size", Value::Integer(20)).unwrap();
+        assert_eq!(seen.lock().unwrap().drain(..).collect::<Vec<String>>(), vec!["* appearance.font_size"]);
+
+        // Nothing is notified when the value does not change.
+        store.set("appearance.font_size", Value::Integer(20)).unwrap();
+        assert!(seen.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn batches_are_atomic() {
+        let (mut store, events, keys) = store(Settings::new());
+
+        let mut batch = Batch::new();
+        batch.set("appearance.theme", string("dark")).set("appearance.font_size", Value::Integer(1000));
+        assert_eq!(store.commit(&batch).unwrap_err().code(), Some(3002));
+        assert_eq!(store.get("appearance.theme").unwrap(), string("system"));
+        assert!(events.take().is_empty());
+        assert!(keys.lock().unwrap().is_empty());
+
+        let mut batch = Batch::new();
+        batch.set("appearance.theme", string("dark")).set("appearance.font_size", Value::Integer(20));
+        let diff = store.commit(&batch).unwrap();
+        assert_eq!(diff.changes, vec![
+            Change { key: String::from("appearance.theme"), old: string("system"), new: string("dark") },
+            Change { key: String::from("appearance.font_size"), old: Value::Integer(16), new: Value::Integer(20) },
+        ]);
+        assert_eq!(*keys.lock().unwrap(), vec!["appearance.theme", "appearance.font_size"]);
+        assert_eq!(events.take(), vec![(String::from(TOPIC), diff.to_table())]);
+    }
+
+    #[test]
+    fn diffs_are_broadcast_as_tables() {
+        let (mut store, events, _) = store(Settings::new());
+        store.set("general.language", string("fr")).unwrap();
+
+        let expected = "[[changes]]\nkey = \"general.language\"\nold = \"en\"\nnew = \"fr\"\n";
+        let events = events.take();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].0, TOPIC);
+        assert_eq!(events[0].1, expected.parse::<Value>().unwrap().as_table().unwrap().clone());
+    }
+
+    #[test]
+    fn preview_changes_nothing() {
+        let (store, events, keys) = store(Settings::new());
+        let mut batch = Batch::new();
+        batch.set("privacy.do_not_track", Value::Boolean(false)).set("general.language", string("en"));
+
+        let diff = store.preview(&batch).unwrap();
+        assert_eq!(diff.changes, vec![Change {
+            key: String::from("privacy.do_not_track"), old: Value::Boolean(true), new: Value::Boolean(false),
+        }]);
+        assert_eq!(store.get("privacy.do_not_track").unwrap(), Value::Boolean(true));
+        assert!(events.take().is_empty());
+        assert!(keys.lock().unwrap().is_empty());
+
+        let mut batch = Batch::new();
+        batch.set("privacy.do_not_track", Value::Integer(0));
+        assert!(store.preview(&batch).is_err());
+    }
+
+    #[test]
+    fn reload_applies_the_file_diff() {
+        let dir = std::env::temp_dir().join(format!("sielo-store-reload-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("settings.toml");
+        fs::write(&path, "settings_version = 1\n[general]\nlanguage = \"fr\"\n").unwrap();
+        let (mut store, events, keys) = store(Settings::load(&path).unwrap());
+
+        fs::write(&path, "settings_version = 1\n[general]\nlanguage = \"de\"\n[network]\ntimeout = 60\n").unwrap();
+        let diff = store.reload().unwrap();
+        assert_eq!(diff.changes, vec![
+            Change { key: String::from("general.language"), old: string("fr"), new: string("de") },
+            Change { key: String::from("network.timeout"), old: Value::Integer(30), new: Value::Integer(60) },
+        ]);
+        assert_eq!(keys.lock().unwrap().drain(..).collect::<Vec<String>>(), vec!["general.language", "network.timeout"]);
+        assert_eq!(events.take(), vec![(String::from(TOPIC), diff.to_table())]);
+
+        // An invalid file keeps the current values.
+        fs::write(&path, "settings_version = 1\n[network]\ntimeout = 0\n").unwrap();
+        assert_eq!(store.reload().unwrap_err().code(), Some(3009));
+        assert_eq!(store.get("network.timeout").unwrap(), Value::Integer(60));
+        assert_eq!(store.last_error().and_then(|e| e.code()), Some(3009));
+        assert!(keys.lock().unwrap().is_empty());
+        let events = events.take();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].0, ERROR_TOPIC);
+        assert_eq!(events[0].1.get("code"), Some(&Value::Integer(3009)));
+
+        fs::write(&path, "settings_version = 1\n").unwrap();
+        assert_eq!(store.reload().unwrap().changes.len(), 2);
+        assert!(store.last_error().is_none());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
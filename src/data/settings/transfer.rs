@@ -0,0 +1,322 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Export and import of settings
+//!
+//! An export is a single TOML file with the effective value of every known
+//! key, and optionally the per-site settings as a `[[sites]]` array:
+//!
+//! ```toml
+//! settings_version = 1
+//!
+//! [appearance]
+//! theme = "dark"
+//!
+//! [[sites]]
+//! pattern = "*.example.com"
+//! permission = "javascript"
+//! value = "block"
+//! ```
+//!
+//! Importing is done in two steps: [`preview`](struct.Import.html#method.preview)
+//! tells what would change, [`apply`](struct.Import.html#method.apply) does
+//! it. Values already chosen in the profile are handled following a
+//! [`Conflict`](enum.Conflict.html) policy.
+
+use std::fs;
+use std::path::Path;
+
+use toml::Value;
+use toml::value::Table;
+
+use crate::data::db::{Error, TableProvider};
+use crate::data::site_settings::{self, SiteSettings, Exception, Pattern, Permission, Decision};
+use crate::data::time::Timestamp;
+use super::{schema, migration, lookup, insert, write_atomically, Issue, Settings};
+use super::store::{Store, Batch, Diff};
+
+/// Name of the array holding the per-site settings in an export.
+pub const SITES_KEY : &str = "sites";
+
+/// What to do with a key the profile already sets to another value.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum Conflict {
+    /// The imported value replaces the current one.
+    Overwrite,
+    /// The current value is kept, only keys the profile leaves to their
+    /// default are imported.
+    KeepExisting,
+}
+
+/// Effective settings as an export document, with the per-site settings if
+/// `sites` is given.
+pub fn export(effective : &Settings, sites : Option<&[Exception]>) -> Result<String, Error> {
+    let mut document = Table::new();
+    document.insert(String::from(migration::VERSION_KEY), Value::Integer(migration::CURRENT_VERSION));
+    for key in schema::SCHEMA {
+        let value = effective.get(key.path)?;
+        insert(&mut document, key.path, value)?
+    }
+
+    if let Some(sites) = sites {
+        document.insert(String::from(SITES_KEY), Value::Array(sites.iter().map(|e| {
+            let mut t = Table::new();
+            t.insert(String::from("pattern"), Value::String(e.pattern.to_string()));
+            t.insert(String::from("permission"), Value::String(String::from(e.permission.name())));
+            t.insert(String::from("value"), match e.value {
+                site_settings::Value::Zoom(z) => Value::Float(z),
+                site_settings::Value::Decision(d) => Value::String(String::from(d.name())),
+            });
+            if let Some(expires) = e.expires {
+                t.insert(String::from("expires"), Value::Integer(expires.as_millis()));
+            }
+            Value::Table(t)
+        }).collect()));
+    }
+
+    match toml::to_string(&Value::Table(document)) {
+        Ok(t) => Ok(t),
+        Err(e) => Err(Error::new(Some(3006), Some(format!("Can not serialize settings: {}", e)))),
+    }
+}
+
+/// Writes an [export](fn.export.html) to `path`.
+pub fn export_to<P : AsRef<Path>>(path : P, effective : &Settings, sites : Option<&[Exception]>) -> Result<(), Error> {
+    match export(effective, sites) {
+        Ok(t) => write_atomically(path.as_ref(), t.as_bytes()),
+        Err(e) => Err(e),
+    }
+}
+
+/// What an import changes, or would change.
+#[derive(PartialEq, Debug, Default, Clone)]
+pub struct Preview {
+    /// Changes of the effective settings.
+    pub diff : Diff,
+    /// Per-site settings written.
+    pub sites : Vec<Exception>,
+    /// Values left out, with the reason.
+    pub skipped : Vec<Issue>,
+}
+
+/// Content of an export file, ready to be imported.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Import {
+    settings : Settings,
+    sites : Vec<Exception>,
+    issues : Vec<Issue>,
+}
+
+impl Import {
+    /// Reads the export document `content`. It is migrated like a settings
+    /// file, so exports of older versions can be imported.
+    pub fn parse(content : &str) -> Result<Self, Error> {
+        let mut document = match content.parse::<Value>() {
+            Ok(Value::Table(t)) => t,
+            Ok(_) => return Err(Error::new(Some(3006), Some(String::from("Settings document must be a table")))),
+            Err(e) => return Err(Error::new(Some(3006), Some(format!("Invalid settings document: {}", e)))),
+        };
+
+        let mut issues = Vec::new();
+        let mut sites = Vec::new();
+        if let Some(Value::Array(t)) = document.remove(SITES_KEY) {
+            for (i, site) in t.iter().enumerate() {
+                match parse_site(site) {
+                    Some(e) => sites.push(e),
+                    None => issues.push(Issue {
+                        key: format!("{}.{}", SITES_KEY, i),
+                        message: String::from("Invalid site setting"),
+                    }),
+                }
+            }
+        }
+
+        let settings = Settings::from_document(document)?;
+        issues.extend(settings.issues().iter().cloned());
+
+        Ok(Self { settings, sites, issues })
+    }
+
+    pub fn read<P : AsRef<Path>>(path : P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        match fs::read_to_string(path) {
+            Ok(t) => Self::parse(&t),
+            Err(e) => Err(Error::new(Some(3005), Some(format!("Can not read {}: {}", path.display(), e)))),
+        }
+    }
+
+    /// Per-site settings found in the file.
+    pub fn sites(&self) -> &[Exception] {
+        &self.sites
+    }
+
+    /// Tells what [`apply`](#method.apply) would do with the settings of
+    /// `store` and the per-site settings `existing`, without changing
+    /// anything.
+    pub fn preview(&self, store : &Store, existing : &[Exception], conflict : Conflict) -> Result<Preview, Error> {
+        let (batch, sites, skipped) = self.plan(store, existing, conflict);
+        match store.preview(&batch) {
+            Ok(diff) => Ok(Preview { diff, sites, skipped }),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Imports the settings into `store`, and the per-site settings into
+    /// `sites` if given.
+    ///
+    /// Both are imported or neither is: the per-site settings are written in
+    /// a transaction which is rolled back if the settings can not be
+    /// committed.
+    pub fn apply<T : TableProvider>(&self,
+                                    store : &mut Store,
+                                    mut sites : Option<&mut SiteSettings<T>>,
+                                    conflict : Conflict) -> Result<Preview, Error> {
+        let existing = match sites.as_mut() {
+            Some(s) if !self.sites.is_empty() => s.exceptions(None, Timestamp::now())?,
+            _ => Vec::new(),
+        };
+
+        let (batch, to_write, skipped) = self.plan(store, &existing, conflict);
+        store.preview(&batch)?;
+        match sites {
+            Some(s) => s.db().transaction(|db| {
+                let mut s = match SiteSettings::new(db) {
+                    Ok(t) => t,
+                    Err(e) => return Err(e),
+                };
+                for e in &to_write {
+                    s.set(&e.pattern.to_string(), e.permission, e.value, e.expires)?
+                }
+                // Last, nothing can fail in the transaction once the settings changed.
+                match store.commit(&batch) {
+                    Ok(diff) => Ok(Preview { diff, sites: to_write, skipped }),
+                    Err(e) => Err(e),
+                }
+            }),
+            None => match store.commit(&batch) {
+                Ok(diff) => Ok(Preview { diff, sites: Vec::new(), skipped }),
+                Err(e) => Err(e),
+            },
+        }
+    }
+
+    fn plan(&self, store : &Store, existing : &[Exception], conflict : Conflict) -> (Batch, Vec<Exception>, Vec<Issue>) {
+        let mut skipped = self.issues.clone();
+        let mut batch = Batch::new();
+
+        for key in schema::SCHEMA {
+            if lookup(self.settings.document(), key.path).is_none() ||
+                    skipped.iter().any(|i| i.key == key.path) {
+                continue;
+            }
+            let value = match self.settings.get(key.path) {
+                Ok(t) => t,
+                Err(_) => continue,
+            };
+            let current = store.settings().get(key.path).ok();
+            if current.as_ref() == Some(&value) {
+                continue;
+            }
+
+            if store.layers().is_locked(key.path) {
+                skipped.push(Issue {
+                    key: String::from(key.path),
+                    message: String::from("Locked by the system policy"),
+                });
+            } else if conflict == Conflict::KeepExisting &&
+                    lookup(store.settings().document(), key.path).is_some() {
+                skipped.push(Issue {
+                    key: String::from(key.path),
+                    message: String::from("Kept the current value"),
+                });
+            } else {
+                batch.set(key.path, value);
+            }
+        }
+
+        let now = Timestamp::now();
+        let mut sites = Vec::new();
+        for site in &self.sites {
+            if site.expires.map(|t| t <= now).unwrap_or(false) {
+                continue;
+            }
+            match existing.iter().find(|e| e.pattern == site.pattern && e.permission == site.permission) {
+                Some(e) if e.value == site.value => continue,
+                Some(_) if conflict == Conflict::KeepExisting => {
+                    skipped.push(Issue {
+                        key: format!("{}.{}", SITES_KEY, site.pattern),
+                        message: format!("Kept the current {} setting", site.permission.name()),
+                    });
+                    continue;
+                },
+                _ => sites.push(site.clone()),
+            }
+        }
+
+        (batch, sites, skipped)
+    }
+}
+
+fn parse_site(site : &Value) -> Option<Exception> {
+    let pattern = Pattern::parse(site.get("pattern")?.as_str()?).ok()?;
+    let permission = Permission::from_name(site.get("permission")?.as_str()?)?;
+    let value = match (permission, site.get("value")?) {
+        (Permission::Zoom, Value::Float(z)) => site_settings::Value::Zoom(*z),
+        (Permission::Zoom, Value::Integer(z)) => site_settings::Value::Zoom(*z as f64),
+        (Permission::Zoom, _) => return None,
+        (_, Value::String(d)) => site_settings::Value::Decision(Decision::from_name(d)?),
+        _ => return None,
+    };
+    let expires = match site.get("expires") {
+        Some(Value::Integer(t)) => Some(Timestamp::from_millis(*t)),
+        Some(_) => return None,
+        None => None,
+    };
+
+    Some(Exception { pattern, permission, value, expires, modified: Timestamp::now() })
+}
+
+#[cfg(test)]
+mod tests {
+    use toml::Value;
+
+    use crate::data::db::sqlite::SQLite;
+    use crate::data::site_settings::SiteSettings;
+    use crate::data::time::Timestamp;
+    use crate::data::settings::Settings;
+    use crate::data::settings::store::Store;
+    use super::{Import, Conflict};
+
+    const EXPORT : &str = "settings_version = 1\n\n[appearance]\ntheme = \"dark\"\n\n\
+                           [[sites]]\npattern = \"example.com\"\npermission = \"javascript\"\nvalue = \"block\"\n";
+
+    #[test]
+    fn settings_and_sites_are_imported() {
+        let mut db = SQLite::new(":memory:").unwrap();
+        let mut sites = SiteSettings::new(&mut db).unwrap();
+        let mut store = Store::new(Settings::new());
+
+        let preview = Import::parse(EXPORT).unwrap().apply(&mut store, Some(&mut sites), Conflict::Overwrite).unwrap();
+        assert_eq!(preview.diff.changes.len(), 1);
+        assert_eq!(preview.sites.len(), 1);
+        assert_eq!(store.get("appearance.theme").unwrap(), Value::String(String::from("dark")));
+        assert_eq!(sites.exceptions(None, Timestamp::now()).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn sites_are_rolled_back_when_settings_fail() {
+        let mut db = SQLite::new(":memory:").unwrap();
+        let mut sites = SiteSettings::new(&mut db).unwrap();
+        // The directory is a file, so saving the settings fails.
+        let file = std::env::temp_dir().join(format!("sielo-transfer-file-{}", std::process::id()));
+        std::fs::write(&file, "").unwrap();
+        let mut store = Store::new(Settings::read(file.join("settings.toml")).unwrap());
+
+        assert!(Import::parse(EXPORT).unwrap().apply(&mut store, Some(&mut sites), Conflict::Overwrite).is_err());
+        assert_eq!(store.get("appearance.theme").unwrap(), Value::String(String::from("system")));
+        assert!(sites.exceptions(None, Timestamp::now()).unwrap().is_empty());
+        let _ = std::fs::remove_file(&file);
+    }
+}
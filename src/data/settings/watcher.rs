@@ -0,0 +1,164 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Hot reload of the settings file
+//!
+//! A [`Watcher`](struct.Watcher.html) polls the profile settings file and
+//! reloads the [`Store`](../store/struct.Store.html) when it changes, so
+//! hand edits apply without restarting. Polling is used instead of native
+//! file notifications to stay portable; the file is tiny and checking its
+//! metadata is cheap.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use super::Store;
+
+/// Default delay between two checks of the file.
+pub const DEFAULT_INTERVAL : Duration = Duration::from_millis(500);
+
+pub struct Watcher {
+    running : Arc<AtomicBool>,
+    thread : Option<thread::JoinHandle<()>>,
+}
+
+/// What is compared to detect a change of the file.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+struct Fingerprint {
+    modified : Option<SystemTime>,
+    len : u64,
+}
+
+fn fingerprint(path : &Path) -> Option<Fingerprint> {
+    match fs::metadata(path) {
+        Ok(t) => Some(Fingerprint { modified: t.modified().ok(), len: t.len() }),
+        Err(_) => None,
+    }
+}
+
+impl Watcher {
+    /// Starts watching the file `store` is bound to. Does nothing if the
+    /// settings are not bound to a file.
+    pub fn start(store : Arc<Mutex<Store>>, interval : Duration) -> Self {
+        let path : Option<PathBuf> = match store.lock() {
+            Ok(t) => t.settings().path().map(|p| p.to_path_buf()),
+            Err(_) => None,
+        };
+        let running = Arc::new(AtomicBool::new(path.is_some()));
+        let path = match path {
+            Some(t) => t,
+            None => return Self { running, thread: None },
+        };
+
+        // Taken before returning, so edits made right after are not missed.
+        let mut last = fingerprint(&path);
+        let flag = running.clone();
+        let thread = thread::spawn(move || {
+
+            while flag.load(Ordering::SeqCst) {
+                thread::sleep(interval);
+
+                let current = fingerprint(&path);
+                // A missing file is usually an editor replacing it, wait for
+                // the new one.
+                if current.is_none() || current == last {
+                    continue;
+                }
+                last = current;
+
+                let mut store = match store.lock() {
+                    Ok(t) => t,
+                    Err(_) => break,
+                };
+                if let Err(e) = store.reload() {
+                    println!("Warning: settings not reloaded: {:?}", e);
+                }
+            }
+        });
+
+        Self { running, thread: Some(thread) }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// Stops watching and waits for the watching thread to end.
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use toml::Value;
+
+    use super::*;
+    use crate::data::settings::Settings;
+
+    const INTERVAL : Duration = Duration::from_millis(10);
+
+    /// Waits until `condition` holds for the store, at most a few seconds.
+    fn wait_for<F : Fn(&Store) -> bool>(store : &Arc<Mutex<Store>>, condition : F) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if condition(&store.lock().unwrap()) {
+                return true;
+            }
+            thread::sleep(INTERVAL);
+        }
+        false
+    }
+
+    #[test]
+    fn invalid_edits_keep_the_last_good_settings() {
+        let dir = std::env::temp_dir().join(format!("sielo-watcher-edits-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("settings.toml");
+        fs::write(&path, "settings_version = 1\n[appearance]\ntheme = \"light\"\n").unwrap();
+
+        let store = Arc::new(Mutex::new(Store::new(Settings::load(&path).unwrap())));
+        let mut watcher = Watcher::start(store.clone(), INTERVAL);
+        assert!(watcher.is_running());
+
+        fs::write(&path, "settings_version = 1\n[appearance]\ntheme = \"blue\"\n").unwrap();
+        assert!(wait_for(&store, |s| s.last_error().is_some()));
+        assert_eq!(store.lock().unwrap().get("appearance.theme").unwrap(), Value::String(String::from("light")));
+
+        fs::write(&path, "settings_version = 1\n[appearance\n").unwrap();
+        assert!(wait_for(&store, |s| s.last_error().and_then(|e| e.code()) == Some(3006)));
+        assert_eq!(store.lock().unwrap().get("appearance.theme").unwrap(), Value::String(String::from("light")));
+
+        fs::write(&path, "settings_version = 1\n[appearance]\ntheme = \"dark\"\n").unwrap();
+        assert!(wait_for(&store, |s| s.get("appearance.theme").unwrap() == Value::String(String::from("dark"))));
+        assert!(store.lock().unwrap().last_error().is_none());
+
+        watcher.stop();
+        assert!(!watcher.is_running());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn unbound_settings_are_not_watched() {
+        let store = Arc::new(Mutex::new(Store::new(Settings::new())));
+        assert!(!Watcher::start(store, INTERVAL).is_running());
+    }
+}
@@ -0,0 +1,643 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Per-site settings
+//!
+//! Exceptions to the global settings, stored in the `site_settings` table.
+//! Each row applies a value for one [`Permission`](enum.Permission.html) to
+//! the origins matching a [`Pattern`](struct.Pattern.html):
+//!  * `https://example.com` only matches this exact origin,
+//!  * `example.com` matches it with any scheme and port,
+//!  * `*.example.com` also matches every subdomain,
+//!  * `*` matches every site.
+//!
+//! When several patterns match an origin, the most specific one wins. Rows
+//! can expire, for permissions only granted for a while.
+//!
+//! The host of each pattern is also stored in its own indexed column, so
+//! the exceptions of a site are found without reading the whole table.
+
+use super::db::{Error, TableProvider, FieldType, FieldParameter, FieldValue};
+use super::time::Timestamp;
+
+/// Name of the table used to store the site settings.
+pub const TABLE : &str = "site_settings";
+/// Index on the host of the patterns.
+pub const HOST_INDEX : &str = "site_settings_host";
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Hash)]
+pub enum Permission {
+    Zoom,
+    JavaScript,
+    Autoplay,
+    Popups,
+    Notifications,
+    Camera,
+    Microphone,
+    Geolocation,
+}
+
+impl Permission {
+    pub const ALL : [Permission; 8] = [
+        Permission::Zoom,
+        Permission::JavaScript,
+        Permission::Autoplay,
+        Permission::Popups,
+        Permission::Notifications,
+        Permission::Camera,
+        Permission::Microphone,
+        Permission::Geolocation,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Permission::Zoom => "zoom",
+            Permission::JavaScript => "javascript",
+            Permission::Autoplay => "autoplay",
+            Permission::Popups => "popups",
+            Permission::Notifications => "notifications",
+            Permission::Camera => "camera",
+            Permission::Microphone => "microphone",
+            Permission::Geolocation => "geolocation",
+        }
+    }
+
+    pub fn from_name(name : &str) -> Option<Permission> {
+        Permission::ALL.iter().find(|p| p.name() == name).copied()
+    }
+
+    /// Decision taken for a site without exception. `None` for the zoom,
+    /// which follows `appearance.default_zoom`.
+    pub fn default_decision(&self) -> Option<Decision> {
+        match self {
+            Permission::Zoom => None,
+            Permission::JavaScript | Permission::Autoplay => Some(Decision::Allow),
+            Permission::Popups => Some(Decision::Block),
+            Permission::Notifications | Permission::Camera |
+            Permission::Microphone | Permission::Geolocation => Some(Decision::Ask),
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Hash)]
+pub enum Decision {
+    Allow,
+    Block,
+    /// Let the user choose each time.
+    Ask,
+}
+
+impl Decision {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Decision::Allow => "allow",
+            Decision::Block => "block",
+            Decision::Ask => "ask",
+        }
+    }
+
+    pub fn from_name(name : &str) -> Option<Decision> {
+        [Decision::Allow, Decision::Block, Decision::Ask].iter().find(|d| d.name() == name).copied()
+    }
+}
+
+/// Value of a site setting: a zoom factor for `Permission::Zoom`, a
+/// decision for everything else.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Value {
+    Zoom(f64),
+    Decision(Decision),
+}
+
+impl Value {
+    fn to_text(self) -> String {
+        match self {
+            Value::Zoom(t) => t.to_string(),
+            Value::Decision(t) => String::from(t.name()),
+        }
+    }
+
+    fn from_text(permission : Permission, text : &str) -> Option<Value> {
+        match permission {
+            Permission::Zoom => text.parse::<f64>().ok().map(Value::Zoom),
+            _ => Decision::from_name(text).map(Value::Decision),
+        }
+    }
+}
+
+/// Origins an exception applies to.
+#[derive(Eq, PartialEq, Debug, Clone, Hash)]
+pub struct Pattern {
+    /// `None` matches every scheme.
+    pub scheme : Option<String>,
+    /// Host name, `*.` followed by a domain, or `*` alone.
+    pub host : String,
+    /// `None` matches every port.
+    pub port : Option<u16>,
+}
+
+impl Pattern {
+    /// Parses a pattern such as `https://*.example.com:8080`.
+    pub fn parse(pattern : &str) -> Result<Self, Error> {
+        let invalid = || Error::new(Some(2301), Some(format!("Invalid site pattern {}", pattern)));
+        let pattern = pattern.trim();
+
+        let (scheme, rest) = match pattern.find("://") {
+            Some(i) => (Some(pattern[..i].to_lowercase()), &pattern[i + 3..]),
+            None => (None, pattern),
+        };
+        let rest = rest.trim_end_matches('/');
+        if rest.contains('/') {
+            return Err(invalid());
+        }
+
+        let (host, port) = match rest.rfind(':') {
+            Some(i) if !rest.ends_with(']') => match rest[i + 1..].parse::<u16>() {
+                Ok(p) => (&rest[..i], Some(p)),
+                Err(_) => return Err(invalid()),
+            },
+            _ => (rest, None),
+        };
+        if host == "*" {
+            return Ok(Self { scheme, host: String::from("*"), port });
+        }
+
+        // Let the url crate normalize the host name.
+        let (prefix, domain) = match host.starts_with("*.") {
+            true => ("*.", &host[2..]),
+            false => ("", host),
+        };
+        let host = match url::Host::parse(domain) {
+            Ok(t) => format!("{}{}", prefix, t),
+            Err(_) => return Err(invalid()),
+        };
+
+        Ok(Self { scheme, host, port })
+    }
+
+    /// Checks if the origin of `url` is matched by this pattern.
+    pub fn matches(&self, url : &url::Url) -> bool {
+        let host = match url.host_str() {
+            Some(t) => t,
+            None => return false,
+        };
+        if let Some(s) = &self.scheme {
+            if s != url.scheme() {
+                return false;
+            }
+        }
+        if let Some(p) = self.port {
+            if Some(p) != url.port_or_known_default() {
+                return false;
+            }
+        }
+
+        if self.host == "*" {
+            true
+        } else if self.host.starts_with("*.") {
+            let domain = &self.host[2..];
+            host == domain || host.ends_with(&*format!(".{}", domain))
+        } else {
+            host == self.host
+        }
+    }
+
+    /// Hosts of the patterns which can match `url`, whatever their scheme
+    /// and port: `*`, its host and `*.` followed by the host or one of its
+    /// parent domains.
+    fn candidate_hosts(url : &url::Url) -> Vec<String> {
+        let mut ret = vec![String::from("*")];
+        match url.host() {
+            Some(url::Host::Domain(domain)) => {
+                ret.push(String::from(domain));
+                let mut parent = domain;
+                loop {
+                    ret.push(format!("*.{}", parent));
+                    match parent.find('.') {
+                        Some(i) => parent = &parent[i + 1..],
+                        None => break,
+                    }
+                }
+            },
+            Some(host) => ret.push(host.to_string()),
+            None => (),
+        }
+        ret
+    }
+
+    /// Orders patterns from the most generic to the most specific.
+    fn specificity(&self) -> (u8, usize, bool, bool) {
+        let host = match self.host.as_str() {
+            "*" => 0,
+            t if t.starts_with("*.") => 1,
+            _ => 2,
+        };
+        (host, self.host.len(), self.scheme.is_some(), self.port.is_some())
+    }
+}
+
+impl std::fmt::Display for Pattern {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(s) = &self.scheme {
+            write!(f, "{}://", s)?;
+        }
+        write!(f, "{}", self.host)?;
+        if let Some(p) = self.port {
+            write!(f, ":{}", p)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Exception {
+    pub pattern : Pattern,
+    pub permission : Permission,
+    pub value : Value,
+    /// `None` if the exception never expires.
+    pub expires : Option<Timestamp>,
+    pub modified : Timestamp,
+}
+
+pub struct SiteSettings<'a, T : TableProvider> {
+    db : &'a mut T,
+}
+
+impl<'a, T : TableProvider> SiteSettings<'a, T> {
+    /// Opens the site settings stored in `db`, creating or upgrading the
+    /// table when needed.
+    pub fn new(db : &'a mut T) -> Result<Self, Error> {
+        db.use_table(TABLE, &[
+            ("id", &FieldType::Integer, &[FieldParameter::AutoIncrement]),
+            ("pattern", &FieldType::Text, &[FieldParameter::NoNull]),
+            ("host", &FieldType::Text, &[FieldParameter::Default(String::new())]),
+            ("permission", &FieldType::Text, &[FieldParameter::NoNull]),
+            ("value", &FieldType::Text, &[FieldParameter::NoNull]),
+            ("expires", &FieldType::Integer, &[FieldParameter::Default(String::from("0"))]),
+            ("modified", &FieldType::Integer, &[FieldParameter::Default(String::from("0"))]),
+        ], false, false)?;
+        db.request(&format!("CREATE INDEX IF NOT EXISTS {} ON {} (host);", HOST_INDEX, TABLE), &[])?;
+        fill_hosts(db)?;
+        Ok(Self { db })
+    }
+
+    /// Gives access to the underlying database.
+    pub fn db(&mut self) -> &mut T {
+        self.db
+    }
+
+    /// Sets `permission` to `value` for the sites matching `pattern`,
+    /// replacing the previous exception of this pattern.
+    pub fn set(&mut self,
+               pattern : &str,
+               permission : Permission,
+               value : Value,
+               expires : Option<Timestamp>) -> Result<(), Error> {
+        let (pattern, host) = match Pattern::parse(pattern) {
+            Ok(t) => (t.to_string(), t.host),
+            Err(e) => return Err(e),
+        };
+        match (permission, value) {
+            (Permission::Zoom, Value::Zoom(z)) if z.is_finite() && z > 0.0 => (),
+            (Permission::Zoom, _) | (_, Value::Zoom(_)) =>
+                return Err(Error::new(Some(2302), Some(format!("Invalid value {:?} for {}", value, permission.name())))),
+            _ => (),
+        }
+
+        self.db.transaction(|db| {
+            db.request_values(
+                &format!("DELETE FROM {} WHERE pattern = ? AND permission = ?;", TABLE),
+                &[FieldValue::Text(pattern.clone()), FieldValue::Text(String::from(permission.name()))])?;
+            match db.request_values(
+                &format!("INSERT INTO {} (pattern, host, permission, value, expires, modified) \
+                          VALUES (?, ?, ?, ?, ?, ?);", TABLE),
+                &[FieldValue::Text(pattern.clone()),
+                  FieldValue::Text(host.clone()),
+                  FieldValue::Text(String::from(permission.name())),
+                  FieldValue::Text(value.to_text()),
+                  FieldValue::Integer(match expires {
+                      Some(t) => t.as_millis(),
+                      None => 0,
+                  }),
+                  FieldValue::Integer(Timestamp::now().as_millis())]) {
+                Ok(_) => Ok(()),
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    /// Removes the exception of `pattern` for `permission`, or every
+    /// exception of `pattern` if no permission is given.
+    pub fn remove(&mut self, pattern : &str, permission : Option<Permission>) -> Result<usize, Error> {
+        let pattern = match Pattern::parse(pattern) {
+            Ok(t) => t.to_string(),
+            Err(e) => return Err(e),
+        };
+        let res = match permission {
+            Some(p) => self.db.request_values(
+                &format!("DELETE FROM {} WHERE pattern = ? AND permission = ?;", TABLE),
+                &[FieldValue::Text(pattern), FieldValue::Text(String::from(p.name()))]),
+            None => self.db.request_values(
+                &format!("DELETE FROM {} WHERE pattern = ?;", TABLE),
+                &[FieldValue::Text(pattern)]),
+        };
+        match res {
+            Ok(_) => self.db.changes(),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Removes the exceptions expired at `now` and returns their number.
+    pub fn remove_expired(&mut self, now : Timestamp) -> Result<usize, Error> {
+        match self.db.request_values(&format!("DELETE FROM {} WHERE expires != 0 AND expires <= ?;", TABLE),
+                                     &[FieldValue::Integer(now.as_millis())]) {
+            Ok(_) => self.db.changes(),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Removes the exceptions of the patterns naming `domain` or one of its
+    /// subdomains, with or without `*.`. Patterns matching every site are
+    /// kept. Returns the number of exceptions removed.
+    ///
+    /// `domain` is normalized like the hosts of the patterns, so
+    /// `Bücher.de` removes the exceptions of `xn--bcher-kva.de`.
+    pub fn forget_site(&mut self, domain : &str) -> Result<usize, Error> {
+        let domain = match url::Host::parse(domain.trim_matches('.')) {
+            Ok(t) => t.to_string(),
+            Err(_) => return Err(Error::new(Some(2301), Some(format!("Invalid domain {}", domain)))),
+        };
+
+        // Hosts ending with `.domain` are its subdomains, with or without
+        // `*.`, and `*.domain` itself.
+        self.db.request_values(
+            &format!("DELETE FROM {} WHERE host = ?1 OR substr(host, -length(?2)) = ?2;", TABLE),
+            &[FieldValue::Text(domain.clone()), FieldValue::Text(format!(".{}", domain))])?;
+        self.db.changes()
+    }
+
+    /// Every exception still valid at `now`, optionally only for one
+    /// permission, as shown in the settings page.
+    pub fn exceptions(&mut self, permission : Option<Permission>, now : Timestamp) -> Result<Vec<Exception>, Error> {
+        match permission {
+            Some(p) => self.select(now, "permission = ?", vec![FieldValue::Text(String::from(p.name()))]),
+            None => self.select(now, "1", Vec::new()),
+        }
+    }
+
+    /// Exceptions valid at `now` whose rows match `condition`, bound to
+    /// `arguments`.
+    fn select(&mut self, now : Timestamp, condition : &str, mut arguments : Vec<FieldValue>) -> Result<Vec<Exception>, Error> {
+        arguments.insert(0, FieldValue::Integer(now.as_millis()));
+        let rows = self.db.request_values(
+            &format!("SELECT * FROM {} WHERE (expires = 0 OR expires > ?) AND {} ORDER BY pattern, permission;",
+                      TABLE, condition),
+            &arguments);
+        match rows {
+            Ok(t) => Ok(t.iter().filter_map(|r| {
+                let text = |name : &str| match r.get(name) {
+                    Some(Some(FieldValue::Text(t))) => Some(t.clone()),
+                    _ => None,
+                };
+                let integer = |name : &str| match r.get(name) {
+                    Some(Some(FieldValue::Integer(t))) => *t,
+                    _ => 0,
+                };
+                let permission = Permission::from_name(&text("permission")?)?;
+                Some(Exception {
+                    pattern: Pattern::parse(&text("pattern")?).ok()?,
+                    permission,
+                    value: Value::from_text(permission, &text("value")?)?,
+                    expires: match integer("expires") {
+                        0 => None,
+                        t => Some(Timestamp::from_millis(t)),
+                    },
+                    modified: Timestamp::from_millis(integer("modified")),
+                })
+            }).collect()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Exceptions applying to the origin of `url` at `now`, one per
+    /// permission, the most specific pattern winning.
+    pub fn for_site(&mut self, url : &str, now : Timestamp) -> Result<Vec<Exception>, Error> {
+        let url = match url::Url::parse(url) {
+            Ok(t) => t,
+            Err(e) => return Err(Error::new(Some(2303), Some(format!("Invalid URL {}: {}", url, e)))),
+        };
+
+        let hosts = Pattern::candidate_hosts(&url);
+        let condition = format!("host IN ({})", vec!["?"; hosts.len()].join(", "));
+        let arguments = hosts.into_iter().map(FieldValue::Text).collect();
+
+        let mut ret : Vec<Exception> = Vec::new();
+        match self.select(now, &condition, arguments) {
+            Ok(t) => for exception in t.into_iter().filter(|e| e.pattern.matches(&url)) {
+                match ret.iter_mut().find(|e| e.permission == exception.permission) {
+                    Some(e) => if exception.pattern.specificity() > e.pattern.specificity() {
+                        *e = exception;
+                    },
+                    None => ret.push(exception),
+                }
+            },
+            Err(e) => return Err(e),
+        }
+        Ok(ret)
+    }
+
+    /// Exception deciding `permission` for `url` at `now`, if any.
+    pub fn get(&mut self, url : &str, permission : Permission, now : Timestamp) -> Result<Option<Exception>, Error> {
+        match self.for_site(url, now) {
+            Ok(t) => Ok(t.into_iter().find(|e| e.permission == permission)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// What the engine must do before using `permission` on `url`.
+    /// Zoom is not a decision, use [`zoom`](#method.zoom) instead.
+    pub fn decision(&mut self, url : &str, permission : Permission, now : Timestamp) -> Result<Decision, Error> {
+        let default = match permission.default_decision() {
+            Some(t) => t,
+            None => return Err(Error::new(Some(2302), Some(format!("{} is not a decision", permission.name())))),
+        };
+        match self.get(url, permission, now) {
+            Ok(Some(Exception { value: Value::Decision(d), .. })) => Ok(d),
+            Ok(_) => Ok(default),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Zoom factor of `url`, `None` if the default zoom applies.
+    pub fn zoom(&mut self, url : &str, now : Timestamp) -> Result<Option<f64>, Error> {
+        match self.get(url, Permission::Zoom, now) {
+            Ok(Some(Exception { value: Value::Zoom(z), .. })) => Ok(Some(z)),
+            Ok(_) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Fills the host of the rows written before it was stored.
+fn fill_hosts<T : TableProvider>(db : &mut T) -> Result<(), Error> {
+    let rows = db.request(&format!("SELECT id, pattern FROM {} WHERE host = '';", TABLE), &[])?;
+    for row in rows {
+        let (id, pattern) = match (row.get("id"), row.get("pattern")) {
+            (Some(Some(FieldValue::Integer(i))), Some(Some(FieldValue::Text(p)))) => (*i, p),
+            _ => continue,
+        };
+        // Invalid patterns are never listed, they can keep an empty host.
+        if let Ok(t) = Pattern::parse(pattern) {
+            db.request_values(&format!("UPDATE {} SET host = ? WHERE id = ?;", TABLE),
+                              &[FieldValue::Text(t.host), FieldValue::Integer(id)])?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::db::sqlite::SQLite;
+    use super::*;
+
+    fn url(t : &str) -> url::Url {
+        url::Url::parse(t).unwrap()
+    }
+
+    fn patterns(exceptions : &[Exception]) -> Vec<String> {
+        exceptions.iter().map(|e| e.pattern.to_string()).collect()
+    }
+
+    #[test]
+    fn patterns_are_normalized() {
+        let pattern = Pattern::parse(" HTTPS://Example.COM:8080/ ").unwrap();
+        assert_eq!(pattern, Pattern { scheme: Some(String::from("https")), host: String::from("example.com"), port: Some(8080) });
+        assert_eq!(pattern.to_string(), "https://example.com:8080");
+        assert_eq!(Pattern::parse("*.Bücher.de").unwrap().host, "*.xn--bcher-kva.de");
+        assert_eq!(Pattern::parse("*:443").unwrap().to_string(), "*:443");
+        assert_eq!(Pattern::parse("http://[::1]:80").unwrap().to_string(), "http://[::1]:80");
+
+        for invalid in &["example.com/path", "example.com:port", "example.com:99999", "exa mple.com", ""] {
+            assert_eq!(Pattern::parse(invalid).unwrap_err().code(), Some(2301), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn patterns_match_origins() {
+        let cases = [
+            ("example.com", "http://example.com:8080/page", true),
+            ("example.com", "https://www.example.com/", false),
+            ("*.example.com", "https://example.com/", true),
+            ("*.example.com", "https://a.b.example.com/", true),
+            ("*.example.com", "https://notexample.com/", false),
+            ("https://example.com", "http://example.com/", false),
+            ("example.com:443", "https://example.com/", true),
+            ("example.com:443", "http://example.com/", false),
+            ("*", "file:///etc/hosts", false),
+            ("*", "https://anything.org/", true),
+            ("bücher.de", "https://xn--bcher-kva.de/", true),
+        ];
+        for (pattern, origin, expected) in cases.iter() {
+            assert_eq!(Pattern::parse(pattern).unwrap().matches(&url(origin)), *expected, "{} {}", pattern, origin);
+        }
+    }
+
+    #[test]
+    fn specific_patterns_come_last() {
+        let mut patterns : Vec<Pattern> = ["https://www.example.com:443", "*.example.com", "*", "www.example.com",
+                                           "https://www.example.com", "*.www.example.com"]
+            .iter().map(|p| Pattern::parse(p).unwrap()).collect();
+        patterns.sort_by_key(|p| p.specificity());
+        let patterns : Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+        assert_eq!(patterns, vec!["*", "*.example.com", "*.www.example.com", "www.example.com",
+                                  "https://www.example.com", "https://www.example.com:443"]);
+        assert_eq!(Pattern::candidate_hosts(&url("https://a.example.com/")),
+                   vec!["*", "a.example.com", "*.a.example.com", "*.example.com", "*.com"]);
+        assert_eq!(Pattern::candidate_hosts(&url("http://127.0.0.1:8080/")), vec!["*", "127.0.0.1"]);
+    }
+
+    #[test]
+    fn most_specific_exception_applies() {
+        let mut db = SQLite::new(":memory:").unwrap();
+        let mut sites = SiteSettings::new(&mut db).unwrap();
+        let now = Timestamp::from_millis(1000);
+        sites.set("*", Permission::JavaScript, Value::Decision(Decision::Block), None).unwrap();
+        sites.set("*.example.com", Permission::JavaScript, Value::Decision(Decision::Ask), None).unwrap();
+        sites.set("https://www.example.com", Permission::JavaScript, Value::Decision(Decision::Allow), None).unwrap();
+        sites.set("example.com", Permission::Zoom, Value::Zoom(1.5), None).unwrap();
+        sites.set("other.org", Permission::Popups, Value::Decision(Decision::Allow), None).unwrap();
+
+        assert_eq!(sites.decision("https://www.example.com/a", Permission::JavaScript, now).unwrap(), Decision::Allow);
+        assert_eq!(sites.decision("http://www.example.com/a", Permission::JavaScript, now).unwrap(), Decision::Ask);
+        assert_eq!(sites.decision("https://example.com/", Permission::JavaScript, now).unwrap(), Decision::Ask);
+        assert_eq!(sites.decision("https://other.org/", Permission::JavaScript, now).unwrap(), Decision::Block);
+        assert_eq!(patterns(&sites.for_site("https://example.com/", now).unwrap()), vec!["*.example.com", "example.com"]);
+        assert_eq!(patterns(&sites.for_site("https://other.org/", now).unwrap()), vec!["*", "other.org"]);
+
+        // Without exception, the default of the permission applies.
+        assert_eq!(sites.decision("https://other.org/", Permission::Popups, now).unwrap(), Decision::Allow);
+        assert_eq!(sites.decision("https://example.com/", Permission::Popups, now).unwrap(), Decision::Block);
+        assert_eq!(sites.decision("https://example.com/", Permission::Camera, now).unwrap(), Decision::Ask);
+        assert_eq!(sites.decision("https://example.com/", Permission::Zoom, now).unwrap_err().code(), Some(2302));
+        assert_eq!(sites.zoom("https://example.com/", now).unwrap(), Some(1.5));
+        assert_eq!(sites.zoom("https://www.example.com/", now).unwrap(), None);
+        assert_eq!(sites.for_site("not a url", now).unwrap_err().code(), Some(2303));
+        assert_eq!(sites.set("example.com", Permission::Zoom, Value::Decision(Decision::Allow), None).unwrap_err().code(),
+                   Some(2302));
+    }
+
+    #[test]
+    fn expired_exceptions_are_ignored() {
+        let mut db = SQLite::new(":memory:").unwrap();
+        let mut sites = SiteSettings::new(&mut db).unwrap();
+        let expires = Timestamp::from_millis(2000);
+        sites.set("example.com", Permission::Camera, Value::Decision(Decision::Allow), Some(expires)).unwrap();
+        sites.set("example.com", Permission::Microphone, Value::Decision(Decision::Allow), None).unwrap();
+
+        let before = Timestamp::from_millis(1999);
+        assert_eq!(sites.decision("https://example.com/", Permission::Camera, before).unwrap(), Decision::Allow);
+        assert_eq!(sites.exceptions(Some(Permission::Camera), before).unwrap()[0].expires, Some(expires));
+        assert_eq!(sites.decision("https://example.com/", Permission::Camera, expires).unwrap(), Decision::Ask);
+        assert_eq!(sites.exceptions(None, expires).unwrap().len(), 1);
+
+        assert_eq!(sites.remove_expired(before).unwrap(), 0);
+        assert_eq!(sites.remove_expired(expires).unwrap(), 1);
+        assert_eq!(sites.exceptions(None, Timestamp::from_millis(0)).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn forget_site_normalizes_the_domain() {
+        let mut db = SQLite::new(":memory:").unwrap();
+        let mut sites = SiteSettings::new(&mut db).unwrap();
+        for pattern in &["bücher.de", "*.bücher.de", "https://shop.bücher.de:8443", "xbücher.de", "*"] {
+            sites.set(pattern, Permission::Popups, Value::Decision(Decision::Allow), None).unwrap();
+        }
+
+        assert_eq!(sites.forget_site("BÜCHER.de.").unwrap(), 3);
+        let left = patterns(&sites.exceptions(None, Timestamp::from_millis(0)).unwrap());
+        assert_eq!(left, vec!["*", "xn--xbcher-4ya.de"]);
+        assert_eq!(sites.forget_site("exa mple.com").unwrap_err().code(), Some(2301));
+    }
+
+    #[test]
+    fn hosts_of_older_rows_are_filled() {
+        let mut db = SQLite::new(":memory:").unwrap();
+        db.use_table(TABLE, &[
+            ("id", &FieldType::Integer, &[FieldParameter::AutoIncrement]),
+            ("pattern", &FieldType::Text, &[FieldParameter::NoNull]),
+            ("permission", &FieldType::Text, &[FieldParameter::NoNull]),
+            ("value", &FieldType::Text, &[FieldParameter::NoNull]),
+            ("expires", &FieldType::Integer, &[FieldParameter::Default(String::from("0"))]),
+            ("modified", &FieldType::Integer, &[FieldParameter::Default(String::from("0"))]),
+        ], false, false).unwrap();
+        db.request(&format!("INSERT INTO {} (pattern, permission, value) VALUES ('*.example.com', 'popups', 'allow');",
+                            TABLE), &[]).unwrap();
+
+        let mut sites = SiteSettings::new(&mut db).unwrap();
+        let now = Timestamp::from_millis(0);
+        assert_eq!(sites.decision("https://www.example.com/", Permission::Popups, now).unwrap(), Decision::Allow);
+        assert_eq!(sites.forget_site("example.com").unwrap(), 1);
+    }
+}
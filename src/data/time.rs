@@ -0,0 +1,77 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Time representation used in the core
+//!
+//! Every date is a [`Timestamp`](struct.Timestamp.html): milliseconds since
+//! the UNIX epoch, in UTC, stored as an `INTEGER` column. Conversions to
+//! local time are done by SQLite, which knows the timezone of the system.
+
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::db::{Error, TableProvider, FieldValue};
+
+/// Milliseconds since 1970-01-01 00:00:00 UTC.
+#[derive(Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default, Clone, Copy)]
+pub struct Timestamp(i64);
+
+impl Timestamp {
+    pub const MIN : Timestamp = Timestamp(i64::MIN);
+    pub const MAX : Timestamp = Timestamp(i64::MAX);
+
+    pub fn now() -> Self {
+        match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(t) => Timestamp(t.as_millis() as i64),
+            Err(e) => Timestamp(-(e.duration().as_millis() as i64)),
+        }
+    }
+
+    pub fn from_millis(millis : i64) -> Self {
+        Timestamp(millis)
+    }
+
+    pub fn from_secs(secs : i64) -> Self {
+        Timestamp(secs.saturating_mul(1000))
+    }
+
+    pub fn from_micros(micros : i64) -> Self {
+        Timestamp(micros / 1000)
+    }
+
+    pub fn as_millis(&self) -> i64 {
+        self.0
+    }
+
+    pub fn as_secs(&self) -> i64 {
+        self.0.div_euclid(1000)
+    }
+
+    /// This timestamp moved by `millis` milliseconds, saturating at the
+    /// bounds.
+    pub fn add_millis(&self, millis : i64) -> Self {
+        Timestamp(self.0.saturating_add(millis))
+    }
+
+    /// Start of the local day containing this timestamp, moved by `days`
+    /// days.
+    pub fn local_day_start<T : TableProvider>(&self, db : &mut T, days : i64) -> Result<Self, Error> {
+        match db.request("SELECT CAST(strftime('%s', ?, 'unixepoch', 'localtime', 'start of day', ?, 'utc') \
+                          AS INTEGER) AS start;",
+                         &[self.as_secs().to_string().as_str(), format!("{:+} days", days).as_str()]) {
+            Ok(t) => match t.first().and_then(|r| r.get("start")) {
+                Some(Some(FieldValue::Integer(v))) => Ok(Timestamp::from_secs(*v)),
+                _ => Err(Error::new(Some(2201), Some(format!("Can not compute local day of {}", self)))),
+            },
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
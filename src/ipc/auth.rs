@@ -0,0 +1,648 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Authentication of UI clients
+//!
+//! The sockets of the core use ZeroMQ CURVE: traffic is encrypted, and
+//! every client proves it owns a keypair. Each profile has its own server
+//! keypair, kept in `keys.toml` in the profile directory, and publishes the
+//! public key in `core.pub` for clients to read.
+//!
+//! Only clients whose public key is in the allowlist (`clients.toml`) are
+//! served. A new client pairs this way:
+//!  1. The user opens a pairing window, from a paired UI with
+//!     `auth.start_pairing` or by starting the core with `--pair`. The core
+//!     gives a short one time code, shown to the user.
+//!  2. While the window is open the core accepts connections from unknown
+//!     keys, but only answers `core.hello` and `auth.pair` on them.
+//!  3. The new client calls `auth.pair` with the code and its name. Its key
+//!     comes from the CURVE handshake, so it can not pair another key.
+//!
+//! A wrong code closes the window, so codes can not be guessed. The event
+//! bus only accepts paired clients.
+//!
+//! Keys are checked on every request, but the event bus only checks them
+//! when a client connects: a revoked client keeps receiving the events
+//! until it reconnects or the core restarts.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use toml::Value;
+use toml::value::Table;
+
+use crate::data::db::Error;
+use crate::data::profile::Profile;
+use crate::data::settings;
+use crate::data::time::Timestamp;
+use super::message::{Request, INVALID_PARAMS};
+use super::server::socket_error;
+
+/// Keypair of the core, with the secret key.
+pub const KEYS_FILE : &str = "keys.toml";
+/// Public key of the core, for the clients.
+pub const PUBLIC_KEY_FILE : &str = "core.pub";
+/// Allowlist of the clients.
+pub const CLIENTS_FILE : &str = "clients.toml";
+
+/// Authentication domain of the request socket.
+pub const CORE_DOMAIN : &str = "sielo.core";
+/// Authentication domain of the event bus.
+pub const EVENTS_DOMAIN : &str = "sielo.events";
+/// Endpoint ZeroMQ sends authentication requests to.
+pub const ZAP_ENDPOINT : &str = "inproc://zeromq.zap.01";
+
+/// Time a pairing window stays open, in milliseconds.
+pub const PAIRING_DURATION : i64 = 5 * 60 * 1000;
+
+/// The client is not paired.
+pub const NOT_PAIRED : isize = 6010;
+/// The pairing code is wrong or no pairing window is open.
+pub const PAIRING_REFUSED : isize = 6011;
+
+/// CURVE keypair, both keys encoded in Z85.
+#[derive(PartialEq, Debug, Clone)]
+pub struct KeyPair {
+    pub public : String,
+    pub secret : String,
+}
+
+impl KeyPair {
+    pub fn generate() -> Result<Self, Error> {
+        let pair = match zmq::CurveKeyPair::new() {
+            Ok(t) => t,
+            Err(e) => return Err(socket_error("generate a keypair", e)),
+        };
+        match (zmq::z85_encode(&pair.public_key), zmq::z85_encode(&pair.secret_key)) {
+            (Ok(public), Ok(secret)) => Ok(Self { public, secret }),
+            _ => Err(key_error("Can not encode the keypair")),
+        }
+    }
+
+    /// Keypair of the core for `profile`, created on first use.
+    pub fn for_profile(profile : &Profile) -> Result<Self, Error> {
+        Self::load_or_create(&profile.directory)
+    }
+
+    /// Keypair stored in `directory`, created if there is none.
+    pub fn load_or_create(directory : &Path) -> Result<Self, Error> {
+        let path = directory.join(KEYS_FILE);
+        if path.exists() {
+            let document = match fs::read_to_string(&path).ok().and_then(|t| t.parse::<Value>().ok()) {
+                Some(Value::Table(t)) => t,
+                _ => return Err(key_error(&format!("Can not read {}", path.display()))),
+            };
+            return match (document.get("public"), document.get("secret")) {
+                (Some(Value::String(public)), Some(Value::String(secret))) if is_key(public) && is_key(secret) =>
+                    Ok(Self { public: public.clone(), secret: secret.clone() }),
+                _ => Err(key_error(&format!("Invalid keypair in {}", path.display()))),
+            };
+        }
+
+        let ret = Self::generate()?;
+        let mut document = Table::new();
+        document.insert(String::from("public"), Value::String(ret.public.clone()));
+        document.insert(String::from("secret"), Value::String(ret.secret.clone()));
+        match toml::to_string(&Value::Table(document)) {
+            Ok(t) => write_secret(&path, t.as_bytes())?,
+            Err(e) => return Err(key_error(&format!("Can not serialize {}: {}", path.display(), e))),
+        }
+        settings::write_atomically(&directory.join(PUBLIC_KEY_FILE), ret.public.as_bytes())?;
+        Ok(ret)
+    }
+}
+
+/// Public key of the core of `profile`, as written for the clients.
+pub fn server_key(profile : &Profile) -> Result<String, Error> {
+    let path = profile.directory.join(PUBLIC_KEY_FILE);
+    match fs::read_to_string(&path) {
+        Ok(t) if is_key(t.trim()) => Ok(String::from(t.trim())),
+        _ => Err(key_error(&format!("Can not read the key of the core in {}", path.display()))),
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Client {
+    pub name : String,
+    pub key : String,
+    pub paired : Timestamp,
+}
+
+struct Pairing {
+    code : String,
+    expires : Timestamp,
+}
+
+pub struct Allowlist {
+    path : Option<PathBuf>,
+    clients : Vec<Client>,
+    pairing : Option<Pairing>,
+}
+
+impl Allowlist {
+    /// Allowlist not saved anywhere.
+    pub fn new() -> Self {
+        Self { path: None, clients: Vec::new(), pairing: None }
+    }
+
+    /// Allowlist of `profile`.
+    pub fn for_profile(profile : &Profile) -> Result<Self, Error> {
+        Self::open(profile.directory.join(CLIENTS_FILE))
+    }
+
+    /// Allowlist saved in `path`, empty if the file does not exist.
+    pub fn open<P : AsRef<Path>>(path : P) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut ret = Self { path: Some(path.clone()), clients: Vec::new(), pairing: None };
+        if !path.exists() {
+            return Ok(ret);
+        }
+
+        let document = match fs::read_to_string(&path).ok().and_then(|t| t.parse::<Value>().ok()) {
+            Some(Value::Table(t)) => t,
+            _ => return Err(key_error(&format!("Can not read {}", path.display()))),
+        };
+        if let Some(Value::Array(clients)) = document.get("clients") {
+            for c in clients {
+                if let (Some(Value::String(name)), Some(Value::String(key))) = (c.get("name"), c.get("key")) {
+                    if is_key(key) {
+                        let paired = match c.get("paired") {
+                            Some(Value::Integer(t)) => Timestamp::from_millis(*t),
+                            _ => Timestamp::default(),
+                        };
+                        ret.clients.push(Client { name: name.clone(), key: key.clone(), paired });
+                    }
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    pub fn clients(&self) -> &[Client] {
+        &self.clients
+    }
+
+    pub fn is_allowed(&self, key : &str) -> bool {
+        self.clients.iter().any(|c| c.key == key)
+    }
+
+    /// Allows the client `name` with the public `key`. A known key is only
+    /// renamed.
+    pub fn add(&mut self, name : &str, key : &str) -> Result<(), Error> {
+        if !is_key(key) {
+            return Err(Error::new(Some(INVALID_PARAMS), Some(format!("Invalid client key {}", key))));
+        }
+        match self.clients.iter_mut().find(|c| c.key == key) {
+            Some(c) => c.name = String::from(name),
+            None => self.clients.push(Client { name: String::from(name), key: String::from(key), paired: Timestamp::now() }),
+        }
+        self.save()
+    }
+
+    /// Removes the client with `key`, returns `false` if it was not known.
+    /// Requests on open connections of the client are refused from now on,
+    /// but an open connection to the event bus is not closed: it keeps
+    /// receiving the events until it reconnects.
+    pub fn remove(&mut self, key : &str) -> Result<bool, Error> {
+        let count = self.clients.len();
+        self.clients.retain(|c| c.key != key);
+        if self.clients.len() == count {
+            return Ok(false);
+        }
+        self.save().map(|_| true)
+    }
+
+    /// Opens a pairing window for `duration` milliseconds and returns its
+    /// code. A previous window is closed.
+    pub fn start_pairing(&mut self, duration : i64) -> Result<String, Error> {
+        // The secret half of a fresh keypair is a good source of randomness.
+        let random = match zmq::CurveKeyPair::new() {
+            Ok(t) => t.secret_key,
+            Err(e) => return Err(socket_error("generate a pairing code", e)),
+        };
+        let number = random[..4].iter().fold(0u32, |n, b| (n << 8) | u32::from(*b));
+        let code = format!("{:06}", number % 1_000_000);
+        self.pairing = Some(Pairing { code: code.clone(), expires: Timestamp::now().add_millis(duration) });
+        Ok(code)
+    }
+
+    pub fn stop_pairing(&mut self) {
+        self.pairing = None;
+    }
+
+    /// Whether unknown clients can connect to pair.
+    pub fn is_pairing(&self) -> bool {
+        match &self.pairing {
+            Some(p) => p.expires > Timestamp::now(),
+            None => false,
+        }
+    }
+
+    /// Pairs the client with `key` if `code` is the one of the open window,
+    /// which is closed either way.
+    pub fn pair(&mut self, code : &str, name : &str, key : &str) -> Result<(), Error> {
+        let open = self.is_pairing();
+        match self.pairing.take() {
+            Some(ref p) if open && p.code == code => self.add(name, key),
+            _ => Err(Error::new(Some(PAIRING_REFUSED), Some(String::from("Wrong pairing code or no pairing in progress")))),
+        }
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let path = match &self.path {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+        let mut document = Table::new();
+        document.insert(String::from("clients"), Value::Array(self.clients.iter().map(|c| Value::Table(client_table(c))).collect()));
+        write_table(path, document)
+    }
+}
+
+impl Default for Allowlist {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Answers the `auth.*` requests of the client with `key`, and refuses
+/// every other request of a client which is not paired. Returns `None` for
+/// the requests to dispatch.
+pub fn handle(allowlist : &Mutex<Allowlist>, key : Option<&str>, request : &Request) -> Option<Result<Value, Error>> {
+    let mut allowlist = match allowlist.lock() {
+        Ok(t) => t,
+        Err(_) => return Some(Err(key_error("The allowlist is not available"))),
+    };
+    let key = match key {
+        Some(t) => t,
+        None => return Some(Err(not_paired())),
+    };
+    let paired = allowlist.is_allowed(key);
+
+    match request.method.as_str() {
+        "core.hello" => None,
+        "auth.pair" => Some(match (request.text("code"), request.text("name")) {
+            (Ok(code), Ok(name)) => allowlist.pair(code, name, key).map(|_| Value::Table(Table::new())),
+            (Err(e), _) | (_, Err(e)) => Err(e),
+        }),
+        _ if !paired => Some(Err(not_paired())),
+        "auth.start_pairing" => Some(allowlist.start_pairing(PAIRING_DURATION).map(|code| {
+            let mut ret = Table::new();
+            ret.insert(String::from("code"), Value::String(code));
+            ret.insert(String::from("duration"), Value::Integer(PAIRING_DURATION));
+            Value::Table(ret)
+        })),
+        "auth.clients" => {
+            let mut ret = Table::new();
+            ret.insert(String::from("clients"), Value::Array(allowlist.clients().iter().map(|c| Value::Table(client_table(c))).collect()));
+            Some(Ok(Value::Table(ret)))
+        },
+        "auth.revoke" => Some(match request.text("key") {
+            Ok(t) => allowlist.remove(t).map(|removed| {
+                let mut ret = Table::new();
+                ret.insert(String::from("removed"), Value::Boolean(removed));
+                Value::Table(ret)
+            }),
+            Err(e) => Err(e),
+        }),
+        _ => None,
+    }
+}
+
+/// Makes `socket` a CURVE server with `keys`, checked in `domain`. Must be
+/// called before binding.
+pub fn secure_server(socket : &zmq::Socket, keys : &KeyPair, domain : &str) -> Result<(), Error> {
+    let secret = match zmq::z85_decode(&keys.secret) {
+        Ok(t) => t,
+        Err(_) => return Err(key_error("Invalid secret key")),
+    };
+    match socket.set_curve_server(true)
+        .and_then(|_| socket.set_curve_secretkey(&secret))
+        .and_then(|_| socket.set_zap_domain(domain)) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(socket_error("enable authentication", e)),
+    }
+}
+
+/// Makes `socket` a CURVE client with `keys`, talking to the server with
+/// the public key `server`. Must be called before connecting.
+pub fn secure_client(socket : &zmq::Socket, keys : &KeyPair, server : &str) -> Result<(), Error> {
+    let (public, secret, server) = match (zmq::z85_decode(&keys.public), zmq::z85_decode(&keys.secret), zmq::z85_decode(server)) {
+        (Ok(p), Ok(s), Ok(k)) => (p, s, k),
+        _ => return Err(key_error("Invalid key")),
+    };
+    match socket.set_curve_serverkey(&server)
+        .and_then(|_| socket.set_curve_publickey(&public))
+        .and_then(|_| socket.set_curve_secretkey(&secret)) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(socket_error("enable authentication", e)),
+    }
+}
+
+/// Answers the authentication requests of ZeroMQ (ZAP) for the sockets of
+/// a context, with an allowlist.
+pub struct Authenticator {
+    running : Arc<AtomicBool>,
+    thread : Option<thread::JoinHandle<()>>,
+}
+
+impl Authenticator {
+    /// Starts answering for the sockets of `context`. Must be started
+    /// before the secured sockets are bound.
+    pub fn start(context : &zmq::Context, allowlist : Arc<Mutex<Allowlist>>) -> Result<Self, Error> {
+        let socket = match context.socket(zmq::REP) {
+            Ok(t) => t,
+            Err(e) => return Err(socket_error("create the authentication socket", e)),
+        };
+        if let Err(e) = socket.set_linger(0) {
+            return Err(socket_error("configure the authentication socket", e));
+        }
+        if let Err(e) = socket.bind(ZAP_ENDPOINT) {
+            return Err(socket_error(&format!("bind {}", ZAP_ENDPOINT), e));
+        }
+
+        let running = Arc::new(AtomicBool::new(true));
+        let flag = running.clone();
+        let thread = thread::spawn(move || {
+            while flag.load(Ordering::SeqCst) {
+                match socket.poll(zmq::POLLIN, super::server::POLL_INTERVAL) {
+                    Ok(0) => continue,
+                    Ok(_) => (),
+                    Err(_) => break,
+                }
+                let frames = match socket.recv_multipart(0) {
+                    Ok(t) => t,
+                    Err(_) => break,
+                };
+                let reply = authenticate(&allowlist, &frames);
+                if let Err(e) = socket.send_multipart(&reply, 0) {
+                    println!("Warning: {:?}", socket_error("answer an authentication request", e));
+                }
+            }
+        });
+
+        Ok(Self { running, thread: Some(thread) })
+    }
+
+    /// Stops answering and waits for the thread to end. Secured sockets
+    /// refuse every connection afterwards.
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+impl Drop for Authenticator {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Reply to a ZAP request: `[version, request id, domain, address,
+/// identity, mechanism, credentials...]`. The user id of accepted clients
+/// is their public key, read back by the server.
+fn authenticate(allowlist : &Mutex<Allowlist>, frames : &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let request_id = frames.get(1).cloned().unwrap_or_default();
+    let status = |code : &str, text : &str, user : &str| vec![
+        b"1.0".to_vec(), request_id.clone(), code.as_bytes().to_vec(), text.as_bytes().to_vec(), user.as_bytes().to_vec(), Vec::new()];
+
+    if frames.len() < 7 || frames[0] != b"1.0" || frames[5] != b"CURVE" {
+        return status("400", "Only CURVE is supported", "");
+    }
+    let key = match zmq::z85_encode(&frames[6]) {
+        Ok(t) => t,
+        Err(_) => return status("400", "Invalid key", ""),
+    };
+    let allowlist = match allowlist.lock() {
+        Ok(t) => t,
+        Err(_) => return status("500", "Allowlist not available", ""),
+    };
+
+    let accepted = match &frames[2][..] {
+        d if d == CORE_DOMAIN.as_bytes() => allowlist.is_allowed(&key) || allowlist.is_pairing(),
+        d if d == EVENTS_DOMAIN.as_bytes() => allowlist.is_allowed(&key),
+        _ => false,
+    };
+    if accepted {
+        status("200", "OK", &key)
+    } else {
+        status("400", "Client not paired", "")
+    }
+}
+
+fn client_table(client : &Client) -> Table {
+    let mut ret = Table::new();
+    ret.insert(String::from("name"), Value::String(client.name.clone()));
+    ret.insert(String::from("key"), Value::String(client.key.clone()));
+    ret.insert(String::from("paired"), Value::Integer(client.paired.as_millis()));
+    ret
+}
+
+/// Whether `key` is a 32 bytes key encoded in Z85.
+fn is_key(key : &str) -> bool {
+    key.len() == 40 && zmq::z85_decode(key).is_ok()
+}
+
+fn write_table(path : &Path, document : Table) -> Result<(), Error> {
+    match toml::to_string(&Value::Table(document)) {
+        Ok(t) => settings::write_atomically(path, t.as_bytes()),
+        Err(e) => Err(key_error(&format!("Can not serialize {}: {}", path.display(), e))),
+    }
+}
+
+/// Replaces the content of `path` with the secret `content` through a
+/// temporary file, readable by its owner only from its creation.
+fn write_secret(path : &Path, content : &[u8]) -> Result<(), Error> {
+    let temporary = path.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    // A temporary file left by a crash may be readable by anyone.
+    let _ = fs::remove_file(&temporary);
+    let written = path.parent().map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| options.open(&temporary))
+        .and_then(|mut t| t.write_all(content).and_then(|_| t.sync_all()))
+        .and_then(|_| fs::rename(&temporary, path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temporary);
+        return Err(key_error(&format!("Can not write {}: {}", path.display(), e)));
+    }
+    Ok(())
+}
+
+fn not_paired() -> Error {
+    Error::new(Some(NOT_PAIRED), Some(String::from("Client is not paired, call auth.pair with a pairing code")))
+}
+
+fn key_error(message : &str) -> Error {
+    Error::new(Some(6012), Some(String::from(message)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use toml::Value;
+    use toml::value::Table;
+
+    use crate::data::db::Error;
+    use crate::data::db::sqlite::SQLite;
+    use crate::data::settings::{Settings, Store};
+    use crate::ipc::dispatch::Dispatcher;
+    use crate::ipc::message::{Request, Reply};
+    use crate::ipc::server::Server;
+    use super::*;
+
+    /// Secured server on a loopback port, and its allowlist.
+    struct Core {
+        server : Server<SQLite>,
+        endpoint : String,
+        keys : KeyPair,
+        allowlist : Arc<Mutex<Allowlist>>,
+        _authenticator : Authenticator,
+    }
+
+    impl Core {
+        fn start(context : &zmq::Context) -> Self {
+            let allowlist = Arc::new(Mutex::new(Allowlist::new()));
+            let authenticator = Authenticator::start(context, allowlist.clone()).unwrap();
+            let keys = KeyPair::generate().unwrap();
+            let dispatcher = Dispatcher::new(SQLite::new(":memory:").unwrap(), Arc::new(Mutex::new(Store::new(Settings::new()))));
+            let server = Server::bind_secure(context, "tcp://127.0.0.1:*", dispatcher, &keys, allowlist.clone()).unwrap();
+            let endpoint = server.endpoint().unwrap();
+            Self { server, endpoint, keys, allowlist, _authenticator: authenticator }
+        }
+
+        /// Client with a fresh keypair.
+        fn connect(&self, context : &zmq::Context) -> (zmq::Socket, KeyPair) {
+            let keys = KeyPair::generate().unwrap();
+            (self.connect_with(context, &keys), keys)
+        }
+
+        fn connect_with(&self, context : &zmq::Context, keys : &KeyPair) -> zmq::Socket {
+            let socket = context.socket(zmq::DEALER).unwrap();
+            socket.set_linger(0).unwrap();
+            socket.set_rcvtimeo(1000).unwrap();
+            // A rejected handshake leaves no peer to queue the request for.
+            socket.set_sndtimeo(1000).unwrap();
+            secure_client(&socket, keys, &self.keys.public).unwrap();
+            socket.connect(&self.endpoint).unwrap();
+            socket
+        }
+
+        /// Reply of the server, `None` if the request never reached it.
+        fn call(&mut self, socket : &zmq::Socket, method : &str, params : &[(&str, &str)]) -> Option<Result<Value, Error>> {
+            let params : Table = params.iter().map(|(k, v)| (String::from(*k), Value::String(String::from(*v)))).collect();
+            if socket.send(Request::new(1, method, params).to_bytes().unwrap(), 0).is_err() {
+                return None;
+            }
+            if !self.server.poll(1000).unwrap() {
+                return None;
+            }
+            Some(Reply::from_bytes(&socket.recv_bytes(0).unwrap()).unwrap().result)
+        }
+    }
+
+    fn code(result : Option<Result<Value, Error>>) -> Option<isize> {
+        result.unwrap().unwrap_err().code()
+    }
+
+    #[test]
+    fn keys_are_created_once_and_kept_secret() {
+        let directory = std::env::temp_dir().join(format!("sielo-keys-{}", std::process::id()));
+        let keys = KeyPair::load_or_create(&directory).unwrap();
+        assert_eq!(KeyPair::load_or_create(&directory).unwrap(), keys);
+        assert_eq!(server_key(&Profile { name: String::from("default"), directory: directory.clone() }).unwrap(), keys.public);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(directory.join(KEYS_FILE)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert!(!directory.join("keys.tmp").exists());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn client_pairs_with_the_code() {
+        let context = zmq::Context::new();
+        let mut core = Core::start(&context);
+        let pairing = core.allowlist.lock().unwrap().start_pairing(PAIRING_DURATION).unwrap();
+        let (client, keys) = core.connect(&context);
+
+        assert!(core.call(&client, "core.hello", &[]).unwrap().is_ok());
+        assert_eq!(code(core.call(&client, "history.entries", &[])), Some(NOT_PAIRED));
+        assert!(core.call(&client, "auth.pair", &[("code", &pairing), ("name", "Tests")]).unwrap().is_ok());
+        assert!(core.call(&client, "history.entries", &[]).unwrap().is_ok());
+
+        let allowlist = core.allowlist.lock().unwrap();
+        assert!(allowlist.is_allowed(&keys.public));
+        assert!(!allowlist.is_pairing());
+    }
+
+    #[test]
+    fn wrong_code_closes_the_window() {
+        let context = zmq::Context::new();
+        let mut core = Core::start(&context);
+        let pairing = core.allowlist.lock().unwrap().start_pairing(PAIRING_DURATION).unwrap();
+        let wrong = if pairing == "000000" { "000001" } else { "000000" };
+        let (client, keys) = core.connect(&context);
+
+        assert_eq!(code(core.call(&client, "auth.pair", &[("code", wrong), ("name", "Tests")])), Some(PAIRING_REFUSED));
+        assert_eq!(code(core.call(&client, "auth.pair", &[("code", &pairing), ("name", "Tests")])), Some(PAIRING_REFUSED));
+        assert!(!core.allowlist.lock().unwrap().is_pairing());
+        assert!(!core.allowlist.lock().unwrap().is_allowed(&keys.public));
+
+        // Without a window, new unknown clients can not even connect.
+        let (other, _) = core.connect(&context);
+        assert!(core.call(&other, "core.hello", &[]).is_none());
+    }
+
+    #[test]
+    fn unpaired_key_is_rejected() {
+        let context = zmq::Context::new();
+        let mut core = Core::start(&context);
+        let (client, _) = core.connect(&context);
+        assert!(core.call(&client, "core.hello", &[]).is_none());
+        assert!(core.call(&client, "history.entries", &[]).is_none());
+
+        let keys = KeyPair::generate().unwrap();
+        core.allowlist.lock().unwrap().add("Tests", &keys.public).unwrap();
+        let paired = core.connect_with(&context, &keys);
+        assert!(core.call(&paired, "history.entries", &[]).unwrap().is_ok());
+    }
+
+    #[test]
+    fn event_bus_needs_a_paired_key() {
+        let allowlist = Mutex::new(Allowlist::new());
+        let keys = KeyPair::generate().unwrap();
+        let request = |domain : &str| vec![
+            b"1.0".to_vec(), b"1".to_vec(), domain.as_bytes().to_vec(), b"127.0.0.1".to_vec(), Vec::new(),
+            b"CURVE".to_vec(), zmq::z85_decode(&keys.public).unwrap()];
+        let status = |domain : &str, allowlist : &Mutex<Allowlist>| authenticate(allowlist, &request(domain))[2].clone();
+
+        assert_eq!(status(EVENTS_DOMAIN, &allowlist), b"400");
+        assert_eq!(status(CORE_DOMAIN, &allowlist), b"400");
+
+        allowlist.lock().unwrap().start_pairing(PAIRING_DURATION).unwrap();
+        assert_eq!(status(CORE_DOMAIN, &allowlist), b"200");
+        assert_eq!(status(EVENTS_DOMAIN, &allowlist), b"400");
+
+        allowlist.lock().unwrap().add("Tests", &keys.public).unwrap();
+        assert_eq!(status(EVENTS_DOMAIN, &allowlist), b"200");
+        assert_eq!(authenticate(&allowlist, &request(EVENTS_DOMAIN))[4], keys.public.as_bytes());
+    }
+}
@@ -0,0 +1,1149 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Client of the core
+//!
+//! Front ends and tests talk to the core through a
+//! [`Client`](struct.Client.html), which has a typed method for every method
+//! of the core. Calls do not block: they return a
+//! [`Pending`](struct.Pending.html) result, which is a `Future` for async
+//! code and can also be waited for.
+//!
+//! Requests are sent in order by a background thread owning the
+//! [connection](../connection/index.html), which also sends the heartbeats
+//! and says goodbye when the client is dropped. Any call can be cancelled,
+//! and long ones can be [streamed](struct.Partials.html).
+//!
+//! Notifications come from an [`Events`](struct.Events.html) stream, which
+//! reports missed events and skips the ones already part of a
+//! [snapshot](struct.Snapshot.html).
+//!
+//! Unlike single replies, streamed replies and events are not futures:
+//! both are iterators which block the calling thread until the next item
+//! comes. Async code reads them from a thread of its own, or polls events
+//! with [`Events::recv`](struct.Events.html#method.recv) and a timeout of 0.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::Duration;
+
+use toml::Value;
+use toml::value::Table;
+
+use crate::data::db::Error;
+use crate::data::history::{ClearReport, Entry, Filter, TimeRange};
+use crate::data::import::{Browser, ImportReport, Progress, Stage};
+use crate::data::mime::Category;
+use crate::data::profile::Profile;
+use crate::data::settings::{Settings, Diff, Change};
+use crate::data::time::Timestamp;
+use super::auth::{self, KeyPair};
+use super::clients::{Client as ClientInfo, Window, Tab};
+use super::connection::{Connection, Options};
+use super::events;
+use super::message;
+use super::server::{self, socket_error};
+use super::stream;
+
+/// The reply of the core does not have the expected shape.
+pub const BAD_REPLY : isize = 6015;
+/// The client was closed before the reply came.
+pub const CLOSED : isize = 6016;
+
+/// Result of a call once it came, and the task waiting for it.
+type SlotState = (Option<Result<Value, Error>>, Option<Waker>);
+
+struct Slot {
+    state : Mutex<SlotState>,
+    ready : Condvar,
+}
+
+impl Slot {
+    fn fill(&self, result : Result<Value, Error>) {
+        let mut state = match self.state.lock() {
+            Ok(t) => t,
+            Err(e) => e.into_inner(),
+        };
+        state.0 = Some(result);
+        if let Some(waker) = state.1.take() {
+            waker.wake();
+        }
+        self.ready.notify_all();
+    }
+}
+
+/// Result of a call still on its way.
+pub struct Pending<T> {
+    slot : Arc<Slot>,
+    cancel : Arc<AtomicBool>,
+    convert : fn(Value) -> Result<T, Error>,
+}
+
+impl<T> Pending<T> {
+    /// Asks the core to stop the call, which then fails with
+    /// [`CANCELLED`](../stream/constant.CANCELLED.html) unless it was
+    /// already done.
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        match self.slot.state.lock() {
+            Ok(t) => t.0.is_some(),
+            Err(_) => true,
+        }
+    }
+
+    /// Blocks until the reply comes.
+    pub fn wait(self) -> Result<T, Error> {
+        let mut state = match self.slot.state.lock() {
+            Ok(t) => t,
+            Err(_) => return Err(closed()),
+        };
+        loop {
+            if let Some(result) = state.0.take() {
+                return result.and_then(self.convert);
+            }
+            state = match self.slot.ready.wait(state) {
+                Ok(t) => t,
+                Err(_) => return Err(closed()),
+            };
+        }
+    }
+}
+
+impl<T> Future for Pending<T> {
+    type Output = Result<T, Error>;
+
+    fn poll(self : Pin<&mut Self>, context : &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = match self.slot.state.lock() {
+            Ok(t) => t,
+            Err(_) => return Poll::Ready(Err(closed())),
+        };
+        match state.0.take() {
+            Some(result) => Poll::Ready(result.and_then(self.convert)),
+            None => {
+                state.1 = Some(context.waker().clone());
+                Poll::Pending
+            },
+        }
+    }
+}
+
+/// Partial replies of a streamed call, then its final reply, in the order
+/// they come.
+///
+/// Iterating blocks the calling thread until the next one comes, so async
+/// code must not iterate from a task of its executor.
+pub struct Partials<P> {
+    receiver : mpsc::Receiver<Result<Value, Error>>,
+    cancel : Arc<AtomicBool>,
+    convert : fn(Value) -> Result<P, Error>,
+}
+
+impl<P> Partials<P> {
+    /// Asks the core to stop the call, whose final reply is then
+    /// [`CANCELLED`](../stream/constant.CANCELLED.html) unless it was
+    /// already done.
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::SeqCst);
+    }
+}
+
+impl<P> Iterator for Partials<P> {
+    type Item = Result<P, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.receiver.recv() {
+            Ok(Ok(t)) => Some((self.convert)(t)),
+            Ok(Err(e)) => Some(Err(e)),
+            Err(_) => None,
+        }
+    }
+}
+
+/// What an [import](struct.Client.html#method.history_import) sends.
+#[derive(PartialEq, Debug, Clone)]
+pub enum Import {
+    Progress(Progress),
+    Done(ImportReport),
+}
+
+enum Target {
+    Slot(Arc<Slot>),
+    Partials(mpsc::Sender<Result<Value, Error>>),
+}
+
+impl Target {
+    fn fill(self, result : Result<Value, Error>) {
+        match self {
+            Target::Slot(t) => t.fill(result),
+            Target::Partials(t) => {
+                let _ = t.send(result);
+            },
+        }
+    }
+}
+
+struct Command {
+    method : String,
+    params : Table,
+    target : Target,
+    cancel : Arc<AtomicBool>,
+}
+
+/// Answer of `core.hello`.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Hello {
+    pub version : i64,
+    /// Version of the core.
+    pub core : String,
+    pub capabilities : Vec<String>,
+    pub methods : Vec<String>,
+}
+
+/// Answer of `core.ping`.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Ping {
+    pub heartbeat_interval : i64,
+    pub client_timeout : i64,
+    /// Time of the core.
+    pub time : Timestamp,
+}
+
+/// Value of a setting, with where it comes from.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Setting {
+    pub value : Value,
+    /// Name of the [layer](../../data/settings/layers/index.html).
+    pub source : String,
+    /// Whether a policy forbids changing it.
+    pub locked : bool,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Integrity {
+    pub ok : bool,
+    pub messages : Vec<String>,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct PairingCode {
+    pub code : String,
+    /// Time the pairing window stays open, in milliseconds.
+    pub duration : i64,
+}
+
+/// Sequence number and last event of a topic.
+#[derive(PartialEq, Debug, Clone)]
+pub struct TopicState {
+    pub topic : String,
+    pub sequence : u64,
+    pub last : Table,
+}
+
+/// State of the core for a client joining the event bus.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Snapshot {
+    /// Run of the core the sequence numbers belong to, 0 for cores which
+    /// do not tell.
+    pub epoch : i64,
+    pub topics : Vec<TopicState>,
+    pub settings : Settings,
+    pub clients : Vec<ClientInfo>,
+}
+
+/// A notification of the core.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Event {
+    pub topic : String,
+    /// Run of the core which published the event, see
+    /// [`Snapshot::epoch`](struct.Snapshot.html#structfield.epoch).
+    pub epoch : i64,
+    pub sequence : u64,
+    /// Number of events of the topic missed just before this one. Take a
+    /// new snapshot when it is not 0.
+    pub missed : u64,
+    pub payload : Table,
+}
+
+pub struct Client {
+    commands : Mutex<mpsc::Sender<Command>>,
+    thread : Option<thread::JoinHandle<()>>,
+    context : zmq::Context,
+    events_endpoint : Option<String>,
+    keys : Option<(KeyPair, String)>,
+}
+
+impl Client {
+    /// Client of the core answering on `endpoint`.
+    pub fn connect(context : &zmq::Context, endpoint : &str, options : Options) -> Result<Self, Error> {
+        let keys = options.keys.clone();
+        let mut connection = Connection::connect(context, endpoint, options)?;
+
+        let (sender, receiver) = mpsc::channel::<Command>();
+        let thread = thread::spawn(move || {
+            loop {
+                match receiver.recv_timeout(Duration::from_millis(server::POLL_INTERVAL as u64)) {
+                    Ok(command) => {
+                        let Command { method, params, target, cancel } = command;
+                        let stop = || cancel.load(Ordering::SeqCst);
+                        let reply = if stop() {
+                            Err(stream::cancelled())
+                        } else if let Target::Partials(sender) = &target {
+                            connection.stream(&method, params, &stop, &mut |t| {
+                                let _ = sender.send(Ok(Value::Table(t)));
+                            })
+                        } else {
+                            connection.call_cancellable(&method, params, &stop)
+                        };
+                        target.fill(match reply {
+                            Ok(t) => t.result,
+                            Err(e) => Err(e),
+                        });
+                    },
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        if let Err(e) = connection.heartbeat() {
+                            println!("Warning: {:?}", e);
+                        }
+                    },
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            let _ = connection.close();
+        });
+
+        Ok(Self {
+            commands: Mutex::new(sender),
+            thread: Some(thread),
+            context: context.clone(),
+            events_endpoint: None,
+            keys,
+        })
+    }
+
+    /// Client of the core of `profile`, authenticating with `keys` if the
+    /// core requires it.
+    pub fn for_profile(context : &zmq::Context, profile : &Profile, keys : Option<KeyPair>) -> Result<Self, Error> {
+        let mut options = Options::default();
+        if let Some(keys) = keys {
+            match auth::server_key(profile) {
+                Ok(t) => options.keys = Some((keys, t)),
+                Err(e) => return Err(e),
+            }
+        }
+        let endpoint = server::find_endpoint(profile, server::ENDPOINT_FILE, server::endpoint_for(profile))?;
+        let events_endpoint = server::find_endpoint(profile, events::ENDPOINT_FILE, events::endpoint_for(profile))?;
+        match Self::connect(context, &endpoint, options) {
+            Ok(mut t) => {
+                t.set_events_endpoint(&events_endpoint);
+                Ok(t)
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Endpoint of the event bus, for [`events`](#method.events).
+    pub fn set_events_endpoint(&mut self, endpoint : &str) {
+        self.events_endpoint = Some(String::from(endpoint));
+    }
+
+    /// Stream of the events whose topic starts with one of `topics`, every
+    /// event if `topics` is empty.
+    pub fn events(&self, topics : &[&str]) -> Result<Events, Error> {
+        match &self.events_endpoint {
+            Some(t) => Events::subscribe(&self.context, t, topics, self.keys.as_ref()),
+            None => Err(Error::new(Some(events::unavailable().code().unwrap_or(0)), Some(String::from("No event bus endpoint")))),
+        }
+    }
+
+    /// Calls `method` with `params`, for methods without a typed version.
+    pub fn call(&self, method : &str, params : Table) -> Pending<Value> {
+        self.send(method, params, Ok)
+    }
+
+    /// Calls `method` with `params` asking for partial replies, for
+    /// methods without a typed version.
+    pub fn stream(&self, method : &str, params : Table) -> Partials<Value> {
+        self.send_stream(method, params, Ok)
+    }
+
+    fn send<T>(&self, method : &str, params : Table, convert : fn(Value) -> Result<T, Error>) -> Pending<T> {
+        let slot = Arc::new(Slot { state: Mutex::new((None, None)), ready: Condvar::new() });
+        let cancel = Arc::new(AtomicBool::new(false));
+        self.queue(method, params, Target::Slot(slot.clone()), cancel.clone());
+        Pending { slot, cancel, convert }
+    }
+
+    fn send_stream<P>(&self, method : &str, params : Table, convert : fn(Value) -> Result<P, Error>) -> Partials<P> {
+        let (sender, receiver) = mpsc::channel();
+        let cancel = Arc::new(AtomicBool::new(false));
+        self.queue(method, params, Target::Partials(sender), cancel.clone());
+        Partials { receiver, cancel, convert }
+    }
+
+    fn queue(&self, method : &str, params : Table, target : Target, cancel : Arc<AtomicBool>) {
+        let command = Command { method: String::from(method), params, target, cancel };
+        let result = match self.commands.lock() {
+            Ok(t) => t.send(command).map_err(|e| e.0),
+            Err(_) => Err(command),
+        };
+        if let Err(command) = result {
+            command.target.fill(Err(closed()));
+        }
+    }
+
+    pub fn hello(&self, versions : (i64, i64), capabilities : &[&str]) -> Pending<Hello> {
+        let mut params = Table::new();
+        params.insert(String::from("versions"), Value::Array(vec![Value::Integer(versions.0), Value::Integer(versions.1)]));
+        params.insert(String::from("capabilities"), text_list(capabilities));
+        self.send("core.hello", params, |v| {
+            let t = match as_table(v) {
+                Ok(t) => t,
+                Err(e) => return Err(e),
+            };
+            match (integer(&t, "version"), text(&t, "core"), texts(&t, "capabilities"), texts(&t, "methods")) {
+                (Ok(version), Ok(core), Ok(capabilities), Ok(methods)) => Ok(Hello { version, core, capabilities, methods }),
+                (Err(e), _, _, _) | (_, Err(e), _, _) | (_, _, Err(e), _) | (_, _, _, Err(e)) => Err(e),
+            }
+        })
+    }
+
+    pub fn ping(&self) -> Pending<Ping> {
+        self.send("core.ping", Table::new(), |v| {
+            let t = match as_table(v) {
+                Ok(t) => t,
+                Err(e) => return Err(e),
+            };
+            match (integer(&t, "heartbeat_interval"), integer(&t, "client_timeout"), integer(&t, "time")) {
+                (Ok(heartbeat_interval), Ok(client_timeout), Ok(time)) =>
+                    Ok(Ping { heartbeat_interval, client_timeout, time: Timestamp::from_millis(time) }),
+                (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => Err(e),
+            }
+        })
+    }
+
+    /// Asks the core to stop.
+    pub fn shutdown(&self) -> Pending<()> {
+        self.send("core.shutdown", Table::new(), nothing)
+    }
+
+    /// Adds a visit of `url`, returns the id of the entry.
+    pub fn history_add(&self, url : &str, title : &str, mime_type : Option<&str>, date : Option<Timestamp>) -> Pending<i64> {
+        let mut params = Table::new();
+        params.insert(String::from("url"), Value::String(String::from(url)));
+        params.insert(String::from("title"), Value::String(String::from(title)));
+        if let Some(t) = mime_type {
+            params.insert(String::from("mime_type"), Value::String(String::from(t)));
+        }
+        if let Some(t) = date {
+            params.insert(String::from("date"), Value::Integer(t.as_millis()));
+        }
+        self.send("history.add", params, id)
+    }
+
+    pub fn history_entries(&self, filter : &Filter) -> Pending<Vec<Entry>> {
+        self.send("history.entries", filter_params(filter), entries)
+    }
+
+    /// Same as [`history_entries`](#method.history_entries), getting the
+    /// entries by chunks as the core sends them.
+    pub fn history_entries_streamed(&self, filter : &Filter) -> Partials<Vec<Entry>> {
+        self.send_stream("history.entries", filter_params(filter), entries)
+    }
+
+    /// Imports the profile of `browser` located in `profile`, which must be
+    /// readable by the core.
+    pub fn history_import(&self, browser : Browser, profile : &Path) -> Partials<Import> {
+        let mut params = Table::new();
+        params.insert(String::from("browser"), Value::String(String::from(browser.name())));
+        params.insert(String::from("profile"), Value::String(profile.display().to_string()));
+        self.send_stream("history.import", params, |v| {
+            let mut t = match as_table(v) {
+                Ok(t) => t,
+                Err(e) => return Err(e),
+            };
+            if let Some(Value::Table(p)) = t.remove("progress") {
+                let stage = match text(&p, "stage").as_ref().map(String::as_str) {
+                    Ok("history") => Stage::History,
+                    Ok("bookmarks") => Stage::Bookmarks,
+                    _ => return Err(bad_reply("stage")),
+                };
+                return match (integer(&p, "done"), integer(&p, "total")) {
+                    (Ok(done), Ok(total)) => Ok(Import::Progress(Progress { stage, done: done as usize, total: total as usize })),
+                    (Err(e), _) | (_, Err(e)) => Err(e),
+                };
+            }
+            match (integer(&t, "history_imported"), integer(&t, "history_skipped"),
+                   integer(&t, "bookmarks_imported"), integer(&t, "bookmarks_skipped"), texts(&t, "warnings")) {
+                (Ok(history_imported), Ok(history_skipped), Ok(bookmarks_imported), Ok(bookmarks_skipped), Ok(warnings)) =>
+                    Ok(Import::Done(ImportReport {
+                        history_imported: history_imported as usize,
+                        history_skipped: history_skipped as usize,
+                        bookmarks_imported: bookmarks_imported as usize,
+                        bookmarks_skipped: bookmarks_skipped as usize,
+                        warnings,
+                    })),
+                (Err(e), _, _, _, _) | (_, Err(e), _, _, _) | (_, _, Err(e), _, _) |
+                (_, _, _, Err(e), _) | (_, _, _, _, Err(e)) => Err(e),
+            }
+        })
+    }
+
+    pub fn history_clear(&self, range : &TimeRange) -> Pending<ClearReport> {
+        let mut params = Table::new();
+        insert_range(&mut params, range);
+        self.send("history.clear", params, clear_report)
+    }
+
+    pub fn history_forget_site(&self, domain : &str) -> Pending<ClearReport> {
+        let mut params = Table::new();
+        params.insert(String::from("domain"), Value::String(String::from(domain)));
+        self.send("history.forget_site", params, clear_report)
+    }
+
+    pub fn settings_get(&self, key : &str) -> Pending<Setting> {
+        let mut params = Table::new();
+        params.insert(String::from("key"), Value::String(String::from(key)));
+        self.send("settings.get", params, |v| {
+            let mut t = match as_table(v) {
+                Ok(t) => t,
+                Err(e) => return Err(e),
+            };
+            match (t.remove("value"), text(&t, "source"), t.get("locked")) {
+                (Some(value), Ok(source), Some(Value::Boolean(locked))) => Ok(Setting { value, source, locked: *locked }),
+                _ => Err(bad_reply("setting")),
+            }
+        })
+    }
+
+    pub fn settings_set(&self, key : &str, value : Value) -> Pending<Diff> {
+        let mut params = Table::new();
+        params.insert(String::from("key"), Value::String(String::from(key)));
+        params.insert(String::from("value"), value);
+        self.send("settings.set", params, diff)
+    }
+
+    /// Puts back the default value of a key, a section, or every key if
+    /// `scope` is empty.
+    pub fn settings_reset(&self, scope : &str) -> Pending<Diff> {
+        let mut params = Table::new();
+        params.insert(String::from("scope"), Value::String(String::from(scope)));
+        self.send("settings.reset", params, diff)
+    }
+
+    pub fn settings_effective(&self) -> Pending<Settings> {
+        self.send("settings.effective", Table::new(), |v| as_table(v).and_then(Settings::from_document))
+    }
+
+    /// Bindings and conflicts of the keyboard shortcuts, as given by
+    /// [`ShortcutMap::to_table`](../../data/settings/shortcuts/struct.ShortcutMap.html#method.to_table).
+    pub fn settings_shortcuts(&self) -> Pending<Table> {
+        self.send("settings.shortcuts", Table::new(), as_table)
+    }
+
+    pub fn db_tables(&self) -> Pending<Vec<String>> {
+        self.send("db.tables", Table::new(), |v| as_table(v).and_then(|t| texts(&t, "tables")))
+    }
+
+    pub fn db_integrity_check(&self) -> Pending<Integrity> {
+        self.send("db.integrity_check", Table::new(), |v| {
+            let t = match as_table(v) {
+                Ok(t) => t,
+                Err(e) => return Err(e),
+            };
+            match (t.get("ok"), texts(&t, "messages")) {
+                (Some(Value::Boolean(ok)), Ok(messages)) => Ok(Integrity { ok: *ok, messages }),
+                (_, Err(e)) => Err(e),
+                _ => Err(bad_reply("ok")),
+            }
+        })
+    }
+
+    /// State of the core for the topics starting with `prefix`, to call
+    /// after subscribing to the events.
+    pub fn events_snapshot(&self, prefix : &str) -> Pending<Snapshot> {
+        let mut params = Table::new();
+        params.insert(String::from("prefix"), Value::String(String::from(prefix)));
+        self.send("events.snapshot", params, snapshot)
+    }
+
+    /// Pairs this client with the code shown by the core.
+    pub fn auth_pair(&self, code : &str, name : &str) -> Pending<()> {
+        let mut params = Table::new();
+        params.insert(String::from("code"), Value::String(String::from(code)));
+        params.insert(String::from("name"), Value::String(String::from(name)));
+        self.send("auth.pair", params, nothing)
+    }
+
+    pub fn auth_start_pairing(&self) -> Pending<PairingCode> {
+        self.send("auth.start_pairing", Table::new(), |v| {
+            let t = match as_table(v) {
+                Ok(t) => t,
+                Err(e) => return Err(e),
+            };
+            match (text(&t, "code"), integer(&t, "duration")) {
+                (Ok(code), Ok(duration)) => Ok(PairingCode { code, duration }),
+                (Err(e), _) | (_, Err(e)) => Err(e),
+            }
+        })
+    }
+
+    pub fn auth_clients(&self) -> Pending<Vec<auth::Client>> {
+        self.send("auth.clients", Table::new(), |v| {
+            match as_table(v).and_then(|t| array(&t, "clients")) {
+                Ok(a) => a.into_iter().map(|v| {
+                    let t = match as_table(v) {
+                        Ok(t) => t,
+                        Err(e) => return Err(e),
+                    };
+                    match (text(&t, "name"), text(&t, "key"), integer(&t, "paired")) {
+                        (Ok(name), Ok(key), Ok(paired)) => Ok(auth::Client { name, key, paired: Timestamp::from_millis(paired) }),
+                        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => Err(e),
+                    }
+                }).collect(),
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    /// Removes a client from the allowlist, returns `false` if it was not
+    /// there.
+    pub fn auth_revoke(&self, key : &str) -> Pending<bool> {
+        let mut params = Table::new();
+        params.insert(String::from("key"), Value::String(String::from(key)));
+        self.send("auth.revoke", params, |v| match as_table(v).map(|t| t.get("removed").cloned()) {
+            Ok(Some(Value::Boolean(t))) => Ok(t),
+            Ok(_) => Err(bad_reply("removed")),
+            Err(e) => Err(e),
+        })
+    }
+
+    /// Gives the name of this client, returns its id.
+    pub fn clients_register(&self, name : &str) -> Pending<i64> {
+        let mut params = Table::new();
+        params.insert(String::from("name"), Value::String(String::from(name)));
+        self.send("clients.register", params, id)
+    }
+
+    pub fn clients_list(&self) -> Pending<Vec<ClientInfo>> {
+        self.send("clients.list", Table::new(), |v| {
+            match as_table(v).and_then(|t| array(&t, "clients")) {
+                Ok(a) => a.into_iter().map(client_info).collect(),
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    pub fn windows_open(&self, window : i64) -> Pending<()> {
+        self.send("windows.open", ids(&[("window", window)]), nothing)
+    }
+
+    pub fn windows_close(&self, window : i64) -> Pending<()> {
+        self.send("windows.close", ids(&[("window", window)]), nothing)
+    }
+
+    pub fn tabs_open(&self, window : i64, tab : i64, url : &str, title : &str, active : bool) -> Pending<()> {
+        let mut params = ids(&[("window", window), ("tab", tab)]);
+        params.insert(String::from("url"), Value::String(String::from(url)));
+        params.insert(String::from("title"), Value::String(String::from(title)));
+        params.insert(String::from("active"), Value::Boolean(active));
+        self.send("tabs.open", params, nothing)
+    }
+
+    /// Changes what is given of a tab.
+    pub fn tabs_update(&self, tab : i64, url : Option<&str>, title : Option<&str>, active : Option<bool>) -> Pending<()> {
+        let mut params = ids(&[("tab", tab)]);
+        if let Some(t) = url {
+            params.insert(String::from("url"), Value::String(String::from(t)));
+        }
+        if let Some(t) = title {
+            params.insert(String::from("title"), Value::String(String::from(t)));
+        }
+        if let Some(t) = active {
+            params.insert(String::from("active"), Value::Boolean(t));
+        }
+        self.send("tabs.update", params, nothing)
+    }
+
+    pub fn tabs_close(&self, tab : i64) -> Pending<()> {
+        self.send("tabs.close", ids(&[("tab", tab)]), nothing)
+    }
+
+    /// Waits for the pending calls, says goodbye to the core and closes
+    /// the connection.
+    pub fn close(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        // Dropping the sender ends the thread once the queue is empty.
+        let (sender, _) = mpsc::channel();
+        match self.commands.lock() {
+            Ok(mut t) => *t = sender,
+            Err(e) => *e.into_inner() = sender,
+        }
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Events published by the core, in the order they come.
+///
+/// Iterating blocks the calling thread until the next event, like
+/// [`recv`](#method.recv) with a timeout of -1. A timeout of 0 gives the
+/// events already received without waiting.
+pub struct Events {
+    socket : zmq::Socket,
+    /// Run of the core the sequence numbers belong to.
+    epoch : i64,
+    sequences : Vec<(String, u64)>,
+}
+
+impl Events {
+    /// Subscribes on `endpoint` to the topics starting with one of
+    /// `topics`, every topic if it is empty.
+    pub fn subscribe(context : &zmq::Context, endpoint : &str, topics : &[&str], keys : Option<&(KeyPair, String)>) -> Result<Self, Error> {
+        let socket = match context.socket(zmq::SUB) {
+            Ok(t) => t,
+            Err(e) => return Err(socket_error("create the event socket", e)),
+        };
+        if let Err(e) = socket.set_linger(0) {
+            return Err(socket_error("configure the event socket", e));
+        }
+        if let Some((keys, server)) = keys {
+            auth::secure_client(&socket, keys, server)?
+        }
+        let topics = if topics.is_empty() { &[""][..] } else { topics };
+        for t in topics {
+            if let Err(e) = socket.set_subscribe(t.as_bytes()) {
+                return Err(socket_error("subscribe to the events", e));
+            }
+        }
+        if let Err(e) = socket.connect(endpoint) {
+            return Err(socket_error(&format!("connect to {}", endpoint), e));
+        }
+        Ok(Self { socket, epoch: 0, sequences: Vec::new() })
+    }
+
+    /// Skips the events already part of `snapshot`.
+    pub fn apply(&mut self, snapshot : &Snapshot) {
+        self.set_epoch(snapshot.epoch);
+        for t in &snapshot.topics {
+            self.set_sequence(&t.topic, t.sequence);
+        }
+    }
+
+    /// Waits up to `timeout` milliseconds for an event, `-1` to wait
+    /// forever. Returns `None` if none came.
+    pub fn recv(&mut self, timeout : i64) -> Result<Option<Event>, Error> {
+        loop {
+            match self.socket.poll(zmq::POLLIN, timeout) {
+                Ok(0) => return Ok(None),
+                Ok(_) => (),
+                Err(e) => return Err(socket_error("poll the events", e)),
+            }
+            let frames = match self.socket.recv_multipart(0) {
+                Ok(t) => t,
+                Err(e) => return Err(socket_error("receive an event", e)),
+            };
+            let (topic, sequence, payload) = match (frames.first(), frames.get(1), frames.get(2)) {
+                (Some(t), Some(s), Some(p)) => (String::from_utf8_lossy(t).into_owned(),
+                                                 String::from_utf8_lossy(s).parse::<u64>().unwrap_or(0),
+                                                 p),
+                _ => continue,
+            };
+            let epoch = match frames.get(3) {
+                Some(t) => String::from_utf8_lossy(t).parse::<i64>().unwrap_or(0),
+                None => 0,
+            };
+            // Left from a previous run of the core.
+            if epoch < self.epoch {
+                continue;
+            }
+            self.set_epoch(epoch);
+
+            let last = self.sequences.iter().find(|(t, _)| *t == topic).map(|(_, s)| *s).unwrap_or(0);
+            // Already part of a snapshot.
+            if sequence <= last {
+                continue;
+            }
+            let payload = message::decode(payload)?;
+            // Events before the first one received since subscribing were
+            // never expected.
+            let missed = if self.sequences.iter().any(|(t, _)| *t == topic) { sequence - last - 1 } else { 0 };
+            self.set_sequence(&topic, sequence);
+            return Ok(Some(Event { topic, epoch, sequence, missed, payload }));
+        }
+    }
+
+    /// Forgets the sequence numbers of an older run of the core. Topics
+    /// stay known, so events published by the new run before the first one
+    /// received count as missed.
+    fn set_epoch(&mut self, epoch : i64) {
+        if epoch > self.epoch {
+            self.epoch = epoch;
+            for t in self.sequences.iter_mut() {
+                t.1 = 0;
+            }
+        }
+    }
+
+    fn set_sequence(&mut self, topic : &str, sequence : u64) {
+        match self.sequences.iter_mut().find(|(t, _)| t == topic) {
+            Some(t) => t.1 = sequence,
+            None => self.sequences.push((String::from(topic), sequence)),
+        }
+    }
+}
+
+impl Iterator for Events {
+    type Item = Result<Event, Error>;
+
+    /// Waits for the next event.
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.recv(-1) {
+            Ok(Some(t)) => Some(Ok(t)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+fn insert_range(params : &mut Table, range : &TimeRange) {
+    match range {
+        TimeRange::Between(from, to) => {
+            params.insert(String::from("from"), Value::Integer(from.as_millis()));
+            params.insert(String::from("to"), Value::Integer(to.as_millis()));
+        },
+        _ => if let Some(name) = range.name() {
+            params.insert(String::from("range"), Value::String(String::from(name)));
+        },
+    }
+}
+
+fn filter_params(filter : &Filter) -> Table {
+    let mut ret = Table::new();
+    if !filter.categories.is_empty() {
+        ret.insert(String::from("categories"), text_list(&filter.categories.iter().map(|c| c.name()).collect::<Vec<_>>()));
+    }
+    if let Some(t) = &filter.text {
+        ret.insert(String::from("text"), Value::String(t.clone()));
+    }
+    if let Some(t) = &filter.range {
+        insert_range(&mut ret, t);
+    }
+    if let Some(t) = filter.limit {
+        ret.insert(String::from("limit"), Value::Integer(t as i64));
+    }
+    ret
+}
+
+fn ids(values : &[(&str, i64)]) -> Table {
+    values.iter().map(|(k, v)| (String::from(*k), Value::Integer(*v))).collect()
+}
+
+fn text_list(values : &[&str]) -> Value {
+    Value::Array(values.iter().map(|t| Value::String(String::from(*t))).collect())
+}
+
+fn nothing(_ : Value) -> Result<(), Error> {
+    Ok(())
+}
+
+fn id(value : Value) -> Result<i64, Error> {
+    as_table(value).and_then(|t| integer(&t, "id"))
+}
+
+fn as_table(value : Value) -> Result<Table, Error> {
+    match value {
+        Value::Table(t) => Ok(t),
+        _ => Err(bad_reply("payload")),
+    }
+}
+
+fn integer(table : &Table, name : &str) -> Result<i64, Error> {
+    match table.get(name) {
+        Some(Value::Integer(t)) => Ok(*t),
+        _ => Err(bad_reply(name)),
+    }
+}
+
+fn text(table : &Table, name : &str) -> Result<String, Error> {
+    match table.get(name) {
+        Some(Value::String(t)) => Ok(t.clone()),
+        _ => Err(bad_reply(name)),
+    }
+}
+
+fn texts(table : &Table, name : &str) -> Result<Vec<String>, Error> {
+    match table.get(name) {
+        Some(Value::Array(t)) => t.iter().map(|v| match v {
+            Value::String(s) => Ok(s.clone()),
+            _ => Err(bad_reply(name)),
+        }).collect(),
+        _ => Err(bad_reply(name)),
+    }
+}
+
+fn array(table : &Table, name : &str) -> Result<Vec<Value>, Error> {
+    match table.get(name) {
+        Some(Value::Array(t)) => Ok(t.clone()),
+        _ => Err(bad_reply(name)),
+    }
+}
+
+fn entries(value : Value) -> Result<Vec<Entry>, Error> {
+    match as_table(value).and_then(|t| array(&t, "entries")) {
+        Ok(t) => t.into_iter().map(entry).collect(),
+        Err(e) => Err(e),
+    }
+}
+
+fn entry(value : Value) -> Result<Entry, Error> {
+    let t = as_table(value)?;
+    match (integer(&t, "id"), text(&t, "url"), text(&t, "title"), integer(&t, "date"), text(&t, "mime_type"), text(&t, "category")) {
+        (Ok(id), Ok(url), Ok(title), Ok(date), Ok(mime_type), Ok(category)) => match Category::from_name(&category) {
+            Some(category) => Ok(Entry { id, url, title, date: Timestamp::from_millis(date), mime_type, category }),
+            None => Err(bad_reply("category")),
+        },
+        _ => Err(bad_reply("entries")),
+    }
+}
+
+fn snapshot(value : Value) -> Result<Snapshot, Error> {
+    let mut t = as_table(value)?;
+    let epoch = match t.get("epoch") {
+        Some(Value::Integer(e)) => *e,
+        _ => 0,
+    };
+    let settings = match t.remove("settings") {
+        Some(Value::Table(s)) => Settings::from_document(s)?,
+        _ => return Err(bad_reply("settings")),
+    };
+    let topics = match array(&t, "topics") {
+        Ok(a) => a.into_iter().map(|v| {
+            let mut t = match as_table(v) {
+                Ok(t) => t,
+                Err(e) => return Err(e),
+            };
+            match (text(&t, "topic"), integer(&t, "sequence"), t.remove("last")) {
+                (Ok(topic), Ok(sequence), Some(Value::Table(last))) => Ok(TopicState { topic, sequence: sequence as u64, last }),
+                _ => Err(bad_reply("topics")),
+            }
+        }).collect::<Result<Vec<_>, Error>>(),
+        Err(e) => Err(e),
+    };
+    let clients = match array(&t, "clients") {
+        Ok(a) => a.into_iter().map(client_info).collect::<Result<Vec<_>, Error>>(),
+        Err(e) => Err(e),
+    };
+    match (topics, clients) {
+        (Ok(topics), Ok(clients)) => Ok(Snapshot { epoch, topics, settings, clients }),
+        (Err(e), _) | (_, Err(e)) => Err(e),
+    }
+}
+
+fn clear_report(value : Value) -> Result<ClearReport, Error> {
+    let t = as_table(value)?;
+    match (integer(&t, "entries"), texts(&t, "hosts"), integer(&t, "favicons"), integer(&t, "site_settings")) {
+        (Ok(entries), Ok(hosts), Ok(favicons), Ok(site_settings)) => Ok(ClearReport {
+            entries: entries as usize,
+            hosts,
+            favicons: favicons as usize,
+            site_settings: site_settings as usize,
+        }),
+        (Err(e), _, _, _) | (_, Err(e), _, _) | (_, _, Err(e), _) | (_, _, _, Err(e)) => Err(e),
+    }
+}
+
+fn diff(value : Value) -> Result<Diff, Error> {
+    match as_table(value).and_then(|t| array(&t, "changes")) {
+        Ok(a) => a.into_iter().map(|v| {
+            let mut t = match as_table(v) {
+                Ok(t) => t,
+                Err(e) => return Err(e),
+            };
+            match (text(&t, "key"), t.remove("old"), t.remove("new")) {
+                (Ok(key), Some(old), Some(new)) => Ok(Change { key, old, new }),
+                _ => Err(bad_reply("changes")),
+            }
+        }).collect::<Result<Vec<_>, Error>>().map(|changes| Diff { changes }),
+        Err(e) => Err(e),
+    }
+}
+
+fn client_info(value : Value) -> Result<ClientInfo, Error> {
+    let t = as_table(value)?;
+    let windows = match array(&t, "windows") {
+        Ok(a) => a.into_iter().map(|v| {
+            let t = match as_table(v) {
+                Ok(t) => t,
+                Err(e) => return Err(e),
+            };
+            let tabs = match array(&t, "tabs") {
+                Ok(a) => a.into_iter().map(|v| {
+                    let t = match as_table(v) {
+                        Ok(t) => t,
+                        Err(e) => return Err(e),
+                    };
+                    match (integer(&t, "id"), text(&t, "url"), text(&t, "title")) {
+                        (Ok(id), Ok(url), Ok(title)) => Ok(Tab { id, url, title }),
+                        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => Err(e),
+                    }
+                }).collect::<Result<Vec<_>, Error>>(),
+                Err(e) => Err(e),
+            };
+            match (integer(&t, "id"), integer(&t, "active_tab"), tabs) {
+                (Ok(id), Ok(active_tab), Ok(tabs)) => Ok(Window { id, active_tab, tabs }),
+                (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => Err(e),
+            }
+        }).collect::<Result<Vec<_>, Error>>(),
+        Err(e) => Err(e),
+    };
+    match (integer(&t, "id"), text(&t, "name"), integer(&t, "connected"), integer(&t, "last_seen"), windows) {
+        (Ok(id), Ok(name), Ok(connected), Ok(last_seen), Ok(windows)) => Ok(ClientInfo {
+            id,
+            // Only the core knows the connections.
+            identity: Vec::new(),
+            name,
+            key: text(&t, "key").ok(),
+            connected: Timestamp::from_millis(connected),
+            last_seen: Timestamp::from_millis(last_seen),
+            windows,
+        }),
+        (Err(e), _, _, _, _) | (_, Err(e), _, _, _) | (_, _, Err(e), _, _) | (_, _, _, Err(e), _) | (_, _, _, _, Err(e)) => Err(e),
+    }
+}
+
+fn bad_reply(field : &str) -> Error {
+    Error::new(Some(BAD_REPLY), Some(format!("Unexpected reply from the core: missing or invalid {}", field)))
+}
+
+fn closed() -> Error {
+    Error::new(Some(CLOSED), Some(String::from("The client is closed")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use toml::Value;
+    use toml::value::Table;
+
+    use crate::data::db::sqlite::SQLite;
+    use crate::data::profile::Profile;
+    use crate::data::settings::{Settings, Store};
+    use crate::ipc::dispatch::Dispatcher;
+    use crate::ipc::events::{self, EventBus};
+    use crate::ipc::harness::Harness;
+    use crate::ipc::server;
+    use super::{snapshot, Client, Events};
+
+    /// Lets a subscription reach the publisher.
+    fn settle() {
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    fn send(publisher : &zmq::Socket, topic : &str, sequence : u64, epoch : i64) {
+        publisher.send_multipart([topic.as_bytes(), sequence.to_string().as_bytes(), b"", epoch.to_string().as_bytes()], 0).unwrap();
+    }
+
+    #[test]
+    fn gaps_and_restarts_are_reported() {
+        let context = zmq::Context::new();
+        let publisher = context.socket(zmq::PUB).unwrap();
+        publisher.bind("inproc://sielo-client-gaps").unwrap();
+        let mut events = Events::subscribe(&context, "inproc://sielo-client-gaps", &["history."], None).unwrap();
+        settle();
+
+        let mut next = || {
+            let t = events.recv(1000).unwrap().unwrap();
+            (t.epoch, t.sequence, t.missed)
+        };
+        send(&publisher, "history.added", 4, 10);
+        send(&publisher, "history.added", 5, 10);
+        send(&publisher, "history.added", 8, 10);
+        assert_eq!(next(), (10, 4, 0));
+        assert_eq!(next(), (10, 5, 0));
+        assert_eq!(next(), (10, 8, 2));
+
+        // The core restarted: numbers start again and are not skipped.
+        send(&publisher, "history.added", 1, 20);
+        send(&publisher, "history.added", 9, 10);
+        send(&publisher, "history.added", 3, 20);
+        assert_eq!(next(), (20, 1, 0));
+        assert_eq!(next(), (20, 3, 1));
+    }
+
+    #[test]
+    fn snapshot_then_updates() {
+        let context = zmq::Context::new();
+        let bus = Arc::new(Mutex::new(EventBus::bind(&context, "inproc://sielo-client-snapshot").unwrap()));
+        let mut dispatcher = Dispatcher::new(SQLite::new(":memory:").unwrap(), Arc::new(Mutex::new(Store::new(Settings::new()))));
+        dispatcher.set_events(bus.clone());
+        let mut harness = Harness::with_dispatcher(dispatcher).unwrap();
+        let mut events = Events::subscribe(&context, "inproc://sielo-client-snapshot", &["history."], None).unwrap();
+        settle();
+
+        let add = |harness : &mut Harness<SQLite>, url : &str| {
+            let mut params = Table::new();
+            params.insert(String::from("url"), Value::String(String::from(url)));
+            harness.call("history.add", params).unwrap().result.unwrap();
+        };
+        add(&mut harness, "https://a.org/");
+        add(&mut harness, "https://b.org/");
+
+        let mut params = Table::new();
+        params.insert(String::from("prefix"), Value::String(String::from("history.")));
+        let snapshot = snapshot(harness.call("events.snapshot", params).unwrap().result.unwrap()).unwrap();
+        assert_eq!(snapshot.epoch, bus.lock().unwrap().epoch());
+        assert_eq!(snapshot.topics.len(), 1);
+        assert_eq!((snapshot.topics[0].topic.as_str(), snapshot.topics[0].sequence), ("history.added", 2));
+        assert_eq!(snapshot.topics[0].last["url"].as_str(), Some("https://b.org/"));
+        events.apply(&snapshot);
+
+        // Both events were received, but are already part of the snapshot.
+        add(&mut harness, "https://c.org/");
+        let event = events.recv(1000).unwrap().unwrap();
+        assert_eq!((event.sequence, event.missed, event.epoch), (3, 0, snapshot.epoch));
+        assert_eq!(event.payload["url"].as_str(), Some("https://c.org/"));
+        assert!(events.recv(100).unwrap().is_none());
+    }
+
+    #[test]
+    fn events_are_found_through_the_profile() {
+        let directory = std::env::temp_dir().join(format!("sielo-events-endpoint-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let profile = Profile { name: String::from("default"), directory: directory.clone() };
+
+        let context = zmq::Context::new();
+        let mut bus = EventBus::bind(&context, "tcp://127.0.0.1:*").unwrap();
+        server::write_endpoint(&profile, events::ENDPOINT_FILE, &bus.endpoint().unwrap()).unwrap();
+        let client = Client::for_profile(&context, &profile, None).unwrap();
+        let mut subscription = client.events(&["history."]).unwrap();
+        settle();
+
+        bus.publish(events::HISTORY_ADDED, &Table::new()).unwrap();
+        let event = subscription.recv(1000).unwrap().unwrap();
+        assert_eq!((event.topic.as_str(), event.sequence), (events::HISTORY_ADDED, 1));
+        drop(client);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
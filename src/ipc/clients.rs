@@ -0,0 +1,201 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Clients connected to the core
+//!
+//! Several UIs can share the core of a profile, like a desktop UI and a
+//! debugging console. A client is known by the routing identity of its
+//! connection, from its first request on. It gives its name with
+//! `clients.register` and keeps the core told about its windows and tabs,
+//! whose ids it chooses.
+//!
+//! Every change is published on the [event bus](../events/index.html), so
+//! all clients see the windows of the others. A client leaves with
+//! `clients.bye`, or is forgotten when it sent nothing, not even a
+//! `core.ping` heartbeat, for the `ipc.client_timeout` setting. Clients of
+//! the JSON-RPC bridge send no heartbeat: they are only forgotten once
+//! their connection is closed.
+
+use toml::Value;
+use toml::value::Table;
+
+use crate::data::time::Timestamp;
+
+/// Start of the identities of the clients of the JSON-RPC bridge, which
+/// never expire.
+pub const BRIDGE_IDENTITY : &[u8] = b"\0jsonrpc-";
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Tab {
+    pub id : i64,
+    pub url : String,
+    pub title : String,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Window {
+    pub id : i64,
+    /// Id of the active tab, 0 if the window has no tab.
+    pub active_tab : i64,
+    pub tabs : Vec<Tab>,
+}
+
+impl Window {
+    pub fn tab_mut(&mut self, id : i64) -> Option<&mut Tab> {
+        self.tabs.iter_mut().find(|t| t.id == id)
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Client {
+    /// Id of the client for the other clients, never reused.
+    pub id : i64,
+    /// Routing identity of the connection.
+    pub identity : Vec<u8>,
+    pub name : String,
+    /// Public key of the client, if it authenticated.
+    pub key : Option<String>,
+    pub connected : Timestamp,
+    pub last_seen : Timestamp,
+    pub windows : Vec<Window>,
+}
+
+impl Client {
+    pub fn window_mut(&mut self, id : i64) -> Option<&mut Window> {
+        self.windows.iter_mut().find(|w| w.id == id)
+    }
+
+    /// Window holding the tab `id`.
+    pub fn window_of_tab(&mut self, id : i64) -> Option<&mut Window> {
+        self.windows.iter_mut().find(|w| w.tabs.iter().any(|t| t.id == id))
+    }
+
+    pub fn to_table(&self) -> Table {
+        let mut ret = Table::new();
+        ret.insert(String::from("id"), Value::Integer(self.id));
+        ret.insert(String::from("name"), Value::String(self.name.clone()));
+        if let Some(key) = &self.key {
+            ret.insert(String::from("key"), Value::String(key.clone()));
+        }
+        ret.insert(String::from("connected"), Value::Integer(self.connected.as_millis()));
+        ret.insert(String::from("last_seen"), Value::Integer(self.last_seen.as_millis()));
+        ret.insert(String::from("windows"), Value::Array(self.windows.iter().map(|w| Value::Table(window_table(w))).collect()));
+        ret
+    }
+}
+
+pub struct Clients {
+    clients : Vec<Client>,
+    next_id : i64,
+    timeout : i64,
+}
+
+impl Clients {
+    /// Clients forgotten after `timeout` milliseconds of silence.
+    pub fn new(timeout : i64) -> Self {
+        Self { clients: Vec::new(), next_id: 1, timeout }
+    }
+
+    pub fn timeout(&self) -> i64 {
+        self.timeout
+    }
+
+    /// Forgets clients silent for `timeout` milliseconds.
+    pub fn set_timeout(&mut self, timeout : i64) {
+        self.timeout = timeout;
+    }
+
+    pub fn list(&self) -> &[Client] {
+        &self.clients
+    }
+
+    pub fn get(&self, identity : &[u8]) -> Option<&Client> {
+        self.clients.iter().find(|c| c.identity == identity)
+    }
+
+    pub fn get_mut(&mut self, identity : &[u8]) -> Option<&mut Client> {
+        self.clients.iter_mut().find(|c| c.identity == identity)
+    }
+
+    /// Notes that the client with `identity` sent a request at `now`.
+    /// Returns the client and whether it is new.
+    pub fn touch(&mut self, identity : &[u8], key : Option<&str>, now : Timestamp) -> (&mut Client, bool) {
+        let index = match self.clients.iter().position(|c| c.identity == identity) {
+            Some(t) => t,
+            None => {
+                self.clients.push(Client {
+                    id: self.next_id,
+                    identity: identity.to_vec(),
+                    name: String::new(),
+                    key: key.map(String::from),
+                    connected: now,
+                    last_seen: now,
+                    windows: Vec::new(),
+                });
+                self.next_id += 1;
+                return (self.clients.last_mut().unwrap(), true);
+            },
+        };
+        let client = &mut self.clients[index];
+        client.last_seen = now;
+        (client, false)
+    }
+
+    pub fn remove(&mut self, identity : &[u8]) -> Option<Client> {
+        match self.clients.iter().position(|c| c.identity == identity) {
+            Some(t) => Some(self.clients.remove(t)),
+            None => None,
+        }
+    }
+
+    /// Removes and returns the clients silent since the timeout at `now`,
+    /// except the ones of the JSON-RPC bridge.
+    pub fn expire(&mut self, now : Timestamp) -> Vec<Client> {
+        let limit = now.add_millis(-self.timeout);
+        let (expired, kept) = self.clients.drain(..)
+            .partition(|c| c.last_seen < limit && !c.identity.starts_with(BRIDGE_IDENTITY));
+        self.clients = kept;
+        expired
+    }
+
+    pub fn to_table(&self) -> Table {
+        let mut ret = Table::new();
+        ret.insert(String::from("clients"), Value::Array(self.clients.iter().map(|c| Value::Table(c.to_table())).collect()));
+        ret
+    }
+}
+
+fn window_table(window : &Window) -> Table {
+    let mut ret = Table::new();
+    ret.insert(String::from("id"), Value::Integer(window.id));
+    ret.insert(String::from("active_tab"), Value::Integer(window.active_tab));
+    ret.insert(String::from("tabs"), Value::Array(window.tabs.iter().map(|t| {
+        let mut tab = Table::new();
+        tab.insert(String::from("id"), Value::Integer(t.id));
+        tab.insert(String::from("url"), Value::String(t.url.clone()));
+        tab.insert(String::from("title"), Value::String(t.title.clone()));
+        Value::Table(tab)
+    }).collect()));
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn silent_clients_expire_except_bridged_ones() {
+        let mut clients = Clients::new(1000);
+        let start = Timestamp::from_millis(10_000);
+        clients.touch(b"\0\x80\0\0\x29", None, start);
+        clients.touch(b"\0jsonrpc-1", None, start);
+        clients.touch(b"\0\x80\0\0\x2a", None, start.add_millis(900));
+
+        let expired : Vec<Vec<u8>> = clients.expire(start.add_millis(1500)).into_iter().map(|c| c.identity).collect();
+        assert_eq!(expired, vec![b"\0\x80\0\0\x29".to_vec()]);
+        let kept : Vec<&[u8]> = clients.list().iter().map(|c| &c.identity[..]).collect();
+        assert_eq!(kept, vec![&b"\0jsonrpc-1"[..], &b"\0\x80\0\0\x2a"[..]]);
+    }
+}
@@ -0,0 +1,415 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Connection of a client to the core
+//!
+//! A [`Connection`](struct.Connection.html) sends requests on a `DEALER`
+//! socket and waits for their replies, keeping the link alive:
+//!  * [`heartbeat`](struct.Connection.html#method.heartbeat), called from
+//!    the event loop of the UI, pings the core with `core.ping` when the
+//!    connection was idle for the heartbeat interval, so the core does not
+//!    forget the client and the client learns when the core is gone. The
+//!    interval is the one the core gives in its reply.
+//!  * A request without reply within the timeout makes the connection
+//!    reconnect. [Idempotent](../dispatch/fn.is_idempotent.html) requests
+//!    are then sent again, others fail with [`LOST`](constant.LOST.html) as
+//!    the core may have applied them.
+//!  * The routing identity is kept across reconnections, so the core sees
+//!    the same client, with its windows, as long as it did not time out.
+//!  * [`close`](struct.Connection.html#method.close) says goodbye with
+//!    `clients.bye` before closing the socket.
+//!  * [`stream`](struct.Connection.html#method.stream) gets the partial
+//!    replies of a request, and can cancel it with `core.cancel`. Every
+//!    partial reply restarts the timeout.
+//!
+//! When the core stops it publishes `core.shutdown` on the
+//! [event bus](../events/index.html), and keeps answering until every
+//! client [closed](struct.Connection.html#method.close) its connection or a
+//! [grace period](../server/constant.SHUTDOWN_GRACE.html) is over.
+
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use toml::Value;
+use toml::value::Table;
+
+use crate::data::db::Error;
+use crate::data::time::Timestamp;
+use super::auth::{self, KeyPair};
+use super::dispatch;
+use super::message::{Request, Reply};
+use super::server::{socket_error, POLL_INTERVAL};
+
+/// Time to wait for a reply, in milliseconds.
+pub const TIMEOUT : i64 = 3000;
+/// Number of times an idempotent request is sent again.
+pub const RETRIES : u32 = 2;
+/// Delay between two heartbeats until the core gives its own, in
+/// milliseconds.
+pub const HEARTBEAT_INTERVAL : i64 = 5000;
+
+/// The core did not answer.
+pub const LOST : isize = 6014;
+
+static NEXT_IDENTITY : AtomicUsize = AtomicUsize::new(0);
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Options {
+    /// Time to wait for a reply, in milliseconds.
+    pub timeout : i64,
+    pub retries : u32,
+    /// Routing identity, one is made up if `None`.
+    pub identity : Option<Vec<u8>>,
+    /// Keypair of the client and public key of the core, to connect to an
+    /// [authenticating](../auth/index.html) core.
+    pub keys : Option<(KeyPair, String)>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self { timeout: TIMEOUT, retries: RETRIES, identity: None, keys: None }
+    }
+}
+
+pub struct Connection {
+    context : zmq::Context,
+    endpoint : String,
+    options : Options,
+    identity : Vec<u8>,
+    socket : zmq::Socket,
+    next_id : i64,
+    heartbeat_interval : i64,
+    last_activity : Instant,
+    reconnections : u32,
+}
+
+impl Connection {
+    /// Connects to the core on `endpoint`. ZeroMQ connects in the
+    /// background, so this does not fail when the core is not running yet.
+    pub fn connect(context : &zmq::Context, endpoint : &str, options : Options) -> Result<Self, Error> {
+        let identity = match &options.identity {
+            Some(t) => t.clone(),
+            None => format!("sielo-{}-{}-{}", process::id(), Timestamp::now().as_millis(),
+                            NEXT_IDENTITY.fetch_add(1, Ordering::SeqCst)).into_bytes(),
+        };
+        let socket = open(context, endpoint, &identity, &options)?;
+        Ok(Self {
+            context: context.clone(),
+            endpoint: String::from(endpoint),
+            options,
+            identity,
+            socket,
+            next_id: 1,
+            heartbeat_interval: HEARTBEAT_INTERVAL,
+            last_activity: Instant::now(),
+            reconnections: 0,
+        })
+    }
+
+    pub fn identity(&self) -> &[u8] {
+        &self.identity
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// Number of times the connection was opened again.
+    pub fn reconnections(&self) -> u32 {
+        self.reconnections
+    }
+
+    /// Calls `method` with `params`, and returns the reply of the core,
+    /// which may carry an error of the method.
+    pub fn call(&mut self, method : &str, params : Table) -> Result<Reply, Error> {
+        self.request(method, params, false, &|| false, &mut |_| ())
+    }
+
+    /// Same as [`call`](#method.call), cancelling the request as soon as
+    /// `cancel` returns `true`.
+    pub fn call_cancellable(&mut self, method : &str, params : Table, cancel : &dyn Fn() -> bool) -> Result<Reply, Error> {
+        self.request(method, params, false, cancel, &mut |_| ())
+    }
+
+    /// Same as [`call_cancellable`](#method.call_cancellable), giving the
+    /// payload of every partial reply to `partial`.
+    pub fn stream(&mut self, method : &str, params : Table, cancel : &dyn Fn() -> bool,
+                  partial : &mut dyn FnMut(Table)) -> Result<Reply, Error> {
+        self.request(method, params, true, cancel, partial)
+    }
+
+    fn request(&mut self, method : &str, params : Table, stream : bool, cancel : &dyn Fn() -> bool,
+               partial : &mut dyn FnMut(Table)) -> Result<Reply, Error> {
+        let mut request = Request::new(self.next_id, method, params);
+        request.stream = stream;
+        self.next_id += 1;
+        let body = request.to_bytes()?;
+
+        let attempts = if dispatch::is_idempotent(&request) { self.options.retries + 1 } else { 1 };
+        for attempt in 0..attempts {
+            if attempt > 0 {
+                self.reconnect()?
+            }
+            match self.exchange(&body, request.id, cancel, partial) {
+                Ok(Some(t)) => return Ok(t),
+                Ok(None) => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        // Replies to the lost request must not be taken for the next ones.
+        self.reconnect()?;
+        Err(Error::new(Some(LOST), Some(if attempts > 1 {
+            format!("No reply from the core to {}", method)
+        } else {
+            format!("No reply from the core to {}, it may or may not have been applied", method)
+        })))
+    }
+
+    /// Pings the core if nothing was exchanged for the heartbeat interval.
+    /// Returns `false` if it was not time yet, and an error if the core did
+    /// not answer.
+    pub fn heartbeat(&mut self) -> Result<bool, Error> {
+        if self.last_activity.elapsed() < Duration::from_millis(self.heartbeat_interval as u64) {
+            return Ok(false);
+        }
+        self.ping().map(|_| true)
+    }
+
+    /// Pings the core now, and adopts its heartbeat interval.
+    pub fn ping(&mut self) -> Result<(), Error> {
+        let reply = self.call("core.ping", Table::new())?;
+        match reply.result {
+            Ok(t) => {
+                if let Some(interval) = t.get("heartbeat_interval").and_then(|v| v.as_integer()) {
+                    self.heartbeat_interval = interval;
+                }
+                Ok(())
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Says goodbye to the core and closes the connection.
+    pub fn close(mut self) -> Result<(), Error> {
+        match self.call("clients.bye", Table::new()) {
+            Ok(t) => t.result.map(|_| ()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn reconnect(&mut self) -> Result<(), Error> {
+        match open(&self.context, &self.endpoint, &self.identity, &self.options) {
+            Ok(t) => {
+                self.socket = t;
+                self.reconnections += 1;
+                Ok(())
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Sends `body` and waits for the final reply to the request `id`,
+    /// skipping late replies to older requests. Returns `None` on timeout.
+    fn exchange(&mut self, body : &[u8], id : i64, cancel : &dyn Fn() -> bool,
+                partial : &mut dyn FnMut(Table)) -> Result<Option<Reply>, Error> {
+        if let Err(e) = self.socket.send(body, 0) {
+            return Err(socket_error("send a request", e));
+        }
+        let timeout = Duration::from_millis(self.options.timeout as u64);
+        let mut deadline = Instant::now() + timeout;
+        let mut cancelled = false;
+        loop {
+            if !cancelled && cancel() {
+                self.cancel(id)?;
+                cancelled = true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            // Wakes up from time to time to look for a cancellation.
+            match self.socket.poll(zmq::POLLIN, ((deadline - now).as_millis() as i64).min(POLL_INTERVAL)) {
+                Ok(0) => continue,
+                Ok(_) => (),
+                Err(e) => return Err(socket_error("poll the connection", e)),
+            }
+            let bytes = match self.socket.recv_bytes(0) {
+                Ok(t) => t,
+                Err(e) => return Err(socket_error("receive a reply", e)),
+            };
+            self.last_activity = Instant::now();
+            match Reply::from_bytes(&bytes) {
+                Ok(t) if t.id == id && t.partial => {
+                    deadline = self.last_activity + timeout;
+                    if let Ok(Value::Table(payload)) = t.result {
+                        partial(payload);
+                    }
+                },
+                Ok(t) if t.id == id => return Ok(Some(t)),
+                Ok(_) => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Asks the core to cancel the request `id`, without waiting for the
+    /// reply, which is skipped as any late reply.
+    fn cancel(&mut self, id : i64) -> Result<(), Error> {
+        let mut params = Table::new();
+        params.insert(String::from("id"), Value::Integer(id));
+        let body = Request::new(self.next_id, "core.cancel", params).to_bytes()?;
+        self.next_id += 1;
+        match self.socket.send(body, 0) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(socket_error("cancel a request", e)),
+        }
+    }
+}
+
+fn open(context : &zmq::Context, endpoint : &str, identity : &[u8], options : &Options) -> Result<zmq::Socket, Error> {
+    let socket = match context.socket(zmq::DEALER) {
+        Ok(t) => t,
+        Err(e) => return Err(socket_error("create the client socket", e)),
+    };
+    if let Err(e) = socket.set_linger(0).and_then(|_| socket.set_identity(identity)) {
+        return Err(socket_error("configure the client socket", e));
+    }
+    if let Some((keys, server)) = &options.keys {
+        auth::secure_client(&socket, keys, server)?
+    }
+    if let Err(e) = socket.connect(endpoint) {
+        return Err(socket_error(&format!("connect to {}", endpoint), e));
+    }
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use toml::Value;
+    use toml::value::Table;
+
+    use crate::data::db::Error;
+    use crate::data::db::sqlite::SQLite;
+    use crate::data::settings::{Settings, Store};
+    use crate::ipc::dispatch::Dispatcher;
+    use crate::ipc::harness::Harness;
+    use crate::ipc::message::Reply;
+    use crate::ipc::server::Server;
+    use super::*;
+
+    fn dispatcher() -> Dispatcher<SQLite> {
+        Dispatcher::new(SQLite::new(":memory:").unwrap(), Arc::new(Mutex::new(Store::new(Settings::new()))))
+    }
+
+    fn options(identity : &str) -> Options {
+        Options { timeout: 300, identity: Some(identity.as_bytes().to_vec()), ..Options::default() }
+    }
+
+    fn name(value : &str) -> Table {
+        let mut ret = Table::new();
+        ret.insert(String::from("name"), Value::String(String::from(value)));
+        ret
+    }
+
+    /// Answers with `server` until `client` is done, and returns its result.
+    fn serve<R>(server : &mut Server<SQLite>, client : thread::JoinHandle<R>) -> R {
+        while !client.is_finished() {
+            server.poll(10).unwrap();
+        }
+        client.join().unwrap()
+    }
+
+    /// Calls `method` from another thread while the core crashes before
+    /// answering and starts again. Returns the result of the call and the
+    /// number of reconnections.
+    fn call_across_restart(endpoint : &'static str, method : &'static str, params : Table) -> (Result<Reply, Error>, u32) {
+        let context = zmq::Context::new();
+        let crashed = Server::bind(&context, endpoint, dispatcher()).unwrap();
+        let client_context = context.clone();
+        let client = thread::spawn(move || {
+            let mut connection = Connection::connect(&client_context, endpoint, options("ui")).unwrap();
+            let reply = connection.call(method, params);
+            (reply, connection.reconnections())
+        });
+
+        assert_eq!(crashed.socket().poll(zmq::POLLIN, 1000).unwrap(), 1);
+        drop(crashed);
+        // The endpoint is released in the background.
+        let mut restarted = loop {
+            match Server::bind(&context, endpoint, dispatcher()) {
+                Ok(t) => break t,
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        serve(&mut restarted, client)
+    }
+
+    #[test]
+    fn restarted_client_keeps_its_identity() {
+        let context = zmq::Context::new();
+        let mut server = Server::bind(&context, "inproc://sielo-connection-identity", dispatcher()).unwrap();
+        let client_context = context.clone();
+        let client = thread::spawn(move || {
+            let endpoint = "inproc://sielo-connection-identity";
+            let mut first = Connection::connect(&client_context, endpoint, options("ui-1")).unwrap();
+            first.call("clients.register", name("Main")).unwrap().result.unwrap();
+            first.call("windows.open", [(String::from("window"), Value::Integer(1))].iter().cloned().collect()).unwrap();
+            // Killed without saying goodbye.
+            drop(first);
+
+            let mut second = Connection::connect(&client_context, endpoint, options("ui-1")).unwrap();
+            assert_eq!(second.identity(), b"ui-1");
+            second.call("core.ping", Table::new()).unwrap().result.unwrap();
+        });
+        serve(&mut server, client);
+
+        let clients = server.dispatcher().clients().list();
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients[0].name, "Main");
+        assert_eq!(clients[0].windows.len(), 1);
+    }
+
+    #[test]
+    fn idempotent_call_is_sent_again() {
+        let mut params = Table::new();
+        params.insert(String::from("key"), Value::String(String::from("general.language")));
+        let (reply, reconnections) = call_across_restart("inproc://sielo-connection-replay", "settings.get", params);
+        assert_eq!(reply.unwrap().result.unwrap()["value"].as_str(), Some("en"));
+        assert_eq!(reconnections, 1);
+    }
+
+    #[test]
+    fn other_call_is_lost() {
+        let mut params = Table::new();
+        params.insert(String::from("url"), Value::String(String::from("https://sielo.app")));
+        let (reply, _) = call_across_restart("inproc://sielo-connection-lost", "history.add", params);
+        assert_eq!(reply.unwrap_err().code(), Some(LOST));
+    }
+
+    #[test]
+    fn silent_client_expires() {
+        let dispatcher = dispatcher();
+        dispatcher.settings().lock().unwrap().set("ipc.client_timeout", Value::Integer(500)).unwrap();
+        let mut harness = Harness::with_dispatcher(dispatcher).unwrap();
+
+        // Heartbeats keep the client known.
+        for _ in 0..3 {
+            harness.call("core.ping", Table::new()).unwrap().result.unwrap();
+            thread::sleep(Duration::from_millis(250));
+        }
+        harness.server().poll(0).unwrap();
+        assert_eq!(harness.server().dispatcher().clients().list().len(), 1);
+
+        thread::sleep(Duration::from_millis(400));
+        harness.server().poll(0).unwrap();
+        assert!(harness.server().dispatcher().clients().list().is_empty());
+    }
+}
@@ -0,0 +1,827 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Dispatch of requests to the data subsystems
+//!
+//! Method names are `<subsystem>.<action>`. Every method takes its
+//! parameters from the `params` table of the request and returns a TOML
+//! value, most of the time a table.
+//!
+//! Requests coming from the server carry the [caller](struct.Caller.html),
+//! so the dispatcher keeps track of the [clients](../clients/index.html)
+//! and their windows, and a [stream](../stream/index.html) for partial
+//! replies and cancellation.
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use toml::Value;
+use toml::value::Table;
+
+use crate::data::db::{Error, TableProvider, FieldValue};
+use crate::data::history::{History, Filter, TimeRange, ClearReport, Entry};
+use crate::data::import::{self, Browser, ImportReport, Progress};
+use crate::data::mime::Category;
+use crate::data::settings::{schema, Settings, Store, Diff};
+use crate::data::time::Timestamp;
+use super::clients::{Clients, Client, Window, Tab};
+use super::events::{self, EventBus};
+use super::message::{self, Request, Reply, INVALID_PARAMS, UNSUPPORTED_VERSION};
+use super::stream::{self, Stream, NoStream};
+
+/// Error code of a call to a method the core does not know.
+pub const UNKNOWN_METHOD : isize = 6004;
+
+/// Optional features of the core, negotiated with `core.hello`.
+pub const CAPABILITIES : &[&str] = &[
+    "history",
+    "settings",
+    "db",
+    "events",
+    "auth",
+    "clients",
+    "stream",
+];
+
+/// Every method answered by the dispatcher.
+pub const METHODS : &[&str] = &[
+    "core.hello",
+    "core.ping",
+    "core.shutdown",
+    "core.cancel",
+    "history.add",
+    "history.entries",
+    "history.clear",
+    "history.forget_site",
+    "history.import",
+    "settings.get",
+    "settings.set",
+    "settings.reset",
+    "settings.effective",
+    "settings.shortcuts",
+    "db.tables",
+    "db.integrity_check",
+    "events.snapshot",
+    "auth.pair",
+    "auth.start_pairing",
+    "auth.clients",
+    "auth.revoke",
+    "clients.register",
+    "clients.list",
+    "clients.bye",
+    "windows.open",
+    "windows.close",
+    "tabs.open",
+    "tabs.update",
+    "tabs.close",
+];
+
+/// Methods which can be sent again without changing the result, like
+/// after a lost connection. `history.clear` only is with explicit bounds,
+/// see [`is_idempotent`](fn.is_idempotent.html).
+pub const IDEMPOTENT : &[&str] = &[
+    "core.hello",
+    "core.ping",
+    "core.cancel",
+    "history.entries",
+    "history.forget_site",
+    "settings.get",
+    "settings.set",
+    "settings.reset",
+    "settings.effective",
+    "settings.shortcuts",
+    "db.tables",
+    "db.integrity_check",
+    "events.snapshot",
+    "auth.clients",
+    "auth.revoke",
+    "clients.register",
+    "clients.list",
+    "windows.open",
+    "windows.close",
+    "tabs.update",
+];
+
+/// Whether `request` can be sent again without changing the result. A
+/// relative range of the history ends when the request runs, so clearing it
+/// again later also removes the visits made in between.
+pub fn is_idempotent(request : &Request) -> bool {
+    match request.method.as_str() {
+        "history.clear" => !request.params.contains_key("range")
+            && request.params.contains_key("from") && request.params.contains_key("to"),
+        t => IDEMPOTENT.contains(&t),
+    }
+}
+
+/// Error code of a method needing a client, called without one.
+pub const NO_CLIENT : isize = 6013;
+
+/// Connection a request comes from.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Caller<'a> {
+    /// Routing identity of the connection, empty for in-process calls.
+    pub identity : &'a [u8],
+    /// Public key of the client, if it authenticated.
+    pub key : Option<&'a str>,
+}
+
+impl<'a> Caller<'a> {
+    /// Caller not tracked as a client.
+    pub const ANONYMOUS : Caller<'static> = Caller { identity: &[], key: None };
+}
+
+pub struct Dispatcher<T : TableProvider> {
+    db : T,
+    settings : Arc<Mutex<Store>>,
+    events : Option<Arc<Mutex<EventBus>>>,
+    clients : Clients,
+    stopping : bool,
+}
+
+impl<T : TableProvider> Dispatcher<T> {
+    /// Dispatcher answering with the database `db` and the settings
+    /// `settings` of the profile.
+    pub fn new(db : T, settings : Arc<Mutex<Store>>) -> Self {
+        let timeout = match settings.lock() {
+            Ok(t) => t.effective().ipc().client_timeout,
+            Err(_) => Settings::new().ipc().client_timeout,
+        };
+        Self { db, settings, events: None, clients: Clients::new(timeout), stopping: false }
+    }
+
+    /// Publishes the changes made by requests on `bus`.
+    pub fn set_events(&mut self, bus : Arc<Mutex<EventBus>>) {
+        self.events = Some(bus);
+    }
+
+    pub fn db(&mut self) -> &mut T {
+        &mut self.db
+    }
+
+    pub fn settings(&self) -> &Arc<Mutex<Store>> {
+        &self.settings
+    }
+
+    pub fn clients(&mut self) -> &mut Clients {
+        &mut self.clients
+    }
+
+    /// Calls the method of `request` and builds the reply.
+    pub fn dispatch(&mut self, request : &Request) -> Reply {
+        self.dispatch_from(&Caller::ANONYMOUS, request)
+    }
+
+    /// Calls the method of `request` sent by `caller` and builds the reply.
+    pub fn dispatch_from(&mut self, caller : &Caller, request : &Request) -> Reply {
+        self.dispatch_stream(caller, request, &NoStream)
+    }
+
+    /// Calls the method of `request` sent by `caller`, sending its partial
+    /// replies on `stream`, and builds the final reply.
+    pub fn dispatch_stream(&mut self, caller : &Caller, request : &Request, stream : &dyn Stream) -> Reply {
+        if !caller.identity.is_empty() {
+            let (client, new) = self.clients.touch(caller.identity, caller.key, Timestamp::now());
+            if new {
+                let event = client.to_table();
+                self.publish(events::CLIENT_CONNECTED, &event);
+            }
+        }
+        // The error of an aborted database request says little.
+        let result = match self.call(caller, request, stream) {
+            Err(_) if stream.is_cancelled() => Err(stream::cancelled()),
+            t => t,
+        };
+        Reply::to(request, result)
+    }
+
+    /// Forgets the clients silent for too long, as if they left.
+    pub fn expire_clients(&mut self, now : Timestamp) {
+        let timeout = match self.store() {
+            Ok(t) => t.effective().ipc().client_timeout,
+            Err(_) => self.clients.timeout(),
+        };
+        self.clients.set_timeout(timeout);
+        for client in self.clients.expire(now) {
+            self.client_left(&client, "timeout");
+        }
+    }
+
+    /// Forgets the client of `identity`, whose connection was closed.
+    pub fn disconnect(&mut self, identity : &[u8]) {
+        if let Some(client) = self.clients.remove(identity) {
+            self.client_left(&client, "closed");
+        }
+    }
+
+    /// Whether a client asked the core to stop with `core.shutdown`.
+    pub fn is_stopping(&self) -> bool {
+        self.stopping
+    }
+
+    /// Tells the clients the core stops, and why.
+    pub fn shutdown(&mut self, reason : &str) {
+        self.stopping = true;
+        let mut event = Table::new();
+        event.insert(String::from("reason"), Value::String(String::from(reason)));
+        self.publish(events::CORE_SHUTDOWN, &event);
+    }
+
+    fn call(&mut self, caller : &Caller, request : &Request, stream : &dyn Stream) -> Result<Value, Error> {
+        match request.method.as_str() {
+            "core.hello" => hello(request),
+            "core.ping" => match self.store() {
+                Ok(s) => {
+                    let ipc = s.effective().ipc();
+                    let mut ret = Table::new();
+                    ret.insert(String::from("heartbeat_interval"), Value::Integer(ipc.heartbeat_interval));
+                    ret.insert(String::from("client_timeout"), Value::Integer(ipc.client_timeout));
+                    ret.insert(String::from("time"), Value::Integer(Timestamp::now().as_millis()));
+                    Ok(Value::Table(ret))
+                },
+                Err(e) => Err(e),
+            },
+            "core.shutdown" => {
+                self.stopping = true;
+                Ok(Value::Table(Table::new()))
+            },
+            // The server answers the cancellation of a running request, so
+            // the request is already done.
+            "core.cancel" => match request.optional_integer("id") {
+                Ok(Some(_)) => {
+                    let mut ret = Table::new();
+                    ret.insert(String::from("cancelled"), Value::Boolean(false));
+                    Ok(Value::Table(ret))
+                },
+                Ok(None) => Err(Error::new(Some(INVALID_PARAMS), Some(String::from("Missing parameter id")))),
+                Err(e) => Err(e),
+            },
+            "history.add" => self.history_add(request),
+            "history.entries" => self.history_entries(request, stream),
+            "history.clear" => self.history_clear(request, stream),
+            "history.forget_site" => self.history_forget_site(request, stream),
+            "history.import" => self.history_import(request, stream),
+            "settings.get" => self.settings_get(request),
+            "settings.set" => self.settings_set(request),
+            "settings.reset" => self.settings_reset(request),
+            "settings.effective" => match self.store() {
+                Ok(s) => Ok(Value::Table(s.effective().document().clone())),
+                Err(e) => Err(e),
+            },
+            "settings.shortcuts" => match self.store() {
+                Ok(s) => Ok(Value::Table(s.effective().shortcuts().to_table())),
+                Err(e) => Err(e),
+            },
+            "db.tables" => self.db_tables(),
+            "db.integrity_check" => self.db_integrity_check(stream),
+            "events.snapshot" => self.events_snapshot(request),
+            "clients.list" => Ok(Value::Table(self.clients.to_table())),
+            "clients.register" | "clients.bye" | "windows.open" | "windows.close" |
+            "tabs.open" | "tabs.update" | "tabs.close" => self.client_call(caller, request),
+            // Answered by the server when it authenticates its clients.
+            t if t.starts_with("auth.") => Err(Error::new(Some(UNKNOWN_METHOD), Some(String::from("Authentication is not enabled")))),
+            t => Err(Error::new(Some(UNKNOWN_METHOD), Some(format!("Unknown method {}", t)))),
+        }
+    }
+
+    fn store(&self) -> Result<MutexGuard<'_, Store>, Error> {
+        match self.settings.lock() {
+            Ok(t) => Ok(t),
+            Err(_) => Err(Error::new(Some(6005), Some(String::from("Settings are not available")))),
+        }
+    }
+
+    fn history_add(&mut self, request : &Request) -> Result<Value, Error> {
+        let url = request.text("url")?;
+        let (title, mime_type, date) = match (request.optional_text("title"),
+                                              request.optional_text("mime_type"),
+                                              request.optional_integer("date")) {
+            (Ok(t), Ok(m), Ok(d)) => (t.unwrap_or(""),
+                                      m.unwrap_or(crate::data::mime::UNKNOWN),
+                                      d.map(Timestamp::from_millis).unwrap_or_else(Timestamp::now)),
+            (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => return Err(e),
+        };
+
+        let id = match History::new(&mut self.db) {
+            Ok(mut h) => h.add_typed(url, title, mime_type, date),
+            Err(e) => Err(e),
+        };
+        match id {
+            Ok(t) => {
+                let mut event = Table::new();
+                event.insert(String::from("id"), Value::Integer(t));
+                event.insert(String::from("url"), Value::String(String::from(url)));
+                event.insert(String::from("title"), Value::String(String::from(title)));
+                event.insert(String::from("date"), Value::Integer(date.as_millis()));
+                self.publish(events::HISTORY_ADDED, &event);
+
+                let mut ret = Table::new();
+                ret.insert(String::from("id"), Value::Integer(t));
+                Ok(Value::Table(ret))
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Sends the entries by chunks when streaming.
+    fn history_entries(&mut self, request : &Request, stream : &dyn Stream) -> Result<Value, Error> {
+        let mut filter = Filter::default();
+        match request.optional_text_list("categories") {
+            Ok(t) => for name in t {
+                match Category::from_name(name) {
+                    Some(c) => filter.categories.push(c),
+                    None => return Err(Error::new(Some(INVALID_PARAMS), Some(format!("Unknown category {}", name)))),
+                }
+            },
+            Err(e) => return Err(e),
+        }
+        match request.optional_text("text") {
+            Ok(t) => filter.text = t.map(String::from),
+            Err(e) => return Err(e),
+        }
+        match time_range(request) {
+            Ok(t) => filter.range = t,
+            Err(e) => return Err(e),
+        }
+        match request.optional_integer("limit") {
+            Ok(Some(t)) if t >= 0 => filter.limit = Some(t as usize),
+            Ok(Some(_)) => return Err(Error::new(Some(INVALID_PARAMS), Some(String::from("Parameter limit must be positive")))),
+            Ok(None) => (),
+            Err(e) => return Err(e),
+        }
+
+        let entries = self.db.interruptible(&|| stream.is_cancelled(), |db| match History::new(db) {
+            Ok(mut h) => h.entries(&filter),
+            Err(e) => Err(e),
+        });
+        let mut entries = entries?;
+        if stream.is_streaming() {
+            while entries.len() > stream::CHUNK {
+                let rest = entries.split_off(stream::CHUNK);
+                stream.send(entries_table(&entries))?;
+                entries = rest;
+            }
+        }
+        Ok(Value::Table(entries_table(&entries)))
+    }
+
+    fn history_clear(&mut self, request : &Request, stream : &dyn Stream) -> Result<Value, Error> {
+        let range = match time_range(request) {
+            Ok(Some(t)) => t,
+            Ok(None) => return Err(Error::new(Some(INVALID_PARAMS), Some(String::from("Missing parameter range")))),
+            Err(e) => return Err(e),
+        };
+        let report = self.db.interruptible(&|| stream.is_cancelled(), |db| match History::new(db) {
+            Ok(mut h) => h.clear_range(range),
+            Err(e) => Err(e),
+        });
+        report.map(|r| {
+            self.publish(events::HISTORY_CLEARED, &report_table(&r));
+            Value::Table(report_table(&r))
+        })
+    }
+
+    fn history_forget_site(&mut self, request : &Request, stream : &dyn Stream) -> Result<Value, Error> {
+        let domain = request.text("domain")?;
+        let report = self.db.interruptible(&|| stream.is_cancelled(), |db| match History::new(db) {
+            Ok(mut h) => h.forget_site(domain),
+            Err(e) => Err(e),
+        });
+        report.map(|r| {
+            self.publish(events::HISTORY_CLEARED, &report_table(&r));
+            Value::Table(report_table(&r))
+        })
+    }
+
+    /// Imports the profile of another browser, sending the progress when
+    /// streaming.
+    fn history_import(&mut self, request : &Request, stream : &dyn Stream) -> Result<Value, Error> {
+        let (browser, profile) = match (request.text("browser"), request.text("profile")) {
+            (Ok(b), Ok(p)) => match Browser::from_name(b) {
+                Some(t) => (t, PathBuf::from(p)),
+                None => return Err(Error::new(Some(INVALID_PARAMS), Some(format!("Unknown browser {}", b)))),
+            },
+            (Err(e), _) | (_, Err(e)) => return Err(e),
+        };
+
+        let mut progress = |p : &Progress| {
+            // A partial reply for each percent at most.
+            if p.done < p.total && !p.done.is_multiple_of((p.total / 100).max(1)) {
+                return !stream.is_cancelled();
+            }
+            stream.send(stream::progress(p.stage.name(), p.done, p.total)).is_ok()
+        };
+        let report = self.db.interruptible(&|| stream.is_cancelled(), |db| import::import(browser, &profile, db, &mut progress));
+        report.map(|r| Value::Table(import_report_table(&r)))
+    }
+
+    fn settings_get(&mut self, request : &Request) -> Result<Value, Error> {
+        let key = request.text("key")?;
+        let store = self.store()?;
+        match (store.get(key), store.source(key)) {
+            (Ok(value), Ok(source)) => {
+                let mut ret = Table::new();
+                ret.insert(String::from("value"), value);
+                ret.insert(String::from("source"), Value::String(String::from(source.name())));
+                ret.insert(String::from("locked"), Value::Boolean(store.layers().is_locked(key)));
+                Ok(Value::Table(ret))
+            },
+            (Err(e), _) | (_, Err(e)) => Err(e),
+        }
+    }
+
+    fn settings_set(&mut self, request : &Request) -> Result<Value, Error> {
+        let (key, value) = match (request.text("key"), request.value("value")) {
+            (Ok(k), Ok(v)) => (k, v.clone()),
+            (Err(e), _) | (_, Err(e)) => return Err(e),
+        };
+        let diff = match self.store() {
+            Ok(mut s) => s.set(key, value),
+            Err(e) => Err(e),
+        };
+        diff.map(|d : Diff| Value::Table(d.to_table()))
+    }
+
+    fn settings_reset(&mut self, request : &Request) -> Result<Value, Error> {
+        let scope = match request.optional_text("scope") {
+            Ok(t) => String::from(t.unwrap_or("")),
+            Err(e) => return Err(e),
+        };
+        if !scope.is_empty() && schema::matching(&scope).is_empty() {
+            return Err(Error::new(Some(INVALID_PARAMS), Some(format!("Unknown setting {}", scope))));
+        }
+        let diff = match self.store() {
+            Ok(mut s) => s.reset(&scope),
+            Err(e) => Err(e),
+        };
+        diff.map(|d : Diff| Value::Table(d.to_table()))
+    }
+
+    /// State for a client joining the event bus: the sequence number and
+    /// last event of the topics starting with `prefix`, and the effective
+    /// settings. The store is locked first, as when it publishes, so the
+    /// settings match the sequence number of `settings.changed`.
+    fn events_snapshot(&mut self, request : &Request) -> Result<Value, Error> {
+        let prefix = match request.optional_text("prefix") {
+            Ok(t) => t.unwrap_or(""),
+            Err(e) => return Err(e),
+        };
+        let bus = match &self.events {
+            Some(t) => t,
+            None => return Err(events::unavailable()),
+        };
+        let store = self.store()?;
+        let mut ret = match bus.lock() {
+            Ok(b) => b.snapshot(prefix),
+            Err(_) => return Err(events::unavailable()),
+        };
+        ret.insert(String::from("settings"), Value::Table(store.effective().document().clone()));
+        ret.insert(String::from("clients"), self.clients.to_table().remove("clients").unwrap_or(Value::Array(Vec::new())));
+        Ok(Value::Table(ret))
+    }
+
+    /// Methods about the caller itself and its windows. Changes of the
+    /// windows are published for the other clients.
+    fn client_call(&mut self, caller : &Caller, request : &Request) -> Result<Value, Error> {
+        if request.method == "clients.bye" {
+            return match self.clients.remove(caller.identity) {
+                Some(t) => {
+                    self.client_left(&t, "bye");
+                    Ok(Value::Table(Table::new()))
+                },
+                None => Err(no_client()),
+            };
+        }
+
+        let client = match self.clients.get_mut(caller.identity) {
+            Some(t) => t,
+            None => return Err(no_client()),
+        };
+        let result = match request.method.as_str() {
+            "clients.register" => match request.text("name") {
+                Ok(t) => {
+                    client.name = String::from(t);
+                    Ok(())
+                },
+                Err(e) => Err(e),
+            },
+            "windows.open" => match request.optional_integer("window") {
+                Ok(Some(id)) => {
+                    if client.window_mut(id).is_none() {
+                        client.windows.push(Window { id, active_tab: 0, tabs: Vec::new() });
+                    }
+                    Ok(())
+                },
+                Ok(None) => Err(Error::new(Some(INVALID_PARAMS), Some(String::from("Missing parameter window")))),
+                Err(e) => Err(e),
+            },
+            "windows.close" => match request.optional_integer("window") {
+                Ok(Some(id)) => {
+                    client.windows.retain(|w| w.id != id);
+                    Ok(())
+                },
+                Ok(None) => Err(Error::new(Some(INVALID_PARAMS), Some(String::from("Missing parameter window")))),
+                Err(e) => Err(e),
+            },
+            "tabs.open" => open_tab(client, request),
+            "tabs.update" => update_tab(client, request),
+            _ => match request.optional_integer("tab") {
+                Ok(Some(id)) => match client.window_of_tab(id) {
+                    Some(w) => {
+                        w.tabs.retain(|t| t.id != id);
+                        if w.active_tab == id {
+                            w.active_tab = w.tabs.last().map(|t| t.id).unwrap_or(0);
+                        }
+                        Ok(())
+                    },
+                    None => Err(unknown_tab(id)),
+                },
+                Ok(None) => Err(Error::new(Some(INVALID_PARAMS), Some(String::from("Missing parameter tab")))),
+                Err(e) => Err(e),
+            },
+        };
+        result?;
+
+        let id = client.id;
+        let state = client.to_table();
+        self.publish(events::CLIENT_CHANGED, &state);
+        let mut ret = Table::new();
+        ret.insert(String::from("id"), Value::Integer(id));
+        Ok(Value::Table(ret))
+    }
+
+    fn client_left(&self, client : &Client, reason : &str) {
+        let mut event = Table::new();
+        event.insert(String::from("id"), Value::Integer(client.id));
+        event.insert(String::from("name"), Value::String(client.name.clone()));
+        event.insert(String::from("reason"), Value::String(String::from(reason)));
+        self.publish(events::CLIENT_DISCONNECTED, &event);
+    }
+
+    /// Publishes an event if there is a bus, failures only warn as the
+    /// request itself succeeded.
+    fn publish(&self, topic : &str, payload : &Table) {
+        if let Some(bus) = &self.events {
+            let result = match bus.lock() {
+                Ok(mut b) => b.publish(topic, payload).map(|_| ()),
+                Err(_) => Err(events::unavailable()),
+            };
+            if let Err(e) = result {
+                println!("Warning: {:?}", e);
+            }
+        }
+    }
+
+    fn db_tables(&mut self) -> Result<Value, Error> {
+        match self.db.request("SELECT name FROM sqlite_master WHERE type = 'table' \
+                               AND name NOT LIKE 'sqlite_%' ORDER BY name;", &[]) {
+            Ok(t) => {
+                let mut ret = Table::new();
+                ret.insert(String::from("tables"), Value::Array(t.iter().filter_map(|r| match r.get("name") {
+                    Some(Some(FieldValue::Text(t))) => Some(Value::String(t.clone())),
+                    _ => None,
+                }).collect()));
+                Ok(Value::Table(ret))
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    fn db_integrity_check(&mut self, stream : &dyn Stream) -> Result<Value, Error> {
+        match self.db.interruptible(&|| stream.is_cancelled(), |db| db.request("PRAGMA integrity_check;", &[])) {
+            Ok(t) => {
+                let messages : Vec<String> = t.iter().filter_map(|r| match r.get("integrity_check") {
+                    Some(Some(FieldValue::Text(t))) => Some(t.clone()),
+                    _ => None,
+                }).collect();
+                let mut ret = Table::new();
+                ret.insert(String::from("ok"), Value::Boolean(messages.len() == 1 && messages[0] == "ok"));
+                ret.insert(String::from("messages"), Value::Array(messages.into_iter().map(Value::String).collect()));
+                Ok(Value::Table(ret))
+            },
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Negotiates the version of the protocol and the capabilities with a new
+/// client. See the [protocol](../message/index.html#negotiation).
+fn hello(request : &Request) -> Result<Value, Error> {
+    let (min, max) = match request.params.get("versions") {
+        None => (request.version, request.version),
+        Some(Value::Array(t)) => match (t.first(), t.get(1), t.len()) {
+            (Some(Value::Integer(min)), Some(Value::Integer(max)), 2) => (*min, *max),
+            _ => return Err(Error::new(Some(INVALID_PARAMS), Some(String::from("Parameter versions must be [min, max]")))),
+        },
+        Some(_) => return Err(Error::new(Some(INVALID_PARAMS), Some(String::from("Parameter versions must be [min, max]")))),
+    };
+    let version = std::cmp::min(max, message::VERSION);
+    if version < std::cmp::max(min, message::MIN_VERSION) {
+        return Err(Error::new(Some(UNSUPPORTED_VERSION), Some(format!(
+            "No common protocol version, the core speaks versions {} to {}", message::MIN_VERSION, message::VERSION))));
+    }
+
+    let capabilities = match request.params.get("capabilities") {
+        None => CAPABILITIES.to_vec(),
+        Some(_) => match request.optional_text_list("capabilities") {
+            Ok(t) => t.into_iter().filter(|c| CAPABILITIES.contains(c)).collect(),
+            Err(e) => return Err(e),
+        },
+    };
+    let list = |t : &[&str]| Value::Array(t.iter().map(|i| Value::String(String::from(*i))).collect());
+
+    let mut ret = Table::new();
+    ret.insert(String::from("version"), Value::Integer(version));
+    ret.insert(String::from("core"), Value::String(String::from(env!("CARGO_PKG_VERSION"))));
+    ret.insert(String::from("capabilities"), list(&capabilities));
+    ret.insert(String::from("methods"), list(METHODS));
+    Ok(Value::Table(ret))
+}
+
+fn open_tab(client : &mut Client, request : &Request) -> Result<(), Error> {
+    let (window, id) = match (request.optional_integer("window"), request.optional_integer("tab")) {
+        (Ok(Some(w)), Ok(Some(t))) => (w, t),
+        (Err(e), _) | (_, Err(e)) => return Err(e),
+        _ => return Err(Error::new(Some(INVALID_PARAMS), Some(String::from("Missing parameter window or tab")))),
+    };
+    let (url, title, active) = match (request.optional_text("url"), request.optional_text("title"), request.params.get("active")) {
+        (Ok(u), Ok(t), None) => (u.unwrap_or(""), t.unwrap_or(""), false),
+        (Ok(u), Ok(t), Some(Value::Boolean(a))) => (u.unwrap_or(""), t.unwrap_or(""), *a),
+        (Err(e), _, _) | (_, Err(e), _) => return Err(e),
+        _ => return Err(Error::new(Some(INVALID_PARAMS), Some(String::from("Parameter active must be a boolean")))),
+    };
+    if client.window_of_tab(id).is_some() {
+        return Err(Error::new(Some(INVALID_PARAMS), Some(format!("Tab {} is already open", id))));
+    }
+    let window = match client.window_mut(window) {
+        Some(t) => t,
+        None => return Err(Error::new(Some(INVALID_PARAMS), Some(format!("Unknown window {}", window)))),
+    };
+    window.tabs.push(Tab { id, url: String::from(url), title: String::from(title) });
+    if active || window.active_tab == 0 {
+        window.active_tab = id;
+    }
+    Ok(())
+}
+
+fn update_tab(client : &mut Client, request : &Request) -> Result<(), Error> {
+    let id = match request.optional_integer("tab") {
+        Ok(Some(t)) => t,
+        Ok(None) => return Err(Error::new(Some(INVALID_PARAMS), Some(String::from("Missing parameter tab")))),
+        Err(e) => return Err(e),
+    };
+    let (url, title) = match (request.optional_text("url"), request.optional_text("title")) {
+        (Ok(u), Ok(t)) => (u, t),
+        (Err(e), _) | (_, Err(e)) => return Err(e),
+    };
+    let window = match client.window_of_tab(id) {
+        Some(t) => t,
+        None => return Err(unknown_tab(id)),
+    };
+    match request.params.get("active") {
+        Some(Value::Boolean(true)) => window.active_tab = id,
+        Some(Value::Boolean(false)) | None => (),
+        Some(_) => return Err(Error::new(Some(INVALID_PARAMS), Some(String::from("Parameter active must be a boolean")))),
+    }
+    if let Some(tab) = window.tab_mut(id) {
+        if let Some(u) = url {
+            tab.url = String::from(u);
+        }
+        if let Some(t) = title {
+            tab.title = String::from(t);
+        }
+    }
+    Ok(())
+}
+
+fn no_client() -> Error {
+    Error::new(Some(NO_CLIENT), Some(String::from("This method needs a connected client")))
+}
+
+fn unknown_tab(id : i64) -> Error {
+    Error::new(Some(INVALID_PARAMS), Some(format!("Unknown tab {}", id)))
+}
+
+/// Time range given either by name in `range`, or by its `from` and `to`
+/// bounds in milliseconds.
+fn time_range(request : &Request) -> Result<Option<TimeRange>, Error> {
+    match (request.optional_text("range"), request.optional_integer("from"), request.optional_integer("to")) {
+        (Ok(Some(name)), _, _) => match TimeRange::from_name(name) {
+            Some(t) => Ok(Some(t)),
+            None => Err(Error::new(Some(INVALID_PARAMS), Some(format!("Unknown range {}", name)))),
+        },
+        (Ok(None), Ok(None), Ok(None)) => Ok(None),
+        (Ok(None), Ok(from), Ok(to)) => Ok(Some(TimeRange::Between(
+            from.map(Timestamp::from_millis).unwrap_or(Timestamp::MIN),
+            to.map(Timestamp::from_millis).unwrap_or(Timestamp::MAX)))),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => Err(e),
+    }
+}
+
+fn entry_table(entry : &Entry) -> Table {
+    let mut ret = Table::new();
+    ret.insert(String::from("id"), Value::Integer(entry.id));
+    ret.insert(String::from("url"), Value::String(entry.url.clone()));
+    ret.insert(String::from("title"), Value::String(entry.title.clone()));
+    ret.insert(String::from("date"), Value::Integer(entry.date.as_millis()));
+    ret.insert(String::from("mime_type"), Value::String(entry.mime_type.clone()));
+    ret.insert(String::from("category"), Value::String(String::from(entry.category.name())));
+    ret
+}
+
+fn report_table(report : &ClearReport) -> Table {
+    let mut ret = Table::new();
+    ret.insert(String::from("entries"), Value::Integer(report.entries as i64));
+    ret.insert(String::from("hosts"), Value::Array(report.hosts.iter().map(|h| Value::String(h.clone())).collect()));
+    ret.insert(String::from("favicons"), Value::Integer(report.favicons as i64));
+    ret.insert(String::from("site_settings"), Value::Integer(report.site_settings as i64));
+    ret
+}
+
+fn entries_table(entries : &[Entry]) -> Table {
+    let mut ret = Table::new();
+    ret.insert(String::from("entries"), Value::Array(entries.iter().map(|e| Value::Table(entry_table(e))).collect()));
+    ret
+}
+
+fn import_report_table(report : &ImportReport) -> Table {
+    let mut ret = Table::new();
+    ret.insert(String::from("history_imported"), Value::Integer(report.history_imported as i64));
+    ret.insert(String::from("history_skipped"), Value::Integer(report.history_skipped as i64));
+    ret.insert(String::from("bookmarks_imported"), Value::Integer(report.bookmarks_imported as i64));
+    ret.insert(String::from("bookmarks_skipped"), Value::Integer(report.bookmarks_skipped as i64));
+    ret.insert(String::from("warnings"), Value::Array(report.warnings.iter().map(|w| Value::String(w.clone())).collect()));
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use toml::Value;
+    use toml::value::Table;
+
+    use crate::ipc::message::{self, Request, UNSUPPORTED_VERSION, INVALID_PARAMS};
+    use super::{hello, is_idempotent, METHODS};
+
+    fn request(version : i64, versions : Option<(i64, i64)>, capabilities : Option<&[&str]>) -> Request {
+        let mut params = Table::new();
+        if let Some((min, max)) = versions {
+            params.insert(String::from("versions"), Value::Array(vec![Value::Integer(min), Value::Integer(max)]));
+        }
+        if let Some(t) = capabilities {
+            params.insert(String::from("capabilities"), Value::Array(t.iter().map(|c| Value::String(String::from(*c))).collect()));
+        }
+        let mut ret = Request::new(1, "core.hello", params);
+        ret.version = version;
+        ret
+    }
+
+    fn version(reply : &Value) -> i64 {
+        reply["version"].as_integer().unwrap()
+    }
+
+    #[test]
+    fn hello_picks_the_highest_common_version() {
+        assert_eq!(version(&hello(&request(1, Some((0, 5)), None)).unwrap()), message::VERSION);
+        assert_eq!(version(&hello(&request(1, Some((message::MIN_VERSION, message::MIN_VERSION)), None)).unwrap()),
+                   message::MIN_VERSION);
+        assert_eq!(version(&hello(&request(1, Some((-3, message::VERSION)), None)).unwrap()), message::VERSION);
+    }
+
+    #[test]
+    fn hello_fails_without_common_version() {
+        let error = hello(&request(1, Some((message::VERSION + 1, message::VERSION + 3)), None)).unwrap_err();
+        assert_eq!(error.code(), Some(UNSUPPORTED_VERSION));
+        let error = hello(&request(1, Some((-5, message::MIN_VERSION - 1)), None)).unwrap_err();
+        assert_eq!(error.code(), Some(UNSUPPORTED_VERSION));
+
+        let mut broken = request(1, None, None);
+        broken.params.insert(String::from("versions"), Value::Array(vec![Value::Integer(1)]));
+        assert_eq!(hello(&broken).unwrap_err().code(), Some(INVALID_PARAMS));
+    }
+
+    #[test]
+    fn hello_without_versions_uses_the_request_version() {
+        assert_eq!(version(&hello(&request(1, None, None)).unwrap()), 1);
+        assert_eq!(version(&hello(&request(0, None, None)).unwrap()), 0);
+    }
+
+    #[test]
+    fn hello_filters_capabilities() {
+        let reply = hello(&request(1, None, Some(&["history", "teleport"]))).unwrap();
+        assert_eq!(reply["capabilities"], Value::Array(vec![Value::String(String::from("history"))]));
+        assert_eq!(reply["methods"].as_array().unwrap().len(), METHODS.len());
+    }
+
+    #[test]
+    fn only_bounded_clears_are_idempotent() {
+        let clear = |params : &[(&str, Value)]| {
+            Request::new(1, "history.clear", params.iter().map(|(k, v)| (String::from(*k), v.clone())).collect())
+        };
+        assert!(is_idempotent(&clear(&[("from", Value::Integer(0)), ("to", Value::Integer(1000))])));
+        assert!(!is_idempotent(&clear(&[("from", Value::Integer(0))])));
+        assert!(!is_idempotent(&clear(&[("range", Value::String(String::from("last_hour")))])));
+        assert!(is_idempotent(&Request::new(1, "history.entries", Table::new())));
+        assert!(!is_idempotent(&Request::new(1, "history.add", Table::new())));
+    }
+}
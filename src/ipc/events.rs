@@ -0,0 +1,199 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Event bus of the core
+//!
+//! Notifications are published on a ZeroMQ `PUB` socket as four frames:
+//! the topic, the sequence number of the event in its topic, the payload as
+//! TOML and the epoch of the bus. Clients subscribe with a topic prefix,
+//! like `history.` for every history event.
+//!
+//! Sequence numbers start at 1 and grow by one for each event of a topic,
+//! so a subscriber seeing a gap knows it missed events. A client joining
+//! late, or after a gap, subscribes first and then calls `events.snapshot`:
+//! the reply gives the current state and the sequence number of every topic,
+//! and updates with a lower or equal number are already part of it.
+//!
+//! Sequence numbers start again at 1 when the core restarts. The epoch, the
+//! time the bus was opened in milliseconds, tells which run of the core they
+//! belong to: a subscriber seeing a new epoch forgets the numbers it knew.
+//! It is the last frame so subscribers reading three frames keep working.
+
+use std::sync::{Arc, Mutex};
+
+use toml::Value;
+use toml::value::Table;
+
+use crate::data::db::Error;
+use crate::data::profile::Profile;
+use crate::data::settings::store::Broadcast;
+use crate::data::time::Timestamp;
+use super::auth::{self, KeyPair};
+use super::message;
+use super::server::{self, socket_error};
+
+/// A page was added to the history.
+pub const HISTORY_ADDED : &str = "history.added";
+/// Entries were removed from the history.
+pub const HISTORY_CLEARED : &str = "history.cleared";
+/// Effective settings changed, the payload is a [diff](../../data/settings/store/struct.Diff.html).
+pub const SETTINGS_CHANGED : &str = crate::data::settings::store::TOPIC;
+/// The settings file could not be reloaded.
+pub const SETTINGS_ERROR : &str = crate::data::settings::store::ERROR_TOPIC;
+/// A client sent its first request, the payload is the client.
+pub const CLIENT_CONNECTED : &str = "client.connected";
+/// The name or the windows of a client changed, the payload is the client.
+pub const CLIENT_CHANGED : &str = "client.changed";
+/// A client left or timed out.
+pub const CLIENT_DISCONNECTED : &str = "client.disconnected";
+/// The core stops, clients should say goodbye and close.
+pub const CORE_SHUTDOWN : &str = "core.shutdown";
+
+/// File of the profile directory giving the endpoint of the event bus of
+/// the running core to its clients.
+pub const ENDPOINT_FILE : &str = "events.endpoint";
+
+/// Endpoint the event bus of `profile` binds, next to the
+/// [endpoint](../server/fn.endpoint_for.html) of the server.
+pub fn endpoint_for(profile : &Profile) -> String {
+    if cfg!(windows) {
+        String::from("tcp://127.0.0.1:*")
+    } else {
+        format!("ipc://{}", profile.directory.join("events.ipc").display())
+    }
+}
+
+struct Topic {
+    name : String,
+    sequence : u64,
+    last : Table,
+}
+
+pub struct EventBus {
+    socket : zmq::Socket,
+    topics : Vec<Topic>,
+    epoch : i64,
+}
+
+impl EventBus {
+    /// Event bus publishing on `endpoint`.
+    pub fn bind(context : &zmq::Context, endpoint : &str) -> Result<Self, Error> {
+        Self::open(context, endpoint, None)
+    }
+
+    /// Event bus publishing on `endpoint` to paired clients only. An
+    /// [`Authenticator`](../auth/struct.Authenticator.html) must run in
+    /// `context`.
+    pub fn bind_secure(context : &zmq::Context, endpoint : &str, keys : &KeyPair) -> Result<Self, Error> {
+        Self::open(context, endpoint, Some(keys))
+    }
+
+    fn open(context : &zmq::Context, endpoint : &str, keys : Option<&KeyPair>) -> Result<Self, Error> {
+        let socket = match context.socket(zmq::PUB) {
+            Ok(t) => t,
+            Err(e) => return Err(socket_error("create the event socket", e)),
+        };
+        if let Err(e) = socket.set_linger(0) {
+            return Err(socket_error("configure the event socket", e));
+        }
+        if let Some(keys) = keys {
+            auth::secure_server(&socket, keys, auth::EVENTS_DOMAIN)?
+        }
+        if let Err(e) = socket.bind(endpoint) {
+            return Err(socket_error(&format!("bind {}", endpoint), e));
+        }
+        Ok(Self { socket, topics: Vec::new(), epoch: Timestamp::now().as_millis() })
+    }
+
+    pub fn socket(&self) -> &zmq::Socket {
+        &self.socket
+    }
+
+    /// Endpoint the bus is bound to, for the subscribers.
+    pub fn endpoint(&self) -> Result<String, Error> {
+        server::bound_endpoint(&self.socket)
+    }
+
+    /// Time the bus was opened, in milliseconds, identifying this run of
+    /// the core.
+    pub fn epoch(&self) -> i64 {
+        self.epoch
+    }
+
+    /// Publishes `payload` under `topic` and returns its sequence number.
+    pub fn publish(&mut self, topic : &str, payload : &Table) -> Result<u64, Error> {
+        let body = message::encode(payload)?;
+
+        let index = match self.topics.iter().position(|t| t.name == topic) {
+            Some(t) => t,
+            None => {
+                self.topics.push(Topic { name: String::from(topic), sequence: 0, last: Table::new() });
+                self.topics.len() - 1
+            },
+        };
+        let current = &mut self.topics[index];
+        current.sequence += 1;
+        current.last = payload.clone();
+
+        let sequence = current.sequence.to_string();
+        let epoch = self.epoch.to_string();
+        match self.socket.send_multipart([topic.as_bytes(), sequence.as_bytes(), &body, epoch.as_bytes()], 0) {
+            Ok(_) => Ok(current.sequence),
+            Err(e) => Err(socket_error(&format!("publish {}", topic), e)),
+        }
+    }
+
+    /// Sequence number of the last event of `topic`, 0 if there was none.
+    pub fn sequence(&self, topic : &str) -> u64 {
+        match self.topics.iter().find(|t| t.name == topic) {
+            Some(t) => t.sequence,
+            None => 0,
+        }
+    }
+
+    /// Epoch of the bus, with the sequence number and last event of every
+    /// topic starting with `prefix`:
+    ///
+    /// ```toml
+    /// epoch = 1571234567890
+    ///
+    /// [[topics]]
+    /// topic = "history.added"
+    /// sequence = 12
+    ///
+    /// [topics.last]
+    /// url = "https://sielo.app"
+    /// ```
+    pub fn snapshot(&self, prefix : &str) -> Table {
+        let mut ret = Table::new();
+        ret.insert(String::from("epoch"), Value::Integer(self.epoch));
+        ret.insert(String::from("topics"), Value::Array(self.topics.iter()
+            .filter(|t| t.name.starts_with(prefix))
+            .map(|t| {
+                let mut entry = Table::new();
+                entry.insert(String::from("topic"), Value::String(t.name.clone()));
+                entry.insert(String::from("sequence"), Value::Integer(t.sequence as i64));
+                entry.insert(String::from("last"), Value::Table(t.last.clone()));
+                Value::Table(entry)
+            }).collect()));
+        ret
+    }
+}
+
+/// The bus is shared between the server and the settings store, which
+/// publishes from the watcher thread.
+impl Broadcast for Arc<Mutex<EventBus>> {
+    fn broadcast(&mut self, topic : &str, payload : &Table) -> Result<(), Error> {
+        match self.lock() {
+            Ok(mut t) => t.publish(topic, payload).map(|_| ()),
+            Err(_) => Err(unavailable()),
+        }
+    }
+}
+
+/// Error of a bus poisoned by a panic, or missing.
+pub fn unavailable() -> Error {
+    Error::new(Some(6009), Some(String::from("Event bus is not available")))
+}
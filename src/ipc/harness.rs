@@ -0,0 +1,95 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Harness running a server and a client in the same thread
+//!
+//! The server listens on a private `inproc://` endpoint, with an in-memory
+//! database and default settings. Each [`call`](struct.Harness.html#method.call)
+//! sends a request, lets the server answer it and returns the reply, so
+//! exchanges are deterministic and need no thread.
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use toml::value::Table;
+
+use crate::data::db::{Error, TableProvider};
+use crate::data::db::sqlite::SQLite;
+use crate::data::settings::{Settings, Store};
+use super::dispatch::Dispatcher;
+use super::message::{Request, Reply};
+use super::server::{Server, socket_error};
+
+/// Time to wait for the reply of the server, in milliseconds.
+pub const TIMEOUT : i32 = 1000;
+
+static NEXT_ENDPOINT : AtomicUsize = AtomicUsize::new(0);
+
+pub struct Harness<T : TableProvider> {
+    server : Server<T>,
+    client : zmq::Socket,
+    next_id : i64,
+    // Sockets must be closed before their context.
+    _context : zmq::Context,
+}
+
+impl Harness<SQLite> {
+    /// Server with an empty in-memory database and default settings.
+    pub fn new() -> Result<Self, Error> {
+        let db = SQLite::new(":memory:")?;
+        Self::with_dispatcher(Dispatcher::new(db, Arc::new(Mutex::new(Store::new(Settings::new())))))
+    }
+}
+
+impl<T : TableProvider> Harness<T> {
+    pub fn with_dispatcher(dispatcher : Dispatcher<T>) -> Result<Self, Error> {
+        let context = zmq::Context::new();
+        let endpoint = format!("inproc://sielo-harness-{}", NEXT_ENDPOINT.fetch_add(1, Ordering::SeqCst));
+        let server = Server::bind(&context, &endpoint, dispatcher)?;
+
+        let client = match context.socket(zmq::DEALER) {
+            Ok(t) => t,
+            Err(e) => return Err(socket_error("create the client socket", e)),
+        };
+        if let Err(e) = client.set_linger(0).and_then(|_| client.set_rcvtimeo(TIMEOUT)) {
+            return Err(socket_error("configure the client socket", e));
+        }
+        if let Err(e) = client.connect(&endpoint) {
+            return Err(socket_error(&format!("connect to {}", endpoint), e));
+        }
+
+        Ok(Self { server, client, next_id: 1, _context: context })
+    }
+
+    pub fn server(&mut self) -> &mut Server<T> {
+        &mut self.server
+    }
+
+    /// Calls `method` with `params` and returns the reply of the server.
+    pub fn call(&mut self, method : &str, params : Table) -> Result<Reply, Error> {
+        let request = Request::new(self.next_id, method, params);
+        self.next_id += 1;
+        match request.to_bytes() {
+            Ok(t) => self.send_raw(&t),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Sends `body` as is, to check how the server handles broken messages.
+    pub fn send_raw(&mut self, body : &[u8]) -> Result<Reply, Error> {
+        if let Err(e) = self.client.send(body, 0) {
+            return Err(socket_error("send a request", e));
+        }
+        match self.server.poll(i64::from(TIMEOUT)) {
+            Ok(true) => (),
+            Ok(false) => return Err(Error::new(Some(6007), Some(String::from("The server received nothing")))),
+            Err(e) => return Err(e),
+        }
+        match self.client.recv_bytes(0) {
+            Ok(t) => Reply::from_bytes(&t),
+            Err(e) => Err(socket_error("receive a reply", e)),
+        }
+    }
+}
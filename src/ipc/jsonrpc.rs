@@ -0,0 +1,441 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! JSON-RPC bridge for the clients which can not use ZeroMQ
+//!
+//! Built with the `jsonrpc` feature and started with `--jsonrpc`, the core
+//! also answers [JSON-RPC 2.0](https://www.jsonrpc.org/specification) on a
+//! Unix socket in the `jsonrpc` directory of the profile, one JSON message
+//! per line:
+//!
+//! ```json
+//! {"jsonrpc": "2.0", "id": 12, "method": "history.entries", "params": {"text": "rust", "limit": 20}}
+//! ```
+//!
+//! Methods and parameters are the ones of the
+//! [ZeroMQ server](../server/index.html), whose dispatcher answers the
+//! requests: the `payload` of a request is its `params`, which must be an
+//! object, and the `payload` of the reply its `result`. TOML dates become
+//! strings. `null` values are left out, as if they were not given.
+//!
+//! Errors keep the code of the core, except for the ones JSON-RPC defines:
+//!  * [`PARSE_ERROR`](constant.PARSE_ERROR.html) and
+//!    [`INVALID_REQUEST`](constant.INVALID_REQUEST.html) for broken messages
+//!  * [`METHOD_NOT_FOUND`](constant.METHOD_NOT_FOUND.html) and
+//!    [`INVALID_PARAMS`](constant.INVALID_PARAMS.html) for the errors 6004
+//!    and 6003 of the core, which are then given as `data.code`
+//!
+//! Batches and notifications are understood. Each connection is a
+//! [client](../clients/index.html) of its own, forgotten once closed but
+//! never for being silent.
+//! When the server [records](../recorder/index.html) its messages, the
+//! requests reaching the dispatcher and their replies are recorded in the
+//! form of the core, so they can be replayed like the others.
+//! Replies are never partial and requests can not be cancelled, and events
+//! are only published on ZeroMQ. Access to the socket is restricted to the
+//! user running the core by the permissions of its directory, so it is
+//! never reachable by others, even while it is being created. The core does
+//! not authenticate the clients of the bridge further: `auth.` methods fail.
+
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde_json::{json, Map, Number};
+use toml::Value;
+use toml::value::Table;
+
+use crate::data::db::{Error, TableProvider};
+use crate::data::profile::Profile;
+use super::clients::BRIDGE_IDENTITY;
+use super::dispatch::{self, Dispatcher, Caller};
+use super::message::{self, Request};
+use super::recorder::{Recorder, Direction};
+
+/// The message is not valid JSON.
+pub const PARSE_ERROR : i64 = -32700;
+/// The message is not a JSON-RPC request.
+pub const INVALID_REQUEST : i64 = -32600;
+pub const METHOD_NOT_FOUND : i64 = -32601;
+pub const INVALID_PARAMS : i64 = -32602;
+/// Code of the errors of the core without one.
+pub const INTERNAL_ERROR : i64 = -32603;
+
+/// Longest message accepted, in bytes. The connection sending a longer one
+/// is closed.
+pub const MAX_MESSAGE : usize = 16 * 1024 * 1024;
+
+/// Time to wait for a client to read its reply before closing its
+/// connection.
+const WRITE_TIMEOUT : Duration = Duration::from_secs(5);
+
+/// Socket of the bridge of `profile`.
+pub fn path_for(profile : &Profile) -> PathBuf {
+    profile.directory.join("jsonrpc").join("core.sock")
+}
+
+struct Connection {
+    stream : UnixStream,
+    /// Identity of the connection among the clients. It starts with
+    /// [`BRIDGE_IDENTITY`](../clients/constant.BRIDGE_IDENTITY.html), a zero
+    /// byte then a name longer than the identities ZeroMQ makes up, so it
+    /// can not be one of a ZeroMQ client.
+    identity : Vec<u8>,
+    /// Received bytes not ending with a new line yet.
+    buffer : Vec<u8>,
+}
+
+pub struct Bridge {
+    listener : UnixListener,
+    path : PathBuf,
+    connections : Vec<Connection>,
+    next_id : u64,
+}
+
+impl Bridge {
+    /// Bridge listening on the socket `path`, replacing the one a core which
+    /// did not stop cleanly left.
+    ///
+    /// The directory of the socket is created if needed and made private to
+    /// the user before the socket is, as the socket itself is created with
+    /// the permissions of the umask.
+    pub fn bind<P : AsRef<Path>>(path : P) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let directory = match path.parent() {
+            Some(t) if !t.as_os_str().is_empty() => t,
+            _ => return Err(Error::new(Some(6019), Some(format!("{} is not in a directory", path.display())))),
+        };
+        if let Err(e) = private_directory(directory) {
+            return Err(io_error(&format!("restrict {}", directory.display()), e));
+        }
+        if let Ok(t) = fs::symlink_metadata(&path) {
+            if !t.file_type().is_socket() {
+                return Err(Error::new(Some(6019), Some(format!("{} exists and is not a socket", path.display()))));
+            }
+            if let Err(e) = fs::remove_file(&path) {
+                return Err(io_error(&format!("remove {}", path.display()), e));
+            }
+        }
+        let listener = match UnixListener::bind(&path) {
+            Ok(t) => t,
+            Err(e) => return Err(io_error(&format!("bind {}", path.display()), e)),
+        };
+        if let Err(e) = listener.set_nonblocking(true)
+            .and_then(|_| fs::set_permissions(&path, fs::Permissions::from_mode(0o600))) {
+            return Err(io_error(&format!("configure {}", path.display()), e));
+        }
+        Ok(Self { listener, path, connections: Vec::new(), next_id: 1 })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Descriptors to wait on for new connections and messages.
+    pub fn fds(&self) -> Vec<RawFd> {
+        let mut ret = vec![self.listener.as_raw_fd()];
+        ret.extend(self.connections.iter().map(|c| c.stream.as_raw_fd()));
+        ret
+    }
+
+    /// Accepts the new connections and answers the requests received on
+    /// `readable`, descriptors of [`fds`](#method.fds) ready to be read.
+    /// The requests and replies are given to `recorder`, if any.
+    pub fn handle<T : TableProvider>(&mut self, readable : &[RawFd], dispatcher : &mut Dispatcher<T>, recorder : Option<&Recorder>) {
+        if readable.contains(&self.listener.as_raw_fd()) {
+            self.accept();
+        }
+        let mut closed = Vec::new();
+        for (index, connection) in self.connections.iter_mut().enumerate() {
+            if readable.contains(&connection.stream.as_raw_fd()) {
+                if let Err(e) = connection.handle(dispatcher, recorder) {
+                    // A client leaving is not worth a warning.
+                    if e.code().is_some() {
+                        println!("Warning: {:?}", e);
+                    }
+                    closed.push(index);
+                }
+            }
+        }
+        for index in closed.into_iter().rev() {
+            let connection = self.connections.remove(index);
+            dispatcher.disconnect(&connection.identity);
+        }
+    }
+
+    fn accept(&mut self) {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((t, _)) => t,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    println!("Warning: {:?}", io_error("accept a connection", e));
+                    return;
+                },
+            };
+            // Streams block, but are only read once ready, and writing
+            // gives up after a while.
+            if let Err(e) = stream.set_nonblocking(false).and_then(|_| stream.set_write_timeout(Some(WRITE_TIMEOUT))) {
+                println!("Warning: {:?}", io_error("configure a connection", e));
+                continue;
+            }
+            let mut identity = BRIDGE_IDENTITY.to_vec();
+            identity.extend_from_slice(self.next_id.to_string().as_bytes());
+            self.next_id += 1;
+            self.connections.push(Connection { stream, identity, buffer: Vec::new() });
+        }
+    }
+}
+
+impl Drop for Bridge {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl Connection {
+    /// Reads what the client sent and answers its complete messages. Fails
+    /// once the connection must be closed, with an error without code if
+    /// the client closed it.
+    fn handle<T : TableProvider>(&mut self, dispatcher : &mut Dispatcher<T>, recorder : Option<&Recorder>) -> Result<(), Error> {
+        let mut data = [0u8; 65536];
+        let size = match self.stream.read(&mut data) {
+            Ok(0) => return Err(Error::new(None, None)),
+            Ok(t) => t,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => return Ok(()),
+            Err(e) => return Err(io_error("read a request", e)),
+        };
+        self.buffer.extend_from_slice(&data[..size]);
+
+        while let Some(end) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line : Vec<u8> = self.buffer.drain(..=end).collect();
+            let caller = Caller { identity: &self.identity, key: None };
+            let reply = match std::str::from_utf8(&line) {
+                Ok(t) if t.trim().is_empty() => None,
+                Ok(t) => answer(dispatcher, &caller, t, recorder),
+                Err(_) => Some(error_reply(serde_json::Value::Null, PARSE_ERROR, "Message is not UTF-8", None)),
+            };
+            if let Some(reply) = reply {
+                self.send(&reply)?
+            }
+        }
+
+        if self.buffer.len() > MAX_MESSAGE {
+            let reply = error_reply(serde_json::Value::Null, PARSE_ERROR, "Message too long", None);
+            let _ = self.send(&reply);
+            return Err(Error::new(Some(6019), Some(String::from("A JSON-RPC client sent a too long message"))));
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, reply : &serde_json::Value) -> Result<(), Error> {
+        let mut text = reply.to_string();
+        text.push('\n');
+        match self.stream.write_all(text.as_bytes()) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(io_error("send a reply", e)),
+        }
+    }
+}
+
+/// Answers the JSON-RPC message `text` sent by `caller`, a request or a
+/// batch. Returns `None` if there is nothing to answer, for notifications.
+pub fn answer<T : TableProvider>(dispatcher : &mut Dispatcher<T>, caller : &Caller, text : &str, recorder : Option<&Recorder>) -> Option<serde_json::Value> {
+    let message = match serde_json::from_str::<serde_json::Value>(text) {
+        Ok(t) => t,
+        Err(e) => return Some(error_reply(serde_json::Value::Null, PARSE_ERROR, &format!("Invalid JSON: {}", e), None)),
+    };
+    match message {
+        serde_json::Value::Array(ref t) if t.is_empty() =>
+            Some(error_reply(serde_json::Value::Null, INVALID_REQUEST, "Empty batch", None)),
+        serde_json::Value::Array(t) => {
+            let replies : Vec<_> = t.iter().filter_map(|r| call(dispatcher, caller, r, recorder)).collect();
+            if replies.is_empty() {
+                None
+            } else {
+                Some(serde_json::Value::Array(replies))
+            }
+        },
+        t => call(dispatcher, caller, &t, recorder),
+    }
+}
+
+/// Answers a single request.
+fn call<T : TableProvider>(dispatcher : &mut Dispatcher<T>, caller : &Caller, request : &serde_json::Value, recorder : Option<&Recorder>) -> Option<serde_json::Value> {
+    let request = match request {
+        serde_json::Value::Object(t) => t,
+        _ => return Some(error_reply(serde_json::Value::Null, INVALID_REQUEST, "Request must be an object", None)),
+    };
+    let id = match request.get("id") {
+        None => None,
+        Some(t) if t.is_string() || t.is_number() || t.is_null() => Some(t.clone()),
+        Some(_) => return Some(error_reply(serde_json::Value::Null, INVALID_REQUEST, "Id must be a string, a number or null", None)),
+    };
+    let reply_id = id.clone().unwrap_or(serde_json::Value::Null);
+    if request.get("jsonrpc").and_then(serde_json::Value::as_str) != Some("2.0") {
+        return Some(error_reply(reply_id, INVALID_REQUEST, "Only JSON-RPC 2.0 is supported", None));
+    }
+    let method = match request.get("method") {
+        Some(serde_json::Value::String(t)) => t,
+        _ => return Some(error_reply(reply_id, INVALID_REQUEST, "Request without method", None)),
+    };
+    let params = match request.get("params") {
+        None => Table::new(),
+        Some(serde_json::Value::Object(t)) => from_json_object(t),
+        Some(_) => return Some(error_reply(reply_id, INVALID_PARAMS, "Parameters must be an object", None)),
+    };
+
+    // The core only uses the id of a request to answer it.
+    let core_id = id.as_ref().and_then(serde_json::Value::as_i64).unwrap_or(0);
+    let request = Request::new(core_id, method, params);
+    if let Some(t) = recorder {
+        record(t, Direction::In, caller.identity, request.to_bytes());
+    }
+    let reply = dispatcher.dispatch_from(caller, &request);
+    if let Some(t) = recorder {
+        record(t, Direction::Out, caller.identity, reply.to_bytes());
+    }
+    let id = id?;
+    Some(match reply.result {
+        Ok(t) => json!({ "jsonrpc": "2.0", "id": id, "result": to_json(&t) }),
+        Err(e) => {
+            let message = e.message().unwrap_or("");
+            match e.code().map(|c| c as i64) {
+                Some(c) if c == dispatch::UNKNOWN_METHOD as i64 => error_reply(id, METHOD_NOT_FOUND, message, Some(c)),
+                Some(c) if c == message::INVALID_PARAMS as i64 => error_reply(id, INVALID_PARAMS, message, Some(c)),
+                Some(c) => error_reply(id, c, message, None),
+                None => error_reply(id, INTERNAL_ERROR, message, None),
+            }
+        },
+    })
+}
+
+/// Records the message `body` of the connection `identity`.
+fn record(recorder : &Recorder, direction : Direction, identity : &[u8], body : Result<Vec<u8>, Error>) {
+    match body {
+        Ok(t) => recorder.record_message(direction, &[identity.to_vec(), t], None),
+        Err(e) => println!("Warning: {:?}", e),
+    }
+}
+
+/// Error response, giving the code of the core as `data.code` if it is not
+/// the one of the response.
+fn error_reply(id : serde_json::Value, code : i64, message : &str, core_code : Option<i64>) -> serde_json::Value {
+    let mut error = json!({ "code": code, "message": message });
+    if let Some(c) = core_code {
+        error["data"] = json!({ "code": c });
+    }
+    json!({ "jsonrpc": "2.0", "id": id, "error": error })
+}
+
+/// JSON form of a TOML value.
+pub fn to_json(value : &Value) -> serde_json::Value {
+    match value {
+        Value::String(t) => serde_json::Value::String(t.clone()),
+        Value::Integer(t) => serde_json::Value::Number(Number::from(*t)),
+        // JSON has no infinity nor NaN.
+        Value::Float(t) => Number::from_f64(*t).map(serde_json::Value::Number).unwrap_or(serde_json::Value::Null),
+        Value::Boolean(t) => serde_json::Value::Bool(*t),
+        Value::Datetime(t) => serde_json::Value::String(t.to_string()),
+        Value::Array(t) => serde_json::Value::Array(t.iter().map(to_json).collect()),
+        Value::Table(t) => serde_json::Value::Object(t.iter().map(|(k, v)| (k.clone(), to_json(v))).collect()),
+    }
+}
+
+/// TOML form of a JSON value, `None` for `null`.
+pub fn from_json(value : &serde_json::Value) -> Option<Value> {
+    match value {
+        serde_json::Value::Null => None,
+        serde_json::Value::Bool(t) => Some(Value::Boolean(*t)),
+        serde_json::Value::Number(t) => match t.as_i64() {
+            Some(i) => Some(Value::Integer(i)),
+            None => t.as_f64().map(Value::Float),
+        },
+        serde_json::Value::String(t) => Some(Value::String(t.clone())),
+        serde_json::Value::Array(t) => Some(Value::Array(t.iter().filter_map(from_json).collect())),
+        serde_json::Value::Object(t) => Some(Value::Table(from_json_object(t))),
+    }
+}
+
+fn from_json_object(object : &Map<String, serde_json::Value>) -> Table {
+    object.iter().filter_map(|(k, v)| from_json(v).map(|v| (k.clone(), v))).collect()
+}
+
+/// Creates `path` readable only by the user, or restricts it if it exists.
+fn private_directory(path : &Path) -> std::io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(t) if t.is_dir() => fs::set_permissions(path, fs::Permissions::from_mode(0o700)),
+        Ok(_) => Err(std::io::Error::new(ErrorKind::AlreadyExists, "it is not a directory")),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => fs::DirBuilder::new().recursive(true).mode(0o700).create(path),
+        Err(e) => Err(e),
+    }
+}
+
+fn io_error(action : &str, error : std::io::Error) -> Error {
+    Error::new(Some(6019), Some(format!("Can not {}: {}", action, error)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::data::db::sqlite::SQLite;
+    use crate::data::settings::{Settings, Store};
+    use crate::ipc::message::Reply;
+    use crate::ipc::recorder;
+    use super::*;
+
+    #[test]
+    fn socket_is_created_in_a_private_directory() {
+        let directory = std::env::temp_dir().join(format!("sielo-jsonrpc-bind-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let mode = |p : &Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+
+        let path = directory.join("jsonrpc").join("core.sock");
+        let bridge = Bridge::bind(&path).unwrap();
+        assert_eq!(mode(&directory.join("jsonrpc")), 0o700);
+        assert_eq!(mode(&path), 0o600);
+        drop(bridge);
+        assert!(!path.exists());
+
+        // An existing directory is restricted too.
+        fs::set_permissions(directory.join("jsonrpc"), fs::Permissions::from_mode(0o755)).unwrap();
+        let _bridge = Bridge::bind(&path).unwrap();
+        assert_eq!(mode(&directory.join("jsonrpc")), 0o700);
+
+        fs::write(directory.join("file"), "").unwrap();
+        assert_eq!(Bridge::bind(directory.join("file").join("core.sock")).err().unwrap().code(), Some(6019));
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn bridged_requests_are_recorded() {
+        let path = std::env::temp_dir().join(format!("sielo-jsonrpc-record-{}.toml", std::process::id()));
+        let recorder = Recorder::create(&path).unwrap();
+        let db = SQLite::new(":memory:").unwrap();
+        let mut dispatcher = Dispatcher::new(db, Arc::new(Mutex::new(Store::new(Settings::new()))));
+        let caller = Caller { identity: b"\0jsonrpc-1", key: None };
+
+        let text = r#"[{"jsonrpc": "2.0", "id": 3, "method": "settings.get", "params": {"key": "appearance.theme"}}, {"jsonrpc": "2.0", "method": "history.frobnicate"}, {"jsonrpc": "1.0", "id": 4, "method": "settings.get"}]"#;
+        assert!(answer(&mut dispatcher, &caller, text, Some(&recorder)).is_some());
+
+        // The broken request never reaches the dispatcher.
+        let frames = recorder::read(&path).unwrap();
+        assert_eq!(frames.len(), 4);
+        assert!(frames.iter().all(|f| f.route == vec![b"\0jsonrpc-1".to_vec()]));
+        let directions : Vec<_> = frames.iter().map(|f| f.direction).collect();
+        assert_eq!(directions, vec![Direction::In, Direction::Out, Direction::In, Direction::Out]);
+        assert_eq!(Request::from_bytes(&frames[0].body).unwrap().method, "settings.get");
+        let reply = Reply::from_bytes(&frames[1].body).unwrap();
+        assert_eq!(reply.id, 3);
+        assert_eq!(reply.result.unwrap()["value"], Value::String(String::from("system")));
+        assert_eq!(Reply::from_bytes(&frames[3].body).unwrap().result.unwrap_err().code(), Some(dispatch::UNKNOWN_METHOD));
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,420 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Wire protocol between the UI and the core
+//!
+//! Every message is a TOML document sent in a single frame, wrapped in the
+//! same envelope:
+//!
+//! ```toml
+//! version = 1
+//! id = 12
+//! method = "history.entries"
+//!
+//! [payload]
+//! text = "rust"
+//! limit = 20
+//! ```
+//!
+//! The reply carries the same `version`, `id` and `method`, and either a
+//! `payload` or an `error` table with a `code` and a `message`.
+//!
+//! Compatibility rules:
+//!  * Unknown keys are ignored, so optional fields and new methods can be
+//!    added without changing the version. Clients learn which methods the
+//!    core has through the `core.hello` [negotiation](#negotiation).
+//!  * Removing or renaming a field, or changing its meaning, requires a new
+//!    [`VERSION`](constant.VERSION.html).
+//!  * The core answers every request in the version of the request, for
+//!    every version from [`MIN_VERSION`](constant.MIN_VERSION.html) to
+//!    `VERSION`, so older UIs keep working with newer cores.
+//!  * A message without `version` is a version 0 message, which used
+//!    `params` for the payload of requests, `result` for the payload of
+//!    replies and did not echo the method.
+//!
+//! # Negotiation
+//!
+//! Right after connecting, a client calls `core.hello` with the versions it
+//! speaks and the capabilities it wants:
+//!
+//! ```toml
+//! version = 1
+//! id = 1
+//! method = "core.hello"
+//!
+//! [payload]
+//! versions = [1, 1]
+//! capabilities = ["history", "settings"]
+//! ```
+//!
+//! The core replies with the highest version both speak, the capabilities
+//! it has among the requested ones, and the list of its methods.
+//!
+//! # Streaming
+//!
+//! A request with `stream = true` in its envelope asks for partial replies:
+//! messages with the same `id` and `partial = true`, sent before the final
+//! reply. Long methods use them for their results as they come, or for
+//! their progress. Only `DEALER` clients can stream, the core ignores the
+//! flag of `REQ` ones. Any request can be stopped with `core.cancel`, see
+//! [streams](../stream/index.html).
+
+use toml::Value;
+use toml::value::Table;
+
+use crate::data::db::Error;
+
+/// Current version of the protocol.
+pub const VERSION : i64 = 1;
+/// Oldest version of the protocol still understood.
+pub const MIN_VERSION : i64 = 0;
+
+/// Parameters are missing or have the wrong type.
+pub const INVALID_PARAMS : isize = 6003;
+/// The message uses a version of the protocol the core does not speak.
+pub const UNSUPPORTED_VERSION : isize = 6008;
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Request {
+    pub version : i64,
+    pub id : i64,
+    pub method : String,
+    pub params : Table,
+    /// Whether the client wants partial replies.
+    pub stream : bool,
+}
+
+impl Request {
+    /// Request in the current version of the protocol.
+    pub fn new(id : i64, method : &str, params : Table) -> Self {
+        Self { version: VERSION, id, method: String::from(method), params, stream: false }
+    }
+
+    pub fn to_table(&self) -> Table {
+        let mut ret = Table::new();
+        ret.insert(String::from("id"), Value::Integer(self.id));
+        ret.insert(String::from("method"), Value::String(self.method.clone()));
+        if self.version == 0 {
+            ret.insert(String::from("params"), Value::Table(self.params.clone()));
+        } else {
+            ret.insert(String::from("version"), Value::Integer(self.version));
+            ret.insert(String::from("payload"), Value::Table(self.params.clone()));
+            if self.stream {
+                ret.insert(String::from("stream"), Value::Boolean(true));
+            }
+        }
+        ret
+    }
+
+    pub fn from_table(table : &Table) -> Result<Self, Error> {
+        let version = version_of(table)?;
+        let id = match table.get("id") {
+            Some(Value::Integer(t)) => *t,
+            _ => return Err(Error::new(Some(6001), Some(String::from("Request without id")))),
+        };
+        let method = match table.get("method") {
+            Some(Value::String(t)) => t.clone(),
+            _ => return Err(Error::new(Some(6001), Some(String::from("Request without method")))),
+        };
+        let params = match table.get(if version == 0 { "params" } else { "payload" }) {
+            Some(Value::Table(t)) => t.clone(),
+            None => Table::new(),
+            Some(_) => return Err(Error::new(Some(INVALID_PARAMS), Some(String::from("Parameters must be a table")))),
+        };
+        let stream = version != 0 && table.get("stream").and_then(Value::as_bool).unwrap_or(false);
+        Ok(Self { version, id, method, params, stream })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        encode(&self.to_table())
+    }
+
+    pub fn from_bytes(bytes : &[u8]) -> Result<Self, Error> {
+        match decode(bytes) {
+            Ok(t) => Self::from_table(&t),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Text parameter `name`.
+    pub fn text(&self, name : &str) -> Result<&str, Error> {
+        match self.params.get(name) {
+            Some(Value::String(t)) => Ok(t),
+            _ => Err(invalid_param(name, "a string")),
+        }
+    }
+
+    /// Optional text parameter `name`.
+    pub fn optional_text(&self, name : &str) -> Result<Option<&str>, Error> {
+        match self.params.get(name) {
+            None => Ok(None),
+            Some(_) => self.text(name).map(Some),
+        }
+    }
+
+    /// Optional integer parameter `name`.
+    pub fn optional_integer(&self, name : &str) -> Result<Option<i64>, Error> {
+        match self.params.get(name) {
+            None => Ok(None),
+            Some(Value::Integer(t)) => Ok(Some(*t)),
+            Some(_) => Err(invalid_param(name, "an integer")),
+        }
+    }
+
+    /// Optional parameter `name` holding a list of strings.
+    pub fn optional_text_list(&self, name : &str) -> Result<Vec<&str>, Error> {
+        match self.params.get(name) {
+            None => Ok(Vec::new()),
+            Some(Value::Array(t)) if t.iter().all(|v| v.is_str()) => Ok(t.iter().filter_map(|v| v.as_str()).collect()),
+            Some(_) => Err(invalid_param(name, "a list of strings")),
+        }
+    }
+
+    /// Parameter `name` of any type.
+    pub fn value(&self, name : &str) -> Result<&Value, Error> {
+        match self.params.get(name) {
+            Some(t) => Ok(t),
+            None => Err(Error::new(Some(INVALID_PARAMS), Some(format!("Missing parameter {}", name)))),
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Reply {
+    pub version : i64,
+    pub id : i64,
+    pub method : String,
+    pub result : Result<Value, Error>,
+    /// Whether more replies to the request follow.
+    pub partial : bool,
+}
+
+impl Reply {
+    pub fn new(version : i64, id : i64, method : &str, result : Result<Value, Error>) -> Self {
+        Self { version, id, method: String::from(method), result, partial: false }
+    }
+
+    /// Reply to `request`, in its version of the protocol.
+    pub fn to(request : &Request, result : Result<Value, Error>) -> Self {
+        Self::new(request.version, request.id, &request.method, result)
+    }
+
+    pub fn to_table(&self) -> Table {
+        let mut ret = Table::new();
+        ret.insert(String::from("id"), Value::Integer(self.id));
+        if self.version != 0 {
+            ret.insert(String::from("version"), Value::Integer(self.version));
+            ret.insert(String::from("method"), Value::String(self.method.clone()));
+            if self.partial {
+                ret.insert(String::from("partial"), Value::Boolean(true));
+            }
+        }
+        match &self.result {
+            Ok(t) => {
+                ret.insert(String::from(if self.version == 0 { "result" } else { "payload" }), t.clone());
+            },
+            Err(e) => {
+                ret.insert(String::from("error"), Value::Table(error_table(e)));
+            },
+        }
+        ret
+    }
+
+    pub fn from_table(table : &Table) -> Result<Self, Error> {
+        let version = version_of(table)?;
+        let id = match table.get("id") {
+            Some(Value::Integer(t)) => *t,
+            _ => return Err(Error::new(Some(6001), Some(String::from("Reply without id")))),
+        };
+        let method = match table.get("method") {
+            Some(Value::String(t)) => t.clone(),
+            _ => String::new(),
+        };
+        let payload = if version == 0 { "result" } else { "payload" };
+        let result = match (table.get(payload), table.get("error")) {
+            (_, Some(Value::Table(e))) => Err(error_from_table(e)),
+            (Some(t), None) => Ok(t.clone()),
+            _ => return Err(Error::new(Some(6001), Some(String::from("Reply without payload")))),
+        };
+        let partial = version != 0 && table.get("partial").and_then(Value::as_bool).unwrap_or(false);
+        Ok(Self { version, id, method, result, partial })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        encode(&self.to_table())
+    }
+
+    pub fn from_bytes(bytes : &[u8]) -> Result<Self, Error> {
+        match decode(bytes) {
+            Ok(t) => Self::from_table(&t),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Version of the protocol used by the message `table`.
+pub fn version_of(table : &Table) -> Result<i64, Error> {
+    match table.get("version") {
+        None => Ok(0),
+        Some(Value::Integer(t)) if *t >= MIN_VERSION && *t <= VERSION => Ok(*t),
+        Some(Value::Integer(t)) => Err(Error::new(Some(UNSUPPORTED_VERSION), Some(format!(
+            "Protocol version {} is not supported, the core speaks versions {} to {}", t, MIN_VERSION, VERSION)))),
+        Some(_) => Err(Error::new(Some(6001), Some(String::from("Protocol version must be an integer")))),
+    }
+}
+
+/// TOML form of an error: `code` and `message`, both optional.
+pub fn error_table(error : &Error) -> Table {
+    let mut ret = Table::new();
+    if let Some(code) = error.code() {
+        ret.insert(String::from("code"), Value::Integer(code as i64));
+    }
+    if let Some(message) = error.message() {
+        ret.insert(String::from("message"), Value::String(String::from(message)));
+    }
+    ret
+}
+
+pub fn error_from_table(table : &Table) -> Error {
+    Error::new(match table.get("code") {
+                   Some(Value::Integer(t)) => Some(*t as isize),
+                   _ => None,
+               },
+               match table.get("message") {
+                   Some(Value::String(t)) => Some(t.clone()),
+                   _ => None,
+               })
+}
+
+pub fn encode(table : &Table) -> Result<Vec<u8>, Error> {
+    // Only `Value` puts plain values before tables, as TOML requires.
+    match toml::to_string(&Value::Table(table.clone())) {
+        Ok(t) => Ok(t.into_bytes()),
+        Err(e) => Err(Error::new(Some(6002), Some(format!("Can not serialize message: {}", e)))),
+    }
+}
+
+pub fn decode(bytes : &[u8]) -> Result<Table, Error> {
+    let text = match std::str::from_utf8(bytes) {
+        Ok(t) => t,
+        Err(_) => return Err(Error::new(Some(6001), Some(String::from("Message is not UTF-8")))),
+    };
+    match text.parse::<Value>() {
+        Ok(Value::Table(t)) => Ok(t),
+        Ok(_) => Err(Error::new(Some(6001), Some(String::from("Message must be a table")))),
+        Err(e) => Err(Error::new(Some(6001), Some(format!("Invalid message: {}", e)))),
+    }
+}
+
+fn invalid_param(name : &str, kind : &str) -> Error {
+    Error::new(Some(INVALID_PARAMS), Some(format!("Parameter {} must be {}", name, kind)))
+}
+
+#[cfg(test)]
+mod tests {
+    use toml::Value;
+    use toml::value::Table;
+
+    use crate::data::db::Error;
+    use super::*;
+
+    fn payload() -> Table {
+        let mut ret = Table::new();
+        ret.insert(String::from("text"), Value::String(String::from("rust")));
+        ret.insert(String::from("limit"), Value::Integer(20));
+        ret
+    }
+
+    fn round_trip_request(request : &Request) -> Request {
+        Request::from_bytes(&request.to_bytes().unwrap()).unwrap()
+    }
+
+    fn round_trip_reply(reply : &Reply) -> Reply {
+        Reply::from_bytes(&reply.to_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn request_v1() {
+        let request = Request::new(12, "history.entries", payload());
+        let table = request.to_table();
+        assert_eq!(table.get("version"), Some(&Value::Integer(VERSION)));
+        assert!(table.contains_key("payload"));
+        assert_eq!(round_trip_request(&request), request);
+    }
+
+    #[test]
+    fn reply_v1() {
+        let reply = Reply::new(1, 12, "history.entries", Ok(Value::Table(payload())));
+        let table = reply.to_table();
+        assert_eq!(table.get("method"), Some(&Value::String(String::from("history.entries"))));
+        assert!(table.contains_key("payload"));
+        assert_eq!(round_trip_reply(&reply), reply);
+    }
+
+    #[test]
+    fn request_v0() {
+        let request = Request::from_bytes(b"id = 3\nmethod = \"settings.get\"\n\n[params]\nkey = \"general.language\"\n").unwrap();
+        assert_eq!(request.version, 0);
+        assert_eq!(request.text("key").unwrap(), "general.language");
+
+        let table = request.to_table();
+        assert!(table.contains_key("params"));
+        assert!(!table.contains_key("version") && !table.contains_key("payload"));
+        assert_eq!(round_trip_request(&request), request);
+    }
+
+    #[test]
+    fn reply_v0() {
+        let request = Request { version: 0, id: 3, method: String::from("settings.get"), params: Table::new(), stream: false };
+        let reply = Reply::to(&request, Ok(Value::Table(payload())));
+        let table = reply.to_table();
+        assert!(table.contains_key("result"));
+        assert!(!table.contains_key("version") && !table.contains_key("method") && !table.contains_key("payload"));
+
+        let back = round_trip_reply(&reply);
+        assert_eq!((back.version, back.id, back.method.as_str()), (0, 3, ""));
+        assert_eq!(back.result, reply.result);
+    }
+
+    #[test]
+    fn error_reply() {
+        for version in &[0, VERSION] {
+            let reply = Reply::new(*version, 4, "core.frobnicate", Err(Error::new(Some(6004), Some(String::from("Unknown method")))));
+            let back = round_trip_reply(&reply);
+            let error = back.result.unwrap_err();
+            assert_eq!(error.code(), Some(6004));
+            assert_eq!(error.message(), Some("Unknown method"));
+        }
+    }
+
+    #[test]
+    fn partial_reply() {
+        let mut request = Request::new(5, "history.entries", Table::new());
+        request.stream = true;
+        let back = round_trip_request(&request);
+        assert!(back.stream);
+
+        let mut reply = Reply::to(&request, Ok(Value::Table(payload())));
+        reply.partial = true;
+        assert_eq!(round_trip_reply(&reply), reply);
+
+        // Version 0 has no streaming.
+        let request = Request::from_bytes(b"id = 6\nmethod = \"history.entries\"\nstream = true\n").unwrap();
+        assert!(!request.stream);
+    }
+
+    #[test]
+    fn broken_messages() {
+        assert_eq!(Request::from_bytes(b"method = \"core.ping\"\nversion = 1\n").unwrap_err().code(), Some(6001));
+        assert_eq!(Request::from_bytes(b"id = 1\nversion = 1\n").unwrap_err().code(), Some(6001));
+        assert_eq!(Request::from_bytes(b"not toml").unwrap_err().code(), Some(6001));
+        assert_eq!(Request::from_bytes(&[0xff, 0xfe]).unwrap_err().code(), Some(6001));
+        assert_eq!(Request::from_bytes(b"id = 1\nmethod = \"core.ping\"\nversion = 99\n").unwrap_err().code(),
+                   Some(UNSUPPORTED_VERSION));
+        assert_eq!(Request::from_bytes(b"id = 1\nmethod = \"core.ping\"\nversion = 1\npayload = 3\n").unwrap_err().code(),
+                   Some(INVALID_PARAMS));
+        assert_eq!(Reply::from_bytes(b"id = 1\nversion = 1\n").unwrap_err().code(), Some(6001));
+    }
+}
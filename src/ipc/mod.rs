@@ -0,0 +1,35 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Interface between the user interfaces and the core.
+//! It implements:
+//!  * [Wire protocol]() with versioned messages and negotiation
+//!  * [Dispatch]() of requests to the data subsystems
+//!  * [Server]() on a ZeroMQ ROUTER socket
+//!  * [Streams]() of partial replies, and cancellation of long requests
+//!  * [Events]() published on a ZeroMQ PUB socket
+//!  * [Authentication]() of the clients with CURVE and pairing
+//!  * [Clients]() sharing the core, with their windows and tabs
+//!  * [Connection]() of a client, with heartbeats and reconnection
+//!  * [Client]() library with typed async calls and an event stream
+//!  * [Recorder]() of the messages, and their [replay]() on a fresh core
+//!  * [JSON-RPC bridge]() for the clients without ZeroMQ, with the `jsonrpc`
+//!    feature
+//!  * [Harness]() to drive a server from tests and tools
+
+pub mod message;
+pub mod dispatch;
+pub mod server;
+pub mod stream;
+pub mod events;
+pub mod auth;
+pub mod clients;
+pub mod connection;
+pub mod client;
+pub mod recorder;
+pub mod replay;
+#[cfg(all(feature = "jsonrpc", unix))]
+pub mod jsonrpc;
+pub mod harness;
@@ -0,0 +1,204 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Recording of the IPC traffic
+//!
+//! Started with `--record <file>`, the core writes every message it
+//! receives or sends on its server socket to a TOML file, as they come:
+//!
+//! ```toml
+//! [[frames]]
+//! body = "id = 12\nmethod = \"history.entries\"\nversion = 1\n"
+//! direction = "in"
+//! route = ["0080000029"]
+//! time = 1571234567890
+//! ```
+//!
+//! `time` is in milliseconds since the UNIX epoch, `route` holds the
+//! routing frames in hexadecimal, and `key` the key of an authenticated
+//! client. A body which is not UTF-8 is kept in hexadecimal as `body_hex`.
+//! Requests of the [JSON-RPC bridge](../jsonrpc/index.html) are recorded
+//! once translated, the route being the identity of their connection.
+//!
+//! A recording is played again on a fresh core by
+//! [`replay`](../replay/index.html).
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+use toml::Value;
+use toml::value::Table;
+
+use crate::data::db::Error;
+use crate::data::time::Timestamp;
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum Direction {
+    /// Received by the core.
+    In,
+    /// Sent by the core.
+    Out,
+}
+
+impl Direction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Direction::In => "in",
+            Direction::Out => "out",
+        }
+    }
+
+    pub fn from_name(name : &str) -> Option<Direction> {
+        match name {
+            "in" => Some(Direction::In),
+            "out" => Some(Direction::Out),
+            _ => None,
+        }
+    }
+}
+
+/// A message received or sent by the core.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Frame {
+    pub time : Timestamp,
+    pub direction : Direction,
+    /// Frames before the body, the first one being the identity of the
+    /// client.
+    pub route : Vec<Vec<u8>>,
+    pub key : Option<String>,
+    pub body : Vec<u8>,
+}
+
+impl Frame {
+    pub fn to_table(&self) -> Table {
+        let mut ret = Table::new();
+        ret.insert(String::from("time"), Value::Integer(self.time.as_millis()));
+        ret.insert(String::from("direction"), Value::String(String::from(self.direction.name())));
+        ret.insert(String::from("route"), Value::Array(self.route.iter().map(|f| Value::String(to_hex(f))).collect()));
+        if let Some(key) = &self.key {
+            ret.insert(String::from("key"), Value::String(key.clone()));
+        }
+        match std::str::from_utf8(&self.body) {
+            Ok(t) => ret.insert(String::from("body"), Value::String(String::from(t))),
+            Err(_) => ret.insert(String::from("body_hex"), Value::String(to_hex(&self.body))),
+        };
+        ret
+    }
+
+    pub fn from_table(table : &Table) -> Result<Self, Error> {
+        let time = match table.get("time") {
+            Some(Value::Integer(t)) => Timestamp::from_millis(*t),
+            _ => return Err(invalid("time")),
+        };
+        let direction = match table.get("direction").and_then(Value::as_str).and_then(Direction::from_name) {
+            Some(t) => t,
+            None => return Err(invalid("direction")),
+        };
+        let route = match table.get("route") {
+            Some(Value::Array(t)) => t.iter().map(|f| f.as_str().and_then(from_hex)).collect::<Option<Vec<_>>>(),
+            _ => None,
+        };
+        let route = match route {
+            Some(t) => t,
+            None => return Err(invalid("route")),
+        };
+        let body = match (table.get("body"), table.get("body_hex")) {
+            (Some(Value::String(t)), _) => t.clone().into_bytes(),
+            (_, Some(Value::String(t))) => match from_hex(t) {
+                Some(t) => t,
+                None => return Err(invalid("body_hex")),
+            },
+            _ => return Err(invalid("body")),
+        };
+        let key = table.get("key").and_then(Value::as_str).map(String::from);
+        Ok(Self { time, direction, route, key, body })
+    }
+}
+
+pub struct Recorder {
+    file : File,
+}
+
+impl Recorder {
+    /// Records into `path`, replacing what it held.
+    pub fn create<P : AsRef<Path>>(path : P) -> Result<Self, Error> {
+        match File::create(path.as_ref()) {
+            Ok(file) => Ok(Self { file }),
+            Err(e) => Err(Error::new(Some(6018), Some(format!("Can not create {}: {}", path.as_ref().display(), e)))),
+        }
+    }
+
+    /// Appends `frame` to the recording.
+    pub fn record(&self, frame : &Frame) -> Result<(), Error> {
+        let mut document = Table::new();
+        document.insert(String::from("frames"), Value::Array(vec![Value::Table(frame.to_table())]));
+        // Each frame is a `[[frames]]` table, so the file stays a valid
+        // document as it grows.
+        let text = match toml::to_string(&Value::Table(document)) {
+            Ok(t) => t,
+            Err(e) => return Err(Error::new(Some(6002), Some(format!("Can not serialize frame: {}", e)))),
+        };
+        match (&self.file).write_all(format!("{}\n", text).as_bytes()) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::new(Some(6018), Some(format!("Can not record a frame: {}", e)))),
+        }
+    }
+
+    /// Records the message of `frames`, the last one being the body.
+    pub fn record_message(&self, direction : Direction, frames : &[Vec<u8>], key : Option<&str>) {
+        let (body, route) = match frames.split_last() {
+            Some(t) => t,
+            None => return,
+        };
+        let frame = Frame {
+            time: Timestamp::now(),
+            direction,
+            route: route.to_vec(),
+            key: key.map(String::from),
+            body: body.clone(),
+        };
+        // Recording is a debugging aid, it must not stop the server.
+        if let Err(e) = self.record(&frame) {
+            println!("Warning: {:?}", e);
+        }
+    }
+}
+
+/// Frames of the recording in `path`.
+pub fn read<P : AsRef<Path>>(path : P) -> Result<Vec<Frame>, Error> {
+    let text = match fs::read_to_string(path.as_ref()) {
+        Ok(t) => t,
+        Err(e) => return Err(Error::new(Some(6018), Some(format!("Can not read {}: {}", path.as_ref().display(), e)))),
+    };
+    let document = match text.parse::<Value>() {
+        Ok(Value::Table(t)) => t,
+        Ok(_) => return Err(invalid("document")),
+        Err(e) => return Err(Error::new(Some(6018), Some(format!("Invalid recording {}: {}", path.as_ref().display(), e)))),
+    };
+    match document.get("frames") {
+        Some(Value::Array(t)) => t.iter().map(|f| match f {
+            Value::Table(f) => Frame::from_table(f),
+            _ => Err(invalid("frames")),
+        }).collect(),
+        None => Ok(Vec::new()),
+        Some(_) => Err(invalid("frames")),
+    }
+}
+
+pub fn to_hex(bytes : &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(text : &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok()).collect()
+}
+
+fn invalid(field : &str) -> Error {
+    Error::new(Some(6018), Some(format!("Invalid recording: missing or invalid {}", field)))
+}
@@ -0,0 +1,332 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Replay of a recording
+//!
+//! A [`Replay`](struct.Replay.html) sends the requests of a
+//! [recording](../recorder/index.html) to a fresh core, one at a time and in
+//! their order, each client of the recording getting its own connection.
+//! The replies are compared with the recorded ones, which reproduces a bug
+//! report without the UI that sent the requests.
+//!
+//! The fresh core starts empty, or from a
+//! [snapshot](struct.Replay.html#method.from_snapshot) of the database and
+//! settings of the profile the recording was made on, to get the same
+//! replies.
+//!
+//! Keys changing with time, like dates, are not compared, see
+//! [`IGNORED`](constant.IGNORED.html). As requests are sent one by one, a
+//! request cancelled while it was running is not cancelled again. The
+//! fresh core does not authenticate its clients, so `auth.` methods fail.
+
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use toml::Value;
+use toml::value::Table;
+
+use crate::data::db::{Error, TableProvider};
+use crate::data::db::sqlite::SQLite;
+use crate::data::settings::{Settings, Store};
+use super::dispatch::Dispatcher;
+use super::message::{self, Request, Reply};
+use super::recorder::{Frame, Direction, to_hex};
+use super::server::{Server, socket_error};
+
+/// Keys of the replies not compared, as they change from one run to the
+/// other.
+pub const IGNORED : &[&str] = &["time", "date", "connected", "last_seen", "paired"];
+
+/// Time to wait for the core to answer, in milliseconds.
+const TIMEOUT : i64 = 3000;
+
+static NEXT_ENDPOINT : AtomicUsize = AtomicUsize::new(0);
+
+/// Replies of the fresh core differing from the recorded ones.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Mismatch {
+    /// Identity of the client in the recording, in hexadecimal.
+    pub client : String,
+    pub id : i64,
+    pub method : String,
+    /// Recorded replies, partial ones first.
+    pub expected : Vec<Table>,
+    pub actual : Vec<Table>,
+}
+
+#[derive(PartialEq, Debug, Default, Clone)]
+pub struct Report {
+    /// Number of requests sent again.
+    pub requests : usize,
+    pub mismatches : Vec<Mismatch>,
+}
+
+struct Client {
+    route : Vec<Vec<u8>>,
+    socket : zmq::Socket,
+}
+
+pub struct Replay<T : TableProvider> {
+    server : Server<T>,
+    endpoint : String,
+    clients : Vec<Client>,
+    ignored : Vec<String>,
+    // Sockets must be closed before their context.
+    context : zmq::Context,
+}
+
+impl Replay<SQLite> {
+    /// Replay on a core with an empty in-memory database and default
+    /// settings.
+    pub fn new() -> Result<Self, Error> {
+        Self::from_snapshot(None, None)
+    }
+
+    /// Replay on a core starting from a copy of the database `database` and
+    /// of the settings file `settings`, which are never written. Without
+    /// them, the database is empty and the settings are the default ones.
+    pub fn from_snapshot(database : Option<&Path>, settings : Option<&Path>) -> Result<Self, Error> {
+        let db = match database {
+            Some(path) => SQLite::copy_in_memory(path),
+            None => SQLite::new(":memory:"),
+        };
+        let db = db?;
+        // Parsed rather than loaded, so the settings are not bound to the
+        // file and changing them does not write it.
+        let settings = match settings {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(t) => Settings::parse(&t),
+                Err(e) => Err(Error::new(Some(3005), Some(format!("Can not read {}: {}", path.display(), e)))),
+            },
+            None => Ok(Settings::new()),
+        };
+        let settings = settings?;
+        Self::with_dispatcher(Dispatcher::new(db, Arc::new(Mutex::new(Store::new(settings)))))
+    }
+}
+
+impl<T : TableProvider> Replay<T> {
+    pub fn with_dispatcher(dispatcher : Dispatcher<T>) -> Result<Self, Error> {
+        let context = zmq::Context::new();
+        let endpoint = format!("inproc://sielo-replay-{}", NEXT_ENDPOINT.fetch_add(1, Ordering::SeqCst));
+        match Server::bind(&context, &endpoint, dispatcher) {
+            Ok(server) => Ok(Self {
+                server,
+                endpoint,
+                clients: Vec::new(),
+                ignored: IGNORED.iter().map(|k| String::from(*k)).collect(),
+                context,
+            }),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn server(&mut self) -> &mut Server<T> {
+        &mut self.server
+    }
+
+    /// Does not compare the values of `key` either.
+    pub fn ignore(&mut self, key : &str) {
+        self.ignored.push(String::from(key));
+    }
+
+    /// Sends the requests of `frames` again and compares the replies.
+    pub fn run(&mut self, frames : &[Frame]) -> Result<Report, Error> {
+        let mut report = Report::default();
+        for (index, frame) in frames.iter().enumerate() {
+            if frame.direction != Direction::In || frame.route.is_empty() {
+                continue;
+            }
+            let (id, method) = match Request::from_bytes(&frame.body) {
+                Ok(t) => (t.id, t.method),
+                // The core answers broken requests with the id 0.
+                Err(_) => (0, String::new()),
+            };
+
+            let actual = self.exchange(&frame.route, &frame.body)?;
+            let expected = expected_replies(&frames[index + 1..], &frame.route, id);
+            let (actual, expected) = (self.normalize(actual), self.normalize(expected));
+            if actual != expected {
+                report.mismatches.push(Mismatch { client: to_hex(&frame.route[0]), id, method, expected, actual });
+            }
+            report.requests += 1;
+        }
+        Ok(report)
+    }
+
+    /// Sends `body` from the client of `route`, and returns its replies.
+    fn exchange(&mut self, route : &[Vec<u8>], body : &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        let index = match self.clients.iter().position(|c| c.route == route) {
+            Some(t) => t,
+            None => {
+                let socket = match self.context.socket(zmq::DEALER) {
+                    Ok(t) => t,
+                    Err(e) => return Err(socket_error("create the client socket", e)),
+                };
+                if let Err(e) = socket.set_linger(0).and_then(|_| socket.connect(&self.endpoint)) {
+                    return Err(socket_error(&format!("connect to {}", self.endpoint), e));
+                }
+                self.clients.push(Client { route: route.to_vec(), socket });
+                self.clients.len() - 1
+            },
+        };
+
+        // A `REQ` client puts an empty frame before the body.
+        let mut frames : Vec<&[u8]> = route[1..].iter().map(|f| &f[..]).collect();
+        frames.push(body);
+        if let Err(e) = self.clients[index].socket.send_multipart(frames, 0) {
+            return Err(socket_error("send a request", e));
+        }
+        match self.server.poll(TIMEOUT) {
+            Ok(true) => (),
+            Ok(false) => return Err(Error::new(Some(6007), Some(String::from("The server received nothing")))),
+            Err(e) => return Err(e),
+        }
+
+        let socket = &self.clients[index].socket;
+        let mut ret = Vec::new();
+        loop {
+            match socket.poll(zmq::POLLIN, 0) {
+                Ok(0) => return Ok(ret),
+                Ok(_) => (),
+                Err(e) => return Err(socket_error("poll the client socket", e)),
+            }
+            match socket.recv_multipart(0) {
+                Ok(mut t) => if let Some(body) = t.pop() {
+                    ret.push(body);
+                },
+                Err(e) => return Err(socket_error("receive a reply", e)),
+            }
+        }
+    }
+
+    fn normalize(&self, bodies : Vec<Vec<u8>>) -> Vec<Table> {
+        bodies.iter().map(|b| {
+            let mut table = match message::decode(b) {
+                Ok(t) => t,
+                Err(e) => message::error_table(&e),
+            };
+            remove_keys(&mut table, &self.ignored);
+            table
+        }).collect()
+    }
+}
+
+/// Bodies of the recorded replies to the request `id` of the client of
+/// `route`, up to the final one.
+fn expected_replies(frames : &[Frame], route : &[Vec<u8>], id : i64) -> Vec<Vec<u8>> {
+    let mut ret = Vec::new();
+    for frame in frames.iter().filter(|f| f.direction == Direction::Out && f.route == route) {
+        match Reply::from_bytes(&frame.body) {
+            Ok(t) if t.id == id => {
+                ret.push(frame.body.clone());
+                if !t.partial {
+                    break;
+                }
+            },
+            _ => (),
+        }
+    }
+    ret
+}
+
+fn remove_keys(table : &mut Table, keys : &[String]) {
+    for key in keys {
+        table.remove(key);
+    }
+    for (_, value) in table.iter_mut() {
+        remove_value_keys(value, keys);
+    }
+}
+
+fn remove_value_keys(value : &mut Value, keys : &[String]) {
+    match value {
+        Value::Table(t) => remove_keys(t, keys),
+        Value::Array(t) => for v in t {
+            remove_value_keys(v, keys);
+        },
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::data::history::{History, Filter};
+    use crate::data::time::Timestamp;
+    use crate::ipc::harness::Harness;
+    use crate::ipc::recorder::{self, Recorder};
+    use super::*;
+
+    const SETTINGS : &str = "[appearance]\ntheme = \"dark\"\n";
+
+    fn directory(name : &str) -> PathBuf {
+        let ret = std::env::temp_dir().join(format!("sielo-replay-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&ret);
+        fs::create_dir_all(&ret).unwrap();
+        ret
+    }
+
+    fn key(value : &str) -> Table {
+        let mut ret = Table::new();
+        ret.insert(String::from("key"), Value::String(String::from(value)));
+        ret
+    }
+
+    /// Profile with one visit and the dark theme, and a recording of a core
+    /// started from it.
+    fn profile(directory : &Path) -> (PathBuf, PathBuf, Vec<Frame>) {
+        let (database, settings, recording) = (directory.join("history.db"), directory.join("settings.toml"), directory.join("record.toml"));
+        {
+            let mut db = SQLite::new(&database).unwrap();
+            History::new(&mut db).unwrap().add("https://sielo.app/", "Sielo", Timestamp::from_millis(1000)).unwrap();
+        }
+        fs::write(&settings, SETTINGS).unwrap();
+
+        let store = Store::new(Settings::parse(SETTINGS).unwrap());
+        let dispatcher = Dispatcher::new(SQLite::copy_in_memory(&database).unwrap(), Arc::new(Mutex::new(store)));
+        let mut harness = Harness::with_dispatcher(dispatcher).unwrap();
+        harness.server().set_recorder(Recorder::create(&recording).unwrap());
+        harness.call("history.entries", Table::new()).unwrap();
+        harness.call("settings.get", key("appearance.theme")).unwrap();
+        let mut params = Table::new();
+        params.insert(String::from("url"), Value::String(String::from("https://sielo.app/news")));
+        params.insert(String::from("title"), Value::String(String::from("News")));
+        harness.call("history.add", params).unwrap();
+        (database, settings, recorder::read(&recording).unwrap())
+    }
+
+    #[test]
+    fn snapshot_gives_the_recorded_replies() {
+        let directory = directory("snapshot");
+        let (database, settings, frames) = profile(&directory);
+
+        let mut replay = Replay::from_snapshot(Some(&database), Some(&settings)).unwrap();
+        let report = replay.run(&frames).unwrap();
+        assert_eq!(report.requests, 3);
+        assert_eq!(report.mismatches, vec![]);
+
+        // The snapshot is left as it was.
+        let mut db = SQLite::new(&database).unwrap();
+        assert_eq!(History::new(&mut db).unwrap().entries(&Filter::default()).unwrap().len(), 1);
+        assert_eq!(fs::read_to_string(&settings).unwrap(), SETTINGS);
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn empty_core_differs() {
+        let directory = directory("empty");
+        let (_, _, frames) = profile(&directory);
+
+        let report = Replay::new().unwrap().run(&frames).unwrap();
+        assert_eq!(report.requests, 3);
+        let methods : Vec<&str> = report.mismatches.iter().map(|m| &*m.method).collect();
+        assert_eq!(methods, vec!["history.entries", "settings.get", "history.add"]);
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
@@ -0,0 +1,622 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! IPC server of the core
+//!
+//! UI clients connect to a ZeroMQ `ROUTER` socket with a `DEALER` or `REQ`
+//! socket. Every message they send is a [request](../message/index.html),
+//! answered with a single reply routed back to the same client. Both
+//! `DEALER` messages (`[identity, body]`) and `REQ` ones
+//! (`[identity, "", body]`) are understood: the reply reuses every frame of
+//! the request but the body.
+//!
+//! Requests are answered one at a time. While a request runs, the server
+//! keeps reading its socket for a `core.cancel` of the request, and answers
+//! the other messages afterwards.
+//!
+//! Every message can be [recorded](../recorder/index.html) to debug the
+//! exchanges with a UI.
+//!
+//! With the `jsonrpc` feature, the server also answers the clients of a
+//! [JSON-RPC bridge](../jsonrpc/index.html) between two messages.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use toml::Value;
+use toml::value::Table;
+
+use crate::data::db::{Error, TableProvider};
+use crate::data::profile::Profile;
+use crate::data::time::Timestamp;
+use super::auth::{self, Allowlist, KeyPair};
+use super::dispatch::{Dispatcher, Caller};
+use super::message::{self, Request, Reply};
+#[cfg(all(feature = "jsonrpc", unix))]
+use super::jsonrpc::Bridge;
+use super::recorder::{Recorder, Direction};
+use super::stream::{self, Stream};
+
+/// Time to wait for a message before checking if the server must stop, in
+/// milliseconds.
+pub const POLL_INTERVAL : i64 = 100;
+/// Time between two looks for a cancellation while a request runs, in
+/// milliseconds.
+pub const CANCEL_INTERVAL : u64 = 10;
+/// Time given to the clients to say goodbye when the core stops, in
+/// milliseconds.
+pub const SHUTDOWN_GRACE : i64 = 2000;
+
+/// File of the profile directory giving the endpoint of the running core to
+/// its clients.
+pub const ENDPOINT_FILE : &str = "core.endpoint";
+
+/// Frames of a message, and the key of the client which sent it.
+type Message = (Vec<Vec<u8>>, Option<String>);
+
+/// Endpoint the core of `profile` binds: a socket file in the profile
+/// directory, or any free local TCP port where ZeroMQ has no IPC transport.
+pub fn endpoint_for(profile : &Profile) -> String {
+    if cfg!(windows) {
+        String::from("tcp://127.0.0.1:*")
+    } else {
+        format!("ipc://{}", profile.directory.join("core.ipc").display())
+    }
+}
+
+/// Writes `endpoint`, as bound by the core, into `file` of the profile
+/// directory for [`find_endpoint`](fn.find_endpoint.html).
+pub fn write_endpoint(profile : &Profile, file : &str, endpoint : &str) -> Result<(), Error> {
+    let path = profile.directory.join(file);
+    if let Err(e) = fs::write(&path, endpoint) {
+        return Err(Error::new(Some(6006), Some(format!("Can not write {}: {}", path.display(), e))));
+    }
+    Ok(())
+}
+
+/// Endpoint written in `file` of the profile directory by the running core,
+/// or `default` where it does not depend on the run. TCP ports are picked
+/// when the core starts, so the file is required on Windows.
+pub fn find_endpoint(profile : &Profile, file : &str, default : String) -> Result<String, Error> {
+    let path = profile.directory.join(file);
+    match fs::read_to_string(&path) {
+        Ok(t) if !t.trim().is_empty() => Ok(String::from(t.trim())),
+        _ if !cfg!(windows) => Ok(default),
+        _ => Err(Error::new(Some(6006), Some(format!("Can not read the endpoint of the core in {}", path.display())))),
+    }
+}
+
+/// Endpoint a socket is bound to, with the port picked by the system for a
+/// `tcp://host:*` endpoint.
+pub fn bound_endpoint(socket : &zmq::Socket) -> Result<String, Error> {
+    match socket.get_last_endpoint() {
+        Ok(Ok(t)) => Ok(t),
+        Ok(Err(_)) => Err(Error::new(Some(6006), Some(String::from("Endpoint is not UTF-8")))),
+        Err(e) => Err(socket_error("read the endpoint", e)),
+    }
+}
+
+pub struct Server<T : TableProvider> {
+    socket : zmq::Socket,
+    dispatcher : Dispatcher<T>,
+    allowlist : Option<Arc<Mutex<Allowlist>>>,
+    /// Messages received while a request was running.
+    backlog : VecDeque<Message>,
+    recorder : Option<Recorder>,
+    shutdown_grace : i64,
+    #[cfg(all(feature = "jsonrpc", unix))]
+    bridge : Option<Bridge>,
+}
+
+impl<T : TableProvider> Server<T> {
+    /// Server answering on `endpoint` with `dispatcher`.
+    pub fn bind(context : &zmq::Context, endpoint : &str, dispatcher : Dispatcher<T>) -> Result<Self, Error> {
+        Self::open(context, endpoint, dispatcher, None)
+    }
+
+    /// Server answering on `endpoint` with `dispatcher`, to the clients of
+    /// `allowlist` only. An [`Authenticator`](../auth/struct.Authenticator.html)
+    /// must run in `context`.
+    pub fn bind_secure(context : &zmq::Context, endpoint : &str, dispatcher : Dispatcher<T>,
+                       keys : &KeyPair, allowlist : Arc<Mutex<Allowlist>>) -> Result<Self, Error> {
+        Self::open(context, endpoint, dispatcher, Some((keys, allowlist)))
+    }
+
+    fn open(context : &zmq::Context, endpoint : &str, dispatcher : Dispatcher<T>,
+            security : Option<(&KeyPair, Arc<Mutex<Allowlist>>)>) -> Result<Self, Error> {
+        let socket = match context.socket(zmq::ROUTER) {
+            Ok(t) => t,
+            Err(e) => return Err(socket_error("create the IPC socket", e)),
+        };
+        // A client reconnecting with the same identity replaces its old
+        // connection, which may not be seen as closed yet.
+        if let Err(e) = socket.set_linger(0).and_then(|_| socket.set_router_handover(true)) {
+            return Err(socket_error("configure the IPC socket", e));
+        }
+        let allowlist = match security {
+            Some((keys, allowlist)) => match auth::secure_server(&socket, keys, auth::CORE_DOMAIN) {
+                Ok(_) => Some(allowlist),
+                Err(e) => return Err(e),
+            },
+            None => None,
+        };
+        if let Err(e) = socket.bind(endpoint) {
+            return Err(socket_error(&format!("bind {}", endpoint), e));
+        }
+        Ok(Self {
+            socket,
+            dispatcher,
+            allowlist,
+            backlog: VecDeque::new(),
+            recorder: None,
+            shutdown_grace: SHUTDOWN_GRACE,
+            #[cfg(all(feature = "jsonrpc", unix))]
+            bridge: None,
+        })
+    }
+
+    pub fn dispatcher(&mut self) -> &mut Dispatcher<T> {
+        &mut self.dispatcher
+    }
+
+    pub fn socket(&self) -> &zmq::Socket {
+        &self.socket
+    }
+
+    /// Endpoint the server is bound to, for the clients.
+    pub fn endpoint(&self) -> Result<String, Error> {
+        bound_endpoint(&self.socket)
+    }
+
+    /// Time given to the clients to say goodbye when the core stops, in
+    /// milliseconds, [`SHUTDOWN_GRACE`](constant.SHUTDOWN_GRACE.html) by
+    /// default.
+    pub fn set_shutdown_grace(&mut self, grace : i64) {
+        self.shutdown_grace = grace;
+    }
+
+    /// Records every message received or sent from now on.
+    pub fn set_recorder(&mut self, recorder : Recorder) {
+        self.recorder = Some(recorder);
+    }
+
+    /// Answers the clients of `bridge` too.
+    #[cfg(all(feature = "jsonrpc", unix))]
+    pub fn set_bridge(&mut self, bridge : Bridge) {
+        self.bridge = Some(bridge);
+    }
+
+    /// Waits up to `timeout` milliseconds for a message, and answers it.
+    /// Returns `false` if no message came. Messages of the bridge are all
+    /// answered as they come.
+    pub fn poll(&mut self, timeout : i64) -> Result<bool, Error> {
+        let (frames, key) = match self.backlog.pop_front() {
+            Some(t) => {
+                self.dispatcher.expire_clients(Timestamp::now());
+                t
+            },
+            None => {
+                let ready = self.wait(timeout);
+                self.dispatcher.expire_clients(Timestamp::now());
+                match ready {
+                    Ok((true, _)) => (),
+                    Ok((false, bridged)) => return Ok(bridged),
+                    Err(e) => return Err(e),
+                }
+                receive(&self.socket, self.recorder.as_ref())?
+            },
+        };
+        match self.handle(frames, key.as_deref()) {
+            Ok(_) => Ok(true),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Answers requests until `running` becomes `false` or a client calls
+    /// `core.shutdown`, then tells the clients the core stops.
+    ///
+    /// Clients are still answered afterwards, until every one of them said
+    /// goodbye with `clients.bye` or the
+    /// [grace period](#method.set_shutdown_grace) is over.
+    pub fn run(&mut self, running : &AtomicBool) -> Result<(), Error> {
+        while running.load(Ordering::SeqCst) && !self.dispatcher.is_stopping() {
+            if let Err(e) = self.poll(POLL_INTERVAL) {
+                self.dispatcher.shutdown("error");
+                return Err(e);
+            }
+        }
+        self.dispatcher.shutdown(if running.load(Ordering::SeqCst) { "requested" } else { "stopped" });
+
+        let deadline = Instant::now() + Duration::from_millis(self.shutdown_grace.max(0) as u64);
+        while !self.dispatcher.clients().list().is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            self.poll(((deadline - now).as_millis() as i64).min(POLL_INTERVAL))?;
+        }
+        Ok(())
+    }
+
+    /// Waits up to `timeout` milliseconds for a message. Returns whether
+    /// the socket has one, and whether messages of the bridge were answered.
+    #[cfg(not(all(feature = "jsonrpc", unix)))]
+    fn wait(&mut self, timeout : i64) -> Result<(bool, bool), Error> {
+        match self.socket.poll(zmq::POLLIN, timeout) {
+            Ok(t) => Ok((t != 0, false)),
+            Err(e) => Err(socket_error("poll the IPC socket", e)),
+        }
+    }
+
+    #[cfg(all(feature = "jsonrpc", unix))]
+    fn wait(&mut self, timeout : i64) -> Result<(bool, bool), Error> {
+        let bridge = match self.bridge.as_mut() {
+            Some(t) => t,
+            None => return match self.socket.poll(zmq::POLLIN, timeout) {
+                Ok(t) => Ok((t != 0, false)),
+                Err(e) => Err(socket_error("poll the IPC socket", e)),
+            },
+        };
+        let fds = bridge.fds();
+        let mut items = vec![self.socket.as_poll_item(zmq::POLLIN)];
+        items.extend(fds.iter().map(|fd| zmq::PollItem::from_fd(*fd, zmq::POLLIN)));
+        if let Err(e) = zmq::poll(&mut items, timeout) {
+            return Err(socket_error("poll the IPC sockets", e));
+        }
+        let readable : Vec<_> = fds.iter().zip(&items[1..]).filter(|(_, i)| i.is_readable()).map(|(fd, _)| *fd).collect();
+        let ready = items[0].is_readable();
+        if !readable.is_empty() {
+            bridge.handle(&readable, &mut self.dispatcher, self.recorder.as_ref());
+        }
+        Ok((ready, !readable.is_empty()))
+    }
+
+    fn handle(&mut self, mut frames : Vec<Vec<u8>>, key : Option<&str>) -> Result<(), Error> {
+        // A message with only the identity of the client is dropped.
+        let body = match frames.pop() {
+            Some(t) if !frames.is_empty() => t,
+            _ => return Ok(()),
+        };
+
+        let reply = match Request::from_bytes(&body) {
+            Ok(t) => match self.allowlist.as_ref().and_then(|a| auth::handle(a, key, &t)) {
+                Some(result) => Reply::to(&t, result),
+                None => {
+                    let job = Job {
+                        socket: &self.socket,
+                        recorder: self.recorder.as_ref(),
+                        route: &frames,
+                        key,
+                        request: &t,
+                        cancelled: Cell::new(false),
+                        checked: Cell::new(Instant::now()),
+                        backlog: RefCell::new(Vec::new()),
+                    };
+                    let reply = self.dispatcher.dispatch_stream(&Caller { identity: &frames[0], key }, &t, &job);
+                    self.backlog.extend(job.backlog.into_inner());
+                    reply
+                },
+            },
+            // The id can not be known, 0 is never used by clients.
+            Err(e) => Reply::new(message::VERSION, 0, "", Err(e)),
+        };
+        let body = match reply.to_bytes() {
+            Ok(t) => t,
+            Err(e) => Reply::new(reply.version, reply.id, &reply.method, Err(e)).to_bytes()?,
+        };
+
+        frames.push(body);
+        send(&self.socket, self.recorder.as_ref(), frames)
+    }
+}
+
+/// Request being answered, which its client can cancel.
+struct Job<'a> {
+    socket : &'a zmq::Socket,
+    recorder : Option<&'a Recorder>,
+    /// Frames of the request before the body.
+    route : &'a [Vec<u8>],
+    key : Option<&'a str>,
+    request : &'a Request,
+    cancelled : Cell<bool>,
+    checked : Cell<Instant>,
+    backlog : RefCell<Vec<Message>>,
+}
+
+impl<'a> Job<'a> {
+    /// Whether `message` cancels the request, in which case it is answered.
+    fn cancels(&self, message : &Message) -> bool {
+        let (frames, key) = message;
+        if frames.len() != self.route.len() + 1 || frames[..self.route.len()] != *self.route
+           || key.as_ref().map(String::as_str) != self.key {
+            return false;
+        }
+        let cancel = match Request::from_bytes(&frames[self.route.len()]) {
+            Ok(t) => t,
+            Err(_) => return false,
+        };
+        if cancel.method != "core.cancel" || cancel.optional_integer("id") != Ok(Some(self.request.id)) {
+            return false;
+        }
+
+        let mut ret = Table::new();
+        ret.insert(String::from("cancelled"), Value::Boolean(true));
+        if let Err(e) = self.reply(Reply::to(&cancel, Ok(Value::Table(ret)))) {
+            println!("Warning: {:?}", e);
+        }
+        true
+    }
+
+    fn reply(&self, reply : Reply) -> Result<(), Error> {
+        let body = reply.to_bytes()?;
+        let mut frames = self.route.to_vec();
+        frames.push(body);
+        send(self.socket, self.recorder, frames)
+    }
+}
+
+impl<'a> Stream for Job<'a> {
+    /// `REQ` clients, whose route ends with an empty frame, can only take
+    /// one reply.
+    fn is_streaming(&self) -> bool {
+        self.request.stream && self.route.last().map(|f| !f.is_empty()).unwrap_or(false)
+    }
+
+    fn send(&self, payload : Table) -> Result<(), Error> {
+        if self.is_cancelled() {
+            return Err(stream::cancelled());
+        }
+        if !self.is_streaming() {
+            return Ok(());
+        }
+        let mut reply = Reply::to(self.request, Ok(Value::Table(payload)));
+        reply.partial = true;
+        self.reply(reply)
+    }
+
+    /// Reads the messages received since the last look, keeping the ones
+    /// not cancelling the request for later.
+    fn is_cancelled(&self) -> bool {
+        if self.cancelled.get() || self.checked.get().elapsed() < Duration::from_millis(CANCEL_INTERVAL) {
+            return self.cancelled.get();
+        }
+        self.checked.set(Instant::now());
+        while let Ok(1) = self.socket.poll(zmq::POLLIN, 0) {
+            let message = match receive(self.socket, self.recorder) {
+                Ok(t) => t,
+                Err(e) => {
+                    println!("Warning: {:?}", e);
+                    break;
+                },
+            };
+            if self.cancels(&message) {
+                self.cancelled.set(true);
+            } else {
+                self.backlog.borrow_mut().push(message);
+            }
+        }
+        self.cancelled.get()
+    }
+}
+
+/// Receives a message. Frames are read one by one to get the key of the
+/// client, attached to them by the authentication.
+fn receive(socket : &zmq::Socket, recorder : Option<&Recorder>) -> Result<Message, Error> {
+    let mut frames = Vec::new();
+    loop {
+        let mut frame = match socket.recv_msg(0) {
+            Ok(t) => t,
+            Err(e) => return Err(socket_error("receive a request", e)),
+        };
+        frames.push(frame.to_vec());
+        if !frame.get_more() {
+            let key = frame.gets("User-Id").filter(|t| !t.is_empty()).map(String::from);
+            if let Some(t) = recorder {
+                t.record_message(Direction::In, &frames, key.as_deref());
+            }
+            return Ok((frames, key));
+        }
+    }
+}
+
+fn send(socket : &zmq::Socket, recorder : Option<&Recorder>, frames : Vec<Vec<u8>>) -> Result<(), Error> {
+    if let Some(t) = recorder {
+        t.record_message(Direction::Out, &frames, None);
+    }
+    match socket.send_multipart(frames, 0) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(socket_error("send a reply", e)),
+    }
+}
+
+pub fn socket_error(action : &str, error : zmq::Error) -> Error {
+    Error::new(Some(6006), Some(format!("Can not {}: {}", action, error)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use toml::Value;
+    use toml::value::Table;
+
+    use crate::data::db::{TableProvider, FieldValue};
+    use crate::data::db::sqlite::SQLite;
+    use crate::data::history::{self, History};
+    use crate::data::profile::Profile;
+    use crate::data::settings::{Settings, Store};
+    use crate::ipc::client::Client;
+    use crate::ipc::connection::{Connection, Options};
+    use crate::ipc::dispatch::Dispatcher;
+    use crate::ipc::message::{Request, Reply};
+    use crate::ipc::stream::{CHUNK, CANCELLED};
+    use super::{Server, ENDPOINT_FILE, write_endpoint, find_endpoint};
+
+    /// Runs a server until a client asks it to stop, the client saying
+    /// goodbye after `bye` milliseconds if given. Returns the time the
+    /// server took to stop after the request.
+    fn shutdown(endpoint : &'static str, bye : Option<u64>) -> Duration {
+        let context = zmq::Context::new();
+        let dispatcher = Dispatcher::new(SQLite::new(":memory:").unwrap(), Arc::new(Mutex::new(Store::new(Settings::new()))));
+        let mut server = Server::bind(&context, endpoint, dispatcher).unwrap();
+        server.set_shutdown_grace(1000);
+
+        let client_context = context.clone();
+        let client = thread::spawn(move || {
+            let mut connection = Connection::connect(&client_context, endpoint, Options::default()).unwrap();
+            connection.call("core.shutdown", Table::new()).unwrap().result.unwrap();
+            let asked = Instant::now();
+            if let Some(t) = bye {
+                thread::sleep(Duration::from_millis(t));
+                connection.close().unwrap();
+            }
+            asked
+        });
+
+        server.run(&AtomicBool::new(true)).unwrap();
+        let stopped = Instant::now();
+        let asked = client.join().unwrap();
+        assert_eq!(server.dispatcher().clients().list().is_empty(), bye.is_some());
+        stopped - asked
+    }
+
+    #[test]
+    fn run_waits_for_goodbyes() {
+        let waited = shutdown("inproc://sielo-server-bye", Some(200));
+        assert!(waited >= Duration::from_millis(200), "{:?}", waited);
+        assert!(waited < Duration::from_millis(900), "{:?}", waited);
+    }
+
+    #[test]
+    fn run_gives_up_after_the_grace_period() {
+        let waited = shutdown("inproc://sielo-server-grace", None);
+        assert!(waited >= Duration::from_millis(900), "{:?}", waited);
+    }
+
+    #[test]
+    fn clients_find_the_port_picked_by_the_core() {
+        let directory = std::env::temp_dir().join(format!("sielo-endpoint-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let profile = Profile { name: String::from("default"), directory: directory.clone() };
+        assert_eq!(find_endpoint(&profile, ENDPOINT_FILE, String::from("ipc://default")).unwrap(), "ipc://default");
+
+        let context = zmq::Context::new();
+        let dispatcher = Dispatcher::new(SQLite::new(":memory:").unwrap(), Arc::new(Mutex::new(Store::new(Settings::new()))));
+        let mut server = Server::bind(&context, "tcp://127.0.0.1:*", dispatcher).unwrap();
+        server.set_shutdown_grace(0);
+        let endpoint = server.endpoint().unwrap();
+        assert!(endpoint.starts_with("tcp://127.0.0.1:") && !endpoint.ends_with('*'), "{}", endpoint);
+        write_endpoint(&profile, ENDPOINT_FILE, &endpoint).unwrap();
+        assert_eq!(find_endpoint(&profile, ENDPOINT_FILE, String::from("ipc://default")).unwrap(), endpoint);
+
+        let client_context = context.clone();
+        let client = thread::spawn(move || {
+            Client::for_profile(&client_context, &profile, None).unwrap().shutdown().wait()
+        });
+        server.run(&AtomicBool::new(true)).unwrap();
+        client.join().unwrap().unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    /// Server on `endpoint` with `visits` entries in its history, and a
+    /// client connected to it.
+    fn server_with_history(context : &zmq::Context, endpoint : &str, visits : usize) -> (Server<SQLite>, zmq::Socket) {
+        let mut db = SQLite::new(":memory:").unwrap();
+        History::new(&mut db).unwrap();
+        db.request(&format!("WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < {}) \
+                             INSERT INTO {} (url, title, mime_type, date) \
+                             SELECT 'https://example.org/' || i, 'Page', 'text/html', i FROM n;", visits, history::TABLE), &[]).unwrap();
+        let server = Server::bind(context, endpoint, Dispatcher::new(db, Arc::new(Mutex::new(Store::new(Settings::new()))))).unwrap();
+
+        let client = context.socket(zmq::DEALER).unwrap();
+        client.set_linger(0).unwrap();
+        client.set_rcvtimeo(1000).unwrap();
+        client.connect(endpoint).unwrap();
+        (server, client)
+    }
+
+    fn send(client : &zmq::Socket, id : i64, method : &str, params : &[(&str, Value)], stream : bool) {
+        let mut request = Request::new(id, method, params.iter().map(|(k, v)| (String::from(*k), v.clone())).collect());
+        request.stream = stream;
+        client.send(request.to_bytes().unwrap(), 0).unwrap();
+    }
+
+    fn receive(client : &zmq::Socket) -> Reply {
+        Reply::from_bytes(&client.recv_bytes(0).unwrap()).unwrap()
+    }
+
+    fn visits(server : &mut Server<SQLite>) -> i64 {
+        let rows = server.dispatcher().db().request(&format!("SELECT COUNT(*) AS n FROM {};", history::TABLE), &[]).unwrap();
+        match rows[0].get("n") {
+            Some(Some(FieldValue::Integer(t))) => *t,
+            _ => panic!("no count"),
+        }
+    }
+
+    #[test]
+    fn entries_are_streamed_in_chunks() {
+        let context = zmq::Context::new();
+        let (mut server, client) = server_with_history(&context, "inproc://sielo-server-chunks", 2 * CHUNK + 5);
+        send(&client, 1, "history.entries", &[], true);
+        assert!(server.poll(1000).unwrap());
+
+        let mut sizes = Vec::new();
+        loop {
+            let reply = receive(&client);
+            assert_eq!(reply.id, 1);
+            sizes.push(reply.result.unwrap()["entries"].as_array().unwrap().len());
+            if !reply.partial {
+                break;
+            }
+        }
+        assert_eq!(sizes, vec![CHUNK, CHUNK, 5]);
+    }
+
+    #[test]
+    fn cancelled_requests_are_rolled_back() {
+        let context = zmq::Context::new();
+        let (mut server, client) = server_with_history(&context, "inproc://sielo-server-cancel", 200_000);
+        send(&client, 1, "history.clear", &[("from", Value::Integer(0)), ("to", Value::Integer(i64::MAX))], true);
+        send(&client, 2, "core.cancel", &[("id", Value::Integer(1))], false);
+        assert!(server.poll(1000).unwrap());
+
+        let cancel = receive(&client);
+        assert_eq!((cancel.id, cancel.result.unwrap()["cancelled"].as_bool()), (2, Some(true)));
+        let reply = receive(&client);
+        assert_eq!((reply.id, reply.result.unwrap_err().code()), (1, Some(CANCELLED)));
+        assert_eq!(visits(&mut server), 200_000);
+
+        send(&client, 3, "db.integrity_check", &[], true);
+        send(&client, 4, "core.cancel", &[("id", Value::Integer(3))], false);
+        assert!(server.poll(1000).unwrap());
+        assert_eq!(receive(&client).id, 4);
+        let reply = receive(&client);
+        assert_eq!((reply.id, reply.result.unwrap_err().code()), (3, Some(CANCELLED)));
+    }
+
+    #[test]
+    fn requests_received_meanwhile_are_answered_in_order() {
+        let context = zmq::Context::new();
+        let (mut server, client) = server_with_history(&context, "inproc://sielo-server-backlog", 200_000);
+        send(&client, 1, "db.integrity_check", &[], true);
+        for id in 2..5 {
+            send(&client, id, "core.ping", &[], false);
+        }
+        for _ in 1..5 {
+            assert!(server.poll(1000).unwrap());
+        }
+
+        let ids : Vec<i64> = (1..5).map(|_| receive(&client).id).collect();
+        assert_eq!(ids, vec![1, 2, 3, 4]);
+        assert!(!server.poll(0).unwrap());
+    }
+}
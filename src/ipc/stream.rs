@@ -0,0 +1,88 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Partial replies and cancellation of long requests
+//!
+//! A request sent with `stream = true` gets
+//! [partial replies](../message/index.html#streaming) before the final one:
+//!  * `history.entries` sends the entries by chunks of
+//!    [`CHUNK`](constant.CHUNK.html) in `entries`, the final reply holding
+//!    the last ones. The whole result is every chunk put together.
+//!  * `history.import` sends its progress as `progress`, a table with the
+//!    `stage`, and the number of entries `done` out of `total`.
+//!
+//! A client stops one of its requests with `core.cancel`, giving the `id` of
+//! the request:
+//!
+//! ```toml
+//! version = 1
+//! id = 13
+//! method = "core.cancel"
+//!
+//! [payload]
+//! id = 12
+//! ```
+//!
+//! The reply tells whether the request was still running. If it was, the
+//! core aborts the database request in progress and the request fails with
+//! [`CANCELLED`](constant.CANCELLED.html), unless it was already done.
+//! Changes of a cancelled request are rolled back.
+
+use toml::Value;
+use toml::value::Table;
+
+use crate::data::db::Error;
+
+/// The request was cancelled by its client.
+pub const CANCELLED : isize = 6017;
+
+/// Number of history entries in a partial reply.
+pub const CHUNK : usize = 100;
+
+/// Where a request sends its partial replies, and learns it was cancelled.
+pub trait Stream {
+    /// Whether the client asked for partial replies. Methods can then send
+    /// their results as they come instead of all at once.
+    fn is_streaming(&self) -> bool;
+
+    /// Sends a partial reply, if the client asked for them. Fails with
+    /// [`CANCELLED`](constant.CANCELLED.html) once the request is
+    /// cancelled, so the method can stop.
+    fn send(&self, payload : Table) -> Result<(), Error>;
+
+    fn is_cancelled(&self) -> bool;
+}
+
+/// Stream of in-process requests, which are never streamed nor cancelled.
+pub struct NoStream;
+
+impl Stream for NoStream {
+    fn is_streaming(&self) -> bool {
+        false
+    }
+
+    fn send(&self, _ : Table) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn is_cancelled(&self) -> bool {
+        false
+    }
+}
+
+/// Payload of a partial reply giving the progress of a request.
+pub fn progress(stage : &str, done : usize, total : usize) -> Table {
+    let mut progress = Table::new();
+    progress.insert(String::from("stage"), Value::String(String::from(stage)));
+    progress.insert(String::from("done"), Value::Integer(done as i64));
+    progress.insert(String::from("total"), Value::Integer(total as i64));
+    let mut ret = Table::new();
+    ret.insert(String::from("progress"), Value::Table(progress));
+    ret
+}
+
+pub fn cancelled() -> Error {
+    Error::new(Some(CANCELLED), Some(String::from("The request was cancelled")))
+}
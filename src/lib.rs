@@ -0,0 +1,18 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Core of the Sielo browser.
+//! It is made of:
+//!  * [Data management]() of the profiles: history, bookmarks, settings...
+//!  * [IPC channel]() the user interfaces talk to the core through, with the
+//!    [client]() front ends use to talk to it, and a [harness]() tests use
+//!    to drive it.
+//!
+//! The `sielo-core` binary runs the core of a profile.
+
+extern crate sqlite;
+
+pub mod data;
+pub mod ipc;
@@ -1,38 +1,188 @@
-extern crate sqlite;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicBool;
 
-mod data;
-use data::db::{TableProvider, FieldType, FieldParameter};
+use sielo_core::{data, ipc};
 
 fn main() {
     println!("  _________.__       .__                        ____.                    .__\n /   _____/|__| ____ |  |   ____               |    | ____   ____   ____ |__| _________.__. ______\n \\_____  \\ |  |/ __ \\|  |  /  _ \\   ______     |    |/ __ \\ /    \\ /    \\|  |/  ___<   |  |/  ___/\n /        \\|  \\  ___/|  |_(  <_> ) /_____/ /\\__|    \\  ___/|   |  \\   |  \\  |\\___ \\ \\___  |\\___ \\\n/_______  /|__|\\___  >____/\\____/          \\________|\\___  >___|  /___|  /__/____  >/ ____/____  >\n        \\/         \\/                                    \\/     \\/     \\/        \\/ \\/         \\/");
 
-    let mut connection = data::db::sqlite::SQLite::new("./demo.db").ok().unwrap();
-    //let mut connection = data::db::sqlite::SQLite::new(":memory:").ok().unwrap();
+    let arguments = match arguments::parse(std::env::args()) {
+        Ok(t) => t,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
 
-    match connection.use_table("history", &[
-        ("id", &FieldType::Integer, &[FieldParameter::AutoIncrement]),
-        ("mime_type", &FieldType::Text, &[FieldParameter::Default(String::from("sielo/unknown"))]),
-        ("url", &FieldType::Text, &[FieldParameter::NoNull]),
-        ("date", &FieldType::Text, &[FieldParameter::Default(String::from("0"))]),
-        ("title", &FieldType::Text, &[FieldParameter::Default(String::new())]),
-        ("favicon", &FieldType::Blob, &[]),
-        ("parent", &FieldType::Integer, &[]),
-        ("children", &FieldType::Blob, &[]),
-    ], false, false) {
-        Ok(t) => (),
+    let mut profiles = match data::profile::ProfileManager::open(data::profile::ProfileManager::default_root()) {
+        Ok(t) => t,
+        Err(e) => {
+            println!("{:?}", e);
+            return;
+        }
+    };
+    let profile = match arguments.get::<String>("profile") {
+        Some(name) => match profiles.get(&name) {
+            Some(t) => Ok(t.clone()),
+            None => profiles.create(&name),
+        },
+        None => match profiles.default_profile() {
+            Some(t) => Ok(t.clone()),
+            None => profiles.create("default"),
+        },
+    };
+    let profile = match profile {
+        Ok(t) => t,
+        Err(e) => {
+            println!("{:?}", e);
+            return;
+        }
+    };
+    let _lock = match profiles.lock(&profile.name) {
+        Ok(t) => t,
         Err(e) => {
             println!("{:?}", e);
+            return;
         }
+    };
+
+    let mut layers = data::settings::Layers::new();
+    if let Err(e) = layers.load_policy(data::settings::layers::POLICY_PATH) {
+        println!("{:?}", e);
     }
+    if let Err(e) = layers.add_arguments(&arguments) {
+        println!("{:?}", e);
+    }
+    for i in layers.issues() {
+        println!("Warning: {}: {}", i.key, i.message);
+    }
+    let settings = match data::settings::Settings::load(profile.settings_path()) {
+        Ok(t) => t,
+        Err(e) => {
+            // Stay bound to the file, so it is reloaded once fixed and
+            // changes made meanwhile are saved.
+            println!("Warning: using the default settings: {:?}", e);
+            let mut t = data::settings::Settings::new();
+            t.set_path(profile.settings_path());
+            t
+        }
+    };
+    let settings = Arc::new(Mutex::new(data::settings::Store::with_layers(settings, layers)));
+    let _watcher = data::settings::Watcher::start(settings.clone(), data::settings::watcher::DEFAULT_INTERVAL);
 
-    /*let mut connection = sqlite::Connection::open("./demo.db").unwrap();
+    let mut connection = data::db::sqlite::SQLite::new(profile.database_path()).ok().unwrap();
+    //let mut connection = data::db::sqlite::SQLite::new(":memory:").ok().unwrap();
 
     match data::history::History::new(&mut connection) {
+        Ok(_) => (),
+        Err(e) => {
+            println!("{:?}", e);
+        }
+    }
+    match data::favicon::Favicons::new(&mut connection) {
+        Ok(mut t) => if let Err(e) = t.remove_expired(data::time::Timestamp::now()) {
+            println!("{:?}", e);
+        },
+        Err(e) => println!("{:?}", e),
+    }
+
+    let keys = match ipc::auth::KeyPair::for_profile(&profile) {
+        Ok(t) => t,
+        Err(e) => {
+            println!("{:?}", e);
+            return;
+        }
+    };
+    let allowlist = match ipc::auth::Allowlist::for_profile(&profile) {
+        Ok(t) => t,
+        Err(e) => {
+            println!("{:?}", e);
+            return;
+        }
+    };
+    let allowlist = Arc::new(Mutex::new(allowlist));
+    if let Ok(mut a) = allowlist.lock() {
+        // Without any paired client, the first UI must be able to pair.
+        if a.clients().is_empty() || arguments.get::<bool>("pair").unwrap_or(false) {
+            match a.start_pairing(ipc::auth::PAIRING_DURATION) {
+                Ok(code) => println!("Pairing code: {}", code),
+                Err(e) => println!("{:?}", e),
+            }
+        }
+    }
+
+    let context = zmq::Context::new();
+    let _authenticator = match ipc::auth::Authenticator::start(&context, allowlist.clone()) {
+        Ok(t) => t,
+        Err(e) => {
+            println!("{:?}", e);
+            return;
+        }
+    };
+    let endpoint = ipc::server::endpoint_for(&profile);
+    let mut dispatcher = ipc::dispatch::Dispatcher::new(connection, settings.clone());
+    let events_endpoint = ipc::events::endpoint_for(&profile);
+    match ipc::events::EventBus::bind_secure(&context, &events_endpoint, &keys) {
         Ok(t) => {
-            println!("Oki");
+            match t.endpoint() {
+                Ok(e) => match ipc::server::write_endpoint(&profile, ipc::events::ENDPOINT_FILE, &e) {
+                    Ok(_) => println!("Publishing events on {}", e),
+                    Err(e) => println!("{:?}", e),
+                },
+                Err(e) => println!("{:?}", e),
+            }
+            let bus = Arc::new(Mutex::new(t));
+            if let Ok(mut s) = settings.lock() {
+                s.add_broadcaster(Box::new(bus.clone()));
+            }
+            dispatcher.set_events(bus);
         },
+        Err(e) => println!("{:?}", e),
+    }
+    let mut server = match ipc::server::Server::bind_secure(&context, &endpoint, dispatcher, &keys, allowlist) {
+        Ok(t) => t,
+        Err(e) => {
+            println!("{:?}", e);
+            return;
+        }
+    };
+    if let Some(path) = arguments.get::<String>("record") {
+        match ipc::recorder::Recorder::create(&path) {
+            Ok(t) => {
+                server.set_recorder(t);
+                println!("Recording messages to {}", path);
+            },
+            Err(e) => println!("{:?}", e),
+        }
+    }
+    #[cfg(all(feature = "jsonrpc", unix))]
+    {
+        if arguments.get::<bool>("jsonrpc").unwrap_or(false) {
+            match ipc::jsonrpc::Bridge::bind(ipc::jsonrpc::path_for(&profile)) {
+                Ok(t) => {
+                    println!("Answering JSON-RPC on {}", t.path().display());
+                    server.set_bridge(t);
+                },
+                Err(e) => println!("{:?}", e),
+            }
+        }
+    }
+    // The port is only known once bound where the endpoint is TCP.
+    let endpoint = match server.endpoint() {
+        Ok(t) => t,
         Err(e) => {
             println!("{:?}", e);
+            return;
         }
-    }*/
+    };
+    if let Err(e) = ipc::server::write_endpoint(&profile, ipc::server::ENDPOINT_FILE, &endpoint) {
+        println!("{:?}", e);
+        return;
+    }
+    println!("Listening on {} with key {}", endpoint, keys.public);
+
+    let running = AtomicBool::new(true);
+    if let Err(e) = server.run(&running) {
+        println!("{:?}", e);
+    }
 }
@@ -0,0 +1,86 @@
+// This code is published under the terms of the GNU GPL license.
+// This license requires you to comply with these conditions in order to be valid:
+//  * Sharing a modified version of sielo-core require you to share the source code.
+//  * Work on program that communicates with the core no needs a GPL compliant license. You are free
+
+//! Requests sent to a real server through the IPC harness.
+
+use toml::Value;
+use toml::value::Table;
+
+use sielo_core::ipc::dispatch::UNKNOWN_METHOD;
+use sielo_core::ipc::harness::Harness;
+
+fn params(values : &[(&str, Value)]) -> Table {
+    values.iter().map(|(k, v)| (String::from(*k), v.clone())).collect()
+}
+
+fn text(value : &str) -> Value {
+    Value::String(String::from(value))
+}
+
+#[test]
+fn history_add_then_entries() {
+    let mut harness = Harness::new().unwrap();
+    for (i, url) in ["https://a.org/", "https://b.org/rust", "https://c.org/rust"].iter().enumerate() {
+        let reply = harness.call("history.add", params(&[
+            ("url", text(url)),
+            ("title", text("Page")),
+            ("date", Value::Integer(1000 + i as i64)),
+        ])).unwrap();
+        assert!(reply.result.unwrap().get("id").and_then(Value::as_integer).is_some());
+    }
+
+    let reply = harness.call("history.entries", params(&[("text", text("rust"))])).unwrap();
+    assert_eq!(reply.method, "history.entries");
+    let payload = reply.result.unwrap();
+    let mut urls : Vec<&str> = payload["entries"].as_array().unwrap().iter()
+        .map(|e| e["url"].as_str().unwrap())
+        .collect();
+    urls.sort();
+    assert_eq!(urls, vec!["https://b.org/rust", "https://c.org/rust"]);
+
+    let reply = harness.call("history.entries", Table::new()).unwrap();
+    assert_eq!(reply.result.unwrap()["entries"].as_array().unwrap().len(), 3);
+}
+
+#[test]
+fn settings_set_then_get() {
+    let mut harness = Harness::new().unwrap();
+    let reply = harness.call("settings.get", params(&[("key", text("appearance.theme"))])).unwrap();
+    let payload = reply.result.unwrap();
+    assert_eq!(payload["value"], text("system"));
+    assert_eq!(payload["locked"], Value::Boolean(false));
+
+    let reply = harness.call("settings.set", params(&[("key", text("appearance.theme")), ("value", text("dark"))])).unwrap();
+    let changes = reply.result.unwrap()["changes"].as_array().unwrap().clone();
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0]["old"], text("system"));
+    assert_eq!(changes[0]["new"], text("dark"));
+
+    let reply = harness.call("settings.get", params(&[("key", text("appearance.theme"))])).unwrap();
+    assert_eq!(reply.result.unwrap()["value"], text("dark"));
+
+    let reply = harness.call("settings.set", params(&[("key", text("appearance.theme")), ("value", text("pink"))])).unwrap();
+    assert!(reply.result.is_err());
+}
+
+#[test]
+fn unknown_method_is_refused() {
+    let mut harness = Harness::new().unwrap();
+    let reply = harness.call("history.frobnicate", Table::new()).unwrap();
+    assert_eq!(reply.id, 1);
+    assert_eq!(reply.result.unwrap_err().code(), Some(UNKNOWN_METHOD));
+}
+
+#[test]
+fn malformed_body_is_refused() {
+    let mut harness = Harness::new().unwrap();
+    let reply = harness.send_raw(b"this is = not [toml").unwrap();
+    assert_eq!(reply.id, 0);
+    assert_eq!(reply.result.unwrap_err().code(), Some(6001));
+
+    // The server keeps answering after it.
+    let reply = harness.call("settings.get", params(&[("key", text("general.language"))])).unwrap();
+    assert_eq!(reply.result.unwrap()["value"], text("en"));
+}